@@ -15,10 +15,10 @@ use rsipstack::{
     EndpointBuilder, Error,
 };
 use std::{env, sync::Arc, time::Duration};
+use tokio::select;
 use tokio::sync::mpsc::unbounded_channel;
-use tokio::{select, time::sleep};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, info};
+use tracing::info;
 mod play_file;
 mod realtime;
 mod stun;
@@ -190,6 +190,7 @@ async fn main() -> rsipstack::Result<()> {
     let credential = Credential {
         username: sip_username.clone(),
         password: sip_password,
+        ..Default::default()
     };
 
     let incoming = endpoint.incoming_transactions();
@@ -236,6 +237,11 @@ async fn main() -> rsipstack::Result<()> {
                     offer: None,
                     contact: contact.clone(),
                     credential: Some(credential.clone()),
+                    session_timer: None,
+                    replaces: None,
+                    call_id: None,
+                    from_tag: None,
+                    routes: vec![],
                 };
 
                 match make_call(dialog_layer, invite_option, opt, state_sender).await {
@@ -271,16 +277,7 @@ async fn process_registration(
     }
 
     let mut registration = Registration::new(endpoint, Some(credential));
-    loop {
-        let resp = registration.register(&sip_server).await?;
-        debug!("received response: {}", resp.to_string());
-        if resp.status_code != rsip::StatusCode::OK {
-            return Err(rsipstack::Error::Error("Failed to register".to_string()));
-        }
-        sleep(Duration::from_secs(registration.expires().max(50) as u64)).await;
-    }
-    #[allow(unreachable_code)]
-    Ok::<_, Error>(())
+    registration.serve(&sip_server, cancel_token).await
 }
 
 async fn process_incoming_request(
@@ -333,6 +330,17 @@ async fn process_incoming_request(
                     Ok::<_, Error>(())
                 });
             }
+            rsip::Method::Message => {
+                info!(
+                    "Received message: {:?} {}",
+                    tx.original.headers.iter().find_map(|h| match h {
+                        rsip::Header::ContentType(c) => Some(c.to_string()),
+                        _ => None,
+                    }),
+                    String::from_utf8_lossy(&tx.original.body)
+                );
+                tx.reply(rsip::StatusCode::OK).await?;
+            }
             _ => {
                 info!("Received request: {:?}", tx.original.method);
                 tx.reply(rsip::StatusCode::OK).await?;
@@ -369,14 +377,16 @@ async fn process_dialog(
                     Dialog::ClientInvite(_) => {
                         info!("Client invite dialog {}", id);
                     }
+                    Dialog::ClientSubscribe(_) => {
+                        info!("Client subscribe dialog {}", id);
+                    }
                 }
             }
             DialogState::Early(id, resp) => {
                 info!("Early dialog {} {}", id, resp);
             }
-            DialogState::Terminated(id, status_code) => {
+            DialogState::Terminated(id, status_code, _) => {
                 info!("Dialog terminated {} {:?}", id, status_code);
-                dialog_layer.remove_dialog(&id);
             }
             _ => {
                 info!("Received dialog state: {}", state);
@@ -158,7 +158,7 @@ async fn process_incoming_request(mut incoming: TransactionReceiver) -> Result<(
                     .expect("client_transaction");
                 let mut ack_tx =
                     Transaction::new_client(key, ack_req, tx.endpoint_inner.clone(), None);
-                ack_tx.destination = Some(target.destination);
+                ack_tx.destinations = vec![target.destination];
                 ack_tx.send().await?;
             }
             _ => {
@@ -308,7 +308,7 @@ async fn handle_invite(state: Arc<AppState>, mut tx: Transaction) -> Result<()>
     info!("Forwarding INVITE to: {} -> {}", caller, target.destination);
 
     let mut inv_tx = Transaction::new_client(key, inv_req, tx.endpoint_inner.clone(), None);
-    inv_tx.destination = Some(target.destination);
+    inv_tx.destinations = vec![target.destination];
 
     // add Via
     inv_tx.send().await?;
@@ -387,7 +387,7 @@ async fn handle_bye(state: Arc<AppState>, mut tx: Transaction) -> Result<()> {
     info!("Forwarding BYE to: {} -> {}", caller, peer.destination);
 
     let mut bye_tx = Transaction::new_client(key, inv_req, tx.endpoint_inner.clone(), None);
-    bye_tx.destination = Some(peer.destination);
+    bye_tx.destinations = vec![peer.destination];
 
     bye_tx.send().await?;
 
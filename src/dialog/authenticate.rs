@@ -1,83 +1,415 @@
 use super::DialogId;
+use crate::rsip_ext::parse_via;
+use crate::transaction::endpoint::EndpointInner;
 use crate::transaction::key::{TransactionKey, TransactionRole};
 use crate::transaction::transaction::Transaction;
-use crate::transaction::{make_via_branch, random_text, CNONCE_LEN};
+use crate::transaction::{random_text, CNONCE_LEN};
 use crate::Result;
-use rsip::headers::auth::AuthQop;
-use rsip::prelude::{HasHeaders, HeadersExt, ToTypedHeader};
-use rsip::services::DigestGenerator;
-use rsip::typed::{Authorization, ProxyAuthorization};
+use md5::{Digest, Md5};
+use rsip::prelude::{HasHeaders, HeadersExt, UntypedHeader};
 use rsip::{Header, Param, Response};
+use sha2::Sha256;
+use std::collections::HashMap;
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Credential {
     pub username: String,
+    /// may be left empty when `ha1` holds the hashes
     pub password: String,
+    /// the realm these credentials belong to, they answer any realm when not set
+    pub realm: Option<String>,
+    /// precomputed `H(username:realm:password)`, used instead of the password
+    /// for the challenges of their algorithm
+    pub ha1: Vec<Ha1>,
+    /// answer with qop=auth-int, hashing the body, when the server offers it
+    pub prefer_auth_int: bool,
+    /// the credentials of other realms, e.g. an outbound proxy and the registrar
+    pub realms: HashMap<String, Credential>,
 }
 
-pub async fn handle_client_authenticate(
-    new_seq: u32,
-    tx: Transaction,
-    resp: Response,
+/// A precomputed HA1 (RFC 7616 3.4.2), what a credential store keeps
+/// instead of the password
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Ha1 {
+    Md5(String),
+    Sha256(String),
+}
+
+impl Ha1 {
+    pub fn new(algorithm: DigestAlgorithm, username: &str, realm: &str, password: &str) -> Self {
+        let ha1 = algorithm.hash(format!("{}:{}:{}", username, realm, password).as_bytes());
+        match algorithm {
+            DigestAlgorithm::Md5 => Ha1::Md5(ha1),
+            DigestAlgorithm::Sha256 => Ha1::Sha256(ha1),
+        }
+    }
+
+    pub fn algorithm(&self) -> DigestAlgorithm {
+        match self {
+            Ha1::Md5(_) => DigestAlgorithm::Md5,
+            Ha1::Sha256(_) => DigestAlgorithm::Sha256,
+        }
+    }
+
+    /// the hash in lower case hex
+    pub fn value(&self) -> &str {
+        match self {
+            Ha1::Md5(ha1) | Ha1::Sha256(ha1) => ha1,
+        }
+    }
+}
+
+impl Credential {
+    /// the precomputed HA1 of `algorithm`, if any
+    pub fn ha1(&self, algorithm: DigestAlgorithm) -> Option<&Ha1> {
+        self.ha1.iter().find(|h| h.algorithm() == algorithm)
+    }
+
+    /// whether a challenge of `algorithm` can be answered, a credential with
+    /// HA1s only answers their algorithms
+    pub fn can_answer(&self, algorithm: DigestAlgorithm) -> bool {
+        self.ha1.is_empty() || !self.password.is_empty() || self.ha1(algorithm).is_some()
+    }
+
+    /// the credential answering a challenge of `realm`
+    pub fn for_realm(&self, realm: &str) -> Option<&Credential> {
+        if let Some(cred) = self.realms.get(realm) {
+            return Some(cred);
+        }
+        match &self.realm {
+            Some(r) if r != realm => None,
+            _ => Some(self),
+        }
+    }
+}
+
+/// A Digest challenge from a WWW-Authenticate or Proxy-Authenticate header
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DigestChallenge {
+    pub realm: String,
+    pub nonce: String,
+    pub opaque: Option<String>,
+    pub algorithm: Option<String>,
+    /// the qop options offered, empty for an RFC 2069 challenge
+    pub qop: Vec<String>,
+    pub stale: bool,
+}
+
+impl DigestChallenge {
+    pub fn parse(value: &str) -> Result<Self> {
+        let value = value.trim();
+        let (scheme, params) = value.split_once(' ').unwrap_or((value, ""));
+        if !scheme.eq_ignore_ascii_case("Digest") {
+            return Err(crate::Error::Error(format!(
+                "unsupported auth scheme: {}",
+                scheme
+            )));
+        }
+        let mut challenge = DigestChallenge::default();
+        for (name, value) in parse_auth_params(params) {
+            match name.to_lowercase().as_str() {
+                "realm" => challenge.realm = value,
+                "nonce" => challenge.nonce = value,
+                "opaque" => challenge.opaque = Some(value),
+                "algorithm" => challenge.algorithm = Some(value),
+                "qop" => {
+                    challenge.qop = value
+                        .split(',')
+                        .map(|q| q.trim().to_lowercase())
+                        .filter(|q| !q.is_empty())
+                        .collect()
+                }
+                "stale" => challenge.stale = value.eq_ignore_ascii_case("true"),
+                _ => {}
+            }
+        }
+        if challenge.nonce.is_empty() {
+            return Err(crate::Error::Error(
+                "digest challenge without nonce".to_string(),
+            ));
+        }
+        Ok(challenge)
+    }
+
+    /// auth is preferred over auth-int unless the credential opts into it
+    pub fn select_qop(&self, prefer_auth_int: bool) -> Option<&'static str> {
+        let auth = self.qop.iter().any(|q| q == "auth");
+        let auth_int = self.qop.iter().any(|q| q == "auth-int");
+        match (auth, auth_int) {
+            (_, true) if prefer_auth_int || !auth => Some("auth-int"),
+            (true, _) => Some("auth"),
+            _ => None,
+        }
+    }
+}
+
+/// the `name=value` pairs of a challenge, values may be quoted and contain commas
+fn parse_auth_params(params: &str) -> Vec<(String, String)> {
+    let mut result = vec![];
+    let mut chars = params.chars().peekable();
+    loop {
+        while matches!(chars.peek(), Some(c) if *c == ',' || c.is_whitespace()) {
+            chars.next();
+        }
+        let name: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if name.trim().is_empty() {
+            break;
+        }
+        while matches!(chars.peek(), Some(c) if c.is_whitespace()) {
+            chars.next();
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+        } else {
+            value = chars.by_ref().take_while(|c| *c != ',').collect();
+        }
+        result.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    result
+}
+
+/// The hash of a digest challenge, ordered from the weakest to the strongest
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DigestAlgorithm {
+    Md5,
+    Sha256,
+}
+
+impl DigestAlgorithm {
+    /// the hash and whether it is the `-sess` variant, e.g. `SHA-256-sess`
+    pub fn parse(algorithm: &str) -> Option<(Self, bool)> {
+        let lower = algorithm.to_lowercase();
+        let (name, sess) = match lower.strip_suffix("-sess") {
+            Some(name) => (name, true),
+            None => (lower.as_str(), false),
+        };
+        match name {
+            "md5" => Some((DigestAlgorithm::Md5, sess)),
+            "sha-256" | "sha256" => Some((DigestAlgorithm::Sha256, sess)),
+            _ => None,
+        }
+    }
+
+    pub fn hash(&self, value: &[u8]) -> String {
+        match self {
+            DigestAlgorithm::Md5 => format!("{:x}", Md5::digest(value)),
+            DigestAlgorithm::Sha256 => format!("{:x}", Sha256::digest(value)),
+        }
+    }
+}
+
+impl DigestChallenge {
+    /// MD5 when the challenge names no algorithm (RFC 7616 3.3)
+    pub fn digest_algorithm(&self) -> Option<(DigestAlgorithm, bool)> {
+        match &self.algorithm {
+            Some(algorithm) => DigestAlgorithm::parse(algorithm),
+            None => Some((DigestAlgorithm::Md5, false)),
+        }
+    }
+}
+
+/// The digest `response` (RFC 7616 3.4.1), `qop` is (qop, cnonce, nc) when the
+/// challenge offered one
+pub fn compute_digest(
     cred: &Credential,
-) -> Result<Transaction> {
-    let header = match resp.www_authenticate_header() {
-        Some(h) => Header::WwwAuthenticate(h.clone()),
+    challenge: &DigestChallenge,
+    method: &str,
+    uri: &str,
+    qop: Option<(&str, &str, u32)>,
+    body: &[u8],
+) -> Result<String> {
+    let (algorithm, sess) = challenge
+        .digest_algorithm()
+        .ok_or(crate::Error::Error(format!(
+            "unsupported digest algorithm: {}",
+            challenge.algorithm.as_deref().unwrap_or_default()
+        )))?;
+    let h = |value: String| algorithm.hash(value.as_bytes());
+
+    let mut ha1 = match cred.ha1(algorithm) {
+        Some(ha1) => ha1.value().to_lowercase(),
+        None if cred.can_answer(algorithm) => h(format!(
+            "{}:{}:{}",
+            cred.username, challenge.realm, cred.password
+        )),
         None => {
-            let proxy_header = rsip::header_opt!(resp.headers().iter(), Header::ProxyAuthenticate);
-            let proxy_header = proxy_header.ok_or(crate::Error::DialogError(
-                "missing proxy/www authenticate".to_string(),
-                DialogId::try_from(&tx.original)?,
-            ))?;
-            Header::ProxyAuthenticate(proxy_header.clone())
+            return Err(crate::Error::Error(format!(
+                "no {:?} HA1 for realm: {}",
+                algorithm, challenge.realm
+            )))
         }
     };
+    if sess {
+        let cnonce = qop.map(|(_, cnonce, _)| cnonce).unwrap_or_default();
+        ha1 = h(format!("{}:{}:{}", ha1, challenge.nonce, cnonce));
+    }
+    let ha2 = match qop {
+        Some(("auth-int", _, _)) => h(format!("{}:{}:{}", method, uri, algorithm.hash(body))),
+        _ => h(format!("{}:{}", method, uri)),
+    };
+    let value = match qop {
+        Some((qop, cnonce, nc)) => format!(
+            "{}:{}:{:08x}:{}:{}:{}",
+            ha1, challenge.nonce, nc, cnonce, qop, ha2
+        ),
+        None => format!("{}:{}:{}", ha1, challenge.nonce, ha2),
+    };
+    Ok(h(value))
+}
 
-    let mut new_req = tx.original.clone();
-    new_req.cseq_header_mut()?.mut_seq(new_seq)?;
+/// The Digest challenges of the response, one per realm and header kind with
+/// the strongest algorithm we support, true if it came from a Proxy-Authenticate
+pub fn select_challenges(resp: &Response) -> Vec<(DigestChallenge, bool)> {
+    select_challenges_with(resp, |_, _| true)
+}
 
-    let auth_qop = AuthQop::Auth {
-        cnonce: random_text(CNONCE_LEN),
-        nc: 1,
-    };
+/// Like [`select_challenges`], skipping the algorithms the credential of a
+/// realm has no HA1 for when it holds no password
+pub fn select_challenges_for(resp: &Response, cred: &Credential) -> Vec<(DigestChallenge, bool)> {
+    select_challenges_with(resp, |challenge, algorithm| {
+        cred.for_realm(&challenge.realm)
+            .is_none_or(|cred| cred.can_answer(algorithm))
+    })
+}
 
-    let challenge = match &header {
-        Header::WwwAuthenticate(h) => h.typed()?,
-        Header::ProxyAuthenticate(h) => h.typed()?.0,
-        _ => unreachable!(),
-    };
+fn select_challenges_with(
+    resp: &Response,
+    usable: impl Fn(&DigestChallenge, DigestAlgorithm) -> bool,
+) -> Vec<(DigestChallenge, bool)> {
+    let mut selected: Vec<(DigestAlgorithm, DigestChallenge, bool)> = vec![];
+    for h in resp.headers().iter() {
+        let (value, proxy) = match h {
+            Header::WwwAuthenticate(h) => (h.value(), false),
+            Header::ProxyAuthenticate(h) => (h.value(), true),
+            _ => continue,
+        };
+        let challenge = match DigestChallenge::parse(value) {
+            Ok(challenge) => challenge,
+            Err(_) => continue,
+        };
+        let algorithm = match challenge.digest_algorithm() {
+            Some((algorithm, _)) if usable(&challenge, algorithm) => algorithm,
+            _ => continue,
+        };
+        match selected
+            .iter_mut()
+            .find(|(_, c, p)| *p == proxy && c.realm == challenge.realm)
+        {
+            Some(entry) if algorithm > entry.0 => *entry = (algorithm, challenge, proxy),
+            Some(_) => {}
+            None => selected.push((algorithm, challenge, proxy)),
+        }
+    }
+    selected
+        .into_iter()
+        .map(|(_, challenge, proxy)| (challenge, proxy))
+        .collect()
+}
 
-    let response = DigestGenerator {
-        username: cred.username.as_str(),
-        password: cred.password.as_str(),
-        algorithm: challenge.algorithm.unwrap_or_default(),
-        nonce: challenge.nonce.as_str(),
-        method: &tx.original.method,
-        qop: Some(&auth_qop),
-        uri: &tx.original.uri,
-        realm: challenge.realm.as_str(),
-    }
-    .compute();
-
-    let auth = Authorization {
-        scheme: challenge.scheme,
-        username: cred.username.clone(),
-        realm: challenge.realm,
-        nonce: challenge.nonce,
-        uri: tx.original.uri.clone(),
-        response,
-        algorithm: challenge.algorithm,
-        opaque: challenge.opaque,
-        qop: Some(auth_qop),
-    };
+/// The value of the Authorization or Proxy-Authorization answering `challenge`
+pub fn make_authorization(
+    cred: &Credential,
+    challenge: &DigestChallenge,
+    req: &rsip::Request,
+    nc: u32,
+) -> Result<String> {
+    let method = req.method.to_string();
+    let uri = req.uri.to_string();
+    let qop = challenge.select_qop(cred.prefer_auth_int);
+    let cnonce = random_text(CNONCE_LEN);
+    let response = compute_digest(
+        cred,
+        challenge,
+        &method,
+        &uri,
+        qop.map(|qop| (qop, cnonce.as_str(), nc)),
+        &req.body,
+    )?;
+
+    let mut auth = format!(
+        "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{}\"",
+        cred.username, challenge.realm, challenge.nonce, uri, response
+    );
+    if let Some(algorithm) = &challenge.algorithm {
+        auth.push_str(&format!(", algorithm={}", algorithm));
+    }
+    if let Some(opaque) = &challenge.opaque {
+        auth.push_str(&format!(", opaque=\"{}\"", opaque));
+    }
+    if let Some(qop) = qop {
+        auth.push_str(&format!(
+            ", qop={}, nc={:08x}, cnonce=\"{}\"",
+            qop, nc, cnonce
+        ));
+    }
+    Ok(auth)
+}
+
+/// true if the server rejected the nonce we answered, not the credentials (RFC 7616 3.3)
+pub fn is_stale(resp: &Response) -> bool {
+    select_challenges(resp).iter().any(|(c, _)| c.stale)
+}
+
+/// Authorize `req` with the challenges answered before, so it is not challenged
+/// again; the nonce-count of each nonce keeps increasing
+pub fn preauthorize(
+    req: &mut rsip::Request,
+    cred: &Credential,
+    challenges: &[(DigestChallenge, bool)],
+    endpoint_inner: &EndpointInner,
+) -> Result<()> {
+    for (challenge, proxy) in challenges {
+        let realm_cred = match cred.for_realm(&challenge.realm) {
+            Some(realm_cred) => realm_cred,
+            None => continue,
+        };
+        let nc = endpoint_inner.next_nonce_count(&challenge.nonce);
+        let auth = make_authorization(realm_cred, challenge, req, nc)?;
+        match proxy {
+            false => req
+                .headers_mut()
+                .push(rsip::headers::Authorization::new(auth).into()),
+            true => req
+                .headers_mut()
+                .push(rsip::headers::ProxyAuthorization::new(auth).into()),
+        }
+    }
+    Ok(())
+}
 
-    let via_header = tx.original.via_header()?.clone();
+/// Retry the request of `tx` answering every challenge of `resp`, each with
+/// the credential of its realm
+pub async fn handle_client_authenticate(
+    new_seq: u32,
+    tx: Transaction,
+    resp: Response,
+    cred: &Credential,
+) -> Result<Transaction> {
+    let challenges = select_challenges_for(&resp, cred);
+    if challenges.is_empty() {
+        return Err(crate::Error::DialogError(
+            "missing proxy/www authenticate".to_string(),
+            DialogId::try_from(&tx.original)?,
+        ));
+    }
+
+    let mut new_req = tx.original.clone();
+    new_req.cseq_header_mut()?.mut_seq(new_seq)?;
 
-    // update new branch
-    let mut params = via_header.params().clone()?;
-    params.push(make_via_branch());
-    params.push(Param::Other("rport".into(), None));
-    new_req.headers_mut().unique_push(via_header.into());
+    // the retry is a new transaction
+    let mut via = parse_via(tx.original.via_header()?)?;
+    via.params.retain(|p| !matches!(p, Param::Branch(_)));
+    via.params.push(tx.endpoint_inner.generate_branch());
+    new_req.headers_mut().unique_push(via.into());
 
     new_req.headers_mut().retain(|h| {
         !matches!(
@@ -89,23 +421,233 @@ pub async fn handle_client_authenticate(
         )
     });
 
-    match header {
-        Header::WwwAuthenticate(_) => {
-            new_req.headers_mut().unique_push(auth.into());
-        }
-        Header::ProxyAuthenticate(_) => {
-            new_req
+    for (challenge, proxy) in challenges {
+        let realm_cred = match cred.for_realm(&challenge.realm) {
+            Some(realm_cred) => realm_cred,
+            None => {
+                return Err(crate::Error::DialogError(
+                    format!("no credential for realm: {}", challenge.realm),
+                    DialogId::try_from(&tx.original)?,
+                ))
+            }
+        };
+        let nc = tx.endpoint_inner.next_nonce_count(&challenge.nonce);
+        let auth = make_authorization(realm_cred, &challenge, &new_req, nc)?;
+        match proxy {
+            false => new_req
                 .headers_mut()
-                .unique_push(ProxyAuthorization(auth).into());
+                .push(rsip::headers::Authorization::new(auth).into()),
+            true => new_req
+                .headers_mut()
+                .push(rsip::headers::ProxyAuthorization::new(auth).into()),
         }
-        _ => unreachable!(),
     }
+
     let key = TransactionKey::from_request(&new_req, TransactionRole::Client)?;
-    let new_tx = Transaction::new_client(
+    let mut new_tx = Transaction::new_client(
         key,
         new_req,
         tx.endpoint_inner.clone(),
         tx.connection.clone(),
     );
+    new_tx.destinations = tx.destination().cloned().into_iter().collect();
     Ok(new_tx)
 }
+
+#[test]
+fn test_digest_qop_auth() {
+    // RFC 2617 3.5
+    let cred = Credential {
+        username: "Mufasa".to_string(),
+        password: "Circle Of Life".to_string(),
+        ..Default::default()
+    };
+    let challenge = DigestChallenge::parse(
+        "Digest realm=\"testrealm@host.com\", qop=\"auth,auth-int\", \
+         nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", opaque=\"5ccc069c403ebaf9f0171e9517f40e41\"",
+    )
+    .expect("parse challenge");
+    assert_eq!(challenge.realm, "testrealm@host.com");
+    assert_eq!(challenge.qop, vec!["auth", "auth-int"]);
+    assert_eq!(challenge.select_qop(false), Some("auth"));
+    assert_eq!(challenge.select_qop(true), Some("auth-int"));
+
+    let response = compute_digest(
+        &cred,
+        &challenge,
+        "GET",
+        "/dir/index.html",
+        Some(("auth", "0a4f113b", 1)),
+        &[],
+    )
+    .expect("compute digest");
+    assert_eq!(response, "6629fae49393a05397450978507c4ef1");
+
+    let challenge = DigestChallenge::parse("Digest realm=\"atlanta.com\", nonce=\"84a4cc6f\"")
+        .expect("parse challenge");
+    assert_eq!(challenge.select_qop(false), None);
+}
+
+#[test]
+fn test_select_challenge_realm() {
+    let resp = Response {
+        status_code: rsip::StatusCode::ProxyAuthenticationRequired,
+        headers: vec![
+            rsip::headers::ProxyAuthenticate::new(
+                "Digest realm=\"atlanta.com\", nonce=\"1\", qop=\"auth\"",
+            )
+            .into(),
+            rsip::headers::WwwAuthenticate::new(
+                "Digest realm=\"biloxi.com\", nonce=\"2\", qop=\"auth\"",
+            )
+            .into(),
+        ]
+        .into(),
+        ..Default::default()
+    };
+    let challenges = select_challenges(&resp);
+    assert_eq!(challenges.len(), 2);
+    assert_eq!(
+        (challenges[0].0.realm.as_str(), challenges[0].1),
+        ("atlanta.com", true)
+    );
+    assert_eq!(
+        (challenges[1].0.realm.as_str(), challenges[1].1),
+        ("biloxi.com", false)
+    );
+
+    let mut cred = Credential {
+        username: "bob".to_string(),
+        password: "zanzibar".to_string(),
+        realm: Some("biloxi.com".to_string()),
+        ..Default::default()
+    };
+    assert!(cred.for_realm("atlanta.com").is_none());
+    cred.realms.insert(
+        "atlanta.com".to_string(),
+        Credential {
+            username: "bob-proxy".to_string(),
+            password: "secret".to_string(),
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        cred.for_realm("atlanta.com").map(|c| c.username.as_str()),
+        Some("bob-proxy")
+    );
+    assert_eq!(
+        cred.for_realm("biloxi.com").map(|c| c.username.as_str()),
+        Some("bob")
+    );
+}
+
+#[test]
+fn test_digest_rfc7616() {
+    // RFC 7616 3.9.1
+    let cred = Credential {
+        username: "Mufasa".to_string(),
+        password: "Circle of Life".to_string(),
+        ..Default::default()
+    };
+    let mut challenge = DigestChallenge {
+        realm: "http-auth@example.org".to_string(),
+        nonce: "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v".to_string(),
+        opaque: Some("FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS".to_string()),
+        algorithm: Some("MD5".to_string()),
+        qop: vec!["auth".to_string(), "auth-int".to_string()],
+        stale: false,
+    };
+    let qop = Some(("auth", "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ", 1));
+    let response = compute_digest(&cred, &challenge, "GET", "/dir/index.html", qop, &[])
+        .expect("compute MD5 digest");
+    assert_eq!(response, "8ca523f5e9506fed4657c9700eebdbec");
+
+    challenge.algorithm = Some("SHA-256".to_string());
+    let response = compute_digest(&cred, &challenge, "GET", "/dir/index.html", qop, &[])
+        .expect("compute SHA-256 digest");
+    assert_eq!(
+        response,
+        "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1"
+    );
+
+    assert_eq!(
+        DigestAlgorithm::parse("SHA-256-sess"),
+        Some((DigestAlgorithm::Sha256, true))
+    );
+    challenge.algorithm = Some("SHA-512-256".to_string());
+    assert!(compute_digest(&cred, &challenge, "GET", "/", qop, &[]).is_err());
+}
+
+#[test]
+fn test_select_strongest_challenge() {
+    let resp = Response {
+        status_code: rsip::StatusCode::Unauthorized,
+        headers: vec![
+            rsip::headers::WwwAuthenticate::new(
+                "Digest realm=\"atlanta.com\", nonce=\"1\", algorithm=MD5, qop=\"auth\"",
+            )
+            .into(),
+            rsip::headers::WwwAuthenticate::new(
+                "Digest realm=\"atlanta.com\", nonce=\"2\", algorithm=SHA-256, qop=\"auth\"",
+            )
+            .into(),
+            rsip::headers::WwwAuthenticate::new(
+                "Digest realm=\"atlanta.com\", nonce=\"3\", algorithm=SHA-512-256, qop=\"auth\"",
+            )
+            .into(),
+        ]
+        .into(),
+        ..Default::default()
+    };
+    let challenges = select_challenges(&resp);
+    assert_eq!(challenges.len(), 1);
+    assert_eq!(challenges[0].0.nonce, "2");
+}
+
+#[test]
+fn test_digest_ha1() {
+    // RFC 2617 3.5, with the HA1 a credential store keeps
+    let ha1 = Ha1::new(
+        DigestAlgorithm::Md5,
+        "Mufasa",
+        "testrealm@host.com",
+        "Circle Of Life",
+    );
+    assert_eq!(ha1.value(), "939e7578ed9e3c518a452acee763bce9");
+    let cred = Credential {
+        username: "Mufasa".to_string(),
+        ha1: vec![ha1],
+        ..Default::default()
+    };
+    let mut challenge = DigestChallenge::parse(
+        "Digest realm=\"testrealm@host.com\", qop=\"auth\", nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\"",
+    )
+    .expect("parse challenge");
+    let qop = Some(("auth", "0a4f113b", 1));
+    let response = compute_digest(&cred, &challenge, "GET", "/dir/index.html", qop, &[])
+        .expect("compute digest");
+    assert_eq!(response, "6629fae49393a05397450978507c4ef1");
+
+    // no password to fall back on
+    challenge.algorithm = Some("SHA-256".to_string());
+    assert!(!cred.can_answer(DigestAlgorithm::Sha256));
+    assert!(compute_digest(&cred, &challenge, "GET", "/", qop, &[]).is_err());
+
+    let resp = Response {
+        status_code: rsip::StatusCode::Unauthorized,
+        headers: vec![
+            rsip::headers::WwwAuthenticate::new(
+                "Digest realm=\"testrealm@host.com\", nonce=\"1\", algorithm=MD5",
+            )
+            .into(),
+            rsip::headers::WwwAuthenticate::new(
+                "Digest realm=\"testrealm@host.com\", nonce=\"2\", algorithm=SHA-256",
+            )
+            .into(),
+        ]
+        .into(),
+        ..Default::default()
+    };
+    assert_eq!(select_challenges(&resp)[0].0.nonce, "2");
+    assert_eq!(select_challenges_for(&resp, &cred)[0].0.nonce, "1");
+}
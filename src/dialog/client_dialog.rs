@@ -1,13 +1,42 @@
-use super::dialog::DialogInnerRef;
+use super::dialog::{is_dialog_header, DialogInnerRef};
+use super::session_timer::{
+    min_se, min_se_header, parse_session_expires, start_session_timer, Refresher, SessionTimer,
+    MIN_SESSION_EXPIRES,
+};
+use super::subscription::SubscriptionState;
 use super::DialogId;
-use crate::dialog::{authenticate::handle_client_authenticate, dialog::DialogState};
+use crate::dialog::{
+    authenticate::{handle_client_authenticate, select_challenges_for},
+    dialog::DialogState,
+};
+use crate::rsip_ext::{
+    contact_values, extract_sdp, extract_uri_from_contact, has_required, make_refer_to,
+    parse_contact, reason_headers, DtmfEvent, Reason, RsipHeadersExt, DTMF_RELAY,
+};
+use crate::transaction::key::{TransactionKey, TransactionRole};
 use crate::transaction::transaction::Transaction;
 use crate::Result;
-use rsip::prelude::HeadersExt;
-use rsip::{Response, SipMessage, StatusCode, StatusCodeKind};
-use std::sync::atomic::Ordering;
+use rsip::prelude::{HeadersExt, UntypedHeader};
+use rsip::{Header, Request, Response, SipMessage, StatusCode, StatusCodeKind};
+use std::{collections::HashMap, sync::atomic::Ordering, time::Duration};
+use tokio::{select, sync::oneshot};
 use tokio_util::sync::CancellationToken;
-use tracing::{info, info_span, trace};
+use tracing::{info, instrument, trace, Instrument};
+
+/// How an INVITE follows the Contacts of a 3xx (RFC 3261 8.1.3.4), see
+/// [`EndpointInner::set_redirect_policy`](crate::transaction::endpoint::EndpointInner::set_redirect_policy)
+#[derive(Clone, Debug)]
+pub struct RedirectPolicy {
+    /// the INVITEs sent after the first one, the last 3xx ends the dialog
+    /// once they are used up
+    pub max_redirects: u32,
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> Self {
+        Self { max_redirects: 5 }
+    }
+}
 
 #[derive(Clone)]
 pub struct ClientInviteDialog {
@@ -24,56 +53,426 @@ impl ClientInviteDialog {
     }
 
     pub async fn bye(&self) -> Result<()> {
+        self.bye_with(None, None).await
+    }
+
+    /// Hang up with an optional Reason header (RFC 3326).
+    ///
+    /// The dialog is terminated locally when the BYE gets no final response
+    /// within `timeout`. A dialog that is not confirmed yet is cancelled instead.
+    pub async fn bye_with(&self, reason: Option<Reason>, timeout: Option<Duration>) -> Result<()> {
+        let headers = reason.map(|r| vec![r.into()]).unwrap_or_default();
+        self.bye_with_headers(headers, timeout).await
+    }
+
+    /// Hang up with extra headers, e.g. a `P-Asserted-Identity` or `X-` header.
+    ///
+    /// The Via, Call-ID, From, To, CSeq, Route and Content-Length headers of the
+    /// dialog can not be replaced and are dropped from `headers`.
+    pub async fn bye_with_headers(
+        &self,
+        headers: Vec<Header>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
         if !self.inner.is_confirmed() {
-            return Ok(());
+            return self.cancel_with(headers, timeout).await;
         }
-        let request = self
+        self.send_bye(headers, timeout).await
+    }
+
+    /// Cancel the INVITE in progress.
+    ///
+    /// Before any provisional response the CANCEL is held back and sent on the
+    /// first 1xx (RFC 3261 9.1), once the INVITE is answered it hangs up instead.
+    pub async fn cancel(&self) -> Result<()> {
+        self.cancel_with(vec![], None).await
+    }
+
+    /// Cancel the INVITE in progress with a Reason header (RFC 3326).
+    pub async fn cancel_with_reason(&self, reason: Reason) -> Result<()> {
+        self.cancel_with(vec![reason.into()], None).await
+    }
+
+    /// Cancel the INVITE in progress with extra headers, see `bye_with_headers`.
+    pub async fn cancel_with_headers(&self, headers: Vec<Header>) -> Result<()> {
+        self.cancel_with(headers, None).await
+    }
+
+    async fn cancel_with(&self, headers: Vec<Header>, timeout: Option<Duration>) -> Result<()> {
+        // the pending cancel lock orders this against the first 1xx and the 2xx
+        let answered = {
+            let mut pending_cancel = self.inner.pending_cancel.lock().unwrap();
+            match *self.inner.state.lock().unwrap() {
+                DialogState::Calling(_) | DialogState::Trying(_) | DialogState::Early(_, _) => {
+                    self.inner.cancelled.store(true, Ordering::Relaxed);
+                    if self.inner.invite_request.lock().unwrap().is_none() {
+                        info!("no provisional response yet, cancel deferred");
+                        pending_cancel.replace(headers);
+                        return Ok(());
+                    }
+                    false
+                }
+                DialogState::WaitAck(_, _) | DialogState::Confirmed(_) => true,
+                _ => return Ok(()),
+            }
+        };
+        match answered {
+            true => self.send_bye(headers, timeout).await,
+            false => self.send_cancel(headers, timeout).await,
+        }
+    }
+
+    async fn send_bye(&self, headers: Vec<Header>, timeout: Option<Duration>) -> Result<()> {
+        let request =
+            self.inner
+                .make_request(rsip::Method::Bye, None, None, Some(headers), None)?;
+        let resp = self
             .inner
-            .make_request(rsip::Method::Bye, None, None, None, None)?;
-        let resp = self.inner.do_request(request).await?;
-        self.inner.transition(DialogState::Terminated(
-            self.id(),
-            resp.map(|r| r.status_code),
-        ))?;
+            .do_request_with_timeout(request, timeout)
+            .await?
+            .map(|r| r.status_code);
+        self.inner
+            .transition(DialogState::Terminated(self.id(), resp, None))?;
         Ok(())
     }
 
-    pub async fn cancel(&self) -> Result<()> {
-        let mut cancel_request = self.inner.initial_request.clone();
+    /// CANCEL the INVITE in progress, with its Via, Call-ID, From, To and CSeq number
+    async fn send_cancel(&self, headers: Vec<Header>, timeout: Option<Duration>) -> Result<()> {
+        let invite = self.inner.invite_request.lock().unwrap().clone();
+        let mut cancel_request = match invite {
+            Some(invite) => invite,
+            None => {
+                let mut request = self.inner.initial_request.clone();
+                request
+                    .cseq_header_mut()?
+                    .mut_seq(self.inner.invite_seq.load(Ordering::Relaxed))?;
+                request
+            }
+        };
         cancel_request.method = rsip::Method::Cancel;
         cancel_request
             .cseq_header_mut()?
-            .mut_seq(self.inner.get_local_seq())?;
+            .mut_method(rsip::Method::Cancel)?;
+        cancel_request.headers.retain(|h| {
+            matches!(
+                h,
+                Header::Via(_)
+                    | Header::From(_)
+                    | Header::To(_)
+                    | Header::CallId(_)
+                    | Header::CSeq(_)
+                    | Header::Route(_)
+                    | Header::MaxForwards(_)
+            )
+        });
+        cancel_request.headers.extend(
+            headers
+                .into_iter()
+                .filter(|h| !is_dialog_header(h))
+                .collect(),
+        );
         cancel_request.body = vec![];
-        self.inner.do_request(cancel_request).await?;
+        // an unanswered CANCEL terminates the dialog
+        self.inner
+            .do_request_with_timeout(cancel_request, timeout)
+            .await?;
         Ok(())
     }
 
-    pub async fn reinvite(&self) -> Result<()> {
+    // a 1xx arrived, the INVITE can be cancelled from now on
+    fn on_provisional(&self, invite: &rsip::Request) {
+        let pending = {
+            let mut pending_cancel = self.inner.pending_cancel.lock().unwrap();
+            self.inner
+                .invite_request
+                .lock()
+                .unwrap()
+                .replace(invite.clone());
+            pending_cancel.take()
+        };
+        if let Some(headers) = pending {
+            let dialog = self.clone();
+            tokio::spawn(
+                async move {
+                    if let Err(e) = dialog.send_cancel(headers, None).await {
+                        info!("send pending cancel error: {}", e);
+                    }
+                }
+                .in_current_span(),
+            );
+        }
+    }
+
+    /// Send a re-INVITE with a new offer, e.g. to change codecs or put the call on hold.
+    ///
+    /// The 2xx is acknowledged automatically and the dialog stays confirmed.
+    /// A `491 Request Pending` is returned as [`Error::RequestPending`](crate::Error::RequestPending)
+    /// so the caller can retry after a backoff (RFC 3261 14.1), a dialog not
+    /// confirmed yet or already terminated is [`Error::DialogNotConfirmed`](crate::Error::DialogNotConfirmed).
+    pub async fn reinvite(
+        &self,
+        body: Option<Vec<u8>>,
+        content_type: Option<String>,
+    ) -> Result<Option<Response>> {
         if !self.inner.is_confirmed() {
-            return Ok(());
+            return Err(crate::Error::DialogNotConfirmed(self.id()));
+        }
+        let headers = body.as_ref().map(|_| {
+            vec![rsip::Header::ContentType(
+                content_type.unwrap_or("application/sdp".to_string()).into(),
+            )]
+        });
+        let request = self.inner.make_request(
+            rsip::Method::Invite,
+            Some(self.inner.increment_local_seq()),
+            None,
+            headers,
+            body,
+        )?;
+        let resp = self.inner.do_request(request.clone()).await?;
+        match resp.as_ref().map(|r| r.status_code.clone()) {
+            Some(StatusCode::RequestPending) => {
+                return Err(crate::Error::RequestPending(self.id()));
+            }
+            Some(StatusCode::CallTransactionDoesNotExist) | Some(StatusCode::RequestTimeout) => {
+                self.inner.transition(DialogState::Terminated(
+                    self.id(),
+                    resp.as_ref().map(|r| r.status_code.clone()),
+                    None,
+                ))?;
+            }
+            Some(code) if code.kind() == StatusCodeKind::Successful => {
+                if let Some(resp) = &resp {
+                    self.inner.update_remote_target(resp)?;
+                }
+                self.inner
+                    .transition(DialogState::Updated(self.id(), request))?;
+            }
+            _ => {}
         }
-        todo!()
+        Ok(resp)
+    }
+
+    /// Send an UPDATE (RFC 3311), allowed in the early dialog as well as after confirmation,
+    /// otherwise it is [`Error::DialogNotConfirmed`](crate::Error::DialogNotConfirmed).
+    pub async fn update(
+        &self,
+        body: Option<Vec<u8>>,
+        content_type: Option<String>,
+    ) -> Result<Option<Response>> {
+        if !self.inner.is_established() {
+            return Err(crate::Error::DialogNotConfirmed(self.id()));
+        }
+        let headers = body.as_ref().map(|_| {
+            vec![rsip::Header::ContentType(
+                content_type.unwrap_or("application/sdp".to_string()).into(),
+            )]
+        });
+        let request = self.inner.make_request(
+            rsip::Method::Update,
+            Some(self.inner.increment_local_seq()),
+            None,
+            headers,
+            body,
+        )?;
+        let resp = self.inner.do_request(request.clone()).await?;
+        if let Some(resp) = resp
+            .as_ref()
+            .filter(|r| r.status_code.kind() == StatusCodeKind::Successful)
+        {
+            self.inner.update_remote_target(resp)?;
+            self.inner
+                .transition(DialogState::Updated(self.id(), request))?;
+        }
+        Ok(resp)
+    }
+
+    /// Send a REFER (RFC 3515) to transfer the remote party to `refer_to`.
+    ///
+    /// `replaces` is the unescaped Replaces header for an attended transfer, e.g.
+    /// `call-id;to-tag=..;from-tag=..`. The progress of the transfer is reported
+    /// by incoming NOTIFYs with a `message/sipfrag` body, raised as `DialogState::Notify`.
+    /// A dialog not confirmed is [`Error::DialogNotConfirmed`](crate::Error::DialogNotConfirmed).
+    pub async fn refer(
+        &self,
+        refer_to: rsip::Uri,
+        replaces: Option<String>,
+    ) -> Result<Option<Response>> {
+        if !self.inner.is_confirmed() {
+            return Err(crate::Error::DialogNotConfirmed(self.id()));
+        }
+        let headers = vec![Header::Other(
+            "Refer-To".to_string(),
+            make_refer_to(&refer_to, replaces.as_deref()),
+        )];
+        let request = self.inner.make_request(
+            rsip::Method::Refer,
+            Some(self.inner.increment_local_seq()),
+            None,
+            Some(headers),
+            None,
+        )?;
+        let resp = self.inner.do_request(request).await?;
+        if let Some(StatusCodeKind::Successful) = resp.as_ref().map(|r| r.status_code.kind()) {
+            self.inner
+                .refer_state
+                .lock()
+                .unwrap()
+                .replace(SubscriptionState::Pending(None));
+        }
+        Ok(resp)
+    }
+
+    /// the state of the implicit subscription created by the last accepted REFER
+    pub fn refer_state(&self) -> Option<SubscriptionState> {
+        self.inner.refer_state.lock().unwrap().clone()
+    }
+
+    /// Send an instant message (RFC 3428) inside the confirmed dialog, a dialog
+    /// not confirmed is [`Error::DialogNotConfirmed`](crate::Error::DialogNotConfirmed).
+    pub async fn message(&self, content_type: String, body: Vec<u8>) -> Result<Option<Response>> {
+        if !self.inner.is_confirmed() {
+            return Err(crate::Error::DialogNotConfirmed(self.id()));
+        }
+        let request = self.inner.make_request(
+            rsip::Method::Message,
+            Some(self.inner.increment_local_seq()),
+            None,
+            Some(vec![Header::ContentType(content_type.into())]),
+            Some(body),
+        )?;
+        self.inner.do_request(request).await
     }
 
     pub async fn info(&self) -> Result<()> {
+        self.info_with_headers(vec![], None, None).await
+    }
+
+    /// Send an INFO with extra headers and an optional body, the Content-Type
+    /// of the body comes with `headers`, see `bye_with_headers`.
+    ///
+    /// Without a final response within `timeout`, 64*T1 by default, the
+    /// dialog is terminated. A dialog not confirmed is
+    /// [`Error::DialogNotConfirmed`](crate::Error::DialogNotConfirmed).
+    pub async fn info_with_headers(
+        &self,
+        headers: Vec<Header>,
+        body: Option<Vec<u8>>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
         if !self.inner.is_confirmed() {
-            return Ok(());
+            return Err(crate::Error::DialogNotConfirmed(self.id()));
         }
 
-        let request = self
-            .inner
-            .make_request(rsip::Method::Info, None, None, None, None)?;
-        self.inner.do_request(request.clone()).await?;
+        let request =
+            self.inner
+                .make_request(rsip::Method::Info, None, None, Some(headers), body)?;
+        self.inner
+            .do_request_with_timeout(request.clone(), timeout)
+            .await?;
         self.inner
             .transition(DialogState::Info(self.id(), request))?;
         Ok(())
     }
 
-    pub async fn handle(&mut self, mut tx: Transaction) -> Result<()> {
-        let span = info_span!("client_invite_dialog", dialog_id = %self.id());
-        let _enter = span.enter();
+    /// Send a DTMF digit (0-9, *, # or A-D) in an `application/dtmf-relay` INFO.
+    pub async fn info_dtmf(&self, digit: char, duration_ms: u32) -> Result<()> {
+        let event = DtmfEvent::new(digit, duration_ms)?;
+        let headers = vec![Header::ContentType(DTMF_RELAY.into())];
+        self.info_with_headers(headers, Some(event.to_string().into_bytes()), None)
+            .await
+    }
 
+    /// Answer the offer of the 2xx to an INVITE sent without offer, the answer
+    /// goes in the ACK. The offer comes with `DialogState::WaitAck`.
+    pub fn answer_ack(&self, answer: Vec<u8>) -> Result<()> {
+        match self.inner.pending_answer.lock().unwrap().take() {
+            Some(sender) => sender.send(answer).map_err(|_| {
+                crate::Error::DialogError("the ACK is already sent".to_string(), self.id())
+            }),
+            None => Err(crate::Error::DialogError(
+                "no offer to answer".to_string(),
+                self.id(),
+            )),
+        }
+    }
+
+    /// Acknowledge the 2xx of an INVITE sent with
+    /// [`DialogLayer::do_invite_manual_ack`](super::dialog_layer::DialogLayer::do_invite_manual_ack)
+    /// and confirm the dialog, the body is e.g. the answer to the offer of the
+    /// 2xx. The retransmitted 2xx are acknowledged from then on.
+    pub async fn send_ack(
+        &self,
+        headers: Option<Vec<Header>>,
+        body: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let (mut tx, resp) = self.take_pending_ack()?;
+        let ack = self.inner.make_ack(&tx.original, &resp, headers, body)?;
+        tx.send_ack(ack.clone()).await?;
+        self.inner.transition(DialogState::Confirmed(self.id()))?;
+        if let Some(timer) = self.negotiated_session_timer(&resp) {
+            start_session_timer(self.inner.clone(), timer);
+        }
+        if let Ok(Some(tag)) = resp.to_header().and_then(|to| to.tag()) {
+            let tag = tag.value().to_string();
+            tokio::spawn(absorb_forks(self.inner.clone(), tx, tag, ack).in_current_span());
+        }
+        let cancelled = {
+            let mut pending_cancel = self.inner.pending_cancel.lock().unwrap();
+            pending_cancel.take();
+            self.inner.cancelled.load(Ordering::Relaxed)
+        };
+        if cancelled {
+            info!("invite answered after cancel, hanging up");
+            self.send_bye(vec![], None).await?;
+        }
+        Ok(())
+    }
+
+    /// Give up the 2xx of an INVITE sent with
+    /// [`DialogLayer::do_invite_manual_ack`](super::dialog_layer::DialogLayer::do_invite_manual_ack)
+    /// without acknowledging it, e.g. the UAC it was relayed to sends the ACK
+    /// end to end. The INVITE transaction is dropped and the dialog terminated
+    /// here, no BYE is sent.
+    pub fn suppress_ack(&self) -> Result<()> {
+        let (tx, resp) = self.take_pending_ack()?;
+        drop(tx);
+        self.inner.transition(DialogState::Terminated(
+            self.id(),
+            Some(resp.status_code),
+            None,
+        ))
+    }
+
+    fn take_pending_ack(&self) -> Result<(Transaction, Response)> {
+        self.inner
+            .pending_ack
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(crate::Error::DialogError(
+                "no 2xx waiting for ACK".to_string(),
+                self.id(),
+            ))
+    }
+
+    // hand the offer of the 2xx to the TU, `None` when it gives no answer while
+    // the UAS retransmits the 2xx
+    async fn wait_answer(&self, resp: &Response) -> Result<Option<Vec<u8>>> {
+        let (sender, receiver) = oneshot::channel();
+        self.inner.pending_answer.lock().unwrap().replace(sender);
+        self.inner
+            .transition(DialogState::WaitAck(self.id(), resp.clone()))?;
+        let answer = select! {
+            answer = receiver => answer.ok(),
+            _ = self.inner.endpoint_inner.clock().sleep(self.inner.endpoint_inner.t1x64 / 2) => None,
+        };
+        self.inner.pending_answer.lock().unwrap().take();
+        Ok(answer)
+    }
+
+    #[instrument(name = "client_invite_dialog", skip_all, fields(dialog_id = %self.id()))]
+    pub async fn handle(&mut self, mut tx: Transaction) -> Result<()> {
         trace!(
             "handle request: {:?} state:{}",
             tx.original,
@@ -81,23 +480,23 @@ impl ClientInviteDialog {
         );
 
         let cseq = tx.original.cseq_header()?.seq()?;
-        if cseq < self.inner.remote_seq.load(Ordering::Relaxed) {
-            info!(
-                "received old request remote_seq: {} > {}",
-                self.inner.remote_seq.load(Ordering::Relaxed),
-                cseq
-            );
-            tx.reply(rsip::StatusCode::ServerInternalError).await?;
+        if let Err(status) = self.inner.check_remote_seq(cseq) {
+            tx.reply(status).await?;
             return Ok(());
         }
 
         self.inner.remote_seq.store(cseq, Ordering::Relaxed);
 
         if self.inner.is_confirmed() {
+            self.inner.refresh_remote_target(&tx.original)?;
             match tx.original.method {
-                rsip::Method::Invite => {}
+                rsip::Method::Invite => return self.handle_reinvite(tx).await,
                 rsip::Method::Bye => return self.handle_bye(tx).await,
                 rsip::Method::Info => return self.handle_info(tx).await,
+                rsip::Method::Message => return self.handle_message(tx).await,
+                rsip::Method::Notify => return self.handle_notify(tx).await,
+                rsip::Method::Update => return self.handle_update(tx).await,
+                rsip::Method::Options => return self.handle_options(tx).await,
                 _ => {
                     info!("invalid request method: {:?}", tx.original.method);
                     tx.reply(rsip::StatusCode::MethodNotAllowed).await?;
@@ -118,42 +517,336 @@ impl ClientInviteDialog {
 
     async fn handle_bye(&mut self, mut tx: Transaction) -> Result<()> {
         info!("received bye");
+        let reason = reason_headers(&tx.original.headers).into_iter().next();
         self.inner
-            .transition(DialogState::Terminated(self.id(), None))?;
+            .transition(DialogState::Terminated(self.id(), None, reason))?;
+        tx.reply(rsip::StatusCode::OK).await?;
+        Ok(())
+    }
+
+    async fn handle_options(&mut self, mut tx: Transaction) -> Result<()> {
         tx.reply(rsip::StatusCode::OK).await?;
         Ok(())
     }
 
     async fn handle_info(&mut self, mut tx: Transaction) -> Result<()> {
+        self.inner.transition(self.inner.info_state(&tx.original))?;
+        tx.reply(rsip::StatusCode::OK).await?;
+        Ok(())
+    }
+
+    async fn handle_message(&mut self, mut tx: Transaction) -> Result<()> {
         self.inner
-            .transition(DialogState::Info(self.id(), tx.original.clone()))?;
+            .transition(DialogState::Message(self.id(), tx.original.clone()))?;
         tx.reply(rsip::StatusCode::OK).await?;
         Ok(())
     }
 
+    async fn handle_update(&mut self, mut tx: Transaction) -> Result<()> {
+        info!("received update");
+        self.inner.session_refreshed.notify_one();
+        let headers = self
+            .inner
+            .session_timer
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|timer| vec![timer.header()]);
+        self.inner
+            .transition(DialogState::Updated(self.id(), tx.original.clone()))?;
+        let resp = self
+            .inner
+            .make_response(&tx.original, rsip::StatusCode::OK, headers, None);
+        tx.respond(resp).await?;
+        Ok(())
+    }
+
+    /// A re-INVITE of the UAS, e.g. its session refresh (RFC 4028 10), is
+    /// answered 200 with our session description as it is
+    async fn handle_reinvite(&mut self, mut tx: Transaction) -> Result<()> {
+        info!("received re-invite");
+        let session_timer = self.inner.session_timer.lock().unwrap().clone();
+        if let (Some(timer), Some((interval, refresher))) =
+            (session_timer, parse_session_expires(&tx.original.headers))
+        {
+            let min_se = self
+                .inner
+                .session_timer_config
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(|config| config.min_se)
+                .unwrap_or(MIN_SESSION_EXPIRES);
+            if interval < min_se {
+                info!("session interval too small, min-se: {}", min_se);
+                let resp = self.inner.make_response(
+                    &tx.original,
+                    StatusCode::SessionIntervalTooSmall,
+                    Some(vec![min_se_header(min_se)]),
+                    None,
+                );
+                tx.respond(resp).await?;
+                while tx.receive().await.is_some() {}
+                return Ok(());
+            }
+            self.inner
+                .session_timer
+                .lock()
+                .unwrap()
+                .replace(SessionTimer {
+                    interval,
+                    refresher: refresher.unwrap_or(timer.refresher),
+                });
+        }
+        self.inner.session_refreshed.notify_one();
+        self.inner
+            .transition(DialogState::Updated(self.id(), tx.original.clone()))?;
+
+        let mut headers = vec![];
+        if let Some(timer) = self.inner.session_timer.lock().unwrap().as_ref() {
+            headers.push(timer.header());
+        }
+        let body = self.inner.local_sdp().map(|(content_type, body)| {
+            headers.push(Header::ContentType(content_type.into()));
+            body
+        });
+        let resp = self
+            .inner
+            .make_response(&tx.original, StatusCode::OK, Some(headers), body);
+        tx.respond(resp).await?;
+        // absorb the ACK
+        while tx.receive().await.is_some() {}
+        Ok(())
+    }
+
+    async fn handle_notify(&mut self, mut tx: Transaction) -> Result<()> {
+        let is_refer = tx.original.headers.iter().any(|h| match h {
+            Header::Event(event) => event.value().trim().starts_with("refer"),
+            _ => false,
+        });
+        if is_refer {
+            let state = tx.original.headers.iter().find_map(|h| match h {
+                Header::SubscriptionState(s) => SubscriptionState::parse(s.value()),
+                _ => None,
+            });
+            info!("received refer notify: {:?}", state);
+            if let Some(state) = state {
+                self.inner.refer_state.lock().unwrap().replace(state);
+            }
+        }
+        self.inner
+            .transition(DialogState::Notify(self.id(), tx.original.clone()))?;
+        tx.reply(rsip::StatusCode::OK).await?;
+        Ok(())
+    }
+
+    /// the session timer from the 2xx, no timer if the UAS doesn't support it
+    fn negotiated_session_timer(&self, resp: &Response) -> Option<SessionTimer> {
+        self.inner.session_timer_config.lock().unwrap().as_ref()?;
+        let (interval, refresher) = parse_session_expires(&resp.headers)?;
+        Some(SessionTimer {
+            interval,
+            refresher: refresher.unwrap_or(Refresher::Uac),
+        })
+    }
+
+    /// Resend the INVITE with the Min-SE of the UAS (RFC 4028 7.4), the
+    /// transaction has acknowledged the 422
+    async fn retry_session_interval(&self, tx: Transaction, min_se: u32) -> Result<Transaction> {
+        let mut request = tx.original.clone();
+        let new_seq = self.inner.increment_local_seq();
+        self.inner.invite_seq.store(new_seq, Ordering::Relaxed);
+        request.cseq_header_mut()?.mut_seq(new_seq)?;
+        request.headers.retain(|h| match h {
+            Header::Via(_) => false,
+            Header::Other(name, _) => {
+                !(name.eq_ignore_ascii_case("Session-Expires")
+                    || name.eq_ignore_ascii_case("x")
+                    || name.eq_ignore_ascii_case("Min-SE"))
+            }
+            _ => true,
+        });
+        request
+            .headers
+            .push_front(self.inner.endpoint_inner.get_via(None)?.into());
+        request.headers.push(
+            SessionTimer {
+                interval: min_se,
+                refresher: Refresher::Uac,
+            }
+            .header(),
+        );
+        request.headers.push(min_se_header(min_se));
+
+        let key = TransactionKey::from_request(&request, TransactionRole::Client)?;
+        let mut new_tx = Transaction::new_client(
+            key,
+            request,
+            self.inner.endpoint_inner.clone(),
+            tx.connection.clone(),
+        );
+        new_tx.destinations = tx.destination().cloned().into_iter().collect();
+        Ok(new_tx)
+    }
+
+    /// The INVITE of `tx` sent again to the target of a 3xx, a new transaction
+    /// with a higher CSeq and the same Call-ID and From tag
+    fn redirect(&self, tx: &Transaction, target: rsip::Uri) -> Result<Transaction> {
+        let mut request = tx.original.clone();
+        let new_seq = self.inner.increment_local_seq();
+        self.inner.invite_seq.store(new_seq, Ordering::Relaxed);
+        request.cseq_header_mut()?.mut_seq(new_seq)?;
+        // the credentials answered the challenges of the last target
+        request.headers.retain(|h| {
+            !matches!(
+                h,
+                Header::Via(_) | Header::Authorization(_) | Header::ProxyAuthorization(_)
+            )
+        });
+        request
+            .headers
+            .push_front(self.inner.endpoint_inner.get_via(None)?.into());
+        request.uri = target.clone();
+        *self.inner.remote_uri.lock().unwrap() = target;
+        self.inner.invite_request.lock().unwrap().take();
+        self.inner.early_dialogs.lock().unwrap().clear();
+
+        let key = TransactionKey::from_request(&request, TransactionRole::Client)?;
+        Ok(Transaction::new_client(
+            key,
+            request,
+            self.inner.endpoint_inner.clone(),
+            None,
+        ))
+    }
+
+    /// The early dialogs of each branch the INVITE forked to, with their last
+    /// provisional response
+    pub fn early_dialogs(&self) -> Vec<(DialogId, Response)> {
+        let early_dialogs = self.inner.early_dialogs.lock().unwrap();
+        early_dialogs
+            .iter()
+            .map(|(id, resp)| (id.clone(), resp.clone()))
+            .collect()
+    }
+
+    /// acknowledge a reliable provisional response (RFC 3262 7.2), within its early dialog
+    async fn send_prack(&self, resp: &Response, rseq: u32) -> Result<()> {
+        let cseq = resp.cseq_header()?;
+        let rack = format!("{} {} {}", rseq, cseq.seq()?, cseq.method()?);
+        let mut request = self.inner.make_request(
+            rsip::Method::PRack,
+            Some(self.inner.increment_local_seq()),
+            None,
+            Some(vec![Header::Other("RAck".to_string(), rack)]),
+            None,
+        )?;
+        request
+            .headers
+            .unique_push(Header::To(resp.to_header()?.clone()));
+        info!("sending prack for rseq: {}", rseq);
+        self.inner.do_request(request).await?;
+        Ok(())
+    }
+
+    #[instrument(name = "client_dialog", skip_all, fields(dialog_id = %self.id()))]
     pub(super) async fn process_invite(
         &self,
         mut tx: Transaction,
+        manual_ack: bool,
     ) -> Result<(DialogId, Option<Response>)> {
-        let span = info_span!("client_dialog", dialog_id = %self.id());
-        let _enter = span.enter();
-
         self.inner.transition(DialogState::Calling(self.id()))?;
         let mut auth_sent = false;
+        let mut challenges = vec![];
         tx.send().await?;
+        self.inner.set_connection(tx.connection.as_ref());
         let mut dialog_id = self.id();
         let mut final_response = None;
-        while let Some(msg) = tx.receive().await {
+        let mut last_rseqs = HashMap::new();
+        let mut accepted = None;
+        let mut awaiting_ack = None;
+        let mut interval_retried = false;
+        let clock = self.inner.endpoint_inner.clock().clone();
+        let timer_c = self.inner.endpoint_inner.timer_c;
+        let mut timer_c_at = timer_c.map(|d| clock.now() + d);
+        let mut timed_out = false;
+        let redirect_policy = self.inner.endpoint_inner.redirect_policy();
+        let mut redirects = 0;
+        let mut targets = vec![];
+        let mut visited = vec![tx.original.uri.to_string()];
+        loop {
+            let msg = match timer_c_at {
+                Some(at) => match select! {
+                    msg = tx.receive() => Some(msg),
+                    _ = clock.sleep_until(at) => None,
+                } {
+                    Some(msg) => msg,
+                    None if timed_out => {
+                        info!("no final response to the cancel, giving up");
+                        self.inner.transition(DialogState::Terminated(
+                            self.id(),
+                            Some(StatusCode::RequestTimeout),
+                            None,
+                        ))?;
+                        break;
+                    }
+                    None => {
+                        info!("timer C fired, cancelling the invite");
+                        timed_out = true;
+                        timer_c_at = Some(clock.now() + self.inner.endpoint_inner.t1x64);
+                        let dialog = self.clone();
+                        tokio::spawn(
+                            async move {
+                                if let Err(e) = dialog.cancel().await {
+                                    info!("timer C cancel error: {}", e);
+                                }
+                            }
+                            .in_current_span(),
+                        );
+                        continue;
+                    }
+                },
+                None => tx.receive().await,
+            };
+            let Some(msg) = msg else {
+                break;
+            };
             match msg {
                 SipMessage::Request(_) => {}
                 SipMessage::Response(resp) => {
                     match resp.status_code {
                         StatusCode::Trying => {
                             self.inner.transition(DialogState::Trying(self.id()))?;
+                            self.on_provisional(&tx.original);
                             continue;
                         }
-                        StatusCode::Ringing | StatusCode::SessionProgress => {
-                            self.inner.transition(DialogState::Early(self.id(), resp))?;
+                        // 180, 181, 182 and 183 alike, an unknown 1xx is a 183 (RFC 3261 8.1.3.2)
+                        _ if resp.status_code.kind() == StatusCodeKind::Provisional => {
+                            // each remote tag is an early dialog of its own (RFC 3261 12.1)
+                            let early_id = match DialogId::try_from(&resp) {
+                                Ok(id) => {
+                                    self.inner
+                                        .early_dialogs
+                                        .lock()
+                                        .unwrap()
+                                        .insert(id.clone(), resp.clone());
+                                    id
+                                }
+                                Err(_) => self.id(),
+                            };
+                            if let Some(rseq) = reliable_rseq(&resp) {
+                                // a retransmission or out of order 1xx must not be PRACKed
+                                let last_rseq = last_rseqs.get(&early_id).copied();
+                                if last_rseq.is_none_or(|last| rseq == last + 1) {
+                                    last_rseqs.insert(early_id.clone(), rseq);
+                                    self.send_prack(&resp, rseq).await?;
+                                }
+                            }
+                            self.inner.transition(DialogState::Early(early_id, resp))?;
+                            self.on_provisional(&tx.original);
+                            if !timed_out {
+                                timer_c_at = timer_c.map(|d| clock.now() + d);
+                            }
                             continue;
                         }
                         StatusCode::ProxyAuthenticationRequired | StatusCode::Unauthorized => {
@@ -162,18 +855,18 @@ impl ClientInviteDialog {
                                 self.inner.transition(DialogState::Terminated(
                                     self.id(),
                                     Some(resp.status_code),
+                                    None,
                                 ))?;
                                 break;
                             }
                             auth_sent = true;
                             if let Some(credential) = &self.inner.credential {
-                                tx = handle_client_authenticate(
-                                    self.inner.increment_local_seq(),
-                                    tx,
-                                    resp,
-                                    credential,
-                                )
-                                .await?;
+                                let new_seq = self.inner.increment_local_seq();
+                                self.inner.invite_seq.store(new_seq, Ordering::Relaxed);
+                                challenges = select_challenges_for(&resp, credential);
+                                self.inner.invite_request.lock().unwrap().take();
+                                tx = handle_client_authenticate(new_seq, tx, resp, credential)
+                                    .await?;
                                 tx.send().await?;
                                 continue;
                             } else {
@@ -181,10 +874,52 @@ impl ClientInviteDialog {
                                 self.inner.transition(DialogState::Terminated(
                                     self.id(),
                                     Some(resp.status_code),
+                                    None,
                                 ))?;
                             }
                             continue;
                         }
+                        _ if resp.status_code.kind() == StatusCodeKind::Redirection
+                            && redirect_policy.is_some() =>
+                        {
+                            let max_redirects = redirect_policy
+                                .as_ref()
+                                .map(|p| p.max_redirects)
+                                .unwrap_or_default();
+                            // the targets of the newest 3xx go first, the already tried are
+                            // skipped so a redirect loop ends
+                            let mut new_targets = redirect_targets(&resp);
+                            new_targets.retain(|t| !visited.contains(&t.to_string()));
+                            new_targets.reverse();
+                            targets.retain(|t| !new_targets.contains(t));
+                            targets.extend(new_targets);
+                            match targets.pop() {
+                                Some(target) if redirects < max_redirects => {
+                                    redirects += 1;
+                                    info!("redirected by {} to {}", resp.status_code, target);
+                                    visited.push(target.to_string());
+                                    auth_sent = false;
+                                    if !timed_out {
+                                        timer_c_at = timer_c.map(|d| clock.now() + d);
+                                    }
+                                    tx = self.redirect(&tx, target)?;
+                                    tx.send().await?;
+                                    self.inner.set_connection(tx.connection.as_ref());
+                                    continue;
+                                }
+                                _ => info!("no redirect target left, {}", resp.status_code),
+                            }
+                        }
+                        StatusCode::SessionIntervalTooSmall if !interval_retried => {
+                            interval_retried = true;
+                            if let Some(min_se) = min_se(&resp.headers) {
+                                info!("session interval too small, retrying with: {}", min_se);
+                                self.inner.invite_request.lock().unwrap().take();
+                                tx = self.retry_session_interval(tx, min_se).await?;
+                                tx.send().await?;
+                                continue;
+                            }
+                        }
                         _ => {}
                     };
                     let to_tag = resp.to_header()?.tag()?;
@@ -193,46 +928,238 @@ impl ClientInviteDialog {
                         self.id(),
                     ))?;
                     self.inner.update_remote_tag(tag.value())?;
+                    if resp.status_code.kind() == StatusCodeKind::Successful {
+                        self.inner.update_remote_target(&resp)?;
+                        self.inner.update_route_set(&resp);
+                        self.inner.update_remote_user_agent(&resp);
+                    }
 
-                    let branch = match resp.status_code.kind() {
-                        StatusCodeKind::Successful => resp
-                            .via_header()?
-                            .params()?
-                            .iter()
-                            .find(|p| matches!(p, rsip::Param::Branch(_)))
-                            .map(|p| p.clone()),
+                    // delayed offer, the 2xx has the offer and the ACK the answer (RFC 3261 13.2.2.4)
+                    let answer = match (
+                        resp.status_code.kind(),
+                        self.inner.initial_request.body.is_empty(),
+                    ) {
+                        (StatusCodeKind::Successful, true)
+                            if !manual_ack && extract_sdp(&resp.headers, &resp.body).is_some() =>
+                        {
+                            Some(self.wait_answer(&resp).await?)
+                        }
                         _ => None,
                     };
-
-                    let ack = self.inner.make_request(
-                        rsip::Method::Ack,
-                        resp.cseq_header()?.seq().ok(),
-                        branch,
-                        None,
-                        None,
-                    )?;
+                    let (headers, body) = match &answer {
+                        Some(Some(answer)) => (
+                            Some(vec![Header::ContentType("application/sdp".into())]),
+                            Some(answer.clone()),
+                        ),
+                        _ => (None, None),
+                    };
+                    let ack = self.inner.make_ack(&tx.original, &resp, headers, body)?;
 
                     dialog_id = DialogId::try_from(&ack)?.clone();
                     final_response = Some(resp.clone());
-                    tx.send_ack(ack).await?;
+                    self.inner.early_dialogs.lock().unwrap().clear();
+                    if resp.status_code.kind() == StatusCodeKind::Successful && !manual_ack {
+                        tx.send_ack(ack.clone()).await?;
+                    }
 
                     match resp.status_code {
                         StatusCode::OK => {
+                            // the next in-dialog requests answer these challenges ahead
+                            *self.inner.auth_challenges.lock().unwrap() =
+                                std::mem::take(&mut challenges);
+                            let session_timer = self.negotiated_session_timer(&resp);
+                            self.inner
+                                .last_invite_response
+                                .lock()
+                                .unwrap()
+                                .replace(resp.clone());
+                            if manual_ack {
+                                awaiting_ack = Some(resp);
+                                break;
+                            }
+                            if answer.is_none() {
+                                self.inner
+                                    .transition(DialogState::WaitAck(dialog_id.clone(), resp))?;
+                            }
+                            // the ACK is sent, the dialog is confirmed on our side
+                            self.inner
+                                .transition(DialogState::Confirmed(dialog_id.clone()))?;
+                            if let Some(timer) = session_timer {
+                                start_session_timer(self.inner.clone(), timer);
+                            }
+                            let cancelled = {
+                                let mut pending_cancel = self.inner.pending_cancel.lock().unwrap();
+                                pending_cancel.take();
+                                self.inner.cancelled.load(Ordering::Relaxed)
+                            };
+                            if cancelled {
+                                // the 2xx crossed the CANCEL (RFC 3261 15)
+                                info!("invite answered after cancel, hanging up");
+                                self.send_bye(vec![], None).await?;
+                            } else if let Some(None) = answer {
+                                info!("no answer to the offer of the 2xx, hanging up");
+                                self.send_bye(vec![], None).await?;
+                            }
+                            accepted = Some((tag.value().to_string(), ack));
+                            break;
+                        }
+                        StatusCode::RequestTerminated if timed_out => {
+                            info!("invite cancelled on timer C");
+                            self.inner.transition(DialogState::Terminated(
+                                self.id(),
+                                Some(StatusCode::RequestTimeout),
+                                None,
+                            ))?;
+                        }
+                        StatusCode::RequestTerminated
+                            if self.inner.cancelled.load(Ordering::Relaxed) =>
+                        {
+                            info!("invite cancelled");
                             self.inner
-                                .transition(DialogState::WaitAck(dialog_id.clone(), resp))?;
+                                .transition(DialogState::Cancelled(self.id(), None))?;
                         }
                         _ => {
                             info!("received failure response: {}", resp.status_code);
                             self.inner.transition(DialogState::Terminated(
                                 self.id(),
                                 Some(resp.status_code),
+                                None,
                             ))?;
                         }
                     }
                 }
             }
         }
+        if let Some(resp) = awaiting_ack {
+            // the TU sends or suppresses the ACK, see send_ack
+            self.inner
+                .pending_ack
+                .lock()
+                .unwrap()
+                .replace((tx, resp.clone()));
+            self.inner
+                .transition(DialogState::WaitAck(dialog_id.clone(), resp))?;
+            let dialog = self.clone();
+            tokio::spawn(
+                async move {
+                    let wait = dialog.inner.endpoint_inner.t1x64;
+                    dialog.inner.endpoint_inner.clock().sleep(wait).await;
+                    if dialog.suppress_ack().is_ok() {
+                        info!("the 2xx was not acknowledged in time, giving up");
+                    }
+                }
+                .in_current_span(),
+            );
+        } else if let Some((tag, ack)) = accepted {
+            tokio::spawn(absorb_forks(self.inner.clone(), tx, tag, ack).in_current_span());
+        }
         trace!("process done");
         Ok((dialog_id, final_response))
     }
 }
+
+/// ACK and hang up the 2xx of the other branches of a forked INVITE, the ACK is
+/// resent for each retransmitted 2xx until the transaction ends (RFC 3261 13.2.2.4)
+async fn absorb_forks(inner: DialogInnerRef, mut tx: Transaction, tag: String, ack: Request) {
+    let mut acks = HashMap::from([(tag, ack)]);
+    while let Some(msg) = tx.receive().await {
+        let resp = match msg {
+            SipMessage::Response(resp) => resp,
+            _ => continue,
+        };
+        let tag = match resp.to_header().and_then(|to| to.tag()) {
+            Ok(Some(tag)) => tag.value().to_string(),
+            _ => continue,
+        };
+        if let Some(ack) = acks.get(&tag) {
+            tx.send_ack(ack.clone()).await.ok();
+            continue;
+        }
+        info!("forked invite answered by: {}, hanging up", tag);
+        let requests = fork_request(&inner, &resp, rsip::Method::Ack).and_then(|ack| {
+            let bye = fork_request(&inner, &resp, rsip::Method::Bye)?;
+            Ok((ack, bye))
+        });
+        let (ack, bye) = match requests {
+            Ok(requests) => requests,
+            Err(e) => {
+                info!(
+                    "forked invite answered by: {}, invalid response: {}",
+                    tag, e
+                );
+                continue;
+            }
+        };
+        tx.send_ack(ack.clone()).await.ok();
+        acks.insert(tag, ack);
+
+        let key = match TransactionKey::from_request(&bye, TransactionRole::Client) {
+            Ok(key) => key,
+            Err(_) => continue,
+        };
+        let connection = inner.connection.lock().unwrap().clone();
+        let mut bye_tx =
+            Transaction::new_client(key, bye, inner.endpoint_inner.clone(), connection);
+        tokio::spawn(
+            async move {
+                if let Err(e) = bye_tx.send().await {
+                    info!("forked dialog bye failed: {}", e);
+                    return;
+                }
+                while bye_tx.receive().await.is_some() {}
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+/// a request within the dialog the 2xx of another branch established
+fn fork_request(inner: &DialogInnerRef, resp: &Response, method: rsip::Method) -> Result<Request> {
+    // the ACK of a 2xx is a new transaction with the CSeq of the INVITE
+    let seq = match method {
+        rsip::Method::Ack => resp.cseq_header()?.seq().ok(),
+        _ => Some(inner.increment_local_seq()),
+    };
+    let mut request = inner.make_request(method, seq, None, None, None)?;
+    request
+        .headers
+        .unique_push(Header::To(resp.to_header()?.clone()));
+    if let Ok(contact) = resp.contact_header() {
+        request.uri = extract_uri_from_contact(contact.value())?;
+    }
+    Ok(request)
+}
+
+/// the Contacts of a 3xx, the highest q value first and in order for the same
+fn redirect_targets(resp: &Response) -> Vec<rsip::Uri> {
+    let mut contacts = contact_values(&resp.headers)
+        .into_iter()
+        .filter_map(|contact| parse_contact(&contact).ok())
+        .map(|contact| {
+            let q = contact
+                .params
+                .iter()
+                .find_map(|p| match p {
+                    rsip::Param::Q(q) => q.value().parse::<f32>().ok(),
+                    _ => None,
+                })
+                .unwrap_or(1.0);
+            (q, contact.uri)
+        })
+        .collect::<Vec<_>>();
+    contacts.sort_by(|a, b| b.0.total_cmp(&a.0));
+    contacts.into_iter().map(|(_, uri)| uri).collect()
+}
+
+/// the RSeq of a provisional response sent reliably with `Require: 100rel`
+fn reliable_rseq(resp: &Response) -> Option<u32> {
+    if !has_required(&resp.headers, "100rel") {
+        return None;
+    }
+    resp.headers.iter().find_map(|h| match h {
+        Header::Other(name, value) if name.eq_ignore_ascii_case("RSeq") => {
+            value.trim().parse::<u32>().ok()
+        }
+        _ => None,
+    })
+}
@@ -1,32 +1,56 @@
 use super::{
-    authenticate::{handle_client_authenticate, Credential},
+    authenticate::{
+        handle_client_authenticate, is_stale, preauthorize, select_challenges_for, Credential,
+        DigestChallenge,
+    },
     client_dialog::ClientInviteDialog,
+    dialog_layer::DialogLayerInner,
     server_dialog::ServerInviteDialog,
+    session_timer::{SessionTimer, SessionTimerConfig},
+    subscription::{ClientSubscribeDialog, SubscriptionState},
     DialogId,
 };
 use crate::{
-    header_pop,
-    rsip_ext::extract_uri_from_contact,
+    rsip_ext::{extract_sdp, extract_uri_from_contact, DtmfEvent, Reason, Replaces, DTMF_RELAY},
     transaction::{
         endpoint::EndpointInnerRef,
         key::{TransactionKey, TransactionRole},
+        make_tag,
         transaction::{Transaction, TransactionEventSender},
     },
+    transport::SipConnection,
     Result,
 };
+use futures::{Stream, StreamExt};
 use rsip::{
     headers::Route,
     prelude::{HeadersExt, ToTypedHeader, UntypedHeader},
     typed::{CSeq, Contact},
-    Header, Param, Request, Response, SipMessage, StatusCode,
+    Header, Param, Request, Response, SipMessage, StatusCode, StatusCodeKind, UriWithParams,
 };
-use std::sync::{
-    atomic::{AtomicU32, Ordering},
-    Arc, Mutex,
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use tokio::{
+    select,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot, Notify,
+    },
+    time::Instant,
 };
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, info};
+use tracing::{debug, info, instrument};
+
+/// a CSeq must be less than 2^31 (RFC 3261 8.1.1.5)
+pub const MAX_CSEQ: u32 = 1 << 31;
 
 /// DialogState is the state of the dialog
 #[derive(Clone)]
@@ -36,15 +60,29 @@ pub enum DialogState {
     Early(DialogId, rsip::Response),
     WaitAck(DialogId, rsip::Response),
     Confirmed(DialogId),
+    /// the ACK of our 2xx arrived, it may carry the answer (RFC 3261 13.2.1)
+    Ack(DialogId, rsip::Request),
     Updated(DialogId, rsip::Request),
     Notify(DialogId, rsip::Request),
     Info(DialogId, rsip::Request),
-    Terminated(DialogId, Option<rsip::StatusCode>),
+    /// incoming INFO with a DTMF digit (application/dtmf-relay), already answered 200
+    Dtmf(DialogId, DtmfEvent),
+    /// incoming MESSAGE (RFC 3428), already answered 200
+    Message(DialogId, rsip::Request),
+    /// incoming REFER with the parsed Refer-To uri, already answered 202
+    Refer(DialogId, rsip::Request, rsip::Uri),
+    /// the status that ended the dialog and the Reason (RFC 3326) of the BYE
+    /// that did, when it came from the remote party
+    Terminated(DialogId, Option<rsip::StatusCode>, Option<Reason>),
+    /// the INVITE was cancelled before it was answered, a final state like Terminated,
+    /// with the Reason of the CANCEL
+    Cancelled(DialogId, Option<Reason>),
 }
 #[derive(Clone)]
 pub enum Dialog {
     ServerInvite(ServerInviteDialog),
     ClientInvite(ClientInviteDialog),
+    ClientSubscribe(ClientSubscribeDialog),
 }
 
 pub struct DialogInner {
@@ -57,35 +95,293 @@ pub struct DialogInner {
     pub local_contact: Option<rsip::Uri>,
 
     pub remote_seq: AtomicU32,
-    pub remote_uri: rsip::Uri,
+    /// the remote target, the Contact of the remote party once known (RFC 3261 12.1.2)
+    pub remote_uri: Mutex<rsip::Uri>,
 
     pub from: String,
     pub to: Mutex<String>,
 
     pub credential: Option<Credential>,
-    pub route_set: Vec<Route>,
+    /// the challenges answered last, sent ahead with the next requests
+    pub(super) auth_challenges: Mutex<Vec<(DigestChallenge, bool)>>,
+    /// the Record-Route of the dialog in the order requests traverse it
+    pub route_set: Mutex<Vec<UriWithParams>>,
     pub(super) endpoint_inner: EndpointInnerRef,
     pub(super) state_sender: DialogStateSender,
+    /// the streams of [`DialogInner::states`], next to the `state_sender`
+    pub(super) state_watchers: Mutex<Vec<DialogStateSender>>,
     pub(super) tu_sender: TuSenderRef,
+    pub(super) update_sender: TuSenderRef,
+    pub(super) last_invite_response: Mutex<Option<Response>>,
+    /// final response of the last in-dialog request by cseq and method,
+    /// replayed when the request is retransmitted
+    pub(super) last_response: Mutex<Option<(u32, rsip::Method, Response)>>,
+    /// the implicit subscription of the last REFER we sent
+    pub(super) refer_state: Mutex<Option<SubscriptionState>>,
+    /// cseq of the INVITE in progress, CANCEL must match it (RFC 3261 9.1)
+    pub(super) invite_seq: AtomicU32,
+    /// the INVITE in progress once it got a 1xx, a CANCEL copies its Via branch
+    pub(super) invite_request: Mutex<Option<Request>>,
+    /// the early dialogs of each branch of a forked INVITE, with their last provisional response
+    pub(super) early_dialogs: Mutex<HashMap<DialogId, Response>>,
+    /// the CANCEL waiting for the first provisional response, with its extra headers
+    pub(super) pending_cancel: Mutex<Option<Vec<Header>>>,
+    /// the INVITE was cancelled, a 2xx arriving anyway is hung up
+    pub(super) cancelled: AtomicBool,
+    /// last RSeq of our reliable provisional responses (RFC 3262)
+    pub(super) rseq: AtomicU32,
+    /// the reliable provisional response waiting for PRACK
+    pub(super) pending_prack: Mutex<Option<(u32, oneshot::Sender<()>)>>,
+    /// the answer to the offer of a 2xx to an INVITE without offer, sent in the ACK
+    pub(super) pending_answer: Mutex<Option<oneshot::Sender<Vec<u8>>>>,
+    /// the 2xx of an INVITE acknowledged by the TU, with its transaction
+    pub(super) pending_ack: Mutex<Option<(Transaction, Response)>>,
+    pub(super) session_timer_config: Mutex<Option<SessionTimerConfig>>,
+    /// the negotiated session timer (RFC 4028)
+    pub(super) session_timer: Mutex<Option<SessionTimer>>,
+    pub(super) session_refreshed: Notify,
+    /// consecutive OPTIONS pings without answer
+    pub(super) ping_failures: AtomicU32,
+    pub(super) ping_token: Mutex<Option<CancellationToken>>,
+    /// the stream connection the dialog was set up over, in-dialog requests
+    /// go back through it since the remote Contact may not be reachable (RFC 7118)
+    pub(super) connection: Mutex<Option<SipConnection>>,
+    /// the transport in-dialog requests are sent on, over the `transport`
+    /// param of the remote target
+    pub(super) transport: Mutex<Option<rsip::Transport>>,
+    /// the dialog layer holding the dialog and its key there, removed on termination
+    pub(super) registry: Mutex<Option<(Weak<DialogLayerInner>, DialogId)>>,
+    /// the User-Agent of the INVITE or the Server of the 2xx that established the dialog
+    pub(super) remote_user_agent: Mutex<Option<String>>,
+    pub(super) created_at: Instant,
+    pub(super) confirmed_at: Mutex<Option<Instant>>,
+    /// the last state change or incoming request, see [`DialogLayer::reap`](super::dialog_layer::DialogLayer::reap)
+    pub(super) last_activity: Mutex<Instant>,
+    /// the dialog taken over by the INVITE of this one (RFC 3891), hung up once confirmed
+    pub(super) replaces: Mutex<Option<Dialog>>,
+    /// the inbound requests held while the dialog is paused, `None` when it is not
+    pub(super) held: Mutex<Option<VecDeque<Transaction>>>,
     pub(super) initial_request: Request,
 }
 
 pub type DialogStateReceiver = UnboundedReceiver<DialogState>;
 pub type DialogStateSender = UnboundedSender<DialogState>;
 
+/// The states of the dialogs given its [`DialogStateSender`], as a [`Stream`].
+///
+/// It owns the receiving end of the channel, a state it or its helpers take is
+/// seen nowhere else: use either the stream or the helpers for a given state.
+pub struct DialogStateStream {
+    source: StateSource,
+}
+
+enum StateSource {
+    Unbounded(DialogStateReceiver),
+    Bounded(Arc<BoundedStates>),
+}
+
+/// The states of a bounded stream waiting for the application, moved off the
+/// channel as soon as they are sent
+struct BoundedStates {
+    queue: Mutex<VecDeque<DialogState>>,
+    capacity: usize,
+    dropped: AtomicU64,
+    closed: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl BoundedStates {
+    fn push(&self, state: DialogState) {
+        {
+            let mut queue = self.queue.lock().unwrap();
+            if queue.len() >= self.capacity {
+                match queue.iter().position(|s| !s.is_terminated()) {
+                    // the oldest state that is not final makes room
+                    Some(pos) => {
+                        queue.remove(pos);
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    // a final state is let in over capacity, it is never dropped
+                    None if state.is_terminated() => {}
+                    None => {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
+                }
+            }
+            queue.push_back(state);
+        }
+        self.wake();
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.wake();
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+}
+
+impl DialogStateStream {
+    /// a sender for `do_invite` or an [`IncomingHandler`](super::dialog_layer::IncomingHandler)
+    /// and the stream of the states sent on it
+    pub fn channel() -> (DialogStateSender, Self) {
+        let (sender, receiver) = unbounded_channel();
+        let source = StateSource::Unbounded(receiver);
+        (sender, Self { source })
+    }
+
+    /// Like `channel`, with at most `capacity` states waiting to be taken from
+    /// the stream.
+    ///
+    /// When it is full the oldest state that is not final is dropped, e.g. a
+    /// 180 or a NOTIFY of a flood. The Terminated and Cancelled states are
+    /// always delivered, even over capacity. The states are moved off the
+    /// sender's channel by a task of their own that ends with the last sender.
+    pub fn bounded(capacity: usize) -> (DialogStateSender, Self) {
+        let (sender, mut receiver) = unbounded_channel();
+        let states = Arc::new(BoundedStates {
+            queue: Mutex::new(VecDeque::new()),
+            capacity: capacity.max(1),
+            dropped: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            waker: Mutex::new(None),
+        });
+        let forwarded = states.clone();
+        tokio::spawn(async move {
+            while let Some(state) = receiver.recv().await {
+                // nobody is left to take them
+                if Arc::strong_count(&forwarded) == 1 {
+                    return;
+                }
+                forwarded.push(state);
+            }
+            forwarded.close();
+        });
+        let source = StateSource::Bounded(states);
+        (sender, Self { source })
+    }
+
+    /// the states dropped because the stream was full, always 0 when unbounded
+    pub fn dropped(&self) -> u64 {
+        match &self.source {
+            StateSource::Unbounded(_) => 0,
+            StateSource::Bounded(states) => states.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Skip the states up to Confirmed and return the id of the dialog, an error
+    /// when it ends first.
+    pub async fn wait_until_confirmed(&mut self) -> Result<DialogId> {
+        while let Some(state) = self.next().await {
+            match state {
+                DialogState::Confirmed(id) => return Ok(id),
+                DialogState::Terminated(id, code, _) => {
+                    return Err(crate::Error::DialogError(
+                        format!("terminated before confirmed: {:?}", code),
+                        id,
+                    ))
+                }
+                DialogState::Cancelled(id, _) => {
+                    return Err(crate::Error::DialogError(
+                        "cancelled before confirmed".to_string(),
+                        id,
+                    ))
+                }
+                _ => {}
+            }
+        }
+        Err(crate::Error::Error(
+            "dialog state channel closed".to_string(),
+        ))
+    }
+
+    /// Skip the states up to the final one and return its status, the one of
+    /// the final response or of the BYE, `None` for a BYE we received. A
+    /// cancelled INVITE ends with 487, an error when the channel closes first.
+    pub async fn wait_until_terminated(&mut self) -> Result<Option<StatusCode>> {
+        while let Some(state) = self.next().await {
+            match state {
+                DialogState::Terminated(_, code, _) => return Ok(code),
+                DialogState::Cancelled(_, _) => return Ok(Some(StatusCode::RequestTerminated)),
+                _ => {}
+            }
+        }
+        Err(crate::Error::Error(
+            "dialog state channel closed".to_string(),
+        ))
+    }
+}
+
+impl From<DialogStateReceiver> for DialogStateStream {
+    fn from(receiver: DialogStateReceiver) -> Self {
+        let source = StateSource::Unbounded(receiver);
+        Self { source }
+    }
+}
+
+impl Stream for DialogStateStream {
+    type Item = DialogState;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<DialogState>> {
+        let states = match &mut self.source {
+            StateSource::Unbounded(receiver) => return receiver.poll_recv(cx),
+            StateSource::Bounded(states) => states,
+        };
+        // the waker is set before the queue is checked again, a push in between wakes it
+        states.waker.lock().unwrap().replace(cx.waker().clone());
+        if let Some(state) = states.queue.lock().unwrap().pop_front() {
+            return Poll::Ready(Some(state));
+        }
+        match states.closed.load(Ordering::Relaxed) {
+            true => Poll::Ready(states.queue.lock().unwrap().pop_front()),
+            false => Poll::Pending,
+        }
+    }
+}
+
 pub(super) type DialogInnerRef = Arc<DialogInner>;
-pub(super) type TuSenderRef = Mutex<Option<TransactionEventSender>>;
+/// the incoming request waiting for the application to answer it, with its transaction
+pub(super) type TuSenderRef = Mutex<Option<(Request, TransactionEventSender)>>;
+
+/// The answer of an in-dialog OPTIONS ping
+#[derive(Clone, Debug)]
+pub struct PingResult {
+    pub rtt: Duration,
+    pub allow: Option<String>,
+    pub accept: Option<String>,
+}
+
+/// consecutive ping timeouts before the dialog is terminated
+pub const MAX_PING_FAILURES: u32 = 3;
+
+/// most inbound requests a paused dialog holds, the next ones are answered 500
+pub const MAX_HELD_REQUESTS: usize = 16;
 
 impl DialogState {
     pub fn is_confirmed(&self) -> bool {
         matches!(self, DialogState::Confirmed(_))
     }
+
+    /// Terminated or Cancelled, no other state follows
+    pub fn is_terminated(&self) -> bool {
+        matches!(
+            self,
+            DialogState::Terminated(_, _, _) | DialogState::Cancelled(_, _)
+        )
+    }
 }
 
 impl DialogInner {
+    /// A client `initial_request` without a From tag is given a random one,
+    /// the local tag of `id` from then on.
     pub fn new(
         role: TransactionRole,
-        id: DialogId,
+        mut id: DialogId,
         initial_request: Request,
         endpoint_inner: EndpointInnerRef,
         state_sender: DialogStateSender,
@@ -95,6 +391,19 @@ impl DialogInner {
         let mut initial_request = initial_request;
         let cseq = initial_request.cseq_header()?.seq()?;
 
+        // the GRUU of the registration reaches this instance (RFC 5627 4.4)
+        let local_contact = match endpoint_inner.gruu_contact(&initial_request.headers) {
+            Some(gruu) => {
+                if matches!(role, TransactionRole::Client) {
+                    initial_request
+                        .headers
+                        .unique_push(Contact::from(gruu.clone()).into());
+                }
+                Some(gruu)
+            }
+            None => local_contact,
+        };
+
         let remote_uri = match role {
             TransactionRole::Client => initial_request.uri.clone(),
             TransactionRole::Server => {
@@ -102,7 +411,18 @@ impl DialogInner {
             }
         };
 
-        let from = initial_request.from_header()?.typed()?;
+        let mut from = initial_request.from_header()?.typed()?;
+        if matches!(role, TransactionRole::Client)
+            && !from.params.iter().any(|p| matches!(p, Param::Tag(_)))
+        {
+            // our tag is kept for the dialog lifetime, re-auth retries included
+            let tag = make_tag();
+            id.from_tag = tag.value().to_string();
+            from = from.with_tag(tag);
+            initial_request
+                .headers
+                .unique_push(Header::From(from.clone().into()));
+        }
         let mut to = initial_request.to_header()?.typed()?;
         if !to.params.iter().any(|p| matches!(p, Param::Tag(_))) {
             to.params.push(rsip::Param::Tag(id.to_tag.clone().into()));
@@ -113,15 +433,22 @@ impl DialogInner {
             TransactionRole::Server => (to.to_string(), from.to_string()),
         };
 
-        let mut route_set = vec![];
-        initial_request.headers.retain(|h| {
-            if let Header::RecordRoute(rr) = h {
-                route_set.push(Route::from(rr.value()));
-                false
-            } else {
-                true
-            }
-        });
+        let route_set = route_set(&initial_request.headers, &role);
+        let remote_user_agent = match role {
+            TransactionRole::Client => None,
+            TransactionRole::Server => user_agent(&initial_request.headers),
+        };
+        // the remote sequence of a UAC is empty until the remote sends a
+        // request, our own CSeq says nothing about it (RFC 3261 12.1.2)
+        let remote_seq = match role {
+            TransactionRole::Client => 0,
+            TransactionRole::Server => cseq,
+        };
+        endpoint_inner.dialog_metrics.on_created();
+        let now = endpoint_inner.clock().now();
+        initial_request
+            .headers
+            .retain(|h| !matches!(h, Header::RecordRoute(_)));
         Ok(Self {
             role,
             cancel_token: CancellationToken::new(),
@@ -129,13 +456,42 @@ impl DialogInner {
             from,
             to: Mutex::new(to),
             local_seq: AtomicU32::new(cseq),
-            remote_uri,
-            remote_seq: AtomicU32::new(cseq),
+            remote_uri: Mutex::new(remote_uri),
+            remote_seq: AtomicU32::new(remote_seq),
             credential,
-            route_set,
+            auth_challenges: Mutex::new(vec![]),
+            route_set: Mutex::new(route_set),
             endpoint_inner,
             state_sender,
             tu_sender: Mutex::new(None),
+            update_sender: Mutex::new(None),
+            last_invite_response: Mutex::new(None),
+            last_response: Mutex::new(None),
+            refer_state: Mutex::new(None),
+            invite_seq: AtomicU32::new(cseq),
+            invite_request: Mutex::new(None),
+            early_dialogs: Mutex::new(HashMap::new()),
+            pending_cancel: Mutex::new(None),
+            cancelled: AtomicBool::new(false),
+            rseq: AtomicU32::new(0),
+            pending_prack: Mutex::new(None),
+            pending_answer: Mutex::new(None),
+            pending_ack: Mutex::new(None),
+            session_timer_config: Mutex::new(None),
+            session_timer: Mutex::new(None),
+            session_refreshed: Notify::new(),
+            state_watchers: Mutex::new(vec![]),
+            ping_failures: AtomicU32::new(0),
+            ping_token: Mutex::new(None),
+            connection: Mutex::new(None),
+            transport: Mutex::new(None),
+            registry: Mutex::new(None),
+            replaces: Mutex::new(None),
+            remote_user_agent: Mutex::new(remote_user_agent),
+            created_at: now,
+            confirmed_at: Mutex::new(None),
+            last_activity: Mutex::new(now),
+            held: Mutex::new(None),
             state: Mutex::new(DialogState::Calling(id)),
             initial_request,
             local_contact,
@@ -145,6 +501,37 @@ impl DialogInner {
     pub fn is_confirmed(&self) -> bool {
         self.state.lock().unwrap().is_confirmed()
     }
+
+    /// the local and remote tags, whichever side sent the initial request
+    pub fn tags(&self) -> (String, String) {
+        let id = self.id.lock().unwrap();
+        (
+            id.local_tag(&self.role).to_string(),
+            id.remote_tag(&self.role).to_string(),
+        )
+    }
+
+    /// keep the connection of the initial transaction if it is a stream
+    pub(super) fn set_connection(&self, connection: Option<&SipConnection>) {
+        if let Some(
+            connection @ (SipConnection::Tcp(_)
+            | SipConnection::Tls(_)
+            | SipConnection::WebSocket(_)),
+        ) = connection
+        {
+            self.connection
+                .lock()
+                .unwrap()
+                .get_or_insert_with(|| connection.clone());
+        }
+    }
+    /// early or confirmed, e.g. UPDATE is allowed (RFC 3311)
+    pub fn is_established(&self) -> bool {
+        matches!(
+            *self.state.lock().unwrap(),
+            DialogState::Early(_, _) | DialogState::WaitAck(_, _) | DialogState::Confirmed(_)
+        )
+    }
     pub fn get_local_seq(&self) -> u32 {
         self.local_seq.load(Ordering::Relaxed)
     }
@@ -153,7 +540,30 @@ impl DialogInner {
         self.local_seq.load(Ordering::Relaxed)
     }
 
-    pub fn increment_remove_seq(&self) -> u32 {
+    /// the CSeq of the last request received in the dialog
+    pub fn get_remote_seq(&self) -> u32 {
+        self.remote_seq.load(Ordering::Relaxed)
+    }
+    /// Check the CSeq of a request received in the dialog, it counts up and
+    /// never wraps (RFC 3261 12.2.2); a CSeq from 2^31 on is invalid (8.1.1.5)
+    /// and answered 400 without moving the remote sequence, a lower one 500
+    pub fn check_remote_seq(&self, cseq: u32) -> std::result::Result<(), StatusCode> {
+        let remote_seq = self.remote_seq.load(Ordering::Relaxed);
+        if cseq >= MAX_CSEQ {
+            info!("received invalid cseq {}, must be under 2^31", cseq);
+            return Err(StatusCode::BadRequest);
+        }
+        if cseq < remote_seq {
+            info!(
+                "received old request cseq {} < remote_seq {}",
+                cseq, remote_seq
+            );
+            return Err(StatusCode::ServerInternalError);
+        }
+        Ok(())
+    }
+
+    pub fn increment_remote_seq(&self) -> u32 {
         self.remote_seq.fetch_add(1, Ordering::Relaxed);
         self.remote_seq.load(Ordering::Relaxed)
     }
@@ -166,6 +576,46 @@ impl DialogInner {
         Ok(())
     }
 
+    /// the route set of a UAC comes from the Record-Route of the 2xx, in reverse (RFC 3261 12.1.2)
+    pub(super) fn update_route_set(&self, resp: &Response) {
+        *self.route_set.lock().unwrap() = route_set(&resp.headers, &self.role);
+    }
+
+    /// the Server (or User-Agent) of the 2xx that established a client dialog
+    pub(super) fn update_remote_user_agent(&self, resp: &Response) {
+        *self.remote_user_agent.lock().unwrap() = user_agent(&resp.headers);
+    }
+
+    /// in-dialog requests go to the Contact of the 2xx, not the request uri of the INVITE
+    pub(super) fn update_remote_target(&self, resp: &Response) -> Result<()> {
+        self.set_remote_target(&resp.headers)
+    }
+
+    /// A target refresh request, a re-INVITE or UPDATE, changes the remote target to its
+    /// Contact, the route set stays the one of the dialog setup (RFC 3261 12.2)
+    pub(super) fn refresh_remote_target(&self, req: &Request) -> Result<()> {
+        if matches!(req.method, rsip::Method::Invite | rsip::Method::Update) {
+            self.set_remote_target(&req.headers)?;
+        }
+        Ok(())
+    }
+
+    fn set_remote_target(&self, headers: &rsip::Headers) -> Result<()> {
+        let contact = match headers.iter().find_map(|h| match h {
+            Header::Contact(contact) => Some(contact),
+            _ => None,
+        }) {
+            Some(contact) => extract_uri_from_contact(contact.value())?,
+            None => return Ok(()),
+        };
+        let mut remote_uri = self.remote_uri.lock().unwrap();
+        if *remote_uri != contact {
+            info!("updating remote target to: {}", contact);
+            *remote_uri = contact;
+        }
+        Ok(())
+    }
+
     pub(super) fn make_request(
         &self,
         method: rsip::Method,
@@ -174,9 +624,15 @@ impl DialogInner {
         headers: Option<Vec<rsip::Header>>,
         body: Option<Vec<u8>>,
     ) -> Result<rsip::Request> {
-        let mut headers = headers.unwrap_or_default();
+        // the headers of the application can not replace the ones of the dialog
+        let mut headers = headers
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|h| !is_dialog_header(h))
+            .collect::<Vec<_>>();
+        // our requests are numbered from the local sequence (RFC 3261 12.2.1.1)
         let cseq_header = CSeq {
-            seq: cseq.unwrap_or(self.increment_remove_seq()),
+            seq: cseq.unwrap_or_else(|| self.increment_local_seq()),
             method,
         };
 
@@ -188,18 +644,38 @@ impl DialogInner {
         headers.push(Header::From(self.from.clone().into()));
         headers.push(Header::To(self.to.lock().unwrap().clone().into()));
         headers.push(Header::CSeq(cseq_header.into()));
-        headers.push(Header::UserAgent(
-            self.endpoint_inner.user_agent.clone().into(),
-        ));
+        if !headers.iter().any(|h| matches!(h, Header::UserAgent(_))) {
+            headers.push(Header::UserAgent(
+                self.endpoint_inner.user_agent.clone().into(),
+            ));
+        }
 
-        self.local_contact
-            .as_ref()
-            .map(|c| headers.push(Contact::from(c.clone()).into()));
+        if !headers.iter().any(|h| matches!(h, Header::Contact(_))) {
+            self.local_contact
+                .as_ref()
+                .map(|c| headers.push(Contact::from(c.clone()).into()));
+        }
 
-        for route in &self.route_set {
-            headers.push(Header::Route(route.clone()));
+        let route_set = self.route_set.lock().unwrap().clone();
+        let remote_target = self.remote_uri.lock().unwrap().clone();
+        let (uri, routes) = match route_set.split_first() {
+            // a strict router takes the request uri, the remote target goes last (RFC 3261 12.2.1.1)
+            Some((first, rest)) if !is_loose_route(first) => {
+                let mut routes = rest.to_vec();
+                routes.push(UriWithParams {
+                    uri: remote_target,
+                    params: vec![],
+                });
+                (first.uri.clone(), routes)
+            }
+            _ => (remote_target, route_set),
+        };
+        for route in routes {
+            headers.push(Header::Route(Route::new(route.to_string())));
+        }
+        if !headers.iter().any(|h| matches!(h, Header::MaxForwards(_))) {
+            headers.push(Header::MaxForwards(70.into()));
         }
-        headers.push(Header::MaxForwards(70.into()));
 
         body.as_ref().map(|b| {
             headers.push(Header::ContentLength((b.len() as u32).into()));
@@ -207,7 +683,7 @@ impl DialogInner {
 
         let req = rsip::Request {
             method,
-            uri: self.remote_uri.clone(),
+            uri,
             headers: headers.into(),
             body: body.unwrap_or_default(),
             version: rsip::Version::V2,
@@ -215,6 +691,63 @@ impl DialogInner {
         Ok(req)
     }
 
+    /// The ACK of the final response `resp` to `invite`. A 2xx is acknowledged in
+    /// a new transaction through the route set to the remote target (RFC 3261
+    /// 13.2.2.4), other responses within the INVITE transaction (RFC 3261 17.1.1.3).
+    pub(super) fn make_ack(
+        &self,
+        invite: &Request,
+        resp: &Response,
+        headers: Option<Vec<rsip::Header>>,
+        body: Option<Vec<u8>>,
+    ) -> Result<rsip::Request> {
+        let seq = invite.cseq_header()?.seq()?;
+        let to = Header::To(resp.to_header()?.clone());
+        if resp.status_code.kind() == StatusCodeKind::Successful {
+            let mut ack = self.make_request(rsip::Method::Ack, Some(seq), None, headers, body)?;
+            ack.headers.unique_push(to);
+            return Ok(ack);
+        }
+        self.endpoint_inner.make_error_ack(invite, resp)
+    }
+
+    /// true if the Allow of the peer's INVITE, or of its 2xx to ours, lists `method`
+    pub(super) fn remote_allows(&self, method: &rsip::Method) -> bool {
+        let headers = match self.role {
+            TransactionRole::Client => self
+                .last_invite_response
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(|resp| resp.headers.clone()),
+            TransactionRole::Server => Some(self.initial_request.headers.clone()),
+        };
+        let method = method.to_string();
+        headers.is_some_and(|headers| {
+            headers.iter().any(|h| match h {
+                Header::Allow(allow) => allow
+                    .value()
+                    .split(',')
+                    .any(|m| m.trim().eq_ignore_ascii_case(&method)),
+                _ => false,
+            })
+        })
+    }
+
+    /// The content type and body of our side of the INVITE exchange, the
+    /// offer we sent or the answer we gave
+    pub(super) fn local_sdp(&self) -> Option<(String, Vec<u8>)> {
+        match self.role {
+            TransactionRole::Client => {
+                extract_sdp(&self.initial_request.headers, &self.initial_request.body)
+            }
+            TransactionRole::Server => {
+                let resp = self.last_invite_response.lock().unwrap().clone()?;
+                extract_sdp(&resp.headers, &resp.body)
+            }
+        }
+    }
+
     pub(super) fn make_response(
         &self,
         request: &Request,
@@ -263,9 +796,25 @@ impl DialogInner {
             }
         }
 
+        if status.kind() == rsip::StatusCodeKind::Successful {
+            match request.method {
+                rsip::Method::Invite => {
+                    resp_headers.push(self.endpoint_inner.allow_header().into())
+                }
+                rsip::Method::Options => {
+                    resp_headers.extend(self.endpoint_inner.capability_headers())
+                }
+                _ => {}
+            }
+        }
+
         if let Some(headers) = headers {
             for header in headers {
-                resp_headers.unique_push(header);
+                match header {
+                    // unique_push would drop the other extension headers
+                    Header::Other(_, _) => resp_headers.push(header),
+                    _ => resp_headers.unique_push(header),
+                }
             }
         }
 
@@ -285,60 +834,171 @@ impl DialogInner {
         }
     }
 
-    pub(super) async fn do_request(&self, mut request: Request) -> Result<Option<rsip::Response>> {
+    pub(super) async fn do_request(&self, request: Request) -> Result<Option<rsip::Response>> {
+        self.do_request_with_timeout(request, None).await
+    }
+
+    /// Send `request` and wait for its final response, for `timeout` or 64*T1
+    /// (Timer F) by default, started over after an authentication challenge.
+    ///
+    /// A timeout is answered with a local 408 and terminates the dialog
+    /// (RFC 3261 12.2.1.2), except for OPTIONS whose failures the ping counts.
+    #[instrument(
+        name = "dialog_request",
+        skip_all,
+        fields(dialog_id = %self.id.lock().unwrap(), method = %request.method)
+    )]
+    pub(super) async fn do_request_with_timeout(
+        &self,
+        mut request: Request,
+        timeout: Option<Duration>,
+    ) -> Result<Option<rsip::Response>> {
         let method = request.method().to_owned();
-        let destination = request
-            .route_header()
-            .map(|r| {
-                r.typed()
-                    .ok()
-                    .map(|r| r.uris().first().map(|u| u.uri.clone()))
-                    .flatten()
-            })
-            .flatten();
+        // the next hop is the first loose router, a strict router is the request uri already
+        let route = match request.route_header() {
+            Some(route) => route
+                .typed()
+                .map_err(|e| {
+                    crate::Error::DialogError(
+                        format!("invalid route {}: {}", route.value(), e),
+                        self.id.lock().unwrap().clone(),
+                    )
+                })?
+                .uris()
+                .first()
+                .filter(|u| is_loose_route(u))
+                .map(|u| u.uri.clone()),
+            None => None,
+        };
 
-        header_pop!(request.headers, Header::Route);
+        // CANCEL can't be challenged (RFC 3261 22.1)
+        let mut preauthorized = false;
+        if let (Some(cred), false) = (&self.credential, method == rsip::Method::Cancel) {
+            let challenges = self.auth_challenges.lock().unwrap().clone();
+            if !challenges.is_empty() {
+                preauthorize(&mut request, cred, &challenges, &self.endpoint_inner)?;
+                preauthorized = true;
+            }
+        }
 
-        let key = TransactionKey::from_request(&request, TransactionRole::Client)?;
-        let mut tx = Transaction::new_client(key, request, self.endpoint_inner.clone(), None);
-        tx.destination = destination.as_ref().map(|d| d.try_into().ok()).flatten();
+        // a CANCEL shares the branch of the INVITE, its transaction is keyed apart
+        let key = match method {
+            rsip::Method::Cancel => {
+                TransactionKey::from_ack_or_cancel(&request, TransactionRole::Client)?
+            }
+            _ => TransactionKey::from_request(&request, TransactionRole::Client)?,
+        };
+        let transport = *self.transport.lock().unwrap();
+        let connection = self
+            .connection
+            .lock()
+            .unwrap()
+            .clone()
+            .filter(|c| transport.is_none_or(|t| c.get_addr().r#type == Some(t)));
+        let mut destinations = vec![];
+        // the first route is resolved like a request uri (RFC 3263), with a
+        // forced transport the transaction resolves it
+        if let (None, Some(route), None) = (&connection, route, transport) {
+            destinations = self.endpoint_inner.transport_layer.resolve(&route).await?;
+        }
+        let mut tx = Transaction::new_client(key, request, self.endpoint_inner.clone(), connection);
+        tx.destinations = destinations;
+        tx.transport = transport;
 
-        tx.send().await?;
-        let mut auth_sent = false;
+        if let Err(e) = tx.send().await {
+            // the kept connection is dead, the next request reconnects
+            self.connection.lock().unwrap().take();
+            return Err(e);
+        }
+        self.set_connection(tx.connection.as_ref());
+        let mut auth_sent = preauthorized;
+        let mut challenges = vec![];
+        let timeout = timeout.unwrap_or(self.endpoint_inner.t1x64);
+        let clock = self.endpoint_inner.clock().clone();
+        let mut deadline = clock.now() + timeout;
 
-        while let Some(msg) = tx.receive().await {
+        loop {
+            let msg = select! {
+                msg = tx.receive() => msg,
+                _ = clock.sleep_until(deadline) => None,
+            };
+            let msg = match msg {
+                Some(msg) => msg,
+                // the transaction timed out or the peer went silent
+                None => {
+                    info!("{} timeout after {:?}", method, timeout);
+                    if method != rsip::Method::Options {
+                        self.transition(DialogState::Terminated(
+                            self.id.lock().unwrap().clone(),
+                            Some(StatusCode::RequestTimeout),
+                            None,
+                        ))?;
+                    }
+                    let resp =
+                        self.make_response(&tx.original, StatusCode::RequestTimeout, None, None);
+                    return Ok(Some(resp));
+                }
+            };
             match msg {
                 SipMessage::Response(resp) => match resp.status_code {
                     StatusCode::Trying => {
                         continue;
                     }
-                    StatusCode::Ringing | StatusCode::SessionProgress => {
-                        self.transition(DialogState::Early(self.id.lock().unwrap().clone(), resp))?;
+                    _ if resp.status_code.kind() == StatusCodeKind::Provisional => {
+                        if !self.is_confirmed() {
+                            self.transition(DialogState::Early(
+                                self.id.lock().unwrap().clone(),
+                                resp,
+                            ))?;
+                        }
                         continue;
                     }
-                    StatusCode::ProxyAuthenticationRequired | StatusCode::Unauthorized => {
+                    // a CANCEL keeps the branch of the INVITE, it is never retried (RFC 3261 22.1)
+                    StatusCode::ProxyAuthenticationRequired | StatusCode::Unauthorized
+                        if method != rsip::Method::Cancel =>
+                    {
                         let id = self.id.lock().unwrap().clone();
-                        if auth_sent {
+                        // a stale nonce only asks us to answer the new challenge
+                        if auth_sent && !(preauthorized && is_stale(&resp)) {
                             info!("received {} response after auth sent", resp.status_code);
-                            self.transition(DialogState::Terminated(id, Some(resp.status_code)))?;
+                            self.auth_challenges.lock().unwrap().clear();
+                            self.transition(DialogState::Terminated(
+                                id,
+                                Some(resp.status_code),
+                                None,
+                            ))?;
                             break;
                         }
                         auth_sent = true;
+                        preauthorized = false;
                         if let Some(cred) = &self.credential {
-                            let new_seq = match method {
-                                rsip::Method::Cancel => self.get_local_seq(),
-                                _ => self.increment_local_seq(),
-                            };
+                            let new_seq = self.increment_local_seq();
+                            challenges = select_challenges_for(&resp, cred);
                             tx = handle_client_authenticate(new_seq, tx, resp, cred).await?;
                             tx.send().await?;
+                            deadline = clock.now() + timeout;
                             continue;
                         } else {
                             info!("received 407 response without auth option");
-                            self.transition(DialogState::Terminated(id, Some(resp.status_code)))?;
+                            self.transition(DialogState::Terminated(
+                                id,
+                                Some(resp.status_code),
+                                None,
+                            ))?;
                         }
                     }
                     _ => {
                         debug!("dialog do_request done: {:?}", resp.status_code);
+                        if !challenges.is_empty() {
+                            *self.auth_challenges.lock().unwrap() = challenges;
+                        }
+                        // the transaction acknowledges the non-2xx itself
+                        if method == rsip::Method::Invite
+                            && resp.status_code.kind() == StatusCodeKind::Successful
+                        {
+                            let ack = self.make_ack(&tx.original, &resp, None, None)?;
+                            tx.send_ack(ack).await?;
+                        }
                         return Ok(Some(resp));
                     }
                 },
@@ -348,21 +1008,228 @@ impl DialogInner {
         Ok(None)
     }
 
-    pub(super) fn transition(&self, state: DialogState) -> Result<()> {
-        self.state_sender.send(state.clone())?;
+    /// Send an OPTIONS to the remote target and measure the round trip time.
+    ///
+    /// A 481 means the peer lost the dialog and terminates it at once
+    /// (RFC 5057 5.1), the dialog is also terminated after
+    /// `MAX_PING_FAILURES` consecutive pings timing out or failing to be sent.
+    pub(super) async fn options_ping(&self) -> Result<Option<PingResult>> {
+        let request = self.make_request(
+            rsip::Method::Options,
+            Some(self.increment_local_seq()),
+            None,
+            None,
+            None,
+        )?;
+        let clock = self.endpoint_inner.clock();
+        let start = clock.now();
+        let resp = match self.do_request(request).await {
+            Ok(Some(resp)) if resp.status_code == StatusCode::CallTransactionDoesNotExist => {
+                info!("options ping answered {}", resp.status_code);
+                self.transition(DialogState::Terminated(
+                    self.id.lock().unwrap().clone(),
+                    Some(resp.status_code),
+                    None,
+                ))?;
+                return Ok(None);
+            }
+            Ok(Some(resp)) if resp.status_code != StatusCode::RequestTimeout => resp,
+            r => {
+                let failures = self.ping_failures.fetch_add(1, Ordering::Relaxed) + 1;
+                info!("options ping failed {}/{}", failures, MAX_PING_FAILURES);
+                if failures >= MAX_PING_FAILURES {
+                    self.transition(DialogState::Terminated(
+                        self.id.lock().unwrap().clone(),
+                        Some(StatusCode::RequestTimeout),
+                        None,
+                    ))?;
+                }
+                return r.map(|_| None);
+            }
+        };
+        self.ping_failures.store(0, Ordering::Relaxed);
+        let rtt = clock.now() - start;
+        let allow = resp.headers.iter().find_map(|h| match h {
+            Header::Allow(v) => Some(v.value().to_string()),
+            _ => None,
+        });
+        let accept = resp.headers.iter().find_map(|h| match h {
+            Header::Accept(v) => Some(v.value().to_string()),
+            _ => None,
+        });
+        Ok(Some(PingResult { rtt, allow, accept }))
+    }
+
+    /// the state of an incoming INFO, a DTMF digit when it carries one
+    pub(super) fn info_state(&self, request: &Request) -> DialogState {
+        let id = self.id.lock().unwrap().clone();
+        let is_dtmf = request.headers.iter().any(|h| match h {
+            Header::ContentType(c) => c
+                .value()
+                .split(';')
+                .next()
+                .is_some_and(|t| t.trim().eq_ignore_ascii_case(DTMF_RELAY)),
+            _ => false,
+        });
+        match is_dtmf.then(|| DtmfEvent::parse(&request.body)).flatten() {
+            Some(event) => DialogState::Dtmf(id, event),
+            None => DialogState::Info(id, request.clone()),
+        }
+    }
+
+    fn update_metrics(&self, old_state: &DialogState, state: &DialogState) {
+        let metrics = &self.endpoint_inner.dialog_metrics;
+        let now = self.endpoint_inner.clock().now();
+        metrics.on_transition(old_state, state);
         match state {
-            DialogState::Updated(_, _) | DialogState::Notify(_, _) | DialogState::Info(_, _) => {
-                return Ok(());
+            DialogState::Confirmed(_) => {
+                let mut confirmed_at = self.confirmed_at.lock().unwrap();
+                if confirmed_at.is_none() {
+                    metrics.on_confirmed(now - self.created_at);
+                    confirmed_at.replace(now);
+                }
+            }
+            DialogState::Terminated(_, status, _) => {
+                let duration = self.confirmed_at.lock().unwrap().map(|t| now - t);
+                metrics.on_terminated(status.into(), duration);
+            }
+            DialogState::Cancelled(_, _) => {
+                // a cancelled INVITE counts as its 487
+                metrics.on_terminated((&Some(StatusCode::RequestTerminated)).into(), None);
             }
             _ => {}
         }
-        let mut old_state = self.state.lock().unwrap();
-        info!("transitioning state: {} -> {}", old_state, state);
-        *old_state = state;
+    }
+
+    /// A stream of the states of the dialog from now on, the ones sent to the
+    /// `state_sender` are still sent there
+    pub(super) fn states(&self) -> DialogStateStream {
+        let (sender, states) = DialogStateStream::channel();
+        self.state_watchers.lock().unwrap().push(sender);
+        states
+    }
+
+    pub(super) fn touch(&self) {
+        *self.last_activity.lock().unwrap() = self.endpoint_inner.clock().now();
+    }
+
+    pub(super) fn transition(&self, state: DialogState) -> Result<()> {
+        self.touch();
+        match state {
+            DialogState::Ack(_, _)
+            | DialogState::Updated(_, _)
+            | DialogState::Notify(_, _)
+            | DialogState::Info(_, _)
+            | DialogState::Dtmf(_, _)
+            | DialogState::Message(_, _)
+            | DialogState::Refer(_, _, _) => {}
+            _ => {
+                let mut old_state = self.state.lock().unwrap();
+                match (&*old_state, &state) {
+                    // e.g. a BYE that timed out, it is terminated already
+                    (old, new) if old.is_terminated() && new.is_terminated() => {
+                        return Ok(());
+                    }
+                    (old, _) if old.is_terminated() => {}
+                    _ => self.update_metrics(&old_state, &state),
+                }
+                info!("transitioning state: {} -> {}", old_state, state);
+                *old_state = state.clone();
+            }
+        }
+        let registry = match state.is_terminated() {
+            true => self.registry.lock().unwrap().take(),
+            false => self.registry.lock().unwrap().clone(),
+        };
+        if let Some((layer, id)) = registry {
+            if let Some(layer) = layer.upgrade() {
+                if state.is_terminated() {
+                    layer.remove_dialog(&id);
+                }
+                layer.publish(&state);
+            }
+        }
+        self.state_watchers
+            .lock()
+            .unwrap()
+            .retain(|watcher| watcher.send(state.clone()).is_ok());
+        self.state_sender.send(state)?;
         Ok(())
     }
 }
 
+impl Drop for DialogInner {
+    fn drop(&mut self) {
+        self.endpoint_inner
+            .dialog_metrics
+            .on_dropped(&self.state.lock().unwrap());
+    }
+}
+
+/// the Record-Route entries of `headers`, reversed for a UAC
+fn route_set(headers: &rsip::Headers, role: &TransactionRole) -> Vec<UriWithParams> {
+    let mut route_set = headers
+        .iter()
+        .filter_map(|h| match h {
+            Header::RecordRoute(rr) => rr.typed().ok(),
+            _ => None,
+        })
+        .flat_map(|rr| rr.uris().to_vec())
+        .collect::<Vec<_>>();
+    if *role == TransactionRole::Client {
+        route_set.reverse();
+    }
+    route_set
+}
+
+const DIALOG_HEADERS: [&str; 12] = [
+    "Via",
+    "v",
+    "Call-ID",
+    "i",
+    "From",
+    "f",
+    "To",
+    "t",
+    "CSeq",
+    "Route",
+    "Content-Length",
+    "l",
+];
+
+/// the headers set by the dialog itself, extra headers must not duplicate them
+pub(super) fn is_dialog_header(header: &Header) -> bool {
+    match header {
+        Header::Via(_)
+        | Header::CallId(_)
+        | Header::From(_)
+        | Header::To(_)
+        | Header::CSeq(_)
+        | Header::Route(_)
+        | Header::ContentLength(_) => true,
+        Header::Other(name, _) => DIALOG_HEADERS.iter().any(|h| name.eq_ignore_ascii_case(h)),
+        _ => false,
+    }
+}
+
+/// the Server header of a response, or the User-Agent of a request
+fn user_agent(headers: &rsip::Headers) -> Option<String> {
+    let server = headers.iter().find_map(|h| match h {
+        Header::Server(server) => Some(server.value().to_string()),
+        _ => None,
+    });
+    server.or_else(|| {
+        headers.iter().find_map(|h| match h {
+            Header::UserAgent(user_agent) => Some(user_agent.value().to_string()),
+            _ => None,
+        })
+    })
+}
+
+pub(crate) fn is_loose_route(route: &UriWithParams) -> bool {
+    route.uri.params.contains(&Param::Lr) || route.params.contains(&Param::Lr)
+}
+
 impl std::fmt::Display for DialogState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -371,10 +1238,15 @@ impl std::fmt::Display for DialogState {
             DialogState::Early(id, _) => write!(f, "{}(Early)", id),
             DialogState::WaitAck(id, _) => write!(f, "{}(WaitAck)", id),
             DialogState::Confirmed(id) => write!(f, "{}(Confirmed)", id),
+            DialogState::Ack(id, _) => write!(f, "{}(Ack)", id),
             DialogState::Updated(id, _) => write!(f, "{}(Updated)", id),
             DialogState::Notify(id, _) => write!(f, "{}(Notify)", id),
             DialogState::Info(id, _) => write!(f, "{}(Info)", id),
-            DialogState::Terminated(id, code) => write!(f, "{}(Terminated {:?})", id, code),
+            DialogState::Dtmf(id, event) => write!(f, "{}(Dtmf {})", id, event.digit),
+            DialogState::Message(id, _) => write!(f, "{}(Message)", id),
+            DialogState::Refer(id, _, refer_to) => write!(f, "{}(Refer {})", id, refer_to),
+            DialogState::Terminated(id, code, _) => write!(f, "{}(Terminated {:?})", id, code),
+            DialogState::Cancelled(id, _) => write!(f, "{}(Cancelled)", id),
         }
     }
 }
@@ -384,14 +1256,309 @@ impl Dialog {
         match self {
             Dialog::ServerInvite(d) => d.inner.id.lock().unwrap().clone(),
             Dialog::ClientInvite(d) => d.inner.id.lock().unwrap().clone(),
+            Dialog::ClientSubscribe(d) => d.inner.id.lock().unwrap().clone(),
         }
     }
-    pub async fn handle(&mut self, tx: Transaction) -> Result<()> {
+    pub async fn handle(&mut self, mut tx: Transaction) -> Result<()> {
+        self.inner().touch();
+        // ACK and CANCEL belong to an INVITE, a BYE ends the dialog at once
+        let method = tx.original.method;
+        if !matches!(
+            method,
+            rsip::Method::Ack | rsip::Method::Cancel | rsip::Method::Bye
+        ) {
+            let full = match self.inner().held.lock().unwrap().as_mut() {
+                Some(held) if held.len() < MAX_HELD_REQUESTS => {
+                    debug!("dialog {} paused, holding {}", self.id(), method);
+                    held.push_back(tx);
+                    return Ok(());
+                }
+                Some(_) => true,
+                None => false,
+            };
+            if full {
+                info!("dialog {} paused, too many held requests", self.id());
+                return tx.reply(StatusCode::ServerInternalError).await;
+            }
+        }
+        let result = self.dispatch(tx).await;
+        if method == rsip::Method::Bye && self.state().is_terminated() {
+            self.release_held().await;
+        }
+        result
+    }
+
+    async fn dispatch(&mut self, tx: Transaction) -> Result<()> {
         match self {
             Dialog::ServerInvite(d) => d.handle(tx).await,
             Dialog::ClientInvite(d) => d.handle(tx).await,
+            Dialog::ClientSubscribe(d) => d.handle(tx).await,
+        }
+    }
+
+    /// Hold the inbound requests of the dialog until [`Dialog::resume`], e.g.
+    /// while a B2BUA negotiates a transfer on the other leg.
+    ///
+    /// The held requests are not answered: the peer retransmits them over UDP
+    /// and gives up after 64*T1. At most [`MAX_HELD_REQUESTS`] are held, the
+    /// next ones are answered 500. ACK and CANCEL are never held, nor is a
+    /// BYE: it is processed at once and the requests held then are answered
+    /// 487 (RFC 3261 15.1.2).
+    pub fn pause(&self) {
+        let mut held = self.inner().held.lock().unwrap();
+        if held.is_none() {
+            held.replace(VecDeque::new());
         }
     }
+
+    pub fn is_paused(&self) -> bool {
+        self.inner().held.lock().unwrap().is_some()
+    }
+
+    /// Process the held requests in the order [`Dialog::handle`] got them,
+    /// each one once the one before it is handled. The requests coming in meanwhile are held
+    /// behind them, the dialog is not paused anymore once none is left.
+    pub async fn resume(&self) {
+        let mut dialog = self.clone();
+        loop {
+            let tx = {
+                let mut held = self.inner().held.lock().unwrap();
+                match held.as_mut().and_then(|held| held.pop_front()) {
+                    Some(tx) => tx,
+                    None => {
+                        held.take();
+                        return;
+                    }
+                }
+            };
+            let key = tx.key.clone();
+            if let Err(e) = dialog.dispatch(tx).await {
+                info!("held request {} failed: {}", key, e);
+            }
+        }
+    }
+
+    // answer the held requests of a dialog just hung up
+    async fn release_held(&self) {
+        let held = self.inner().held.lock().unwrap().take();
+        for mut tx in held.into_iter().flatten() {
+            if let Err(e) = tx.reply(StatusCode::RequestTerminated).await {
+                info!("failed to answer held request {}: {}", tx.key, e);
+            }
+        }
+    }
+    pub fn state(&self) -> DialogState {
+        self.inner().state.lock().unwrap().clone()
+    }
+
+    /// when the dialog last changed state or got a request, on the clock of
+    /// the endpoint
+    pub fn last_activity(&self) -> Instant {
+        *self.inner().last_activity.lock().unwrap()
+    }
+    pub(super) fn inner(&self) -> &DialogInnerRef {
+        match self {
+            Dialog::ServerInvite(d) => &d.inner,
+            Dialog::ClientInvite(d) => &d.inner,
+            Dialog::ClientSubscribe(d) => &d.inner,
+        }
+    }
+
+    /// The content type and body of the initial request, the SDP offer of an INVITE
+    pub fn offer(&self) -> Option<(String, Vec<u8>)> {
+        let request = &self.inner().initial_request;
+        extract_sdp(&request.headers, &request.body)
+    }
+
+    /// The content type and body of the 2xx that established the dialog
+    pub fn answer(&self) -> Option<(String, Vec<u8>)> {
+        let inner = self.inner();
+        let resp = match &*inner.state.lock().unwrap() {
+            DialogState::WaitAck(_, resp) => Some(resp.clone()),
+            DialogState::Confirmed(_) => inner.last_invite_response.lock().unwrap().clone(),
+            _ => None,
+        }?;
+        if resp.status_code.kind() != StatusCodeKind::Successful {
+            return None;
+        }
+        extract_sdp(&resp.headers, &resp.body)
+    }
+
+    /// The Replaces header for an INVITE taking over this dialog at the remote
+    /// party, e.g. in the Refer-To of an attended transfer (RFC 3891)
+    pub fn replaces(&self) -> Replaces {
+        let (local_tag, remote_tag) = self.inner().tags();
+        Replaces {
+            call_id: self.id().call_id,
+            to_tag: remote_tag,
+            from_tag: local_tag,
+            early_only: false,
+        }
+    }
+
+    /// The User-Agent of the INVITE of a server dialog, or the Server header of the
+    /// 2xx of a client dialog, e.g. to work around the quirks of a known peer
+    pub fn remote_user_agent(&self) -> Option<String> {
+        self.inner().remote_user_agent.lock().unwrap().clone()
+    }
+
+    /// The Contact of the remote party the in-dialog requests go to, refreshed by
+    /// re-INVITEs and UPDATEs
+    pub fn remote_target(&self) -> rsip::Uri {
+        self.inner().remote_uri.lock().unwrap().clone()
+    }
+
+    /// The proxies the in-dialog requests go through, fixed once the dialog is set up
+    pub fn route_set(&self) -> Vec<UriWithParams> {
+        self.inner().route_set.lock().unwrap().clone()
+    }
+
+    /// Send an in-dialog OPTIONS, `None` if it timed out
+    pub async fn options_ping(&self) -> Result<Option<PingResult>> {
+        self.inner().options_ping().await
+    }
+
+    /// Send a request of any other method in the dialog, e.g. a PUBLISH, with
+    /// extra headers and an optional body whose Content-Type comes with
+    /// `headers`. The dialog numbers it and sets its Via, Call-ID, From, To,
+    /// CSeq, Route and Content-Length, the ones in `headers` are dropped.
+    ///
+    /// The final response is returned raw for the caller to interpret, only
+    /// an authentication challenge is answered; a local 408 stands for no
+    /// response within 64*T1 and terminates the dialog. INVITE, ACK, CANCEL,
+    /// BYE and PRACK move the dialog state and are refused here, rsip has no
+    /// methods beyond the ones of RFC 3261 and its extensions.
+    pub async fn send_request(
+        &self,
+        method: rsip::Method,
+        headers: Vec<Header>,
+        body: Option<Vec<u8>>,
+    ) -> Result<Response> {
+        let inner = self.inner();
+        if matches!(
+            method,
+            rsip::Method::Invite
+                | rsip::Method::Ack
+                | rsip::Method::Cancel
+                | rsip::Method::Bye
+                | rsip::Method::PRack
+                | rsip::Method::Register
+        ) {
+            return Err(crate::Error::DialogError(
+                format!("{} has its own dialog method", method),
+                self.id(),
+            ));
+        }
+        if !inner.is_established() {
+            return Err(crate::Error::DialogError(
+                format!("{} outside of an established dialog", method),
+                self.id(),
+            ));
+        }
+        let request = inner.make_request(method, None, None, Some(headers), body)?;
+        inner
+            .do_request(request)
+            .await?
+            .ok_or(crate::Error::DialogError(
+                format!("{} got no final response", method),
+                self.id(),
+            ))
+    }
+
+    /// Send the in-dialog requests on `transport` whatever the remote target
+    /// says, `None` (the default) follows its `transport` param.
+    pub fn set_transport(&self, transport: Option<rsip::Transport>) {
+        let inner = self.inner();
+        *inner.transport.lock().unwrap() = transport;
+        // a kept connection of another transport is not used anymore
+        let mut connection = inner.connection.lock().unwrap();
+        if connection
+            .as_ref()
+            .is_some_and(|c| transport.is_some_and(|t| c.get_addr().r#type != Some(t)))
+        {
+            connection.take();
+        }
+    }
+
+    /// Ping the remote target periodically, `None` (the default) stops pinging.
+    pub fn set_ping_interval(&self, interval: Option<Duration>) {
+        let inner = self.inner().clone();
+        let token = inner.cancel_token.child_token();
+        if let Some(old) = inner.ping_token.lock().unwrap().replace(token.clone()) {
+            old.cancel();
+        }
+        let interval = match interval {
+            Some(interval) => interval,
+            None => {
+                token.cancel();
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            loop {
+                select! {
+                    _ = token.cancelled() => break,
+                    _ = inner.endpoint_inner.clock().sleep(interval) => {}
+                }
+                if inner.state.lock().unwrap().is_terminated() {
+                    break;
+                }
+                match inner.options_ping().await {
+                    Ok(Some(r)) => debug!("options ping rtt: {:?}", r.rtt),
+                    Ok(None) => {}
+                    Err(e) => info!("options ping error: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Hang up for the endpoint shutdown: BYE a confirmed dialog, CANCEL an INVITE
+    /// in progress and reject one not answered yet. True when the dialog got its
+    /// final response within `timeout`, otherwise it is terminated locally.
+    pub(crate) async fn shutdown(&self, timeout: Duration) -> bool {
+        // taken first, the final state may come while the hangup is sent
+        let mut states = self.inner().states();
+        let hangup = async {
+            let result = match self {
+                Dialog::ClientInvite(d) => d.bye_with(None, Some(timeout)).await,
+                Dialog::ServerInvite(d) => match self.state() {
+                    DialogState::Confirmed(_) => d.bye().await,
+                    DialogState::Calling(_) | DialogState::Trying(_) | DialogState::Early(_, _) => {
+                        d.reject()
+                    }
+                    _ => Ok(()),
+                },
+                Dialog::ClientSubscribe(d) => d.unsubscribe().await.map(|_| ()),
+            };
+            if let Err(e) = result {
+                info!("shutdown dialog {} error: {}", self.id(), e);
+                return;
+            }
+            // a deferred CANCEL or a reject completes later
+            if !self.state().is_terminated() {
+                states.wait_until_terminated().await.ok();
+            }
+        };
+        select! {
+            _ = hangup => {}
+            _ = self.inner().endpoint_inner.clock().sleep(timeout) => {}
+        }
+        match self.state() {
+            DialogState::Terminated(_, Some(rsip::StatusCode::RequestTimeout), _) => false,
+            DialogState::Terminated(_, _, _) | DialogState::Cancelled(_, _) => true,
+            _ => {
+                self.inner()
+                    .transition(DialogState::Terminated(
+                        self.id(),
+                        Some(rsip::StatusCode::RequestTimeout),
+                        None,
+                    ))
+                    .ok();
+                false
+            }
+        }
+    }
+
     pub fn on_remove(&self) {
         match self {
             Dialog::ServerInvite(d) => {
@@ -400,6 +1567,9 @@ impl Dialog {
             Dialog::ClientInvite(d) => {
                 d.inner.cancel_token.cancel();
             }
+            Dialog::ClientSubscribe(d) => {
+                d.inner.cancel_token.cancel();
+            }
         }
     }
 }
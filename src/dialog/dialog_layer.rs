@@ -1,25 +1,122 @@
 use super::authenticate::Credential;
-use super::dialog::DialogStateSender;
+use super::dialog::{DialogState, DialogStateSender, DialogStateStream};
 use super::{dialog::Dialog, server_dialog::ServerInviteDialog, DialogId};
 use crate::dialog::dialog::DialogInner;
+use crate::rsip_ext::{replaces_header, Replaces};
 use crate::transaction::key::TransactionRole;
-use crate::transaction::make_tag;
 use crate::transaction::{endpoint::EndpointInnerRef, transaction::Transaction};
+use crate::transaction::{make_tag, TransactionSender};
+use crate::transport::SipConnection;
 use crate::Result;
-use rsip::Request;
+use futures::future::join_all;
+use rsip::prelude::HeadersExt;
+use rsip::{Method, Request, StatusCode};
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::{
     collections::HashMap,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+use tokio::{
+    select,
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    },
 };
 use tracing::info;
 
+/// states buffered per subscriber, a slower subscriber misses the oldest ones
+const STATE_BROADCAST_CAPACITY: usize = 256;
+
 pub struct DialogLayerInner {
     pub(super) last_seq: AtomicU32,
     pub(super) dialogs: RwLock<HashMap<DialogId, Dialog>>,
+    pub(super) state_broadcast: broadcast::Sender<DialogState>,
+    /// Terminated and Cancelled go to every subscriber unbounded, they can not be missed
+    pub(super) terminated_senders: Mutex<Vec<UnboundedSender<DialogState>>>,
 }
 pub type DialogLayerInnerRef = Arc<DialogLayerInner>;
 
+impl DialogLayerInner {
+    pub(super) fn publish(&self, state: &DialogState) {
+        match state.is_terminated() {
+            true => self
+                .terminated_senders
+                .lock()
+                .unwrap()
+                .retain(|sender| sender.send(state.clone()).is_ok()),
+            // no subscriber is not an error
+            false => {
+                self.state_broadcast.send(state.clone()).ok();
+            }
+        }
+    }
+
+    /// The dialogs held, once each though a dialog may be held by several ids
+    pub(crate) fn dialogs(&self) -> Vec<Dialog> {
+        let mut dialogs: Vec<Dialog> = vec![];
+        for dialog in self.dialogs.read().unwrap().values() {
+            if !dialogs
+                .iter()
+                .any(|d| Arc::ptr_eq(d.inner(), dialog.inner()))
+            {
+                dialogs.push(dialog.clone());
+            }
+        }
+        dialogs
+    }
+
+    pub(super) fn remove_dialog(&self, id: &DialogId) {
+        info!("remove dialog: {id}");
+        let dialog = self.dialogs.write().unwrap().remove(id);
+        if let Some(dialog) = dialog {
+            dialog.on_remove();
+            self.close_connection(&dialog);
+        }
+    }
+
+    /// Close the WebSocket we opened for a dialog once no other dialog uses it
+    fn close_connection(&self, dialog: &Dialog) {
+        let inner = dialog.inner();
+        if inner.role != TransactionRole::Client {
+            return;
+        }
+        let connection = match inner.connection.lock().unwrap().clone() {
+            Some(connection @ SipConnection::WebSocket(_)) => connection,
+            _ => return,
+        };
+        let in_use = self.dialogs.read().unwrap().values().any(|d| {
+            d.inner()
+                .connection
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_some_and(|c| c.get_addr() == connection.get_addr())
+        });
+        if in_use {
+            return;
+        }
+        tokio::spawn(async move {
+            if let Err(e) = connection.close().await {
+                info!("close connection failed: {} {:?}", connection, e);
+            }
+        });
+    }
+}
+
+/// Where [`DialogLayer::handle_incoming`] delivers what no existing dialog takes
+#[derive(Clone)]
+pub struct IncomingHandler {
+    pub state_sender: DialogStateSender,
+    pub credential: Option<Credential>,
+    pub contact: Option<rsip::Uri>,
+    /// new server INVITE dialogs, already handled, to accept or reject
+    pub invite_sender: UnboundedSender<ServerInviteDialog>,
+    /// out-of-dialog requests other than INVITE, e.g. OPTIONS or MESSAGE
+    pub request_sender: TransactionSender,
+}
+
 pub struct DialogLayer {
     pub endpoint: EndpointInnerRef,
     pub inner: DialogLayerInnerRef,
@@ -27,13 +124,18 @@ pub struct DialogLayer {
 
 impl DialogLayer {
     pub fn new(endpoint: EndpointInnerRef) -> Self {
-        Self {
-            endpoint,
-            inner: Arc::new(DialogLayerInner {
-                last_seq: AtomicU32::new(0),
-                dialogs: RwLock::new(HashMap::new()),
-            }),
-        }
+        let inner = Arc::new(DialogLayerInner {
+            last_seq: AtomicU32::new(0),
+            dialogs: RwLock::new(HashMap::new()),
+            state_broadcast: broadcast::channel(STATE_BROADCAST_CAPACITY).0,
+            terminated_senders: Mutex::new(vec![]),
+        });
+        // the endpoint hangs up the dialogs of its layers on shutdown
+        let mut layers = endpoint.dialog_layers.lock().unwrap();
+        layers.retain(|layer| layer.strong_count() > 0);
+        layers.push(Arc::downgrade(&inner));
+        drop(layers);
+        Self { endpoint, inner }
     }
 
     pub fn get_or_create_server_invite(
@@ -67,15 +169,12 @@ impl DialogLayer {
             credential,
             contact,
         )?;
+        dlg_inner.set_connection(tx.connection.as_ref());
 
         let dialog = ServerInviteDialog {
             inner: Arc::new(dlg_inner),
         };
-        self.inner
-            .dialogs
-            .write()
-            .unwrap()
-            .insert(id.clone(), Dialog::ServerInvite(dialog.clone()));
+        self.insert_dialog(Dialog::ServerInvite(dialog.clone()));
         info!("server invite dialog created: {id}");
         Ok(dialog)
     }
@@ -91,29 +190,272 @@ impl DialogLayer {
             Some(dialog) => return Some(dialog.clone()),
             None => {}
         }
-        let swap_id = DialogId {
-            call_id: id.call_id.clone(),
-            from_tag: id.to_tag.clone(),
-            to_tag: id.from_tag.clone(),
-        };
-        match dialogs.get(&swap_id) {
-            Some(dialog) => Some(dialog.clone()),
-            None => None,
+        if let Some(dialog) = dialogs.get(&id.swapped()) {
+            return Some(dialog.clone());
         }
+        // a client dialog is keyed without the remote tag until its INVITE completes
+        let early_id = DialogId {
+            to_tag: String::new(),
+            ..id.clone()
+        };
+        dialogs.get(&early_id).cloned()
+    }
+
+    /// Add `dialog` under its current id, it is removed once terminated
+    pub fn insert_dialog(&self, dialog: Dialog) {
+        let id = dialog.id();
+        dialog
+            .inner()
+            .registry
+            .lock()
+            .unwrap()
+            .replace((Arc::downgrade(&self.inner), id.clone()));
+        self.inner.dialogs.write().unwrap().insert(id, dialog);
     }
 
     pub fn remove_dialog(&self, id: &DialogId) {
-        info!("remove dialog: {id}");
+        self.inner.remove_dialog(id);
+    }
+
+    /// Hang up and remove the dialogs without a state change or an incoming
+    /// request for longer than `older_than`, e.g. a confirmed call whose peer
+    /// vanished without a BYE.
+    ///
+    /// The BYE, CANCEL or reject is sent best effort and awaited up to T1*64
+    /// as on [`Endpoint::shutdown`](crate::transaction::Endpoint::shutdown),
+    /// the dialogs are removed either way. Returns the ids of the reaped ones.
+    pub async fn reap(&self, older_than: Duration) -> Vec<DialogId> {
+        let now = self.endpoint.clock().now();
+        let stale: Vec<Dialog> = self
+            .inner
+            .dialogs()
+            .into_iter()
+            .filter(|d| now.saturating_duration_since(d.last_activity()) > older_than)
+            .collect();
+        if stale.is_empty() {
+            return vec![];
+        }
+        let timeout = self.endpoint.t1x64;
+        join_all(stale.iter().map(|d| d.shutdown(timeout))).await;
+
+        let mut reaped = vec![];
+        for dialog in stale {
+            info!("reaped dialog: {}", dialog.id());
+            // an early dialog of a fork may be held under another id too
+            let ids: Vec<DialogId> = self
+                .inner
+                .dialogs
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|(_, d)| Arc::ptr_eq(d.inner(), dialog.inner()))
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in ids {
+                self.inner.remove_dialog(&id);
+            }
+            reaped.push(dialog.id());
+        }
+        reaped
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.dialogs.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.dialogs.read().unwrap().is_empty()
+    }
+
+    /// A snapshot of the active dialogs and their states
+    pub fn iter(&self) -> impl Iterator<Item = (DialogId, DialogState)> {
+        let dialogs = self.inner.dialogs.read().unwrap();
+        dialogs
+            .iter()
+            .map(|(id, dialog)| (id.clone(), dialog.state()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Observe the states of the dialogs of the layer, every subscriber gets
+    /// every state along with the `DialogStateSender` of the dialog
+    pub fn subscribe(&self) -> DialogStateSubscriber {
+        let (terminated_sender, terminated) = unbounded_channel();
         self.inner
-            .dialogs
-            .write()
+            .terminated_senders
+            .lock()
             .unwrap()
-            .remove(id)
-            .map(|d| d.on_remove());
+            .push(terminated_sender);
+        DialogStateSubscriber {
+            states: self.inner.state_broadcast.subscribe(),
+            terminated,
+        }
+    }
+
+    /// A state channel for `do_invite` or an [`IncomingHandler`], bounded when
+    /// the endpoint was built with a `dialog_state_capacity`
+    pub fn state_channel(&self) -> (DialogStateSender, DialogStateStream) {
+        match self.endpoint.dialog_state_capacity() {
+            Some(capacity) => DialogStateStream::bounded(capacity),
+            None => DialogStateStream::channel(),
+        }
     }
 
     pub fn match_dialog(&self, req: &Request) -> Option<Dialog> {
         let id = DialogId::try_from(req).ok()?;
         self.get_dialog(&id)
     }
+
+    /// The INVITE dialog a received Replaces header refers to, its to-tag is our tag
+    pub fn match_replaces(&self, replaces: &Replaces) -> Option<Dialog> {
+        let tags = (replaces.to_tag.clone(), replaces.from_tag.clone());
+        self.inner.dialogs().into_iter().find(|dialog| {
+            !matches!(dialog, Dialog::ClientSubscribe(_))
+                && dialog.id().call_id == replaces.call_id
+                && dialog.inner().tags() == tags
+        })
+    }
+
+    /// The dialog the Replaces header of a new INVITE takes over, or the status
+    /// to reject the INVITE with (RFC 3891 3)
+    fn replaced_dialog(&self, req: &Request) -> std::result::Result<Option<Dialog>, StatusCode> {
+        let replaces = match replaces_header(&req.headers) {
+            None => return Ok(None),
+            Some(None) => return Err(StatusCode::BadRequest),
+            Some(Some(replaces)) => replaces,
+        };
+        let dialog = self
+            .match_replaces(&replaces)
+            .ok_or(StatusCode::CallTransactionDoesNotExist)?;
+        // an early dialog is only replaced by its own UAC, which we don't do
+        if replaces.early_only || !dialog.inner().is_confirmed() {
+            return Err(StatusCode::BusyHere);
+        }
+        Ok(Some(dialog))
+    }
+
+    /// The server dialog of an earlier INVITE with the Call-ID, From tag and
+    /// CSeq of `req` in another transaction, the same request come over
+    /// another path (RFC 3261 8.2.2.2)
+    fn merged_dialog(&self, req: &Request) -> Option<ServerInviteDialog> {
+        let id = DialogId::try_from(req).ok()?;
+        let cseq = req.cseq_header().ok()?.seq().ok()?;
+        let dialogs = self.inner.dialogs.read().unwrap();
+        dialogs.values().find_map(|dialog| match dialog {
+            Dialog::ServerInvite(dialog) => {
+                let dialog_id = dialog.id();
+                let initial_cseq = dialog
+                    .inner
+                    .initial_request
+                    .cseq_header()
+                    .ok()?
+                    .seq()
+                    .ok()?;
+                (dialog_id.call_id == id.call_id
+                    && dialog_id.from_tag == id.from_tag
+                    && initial_cseq == cseq)
+                    .then(|| dialog.clone())
+            }
+            _ => None,
+        })
+    }
+
+    /// Route a transaction from `Endpoint::incoming_transactions`: in-dialog
+    /// requests go to their dialog, a new INVITE creates a server dialog and
+    /// the other requests go to the `request_sender` of the handler
+    pub async fn handle_incoming(
+        &self,
+        mut tx: Transaction,
+        handler: &IncomingHandler,
+    ) -> Result<()> {
+        let method = tx.original.method;
+        if tx.original.to_header()?.tag()?.is_some() {
+            match self.match_dialog(&tx.original) {
+                Some(mut dialog) => {
+                    tokio::spawn(async move { dialog.handle(tx).await });
+                }
+                None if method == Method::Ack => {
+                    info!("ack without dialog: {}", tx.key);
+                }
+                None => tx.reply(StatusCode::CallTransactionDoesNotExist).await?,
+            }
+            return Ok(());
+        }
+        match method {
+            Method::Invite => {
+                // the retransmissions are absorbed by the INVITE transaction,
+                // a merged request is rejected instead of making another dialog
+                if let Some(dialog) = self.merged_dialog(&tx.original) {
+                    info!("merged invite of dialog {}: {}", dialog.id(), tx.key);
+                    return tx.reply(StatusCode::LoopDetected).await;
+                }
+                let replaced = match self.replaced_dialog(&tx.original) {
+                    Ok(replaced) => replaced,
+                    Err(status) => {
+                        info!("invite with replaces rejected: {} {}", tx.key, status);
+                        return tx.reply(status).await;
+                    }
+                };
+                let dialog = self.get_or_create_server_invite(
+                    &tx,
+                    handler.state_sender.clone(),
+                    handler.credential.clone(),
+                    handler.contact.clone(),
+                )?;
+                if let Some(replaced) = replaced {
+                    info!("dialog {} replaces {}", dialog.id(), replaced.id());
+                    dialog.inner.replaces.lock().unwrap().replace(replaced);
+                }
+                // the application may accept before the dialog task runs
+                dialog
+                    .inner
+                    .tu_sender
+                    .lock()
+                    .unwrap()
+                    .replace((tx.original.clone(), tx.tu_sender.clone()));
+                let mut handle_dialog = Dialog::ServerInvite(dialog.clone());
+                tokio::spawn(async move { handle_dialog.handle(tx).await });
+                handler.invite_sender.send(dialog).ok();
+            }
+            // a CANCEL matching an INVITE never reaches the transaction user
+            Method::Cancel => tx.reply(StatusCode::CallTransactionDoesNotExist).await?,
+            Method::Ack => info!("ack without dialog: {}", tx.key),
+            _ => {
+                if let Err(e) = handler.request_sender.send(tx) {
+                    let mut tx = e.0;
+                    tx.reply(StatusCode::ServiceUnavailable).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The states of all the dialogs of a [`DialogLayer`].
+///
+/// A subscriber more than `STATE_BROADCAST_CAPACITY` states behind skips the
+/// oldest ones, the Terminated states are always delivered.
+pub struct DialogStateSubscriber {
+    states: broadcast::Receiver<DialogState>,
+    terminated: UnboundedReceiver<DialogState>,
+}
+
+impl DialogStateSubscriber {
+    /// the next state, `None` once the layer is dropped
+    pub async fn recv(&mut self) -> Option<DialogState> {
+        loop {
+            select! {
+                // the earlier states of a dialog come before its Terminated
+                biased;
+                state = self.states.recv() => match state {
+                    Ok(state) => return Some(state),
+                    Err(RecvError::Lagged(n)) => {
+                        info!("dialog state subscriber lagged, {} states skipped", n);
+                    }
+                    Err(RecvError::Closed) => return self.terminated.recv().await,
+                },
+                state = self.terminated.recv() => return state,
+            }
+        }
+    }
 }
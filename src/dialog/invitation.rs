@@ -3,9 +3,11 @@ use super::{
     client_dialog::ClientInviteDialog,
     dialog::{DialogInner, DialogStateSender},
     dialog_layer::DialogLayer,
+    session_timer::{min_se_header, Refresher, SessionTimer, SessionTimerConfig},
 };
 use crate::{
     dialog::{dialog::Dialog, DialogId},
+    rsip_ext::{is_call_id, is_token, Replaces},
     transaction::{
         key::{TransactionKey, TransactionRole},
         make_tag,
@@ -23,6 +25,16 @@ pub struct InviteOption {
     pub offer: Option<Vec<u8>>,
     pub contact: rsip::Uri,
     pub credential: Option<Credential>,
+    /// enable session timers (RFC 4028), we ask to be the refresher
+    pub session_timer: Option<SessionTimerConfig>,
+    /// the dialog the call takes over at the callee, e.g. for an attended transfer (RFC 3891)
+    pub replaces: Option<Replaces>,
+    /// the Call-ID and From tag of the dialog, e.g. an upstream request id, random if unset
+    pub call_id: Option<String>,
+    pub from_tag: Option<String>,
+    /// the pre-loaded route set of the INVITE, e.g. the Path of the binding
+    /// of the callee (RFC 3327 5.3)
+    pub routes: Vec<rsip::UriWithParams>,
 }
 
 impl DialogLayer {
@@ -35,17 +47,35 @@ impl DialogLayer {
         };
         let recipient = to.uri.clone();
 
+        let from_tag = match &opt.from_tag {
+            Some(tag) if !is_token(tag) => {
+                return Err(crate::Error::Error(format!("invalid from tag: {:?}", tag)))
+            }
+            Some(tag) => tag.clone().into(),
+            None => make_tag(),
+        };
         let form = rsip::typed::From {
             display_name: None,
             uri: opt.caller.clone(),
             params: vec![],
         }
-        .with_tag(make_tag());
+        .with_tag(from_tag);
 
         let via = self.endpoint.get_via(None)?;
         let mut request =
             self.endpoint
                 .make_request(rsip::Method::Invite, recipient, via, form, to, last_seq);
+        if let Some(call_id) = &opt.call_id {
+            if !is_call_id(call_id) {
+                return Err(crate::Error::Error(format!(
+                    "invalid call-id: {:?}",
+                    call_id
+                )));
+            }
+            request
+                .headers
+                .unique_push(rsip::Header::CallId(call_id.clone().into()));
+        }
 
         let contact = rsip::typed::Contact {
             display_name: None,
@@ -56,6 +86,36 @@ impl DialogLayer {
         request
             .headers
             .unique_push(rsip::Header::Contact(contact.into()));
+        request
+            .headers
+            .unique_push(self.endpoint.allow_header().into());
+        for route in &opt.routes {
+            request
+                .headers
+                .push(rsip::Header::Route(route.to_string().into()));
+        }
+        if let Some(replaces) = &opt.replaces {
+            request.headers.push(replaces.clone().into());
+        }
+        // PRACK is answered automatically, see ClientInviteDialog::process_invite
+        let mut supported = self.endpoint.supported();
+        supported.retain(|tag| !tag.eq_ignore_ascii_case("timer"));
+        if let Some(config) = &opt.session_timer {
+            supported.push("timer".to_string());
+            request.headers.push(
+                SessionTimer {
+                    interval: config.session_expires,
+                    refresher: Refresher::Uac,
+                }
+                .header(),
+            );
+            request.headers.push(min_se_header(config.min_se));
+        }
+        if !supported.is_empty() {
+            request
+                .headers
+                .unique_push(rsip::Header::Supported(supported.join(", ").into()));
+        }
 
         request.headers.unique_push(rsip::Header::ContentType(
             opt.content_type
@@ -70,6 +130,35 @@ impl DialogLayer {
         &self,
         opt: InviteOption,
         state_sender: DialogStateSender,
+    ) -> Result<(ClientInviteDialog, Option<Response>)> {
+        self.invite(opt, state_sender, false).await
+    }
+
+    /// Send an INVITE like [`do_invite`](Self::do_invite) but leave the ACK of
+    /// the 2xx to the caller, e.g. a proxy or B2BUA relaying the 2xx upstream
+    /// before it is acknowledged.
+    ///
+    /// The 2xx is returned with the dialog in `DialogState::WaitAck`, the caller
+    /// then owns the INVITE transaction and must either
+    /// [`send_ack`](ClientInviteDialog::send_ack), which confirms the dialog, or
+    /// [`suppress_ack`](ClientInviteDialog::suppress_ack), which ends it here.
+    /// Until then the retransmitted 2xx are not acknowledged and the delayed
+    /// offer of a 2xx is not answered, `answer_ack` does not apply. A 2xx left
+    /// pending for 64*T1, when the UAS gives up on it (RFC 3261 13.3.1.4), is
+    /// suppressed.
+    pub async fn do_invite_manual_ack(
+        &self,
+        opt: InviteOption,
+        state_sender: DialogStateSender,
+    ) -> Result<(ClientInviteDialog, Option<Response>)> {
+        self.invite(opt, state_sender, true).await
+    }
+
+    async fn invite(
+        &self,
+        opt: InviteOption,
+        state_sender: DialogStateSender,
+        manual_ack: bool,
     ) -> Result<(ClientInviteDialog, Option<Response>)> {
         let mut request = self.make_invite_request(&opt)?;
         request.body = opt.offer.unwrap_or_default();
@@ -87,35 +176,36 @@ impl DialogLayer {
             opt.credential,
             Some(opt.contact),
         )?;
+        *dlg_inner.session_timer_config.lock().unwrap() = opt.session_timer;
 
         let key =
             TransactionKey::from_request(&dlg_inner.initial_request, TransactionRole::Client)?;
-        let tx = Transaction::new_client(key, request.clone(), self.endpoint.clone(), None);
+        let tx = Transaction::new_client(
+            key,
+            dlg_inner.initial_request.clone(),
+            self.endpoint.clone(),
+            None,
+        );
 
         let dialog = ClientInviteDialog {
             inner: Arc::new(dlg_inner),
         };
-        self.inner
-            .dialogs
-            .write()
-            .unwrap()
-            .insert(id.clone(), Dialog::ClientInvite(dialog.clone()));
+        self.insert_dialog(Dialog::ClientInvite(dialog.clone()));
 
         info!("client invite dialog created: {:?}", id);
 
-        match dialog.process_invite(tx).await {
+        match dialog.process_invite(tx, manual_ack).await {
             Ok((new_dialog_id, resp)) => {
                 debug!(
                     "client invite dialog confirmed: {} => {}",
                     id, new_dialog_id
                 );
                 self.inner.dialogs.write().unwrap().remove(&id);
-                // update with new dialog id
-                self.inner
-                    .dialogs
-                    .write()
-                    .unwrap()
-                    .insert(new_dialog_id, Dialog::ClientInvite(dialog.clone()));
+                // update with new dialog id, unless the INVITE failed or was hung up
+                let terminated = dialog.inner.state.lock().unwrap().is_terminated();
+                if !terminated {
+                    self.insert_dialog(Dialog::ClientInvite(dialog.clone()));
+                }
                 return Ok((dialog, resp));
             }
             Err(e) => {
@@ -0,0 +1,242 @@
+use super::dialog::DialogState;
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// upper bounds of the call setup time buckets, Calling to Confirmed
+pub const SETUP_TIME_BUCKETS: [Duration; 8] = [
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+    Duration::from_secs(30),
+];
+
+/// upper bounds of the call duration buckets, Confirmed to Terminated
+pub const DURATION_BUCKETS: [Duration; 8] = [
+    Duration::from_secs(10),
+    Duration::from_secs(30),
+    Duration::from_secs(60),
+    Duration::from_secs(5 * 60),
+    Duration::from_secs(10 * 60),
+    Duration::from_secs(30 * 60),
+    Duration::from_secs(60 * 60),
+    Duration::from_secs(4 * 60 * 60),
+];
+
+// terminations with a failure status, indexed from 300 to 699
+const FAILURE_CODES: usize = 400;
+
+pub struct DurationHistogram {
+    bounds: &'static [Duration],
+    // one more bucket for the values above the last bound
+    buckets: Vec<AtomicU64>,
+    sum_ms: AtomicU64,
+    count: AtomicU64,
+}
+
+/// The values counted in each bucket, `buckets[i]` are the values up to
+/// `bounds[i]` and greater than the previous bound, the last one the values
+/// above all the bounds
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    pub bounds: Vec<Duration>,
+    pub buckets: Vec<u64>,
+    pub sum: Duration,
+    pub count: u64,
+}
+
+impl DurationHistogram {
+    fn new(bounds: &'static [Duration]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_ms: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, value: Duration) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_ms
+            .fetch_add(value.as_millis() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            bounds: self.bounds.to_vec(),
+            buckets: self
+                .buckets
+                .iter()
+                .map(|b| b.load(Ordering::Relaxed))
+                .collect(),
+            sum: Duration::from_millis(self.sum_ms.load(Ordering::Relaxed)),
+            count: self.count.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// How a dialog ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TerminationReason {
+    /// hung up with BYE, or the dialog was answered elsewhere
+    Normal,
+    /// no answer, no ACK or the session expired, a 408
+    Timeout,
+    /// rejected or failed with a 3xx-6xx status
+    Failure(u16),
+}
+
+impl From<&Option<rsip::StatusCode>> for TerminationReason {
+    fn from(status: &Option<rsip::StatusCode>) -> Self {
+        match status.as_ref().map(|s| s.code()) {
+            None => TerminationReason::Normal,
+            Some(408) => TerminationReason::Timeout,
+            Some(code) if code >= 300 => TerminationReason::Failure(code),
+            Some(_) => TerminationReason::Normal,
+        }
+    }
+}
+
+/// Dialog counters of an endpoint, updated on every state transition with
+/// atomics only so they can stay always on
+pub struct DialogMetrics {
+    calling: AtomicU64,
+    trying: AtomicU64,
+    early: AtomicU64,
+    wait_ack: AtomicU64,
+    confirmed: AtomicU64,
+    created: AtomicU64,
+    terminated_normal: AtomicU64,
+    terminated_timeout: AtomicU64,
+    terminated_failures: Vec<AtomicU64>,
+    setup_time: DurationHistogram,
+    duration: DurationHistogram,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DialogMetricsSnapshot {
+    /// the dialogs currently in each state
+    pub calling: u64,
+    pub trying: u64,
+    pub early: u64,
+    pub wait_ack: u64,
+    pub confirmed: u64,
+    /// the dialogs created since the endpoint started
+    pub created: u64,
+    pub terminated_normal: u64,
+    pub terminated_timeout: u64,
+    /// the failed dialogs by status code
+    pub terminated_failures: BTreeMap<u16, u64>,
+    pub setup_time: HistogramSnapshot,
+    pub duration: HistogramSnapshot,
+}
+
+impl Default for DialogMetrics {
+    fn default() -> Self {
+        Self {
+            calling: AtomicU64::new(0),
+            trying: AtomicU64::new(0),
+            early: AtomicU64::new(0),
+            wait_ack: AtomicU64::new(0),
+            confirmed: AtomicU64::new(0),
+            created: AtomicU64::new(0),
+            terminated_normal: AtomicU64::new(0),
+            terminated_timeout: AtomicU64::new(0),
+            terminated_failures: (0..FAILURE_CODES).map(|_| AtomicU64::new(0)).collect(),
+            setup_time: DurationHistogram::new(&SETUP_TIME_BUCKETS),
+            duration: DurationHistogram::new(&DURATION_BUCKETS),
+        }
+    }
+}
+
+impl DialogMetrics {
+    fn gauge(&self, state: &DialogState) -> Option<&AtomicU64> {
+        match state {
+            DialogState::Calling(_) => Some(&self.calling),
+            DialogState::Trying(_) => Some(&self.trying),
+            DialogState::Early(_, _) => Some(&self.early),
+            DialogState::WaitAck(_, _) => Some(&self.wait_ack),
+            DialogState::Confirmed(_) => Some(&self.confirmed),
+            _ => None,
+        }
+    }
+
+    /// a new dialog, in the Calling state
+    pub(super) fn on_created(&self) {
+        self.created.fetch_add(1, Ordering::Relaxed);
+        self.calling.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn on_transition(&self, old: &DialogState, new: &DialogState) {
+        if let Some(gauge) = self.gauge(old) {
+            gauge.fetch_sub(1, Ordering::Relaxed);
+        }
+        if let Some(gauge) = self.gauge(new) {
+            gauge.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// a dialog dropped before it was terminated
+    pub(super) fn on_dropped(&self, state: &DialogState) {
+        if let Some(gauge) = self.gauge(state) {
+            gauge.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(super) fn on_confirmed(&self, setup_time: Duration) {
+        self.setup_time.observe(setup_time);
+    }
+
+    /// `duration` is the time since Confirmed, unset when it never was
+    pub(super) fn on_terminated(&self, reason: TerminationReason, duration: Option<Duration>) {
+        match reason {
+            TerminationReason::Normal => &self.terminated_normal,
+            TerminationReason::Timeout => &self.terminated_timeout,
+            TerminationReason::Failure(code) => {
+                let index = (code as usize).clamp(300, 300 + FAILURE_CODES - 1) - 300;
+                &self.terminated_failures[index]
+            }
+        }
+        .fetch_add(1, Ordering::Relaxed);
+        if let Some(duration) = duration {
+            self.duration.observe(duration);
+        }
+    }
+
+    pub fn snapshot(&self) -> DialogMetricsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        DialogMetricsSnapshot {
+            calling: load(&self.calling),
+            trying: load(&self.trying),
+            early: load(&self.early),
+            wait_ack: load(&self.wait_ack),
+            confirmed: load(&self.confirmed),
+            created: load(&self.created),
+            terminated_normal: load(&self.terminated_normal),
+            terminated_timeout: load(&self.terminated_timeout),
+            terminated_failures: self
+                .terminated_failures
+                .iter()
+                .enumerate()
+                .filter_map(|(i, counter)| match load(counter) {
+                    0 => None,
+                    n => Some((300 + i as u16, n)),
+                })
+                .collect(),
+            setup_time: self.setup_time.snapshot(),
+            duration: self.duration.snapshot(),
+        }
+    }
+}
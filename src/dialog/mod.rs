@@ -1,4 +1,4 @@
-use crate::{Error, Result};
+use crate::{transaction::key::TransactionRole, Error, Result};
 use rsip::{
     prelude::{HeadersExt, UntypedHeader},
     Request, Response,
@@ -9,8 +9,18 @@ pub mod client_dialog;
 pub mod dialog;
 pub mod dialog_layer;
 pub mod invitation;
+pub mod metrics;
+pub mod registrar;
 pub mod registration;
 pub mod server_dialog;
+pub mod session_timer;
+pub mod subscription;
+#[cfg(test)]
+mod tests;
+/// The id of a dialog, with the tags as in its initial request: `from_tag` is
+/// the tag of the UAC and `to_tag` the one of the UAS, so both ends of a call
+/// derive the same id. [`DialogId::local_tag`] and [`DialogId::remote_tag`]
+/// give the view of one end.
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct DialogId {
     pub call_id: String,
@@ -18,6 +28,49 @@ pub struct DialogId {
     pub to_tag: String,
 }
 
+impl DialogId {
+    /// the id of a dialog from the tags of one end, `role` being the one it
+    /// had in the initial transaction
+    pub fn new(role: &TransactionRole, call_id: &str, local_tag: &str, remote_tag: &str) -> Self {
+        let (from_tag, to_tag) = match role {
+            TransactionRole::Client => (local_tag, remote_tag),
+            TransactionRole::Server => (remote_tag, local_tag),
+        };
+        DialogId {
+            call_id: call_id.to_string(),
+            from_tag: from_tag.to_string(),
+            to_tag: to_tag.to_string(),
+        }
+    }
+
+    pub fn local_tag(&self, role: &TransactionRole) -> &str {
+        match role {
+            TransactionRole::Client => &self.from_tag,
+            TransactionRole::Server => &self.to_tag,
+        }
+    }
+
+    pub fn remote_tag(&self, role: &TransactionRole) -> &str {
+        match role {
+            TransactionRole::Client => &self.to_tag,
+            TransactionRole::Server => &self.from_tag,
+        }
+    }
+
+    /// the tags exchanged, the id of an in-dialog request sent by the UAS
+    /// is the swapped one of its dialog
+    pub fn swapped(&self) -> Self {
+        DialogId {
+            call_id: self.call_id.clone(),
+            from_tag: self.to_tag.clone(),
+            to_tag: self.from_tag.clone(),
+        }
+    }
+}
+
+/// The tags as in the request, an in-dialog request of the UAS gives the
+/// [`DialogId::swapped`] id of its dialog. The To tag is empty for an initial
+/// request.
 impl TryFrom<&Request> for DialogId {
     type Error = crate::Error;
 
@@ -42,6 +95,8 @@ impl TryFrom<&Request> for DialogId {
     }
 }
 
+/// The tags of the request a response answers, the To tag is the one of the
+/// responding end and required.
 impl TryFrom<&Response> for DialogId {
     type Error = crate::Error;
 
@@ -0,0 +1,244 @@
+use super::registration::DEFAULT_EXPIRES;
+use crate::{rsip_ext::path_set, Result};
+use rsip::{
+    prelude::{HeadersExt, ToTypedHeader, UntypedHeader},
+    Param, Request, StatusCode,
+};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// A Contact bound to an address-of-record by a REGISTER (RFC 3261 10.3)
+#[derive(Clone, Debug)]
+pub struct Binding {
+    pub contact: rsip::typed::Contact,
+    pub expires_at: Instant,
+    /// the Call-ID and CSeq of the REGISTER that last updated the binding
+    pub call_id: String,
+    pub cseq: u32,
+    /// the Path of the REGISTER, the proxies the requests to the contact
+    /// go through (RFC 3327 5.3)
+    pub path: Vec<rsip::UriWithParams>,
+}
+
+impl Binding {
+    /// the seconds left, zero once expired
+    pub fn remaining(&self, now: Instant) -> u32 {
+        self.expires_at.saturating_duration_since(now).as_secs() as u32
+    }
+
+    /// the Contact of the 2xx, with the remaining seconds as its expires param
+    pub fn contact_header(&self, now: Instant) -> rsip::Header {
+        let mut contact = self.contact.clone();
+        contact.params.retain(|p| !matches!(p, Param::Expires(_)));
+        contact
+            .params
+            .push(Param::Expires(self.remaining(now).to_string().into()));
+        contact.into()
+    }
+
+    /// the pre-loaded route set of a request to the contact, its Route
+    /// headers are the Path entries in order
+    pub fn route_headers(&self) -> Vec<rsip::Header> {
+        self.path
+            .iter()
+            .map(|route| rsip::Header::Route(route.to_string().into()))
+            .collect()
+    }
+
+    /// the Path headers the 2xx copies from the REGISTER
+    pub fn path_headers(&self) -> Vec<rsip::Header> {
+        self.path
+            .iter()
+            .map(|route| rsip::Header::Other("Path".into(), route.to_string()))
+            .collect()
+    }
+
+    fn matches(&self, uri: &rsip::Uri) -> bool {
+        same_uri(&self.contact.uri, uri)
+    }
+}
+
+/// The bindings of a registrar per address-of-record.
+///
+/// [`Registrar::apply_register`] updates them from an incoming REGISTER,
+/// the 2xx carries the current ones with [`Binding::contact_header`].
+pub struct Registrar {
+    /// for a Contact without expires param in a REGISTER without Expires,
+    /// brought into `[min_expires, max_expires]`
+    pub default_expires: u32,
+    /// shorter non-zero intervals are rejected with 423, the reply needs a
+    /// Min-Expires header with this value
+    pub min_expires: u32,
+    /// longer intervals are shortened to this one
+    pub max_expires: u32,
+    aors: HashMap<String, Vec<Binding>>,
+}
+
+impl Default for Registrar {
+    fn default() -> Self {
+        Self {
+            default_expires: DEFAULT_EXPIRES,
+            min_expires: 60,
+            max_expires: 86400,
+            aors: HashMap::new(),
+        }
+    }
+}
+
+impl Registrar {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the bindings of `aor` not expired at `now`
+    pub fn bindings(&mut self, aor: &str, now: Instant) -> Vec<Binding> {
+        self.purge(aor, now);
+        self.aors.get(aor).cloned().unwrap_or_default()
+    }
+
+    /// Add, refresh and remove the bindings of the To address of `request`
+    /// (RFC 3261 10.3 steps 5 to 8) and return the current ones, a REGISTER
+    /// without Contact only queries them.
+    ///
+    /// Nothing is changed when the request fails, its status is returned:
+    /// 400 for a malformed Contact or a `*` with other contacts or a non-zero
+    /// expires, 423 for an interval under `min_expires` and 500 for a CSeq not
+    /// higher than the one of a binding with the same Call-ID.
+    pub fn apply_register(
+        &mut self,
+        request: &Request,
+        now: Instant,
+    ) -> std::result::Result<Vec<Binding>, StatusCode> {
+        let aor = address_of_record(request).map_err(|_| StatusCode::BadRequest)?;
+        let call_id = request
+            .call_id_header()
+            .map_err(|_| StatusCode::BadRequest)?
+            .value()
+            .to_string();
+        let cseq = request
+            .cseq_header()
+            .and_then(|c| c.seq())
+            .map_err(|_| StatusCode::BadRequest)?;
+        let expires = match request.expires_header() {
+            Some(expires) => Some(expires.seconds().map_err(|_| StatusCode::BadRequest)?),
+            None => None,
+        };
+        self.purge(&aor, now);
+        let stale = |binding: &Binding| binding.call_id == call_id && cseq <= binding.cseq;
+
+        let contacts = request.contact_headers();
+        if contacts.iter().any(|c| c.value().trim() == "*") {
+            if contacts.len() > 1 || expires != Some(0) {
+                return Err(StatusCode::BadRequest);
+            }
+            if let Some(bindings) = self.aors.get_mut(&aor) {
+                if bindings.iter().any(stale) {
+                    return Err(StatusCode::ServerInternalError);
+                }
+                bindings.clear();
+            }
+            return Ok(self.bindings(&aor, now));
+        }
+
+        let mut updates = vec![];
+        for contact in contacts {
+            let contact = contact.typed().map_err(|_| StatusCode::BadRequest)?;
+            let seconds = match contact.expires() {
+                Some(e) => e.seconds().map_err(|_| StatusCode::BadRequest)?,
+                None => match expires {
+                    Some(expires) => expires,
+                    None => self.default_expires.max(self.min_expires),
+                },
+            };
+            if seconds != 0 && seconds < self.min_expires {
+                return Err(StatusCode::IntervalTooBrief);
+            }
+            let existing = self.aors.get(&aor).into_iter().flatten();
+            if existing.filter(|b| b.matches(&contact.uri)).any(stale) {
+                return Err(StatusCode::ServerInternalError);
+            }
+            updates.push((contact, seconds.min(self.max_expires)));
+        }
+
+        let path = path_set(&request.headers);
+        let bindings = self.aors.entry(aor.clone()).or_default();
+        for (mut contact, seconds) in updates {
+            bindings.retain(|b| !b.matches(&contact.uri));
+            if seconds == 0 {
+                continue;
+            }
+            contact.params.retain(|p| !matches!(p, Param::Expires(_)));
+            bindings.push(Binding {
+                contact,
+                expires_at: now + Duration::from_secs(seconds as u64),
+                call_id: call_id.clone(),
+                cseq,
+                path: path.clone(),
+            });
+        }
+        Ok(self.bindings(&aor, now))
+    }
+
+    /// [`apply_register`](Self::apply_register) and the status and headers of
+    /// its reply: the Contact of each binding with the granted expires and the
+    /// Path of the REGISTER for a 2xx, the Min-Expires for a 423 (RFC 3261
+    /// 10.3 step 7)
+    pub fn reply_register(
+        &mut self,
+        request: &Request,
+        now: Instant,
+    ) -> (StatusCode, Vec<rsip::Header>) {
+        match self.apply_register(request, now) {
+            Ok(bindings) => {
+                let mut headers: Vec<rsip::Header> =
+                    bindings.iter().map(|b| b.contact_header(now)).collect();
+                headers.extend(
+                    path_set(&request.headers)
+                        .iter()
+                        .map(|route| rsip::Header::Other("Path".into(), route.to_string())),
+                );
+                (StatusCode::OK, headers)
+            }
+            Err(StatusCode::IntervalTooBrief) => (
+                StatusCode::IntervalTooBrief,
+                vec![rsip::Header::MinExpires(self.min_expires.into())],
+            ),
+            Err(status) => (status, vec![]),
+        }
+    }
+
+    fn purge(&mut self, aor: &str, now: Instant) {
+        if let Some(bindings) = self.aors.get_mut(aor) {
+            bindings.retain(|b| b.expires_at > now);
+            if bindings.is_empty() {
+                self.aors.remove(aor);
+            }
+        }
+    }
+}
+
+/// The canonical address-of-record of a REGISTER, its To uri without port
+/// params or password, with the host in lower case (RFC 3261 10.3 step 5)
+pub fn address_of_record(request: &Request) -> Result<String> {
+    let uri = request.to_header()?.typed()?.uri;
+    let user = uri
+        .auth
+        .map(|auth| format!("{}@", auth.user))
+        .unwrap_or_default();
+    let scheme = uri.scheme.unwrap_or(rsip::Scheme::Sip);
+    Ok(format!(
+        "{}:{}{}",
+        scheme,
+        user,
+        uri.host_with_port.host.to_string().to_ascii_lowercase()
+    ))
+}
+
+// the Contact uris of a binding are the same when user, host and port are
+fn same_uri(a: &rsip::Uri, b: &rsip::Uri) -> bool {
+    a.scheme == b.scheme
+        && a.auth.as_ref().map(|a| &a.user) == b.auth.as_ref().map(|b| &b.user)
+        && a.host_with_port == b.host_with_port
+}
@@ -3,45 +3,158 @@ use super::{
     DialogId,
 };
 use crate::{
+    rsip_ext::{contact_values, parse_contact, parse_uri, path_set, service_route_set},
     transaction::{
         endpoint::EndpointInnerRef,
         key::{TransactionKey, TransactionRole},
-        make_tag,
+        make_call_id, make_tag,
         transaction::Transaction,
     },
     Result,
 };
-use rsip::{Response, SipMessage, StatusCode};
+use rsip::{prelude::HeadersExt, Param, Response, SipMessage, StatusCode, StatusCodeKind};
+use std::time::Duration;
+use tokio::select;
+use tokio_util::sync::CancellationToken;
 use tracing::info;
 
+pub const DEFAULT_EXPIRES: u32 = 3600;
+
+/// The GRUUs a registrar assigned to an instance (RFC 5627), from the
+/// `pub-gruu` and `temp-gruu` params of its Contact in the 2xx of a REGISTER
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gruu {
+    /// the `+sip.instance` of the binding, e.g. `<urn:uuid:...>`
+    pub instance: String,
+    pub public: Option<rsip::Uri>,
+    /// changes on each registration, the one to hide the AOR with
+    pub temporary: Option<rsip::Uri>,
+}
+
+impl Gruu {
+    /// the GRUUs of a Contact of a 2xx, `None` without any
+    pub fn from_contact(contact: &rsip::typed::Contact) -> Option<Self> {
+        let param = |name: &str| {
+            contact.params.iter().find_map(|p| match p {
+                Param::Other(n, Some(v)) if n.value().eq_ignore_ascii_case(name) => {
+                    Some(v.value().trim_matches('"').to_string())
+                }
+                _ => None,
+            })
+        };
+        let gruu = Self {
+            instance: param("+sip.instance").unwrap_or_default(),
+            public: param("pub-gruu").and_then(|v| parse_uri(&v).ok()),
+            temporary: param("temp-gruu").and_then(|v| parse_uri(&v).ok()),
+        };
+        (gruu.public.is_some() || gruu.temporary.is_some()).then_some(gruu)
+    }
+
+    /// the uri of the Contact of a dialog, the temporary GRUU if preferred
+    /// and assigned, the public one otherwise
+    pub fn contact_uri(&self, prefer_temporary: bool) -> Option<rsip::Uri> {
+        match prefer_temporary {
+            true => self.temporary.clone().or_else(|| self.public.clone()),
+            false => self.public.clone().or_else(|| self.temporary.clone()),
+        }
+    }
+}
+
+/// Whether a uri is a GRUU, its `gr` param is kept on the way (RFC 5627 4.5)
+pub fn is_gruu(uri: &rsip::Uri) -> bool {
+    uri.params
+        .iter()
+        .any(|p| matches!(p, Param::Other(name, _) if name.value().eq_ignore_ascii_case("gr")))
+}
+
 pub struct Registration {
     pub last_seq: u32,
     pub endpoint: EndpointInnerRef,
     pub credential: Option<Credential>,
     pub contact: Option<rsip::typed::Contact>,
     pub allow: rsip::headers::Allow,
+    /// the Call-ID is kept across refreshes (RFC 3261 10.2.4)
+    pub call_id: rsip::headers::CallId,
+    /// the lifetime granted by the registrar in the last 2xx
+    pub granted_expires: Option<u32>,
+    /// the contacts currently bound to the AOR, from the last 2xx
+    pub bindings: Vec<rsip::typed::Contact>,
+    /// the `+sip.instance` of the Contact, e.g. `<urn:uuid:...>`, the
+    /// registrar assigns GRUUs to an instance only (RFC 5627 4.1)
+    pub instance: Option<String>,
+    /// the GRUUs of our binding from the last 2xx, the Contact of the dialogs
+    /// created after it
+    pub gruu: Option<Gruu>,
+    /// the Path of the last 2xx, the proxies between us and the registrar
+    /// that the requests to the AOR come through (RFC 3327)
+    pub path: Vec<rsip::UriWithParams>,
+    /// the Service-Route of the last 2xx, the route set of our requests out
+    /// of a dialog until the registration ends (RFC 3608)
+    pub service_route: Vec<rsip::UriWithParams>,
 }
 
 impl Registration {
     pub fn new(endpoint: EndpointInnerRef, credential: Option<Credential>) -> Self {
         Self {
             last_seq: 0,
+            allow: endpoint.allow_header(),
             endpoint,
             credential,
             contact: None,
-            allow: Default::default(),
+            call_id: make_call_id(None),
+            granted_expires: None,
+            bindings: vec![],
+            instance: None,
+            gruu: None,
+            path: vec![],
+            service_route: vec![],
         }
     }
 
+    /// the granted expires if registered, otherwise the requested one
     pub fn expires(&self) -> u32 {
-        self.contact
-            .as_ref()
-            .and_then(|c| c.expires())
-            .map(|e| e.seconds().unwrap_or(50))
-            .unwrap_or(50)
+        self.granted_expires.unwrap_or_else(|| {
+            self.contact
+                .as_ref()
+                .and_then(|c| c.expires())
+                .map(|e| e.seconds().unwrap_or(DEFAULT_EXPIRES))
+                .unwrap_or(DEFAULT_EXPIRES)
+        })
     }
 
     pub async fn register(&mut self, server: &String) -> Result<Response> {
+        let expires = self.expires();
+        self.do_register(server, expires).await
+    }
+
+    /// remove the binding with Expires: 0
+    pub async fn unregister(&mut self, server: &String) -> Result<Response> {
+        self.do_register(server, 0).await
+    }
+
+    /// register and refresh at 90% of the granted lifetime, until the token is cancelled
+    pub async fn serve(&mut self, server: &String, cancel_token: CancellationToken) -> Result<()> {
+        loop {
+            let resp = self.register(server).await?;
+            if resp.status_code.kind() != StatusCodeKind::Successful {
+                return Err(crate::Error::Error(format!(
+                    "registration failed: {}",
+                    resp.status_code
+                )));
+            }
+            let refresh = Duration::from_millis(self.expires().max(1) as u64 * 900);
+            info!("registered, refresh after {:?}", refresh);
+            select! {
+                _ = cancel_token.cancelled() => {
+                    info!("registration refresh cancelled");
+                    return Ok(());
+                }
+                _ = self.endpoint.clock().sleep(refresh) => {}
+            }
+        }
+    }
+
+    async fn do_register(&mut self, server: &String, expires: u32) -> Result<Response> {
         self.last_seq += 1;
 
         let recipient = rsip::Uri::try_from(format!("sip:{}", server))?;
@@ -81,12 +194,27 @@ impl Registration {
                 uri: rsip::Uri {
                     auth: to.uri.auth.clone(),
                     scheme: Some(rsip::Scheme::Sip),
-                    host_with_port: first_addr.addr.into(),
+                    host_with_port: first_addr.into(),
                     params: vec![],
                     headers: vec![],
                 },
                 params: vec![],
             });
+        let mut contact = contact;
+        contact.params.retain(|p| !matches!(p, Param::Expires(_)));
+        contact
+            .params
+            .push(Param::Expires(expires.to_string().into()));
+        if let Some(instance) = &self.instance {
+            contact.params.retain(
+                |p| !matches!(p, Param::Other(n, _) if n.value().eq_ignore_ascii_case("+sip.instance")),
+            );
+            contact.params.push(Param::Other(
+                "+sip.instance".into(),
+                Some(format!("\"{}\"", instance.trim_matches('"')).into()),
+            ));
+        }
+        let contact_uri = contact.uri.clone();
         let via = self.endpoint.get_via(None)?;
         let mut request = self.endpoint.make_request(
             rsip::Method::Register,
@@ -97,8 +225,22 @@ impl Registration {
             self.last_seq,
         );
 
+        request
+            .headers
+            .unique_push(rsip::Header::CallId(self.call_id.clone()));
         request.headers.unique_push(contact.into());
+        request
+            .headers
+            .unique_push(rsip::Header::Expires(expires.into()));
         request.headers.unique_push(self.allow.clone().into());
+        // an edge proxy adds its Path only if we support it (RFC 3327 5.1)
+        let supported = match self.instance {
+            Some(_) => "path, gruu",
+            None => "path",
+        };
+        request
+            .headers
+            .unique_push(rsip::Header::Supported(supported.into()));
 
         let key = TransactionKey::from_request(&request, TransactionRole::Client)?;
         let mut tx = Transaction::new_client(key, request, self.endpoint.clone(), None);
@@ -131,6 +273,9 @@ impl Registration {
                     }
                     _ => {
                         info!("registration do_request done: {:?}", resp.status_code);
+                        if resp.status_code.kind() == StatusCodeKind::Successful {
+                            self.update_bindings(&resp, &contact_uri, expires);
+                        }
                         return Ok(resp);
                     }
                 },
@@ -142,4 +287,39 @@ impl Registration {
             DialogId::try_from(&tx.original)?,
         ));
     }
+
+    fn update_bindings(&mut self, resp: &Response, contact_uri: &rsip::Uri, expires: u32) {
+        self.bindings = contact_values(&resp.headers)
+            .into_iter()
+            .filter_map(|c| parse_contact(&c).ok())
+            .collect();
+        self.path = path_set(&resp.headers);
+        self.service_route = match expires {
+            0 => vec![],
+            _ => service_route_set(&resp.headers),
+        };
+        self.endpoint.set_service_route(self.service_route.clone());
+
+        if expires == 0 {
+            self.granted_expires = None;
+            if self.gruu.take().is_some() {
+                self.endpoint.set_gruu(None);
+            }
+            return;
+        }
+        let binding = self
+            .bindings
+            .iter()
+            .find(|c| c.uri.host_with_port == contact_uri.host_with_port);
+        if self.instance.is_some() {
+            self.gruu = binding.and_then(Gruu::from_contact);
+            self.endpoint.set_gruu(self.gruu.clone());
+        }
+        // the expires param of our own binding wins over the Expires header
+        let granted = binding
+            .and_then(|c| c.expires())
+            .and_then(|e| e.seconds().ok())
+            .or_else(|| resp.expires_header().and_then(|e| e.seconds().ok()));
+        self.granted_expires = granted.or(Some(expires));
+    }
 }
@@ -1,13 +1,18 @@
 use super::dialog::{Dialog, DialogInnerRef};
 use super::DialogId;
 use crate::dialog::dialog::DialogState;
+use crate::dialog::session_timer::{
+    min_se_header, start_session_timer, SessionTimer, SessionTimerConfig,
+};
+use crate::rsip_ext::{has_supported, parse_refer_to, reason_headers, Reason};
 use crate::transaction::transaction::{Transaction, TransactionEvent};
 use crate::Result;
 use rsip::prelude::HeadersExt;
-use rsip::{Header, Request, SipMessage, StatusCode};
-use std::sync::atomic::Ordering;
+use rsip::{Header, Request, Response, SipMessage, StatusCode, StatusCodeKind};
+use std::{sync::atomic::Ordering, time::Duration};
+use tokio::{select, sync::oneshot};
 use tokio_util::sync::CancellationToken;
-use tracing::{info, info_span, trace, warn};
+use tracing::{info, instrument, trace, warn, Instrument};
 
 #[derive(Clone)]
 pub struct ServerInviteDialog {
@@ -25,15 +30,18 @@ impl ServerInviteDialog {
         &self.inner.initial_request
     }
 
+    /// Answer the pending INVITE (initial or re-INVITE) with 200 OK.
     pub fn accept(&self, headers: Option<Vec<Header>>, body: Option<Vec<u8>>) -> Result<()> {
-        if let Some(sender) = self.inner.tu_sender.lock().unwrap().as_ref() {
-            let resp = self.inner.make_response(
-                &self.inner.initial_request,
-                rsip::StatusCode::OK,
-                headers,
-                body,
-            );
-
+        if let Some((request, sender)) = self.inner.tu_sender.lock().unwrap().as_ref() {
+            let headers = self.with_session_timer(request, headers);
+            let resp = self
+                .inner
+                .make_response(request, rsip::StatusCode::OK, headers, body);
+            self.inner
+                .last_invite_response
+                .lock()
+                .unwrap()
+                .replace(resp.clone());
             sender
                 .send(TransactionEvent::Respond(resp))
                 .map_err(Into::into)
@@ -45,39 +53,223 @@ impl ServerInviteDialog {
         }
     }
 
-    pub fn reject(&self) -> Result<()> {
-        if let Some(sender) = self.inner.tu_sender.lock().unwrap().as_ref() {
-            let resp = self.inner.make_response(
-                &self.inner.initial_request,
-                rsip::StatusCode::Decline,
-                None,
-                None,
-            );
-            sender
+    /// Answer the pending INVITE with 183 Session Progress carrying `body`, e.g.
+    /// the SDP of an announcement played before the call is answered. The 183
+    /// has our To tag, the early dialog is the one `accept` confirms; call it
+    /// again to send updated early media.
+    pub fn progress(&self, body: Vec<u8>, content_type: Option<String>) -> Result<()> {
+        let (request, sender) = match self.inner.tu_sender.lock().unwrap().clone() {
+            Some(pending) => pending,
+            None => {
+                return Err(crate::Error::DialogError(
+                    "transaction is already terminated".to_string(),
+                    self.id(),
+                ))
+            }
+        };
+        let content_type = content_type.unwrap_or("application/sdp".to_string());
+        let resp = self.inner.make_response(
+            &request,
+            StatusCode::SessionProgress,
+            Some(vec![Header::ContentType(content_type.into())]),
+            Some(body),
+        );
+        sender.send(TransactionEvent::Respond(resp.clone()))?;
+        if !self.inner.is_confirmed() {
+            self.inner.transition(DialogState::Early(self.id(), resp))?;
+        }
+        Ok(())
+    }
+
+    /// Enable session timers (RFC 4028), must be set before the INVITE is handled.
+    pub fn set_session_timer(&self, config: Option<SessionTimerConfig>) {
+        *self.inner.session_timer_config.lock().unwrap() = config;
+    }
+
+    /// the negotiated session timer, if any
+    pub fn session_timer(&self) -> Option<SessionTimer> {
+        self.inner.session_timer.lock().unwrap().clone()
+    }
+
+    fn with_session_timer(
+        &self,
+        request: &Request,
+        headers: Option<Vec<Header>>,
+    ) -> Option<Vec<Header>> {
+        let timer = match self.inner.session_timer.lock().unwrap().clone() {
+            Some(timer) => timer,
+            None => return headers,
+        };
+        let mut headers = headers.unwrap_or_default();
+        headers.push(timer.header());
+        if has_supported(&request.headers, "timer") {
+            headers.push(Header::Require("timer".into()));
+        }
+        Some(headers)
+    }
+
+    /// Send a 183 reliably (RFC 3262) when the caller supports 100rel and wait for
+    /// its PRACK, retransmitting on T1 doubling until 64*T1. Without 100rel support
+    /// a plain 183 is sent.
+    pub async fn reliable_provisional(
+        &self,
+        headers: Option<Vec<Header>>,
+        body: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let (request, sender) = match self.inner.tu_sender.lock().unwrap().as_ref() {
+            Some((request, sender)) => (request.clone(), sender.clone()),
+            None => {
+                return Err(crate::Error::DialogError(
+                    "transaction is already terminated".to_string(),
+                    self.id(),
+                ))
+            }
+        };
+        if !has_supported(&request.headers, "100rel") {
+            let resp =
+                self.inner
+                    .make_response(&request, StatusCode::SessionProgress, headers, body);
+            return sender
                 .send(TransactionEvent::Respond(resp))
-                .map_err(Into::into)
-        } else {
-            Err(crate::Error::DialogError(
-                "transaction is already terminated".to_string(),
-                self.id(),
-            ))
+                .map_err(Into::into);
         }
+
+        let rseq = self.inner.rseq.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut headers = headers.unwrap_or_default();
+        headers.push(Header::Require("100rel".into()));
+        headers.push(Header::Other("RSeq".to_string(), rseq.to_string()));
+        let resp =
+            self.inner
+                .make_response(&request, StatusCode::SessionProgress, Some(headers), body);
+
+        let (prack_sender, mut prack_receiver) = oneshot::channel();
+        self.inner
+            .pending_prack
+            .lock()
+            .unwrap()
+            .replace((rseq, prack_sender));
+
+        let mut interval = self.inner.endpoint_inner.t1;
+        let mut elapsed = Duration::ZERO;
+        while elapsed < self.inner.endpoint_inner.t1x64 {
+            sender.send(TransactionEvent::Respond(resp.clone()))?;
+            select! {
+                _ = &mut prack_receiver => {
+                    info!("reliable provisional acknowledged rseq: {}", rseq);
+                    return Ok(());
+                }
+                _ = self.inner.cancel_token.cancelled() => break,
+                _ = self.inner.endpoint_inner.clock().sleep(interval) => {}
+            }
+            elapsed += interval;
+            interval *= 2;
+        }
+        self.inner.pending_prack.lock().unwrap().take();
+        Err(crate::Error::DialogError(
+            "PRACK timeout".to_string(),
+            self.id(),
+        ))
+    }
+
+    /// Reject the pending INVITE with 603 Decline.
+    pub fn reject(&self) -> Result<()> {
+        self.reject_with(StatusCode::Decline, None, None)
+    }
+
+    /// Reject the pending INVITE with `status`, a Warning with the `warning` text
+    /// (RFC 3261 20.43) and a Retry-After of `retry_after` seconds on the statuses
+    /// it is defined for, e.g. 486 Busy Here or 503 (RFC 3261 20.33).
+    pub fn reject_with(
+        &self,
+        status: StatusCode,
+        warning: Option<String>,
+        retry_after: Option<u32>,
+    ) -> Result<()> {
+        let mut headers = vec![];
+        if let Some(warning) = warning {
+            headers.push(Header::Warning(
+                format!("399 rsipstack \"{}\"", warning).into(),
+            ));
+        }
+        match (retry_after, status.code()) {
+            (Some(seconds), 404 | 413 | 480 | 486 | 500 | 503 | 600 | 603) => {
+                headers.push(Header::RetryAfter(seconds.to_string().into()))
+            }
+            (Some(_), code) => info!("no retry-after on a {} response", code),
+            (None, _) => {}
+        }
+        self.respond_final(status, headers)
+    }
+
+    /// Redirect the pending INVITE with 302 and a Contact for each of `contacts`.
+    pub fn redirect(&self, contacts: Vec<rsip::Uri>) -> Result<()> {
+        let headers = contacts
+            .into_iter()
+            .map(|uri| rsip::typed::Contact::from(uri).into())
+            .collect();
+        self.respond_final(StatusCode::MovedTemporarily, headers)
+    }
+
+    /// Answer the pending INVITE with a non-2xx, the initial INVITE terminates the dialog
+    fn respond_final(&self, status: StatusCode, headers: Vec<Header>) -> Result<()> {
+        let (request, sender) = match self.inner.tu_sender.lock().unwrap().clone() {
+            Some(pending) => pending,
+            None => {
+                return Err(crate::Error::DialogError(
+                    "transaction is already terminated".to_string(),
+                    self.id(),
+                ))
+            }
+        };
+        let mut resp = self
+            .inner
+            .make_response(&request, status.clone(), None, None);
+        // the Contacts of a redirect replace ours
+        if headers.iter().any(|h| matches!(h, Header::Contact(_))) {
+            resp.headers.retain(|h| !matches!(h, Header::Contact(_)));
+        }
+        resp.headers.extend(headers);
+        self.inner
+            .last_invite_response
+            .lock()
+            .unwrap()
+            .replace(resp.clone());
+        sender.send(TransactionEvent::Respond(resp))?;
+        if !self.inner.is_confirmed() {
+            self.inner
+                .transition(DialogState::Terminated(self.id(), Some(status), None))?;
+        }
+        Ok(())
     }
 
     pub async fn bye(&self) -> Result<()> {
+        self.bye_with_headers(vec![]).await
+    }
+
+    /// Hang up with a Reason header (RFC 3326), e.g. `Q.850;cause=16`.
+    pub async fn bye_with_reason(&self, reason: Reason) -> Result<()> {
+        self.bye_with_headers(vec![reason.into()]).await
+    }
+
+    /// Hang up with extra headers, e.g. a Reason (RFC 3326) or `X-` header.
+    ///
+    /// The Via, Call-ID, From, To, CSeq, Route and Content-Length headers of the
+    /// dialog can not be replaced and are dropped from `headers`.
+    pub async fn bye_with_headers(&self, headers: Vec<Header>) -> Result<()> {
         if !self.inner.is_confirmed() {
             return Ok(());
         }
-        let request = self
-            .inner
-            .make_request(rsip::Method::Bye, None, None, None, None)?;
+        let request =
+            self.inner
+                .make_request(rsip::Method::Bye, None, None, Some(headers), None)?;
         let resp = self.inner.do_request(request).await?;
         self.inner.transition(DialogState::Terminated(
             self.id(),
             resp.map(|r| r.status_code),
+            None,
         ))?;
         self.inner
-            .transition(DialogState::Terminated(self.id(), None))?;
+            .transition(DialogState::Terminated(self.id(), None, None))?;
         Ok(())
     }
 
@@ -85,21 +277,115 @@ impl ServerInviteDialog {
         todo!()
     }
 
+    /// Send an UPDATE (RFC 3311), allowed in the early dialog as well as after confirmation,
+    /// otherwise it is [`Error::DialogNotConfirmed`](crate::Error::DialogNotConfirmed).
+    pub async fn update(
+        &self,
+        body: Option<Vec<u8>>,
+        content_type: Option<String>,
+    ) -> Result<Option<Response>> {
+        if !self.inner.is_established() && self.inner.tu_sender.lock().unwrap().is_none() {
+            return Err(crate::Error::DialogNotConfirmed(self.id()));
+        }
+        let headers = body.as_ref().map(|_| {
+            vec![rsip::Header::ContentType(
+                content_type.unwrap_or("application/sdp".to_string()).into(),
+            )]
+        });
+        let request = self.inner.make_request(
+            rsip::Method::Update,
+            Some(self.inner.increment_local_seq()),
+            None,
+            headers,
+            body,
+        )?;
+        let resp = self.inner.do_request(request.clone()).await?;
+        if let Some(resp) = resp
+            .as_ref()
+            .filter(|r| r.status_code.kind() == StatusCodeKind::Successful)
+        {
+            self.inner.update_remote_target(resp)?;
+            self.inner
+                .transition(DialogState::Updated(self.id(), request))?;
+        }
+        Ok(resp)
+    }
+
+    /// Answer the pending incoming UPDATE, raised as `DialogState::Updated`.
+    pub fn answer_update(
+        &self,
+        status: StatusCode,
+        headers: Option<Vec<Header>>,
+        body: Option<Vec<u8>>,
+    ) -> Result<()> {
+        match self.inner.update_sender.lock().unwrap().take() {
+            Some((request, sender)) => {
+                let resp = self.inner.make_response(&request, status, headers, body);
+                sender
+                    .send(TransactionEvent::Respond(resp))
+                    .map_err(Into::into)
+            }
+            None => Err(crate::Error::DialogError(
+                "no pending update".to_string(),
+                self.id(),
+            )),
+        }
+    }
+
+    /// Report the progress of an accepted REFER with a `message/sipfrag` NOTIFY (RFC 3515 2.4.5),
+    /// a final status terminates the implicit subscription.
+    pub async fn notify_refer(&self, status: StatusCode) -> Result<Option<Response>> {
+        if !self.inner.is_confirmed() {
+            return Ok(None);
+        }
+        let subscription_state = match status.kind() {
+            StatusCodeKind::Provisional => "active".to_string(),
+            _ => "terminated;reason=noresource".to_string(),
+        };
+        let headers = vec![
+            Header::Event("refer".into()),
+            Header::SubscriptionState(subscription_state.into()),
+            Header::ContentType("message/sipfrag;version=2.0".into()),
+        ];
+        let body = format!("SIP/2.0 {}\r\n", status).into_bytes();
+        let request = self.inner.make_request(
+            rsip::Method::Notify,
+            Some(self.inner.increment_local_seq()),
+            None,
+            Some(headers),
+            Some(body),
+        )?;
+        self.inner.do_request(request).await
+    }
+
     pub async fn info(&self) -> Result<()> {
+        self.info_with_headers(vec![], None, None).await
+    }
+
+    /// Send an INFO with extra headers and an optional body, the Content-Type
+    /// of the body comes with `headers`, see `bye_with_headers`.
+    ///
+    /// Without a final response within `timeout`, 64*T1 by default, the
+    /// dialog is terminated. A dialog not confirmed is
+    /// [`Error::DialogNotConfirmed`](crate::Error::DialogNotConfirmed).
+    pub async fn info_with_headers(
+        &self,
+        headers: Vec<Header>,
+        body: Option<Vec<u8>>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
         if !self.inner.is_confirmed() {
-            return Ok(());
+            return Err(crate::Error::DialogNotConfirmed(self.id()));
         }
-        let request = self
-            .inner
-            .make_request(rsip::Method::Info, None, None, None, None)?;
-        self.inner.do_request(request).await?;
+        let request =
+            self.inner
+                .make_request(rsip::Method::Info, None, None, Some(headers), body)?;
+        self.inner.do_request_with_timeout(request, timeout).await?;
         Ok(())
     }
 
+    #[instrument(name = "server_invite_dialog", skip_all, fields(dialog_id = %self.id()))]
     pub async fn handle(&mut self, mut tx: Transaction) -> Result<()> {
-        let span = info_span!("server_invite_dialog", dialog_id = %self.id());
-        let _enter = span.enter();
-
         trace!(
             "handle request: {:?} state:{}",
             tx.original,
@@ -107,25 +393,81 @@ impl ServerInviteDialog {
         );
 
         let cseq = tx.original.cseq_header()?.seq()?;
-        if cseq < self.inner.remote_seq.load(Ordering::Relaxed) {
-            info!(
-                "received old request {} remote_seq: {} > {}",
-                tx.original.method(),
-                self.inner.remote_seq.load(Ordering::Relaxed),
-                cseq
-            );
-            // discard old request
+        // the ACK keeps the cseq of the INVITE, which may be older than a PRACK
+        if tx.original.method != rsip::Method::Ack {
+            if let Err(status) = self.inner.check_remote_seq(cseq) {
+                tx.reply(status).await?;
+                return Ok(());
+            }
+        }
+
+        if tx.original.method == rsip::Method::Ack {
+            if let Some((invite, sender)) = self.inner.tu_sender.lock().unwrap().as_ref() {
+                // the ACK of a 2xx is a new transaction, match it to the INVITE
+                let to_tag = tx
+                    .original
+                    .to_header()?
+                    .tag()?
+                    .map(|t| t.value().to_string());
+                if invite.cseq_header()?.seq()? != cseq
+                    || to_tag.as_deref() != Some(self.id().to_tag.as_str())
+                {
+                    info!("received ack not matching the invite: {}", cseq);
+                    return Ok(());
+                }
+                sender
+                    .send(TransactionEvent::Received(
+                        tx.original.clone().into(),
+                        tx.connection.clone(),
+                    ))
+                    .ok();
+            }
             return Ok(());
         }
 
+        if self.inner.is_confirmed()
+            && tx.original.method == rsip::Method::Invite
+            && cseq == self.inner.remote_seq.load(Ordering::Relaxed)
+        {
+            // retransmission of the last (re-)INVITE, answer with the previous response
+            let last_response = self.inner.last_invite_response.lock().unwrap().clone();
+            if let Some(resp) = last_response {
+                info!("received retransmitted invite, replying previous response");
+                tx.respond(resp).await?;
+            }
+            return Ok(());
+        }
+
+        let method = tx.original.method;
+        if method != rsip::Method::Invite && cseq == self.inner.remote_seq.load(Ordering::Relaxed) {
+            let last_response = self.inner.last_response.lock().unwrap().clone();
+            if let Some((_, _, resp)) = last_response
+                .filter(|(last_cseq, last_method, _)| *last_cseq == cseq && *last_method == method)
+            {
+                info!(
+                    "received retransmitted {}, replying previous response",
+                    method
+                );
+                tx.respond(resp).await?;
+                return Ok(());
+            }
+        }
+
         self.inner.remote_seq.store(cseq, Ordering::Relaxed);
 
-        if self.inner.is_confirmed() {
-            match tx.original.method {
-                rsip::Method::Invite => {}
-                rsip::Method::Ack => {}
-                rsip::Method::Bye => return self.handle_bye(tx).await,
-                rsip::Method::Info => return self.handle_info(tx).await,
+        // the Contact of the INVITE is the remote target already
+        if self.inner.is_established() {
+            self.inner.refresh_remote_target(&tx.original)?;
+        }
+        let r = if self.inner.is_confirmed() {
+            match method {
+                rsip::Method::Invite => return self.handle_invite(tx).await,
+                rsip::Method::Bye => self.handle_bye(&mut tx).await,
+                rsip::Method::Info => self.handle_info(&mut tx).await,
+                rsip::Method::Message => self.handle_message(&mut tx).await,
+                rsip::Method::Update => self.handle_update(&mut tx).await,
+                rsip::Method::Refer => self.handle_refer(&mut tx).await,
+                rsip::Method::Options => self.handle_options(&mut tx).await,
                 _ => {
                     info!("invalid request method: {:?}", tx.original.method);
                     tx.reply(rsip::StatusCode::MethodNotAllowed).await?;
@@ -135,73 +477,236 @@ impl ServerInviteDialog {
                     ));
                 }
             }
+        } else if method == rsip::Method::PRack {
+            self.handle_prack(&mut tx).await
+        } else if method == rsip::Method::Update {
+            self.handle_update(&mut tx).await
         } else {
-            match tx.original.method {
-                rsip::Method::Ack => {
-                    if let Some(sender) = self.inner.tu_sender.lock().unwrap().as_ref() {
-                        sender
-                            .send(TransactionEvent::Received(
-                                tx.original.clone().into(),
-                                tx.connection.clone(),
-                            ))
-                            .ok();
-                    }
-                    return Ok(());
-                }
-                _ => {}
-            }
+            return self.handle_invite(tx).await;
+        };
+        // absorb retransmissions of the request with the same response
+        if let Some(resp) = tx.last_response.clone() {
+            self.inner
+                .last_response
+                .lock()
+                .unwrap()
+                .replace((cseq, method, resp));
         }
-        self.handle_invite(tx).await
+        r
     }
 
-    async fn handle_bye(&mut self, mut tx: Transaction) -> Result<()> {
+    async fn handle_bye(&mut self, tx: &mut Transaction) -> Result<()> {
         info!("received bye");
+        let reason = reason_headers(&tx.original.headers).into_iter().next();
         self.inner
-            .transition(DialogState::Terminated(self.id(), None))?;
+            .transition(DialogState::Terminated(self.id(), None, reason))?;
+        tx.reply(rsip::StatusCode::OK).await?;
+        Ok(())
+    }
+
+    async fn handle_options(&mut self, tx: &mut Transaction) -> Result<()> {
+        tx.reply(rsip::StatusCode::OK).await?;
+        Ok(())
+    }
+
+    async fn handle_info(&mut self, tx: &mut Transaction) -> Result<()> {
+        self.inner.transition(self.inner.info_state(&tx.original))?;
         tx.reply(rsip::StatusCode::OK).await?;
         Ok(())
     }
 
-    async fn handle_info(&mut self, mut tx: Transaction) -> Result<()> {
+    async fn handle_message(&mut self, tx: &mut Transaction) -> Result<()> {
         self.inner
-            .transition(DialogState::Info(self.id(), tx.original.clone()))?;
+            .transition(DialogState::Message(self.id(), tx.original.clone()))?;
         tx.reply(rsip::StatusCode::OK).await?;
         Ok(())
     }
 
+    async fn handle_prack(&mut self, tx: &mut Transaction) -> Result<()> {
+        let rseq = tx.original.headers.iter().find_map(|h| match h {
+            Header::Other(name, value) if name.eq_ignore_ascii_case("RAck") => {
+                value.split_whitespace().next()?.parse::<u32>().ok()
+            }
+            _ => None,
+        });
+        let pending = self.inner.pending_prack.lock().unwrap().take();
+        match (pending, rseq) {
+            (Some((pending_rseq, sender)), Some(rseq)) if pending_rseq == rseq => {
+                info!("received prack rseq: {}", rseq);
+                sender.send(()).ok();
+                tx.reply(rsip::StatusCode::OK).await?;
+            }
+            (pending, _) => {
+                info!("received unmatched prack: {:?}", rseq);
+                *self.inner.pending_prack.lock().unwrap() = pending;
+                tx.reply(rsip::StatusCode::CallTransactionDoesNotExist)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_refer(&mut self, tx: &mut Transaction) -> Result<()> {
+        let refer_to = tx.original.headers.iter().find_map(|h| match h {
+            Header::Other(name, value)
+                if name.eq_ignore_ascii_case("Refer-To") || name.eq_ignore_ascii_case("r") =>
+            {
+                parse_refer_to(value).ok()
+            }
+            _ => None,
+        });
+        let refer_to = match refer_to {
+            Some((refer_to, _)) => refer_to,
+            None => {
+                info!("received refer without valid Refer-To");
+                tx.reply(rsip::StatusCode::BadRequest).await?;
+                return Ok(());
+            }
+        };
+        info!("received refer to: {}", refer_to);
+        self.inner
+            .transition(DialogState::Refer(self.id(), tx.original.clone(), refer_to))?;
+        tx.reply(StatusCode::Other(202, "Accepted".to_string()))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_update(&mut self, tx: &mut Transaction) -> Result<()> {
+        info!("received update");
+        self.inner.session_refreshed.notify_one();
+        let session_timer = self.inner.session_timer.lock().unwrap().clone();
+        if let (Some(timer), true) = (session_timer, tx.original.body.is_empty()) {
+            // a session refresh without offer is answered right away (RFC 4028 9)
+            let resp = self.inner.make_response(
+                &tx.original,
+                StatusCode::OK,
+                Some(vec![timer.header()]),
+                None,
+            );
+            tx.respond(resp).await?;
+            return Ok(());
+        }
+        self.inner
+            .update_sender
+            .lock()
+            .unwrap()
+            .replace((tx.original.clone(), tx.tu_sender.clone()));
+        self.inner
+            .transition(DialogState::Updated(self.id(), tx.original.clone()))?;
+        // wait for the application to answer via answer_update
+        while tx.receive().await.is_some() {}
+        self.inner.update_sender.lock().unwrap().take();
+        Ok(())
+    }
+
     async fn handle_invite(&mut self, mut tx: Transaction) -> Result<()> {
         self.inner
             .tu_sender
             .lock()
             .unwrap()
-            .replace(tx.tu_sender.clone());
+            .replace((tx.original.clone(), tx.tu_sender.clone()));
 
+        let reinvite = self.inner.is_confirmed();
+        let config = self.inner.session_timer_config.lock().unwrap().clone();
+        if let Some(config) = config {
+            match SessionTimer::negotiate(&tx.original.headers, &config) {
+                Ok(timer) => {
+                    self.inner.session_timer.lock().unwrap().replace(timer);
+                    self.inner.session_refreshed.notify_one();
+                }
+                Err(min_se) => {
+                    info!("session interval too small, min-se: {}", min_se);
+                    self.inner.tu_sender.lock().unwrap().take();
+                    let resp = self.inner.make_response(
+                        &tx.original,
+                        StatusCode::SessionIntervalTooSmall,
+                        Some(vec![min_se_header(min_se)]),
+                        None,
+                    );
+                    tx.respond(resp).await?;
+                    if !reinvite {
+                        self.inner.transition(DialogState::Terminated(
+                            self.id(),
+                            Some(StatusCode::SessionIntervalTooSmall),
+                            None,
+                        ))?;
+                    }
+                    // absorb the ACK
+                    while tx.receive().await.is_some() {}
+                    return Ok(());
+                }
+            }
+        }
         let handle_loop = async {
-            if !self.inner.is_confirmed() {
+            if reinvite {
+                // re-INVITE, e.g. hold/resume, the dialog stays confirmed
+                info!("received re-invite");
+                self.inner
+                    .transition(DialogState::Updated(self.id(), tx.original.clone()))?;
+            } else {
                 self.inner.transition(DialogState::Calling(self.id()))?;
+            }
+            // the endpoint may have sent it already
+            if tx.last_response.is_none() {
                 tx.send_trying().await?;
             }
 
+            let mut acked = false;
             while let Some(msg) = tx.receive().await {
                 match msg {
                     SipMessage::Request(req) => match req.method {
                         rsip::Method::Ack => {
                             info!("received ack");
+                            let accepted = tx.last_response.as_ref().map(|r| r.status_code.kind())
+                                == Some(StatusCodeKind::Successful);
+                            if !accepted || acked {
+                                continue;
+                            }
+                            acked = true;
+                            self.inner.transition(DialogState::Ack(self.id(), req))?;
                             self.inner.transition(DialogState::Confirmed(self.id()))?;
+                            let replaced = self.inner.replaces.lock().unwrap().take();
+                            if let Some(replaced) = replaced {
+                                tokio::spawn(hangup_replaced(replaced).in_current_span());
+                            }
+                            let session_timer = self.inner.session_timer.lock().unwrap().clone();
+                            if let (false, Some(timer)) = (reinvite, session_timer) {
+                                start_session_timer(self.inner.clone(), timer);
+                            }
                         }
                         rsip::Method::Cancel => {
+                            // only a CANCEL ahead of the final response gets here
                             info!("received cancel");
                             tx.reply(rsip::StatusCode::RequestTerminated).await?;
-                            self.inner.transition(DialogState::Terminated(
-                                self.id(),
-                                Some(StatusCode::RequestTerminated),
-                            ))?;
+                            if !reinvite {
+                                let reason = reason_headers(&req.headers).into_iter().next();
+                                self.inner
+                                    .transition(DialogState::Cancelled(self.id(), reason))?;
+                            }
                         }
                         _ => {}
                     },
                     SipMessage::Response(_) => {}
                 }
             }
+            // the 2xx was retransmitted until Timer H without an ACK (RFC 3261 13.3.1.4)
+            let accepted = tx.last_response.as_ref().map(|r| r.status_code.kind())
+                == Some(StatusCodeKind::Successful);
+            if accepted && !acked && !reinvite {
+                info!("no ack received for 2xx, hanging up");
+                // the remote party may think the call is up, end it (RFC 3261 13.3.1.4)
+                let bye = self
+                    .inner
+                    .make_request(rsip::Method::Bye, None, None, None, None)?;
+                if let Err(e) = self.inner.do_request(bye).await {
+                    info!("bye after ack timeout failed: {}", e);
+                }
+                self.inner.transition(DialogState::Terminated(
+                    self.id(),
+                    Some(StatusCode::RequestTimeout),
+                    None,
+                ))?;
+            }
             Ok::<(), crate::Error>(())
         };
         match handle_loop.await {
@@ -219,6 +724,19 @@ impl ServerInviteDialog {
     }
 }
 
+/// BYE the dialog a confirmed INVITE with Replaces took over (RFC 3891 3)
+async fn hangup_replaced(replaced: Dialog) {
+    info!("hanging up replaced dialog: {}", replaced.id());
+    let result = match &replaced {
+        Dialog::ServerInvite(d) => d.bye().await,
+        Dialog::ClientInvite(d) => d.bye().await,
+        Dialog::ClientSubscribe(_) => Ok(()),
+    };
+    if let Err(e) = result {
+        info!("hangup replaced dialog {} error: {}", replaced.id(), e);
+    }
+}
+
 impl TryFrom<&Dialog> for ServerInviteDialog {
     type Error = crate::Error;
 
@@ -0,0 +1,290 @@
+use super::dialog::{DialogInnerRef, DialogState};
+use crate::{rsip_ext::has_supported, transaction::key::TransactionRole, Result};
+use rsip::{Header, StatusCode, StatusCodeKind};
+use std::time::Duration;
+use tokio::select;
+use tracing::{info, Instrument};
+
+pub const DEFAULT_SESSION_EXPIRES: u32 = 1800;
+/// the lowest Min-SE allowed by RFC 4028 4
+pub const MIN_SESSION_EXPIRES: u32 = 90;
+
+/// Session timer settings of a dialog (RFC 4028)
+#[derive(Clone, Debug)]
+pub struct SessionTimerConfig {
+    /// the session interval we ask for, in seconds
+    pub session_expires: u32,
+    /// the smallest session interval we accept, smaller requests are rejected with 422
+    pub min_se: u32,
+}
+
+impl Default for SessionTimerConfig {
+    fn default() -> Self {
+        SessionTimerConfig {
+            session_expires: DEFAULT_SESSION_EXPIRES,
+            min_se: MIN_SESSION_EXPIRES,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Refresher {
+    Uac,
+    Uas,
+}
+
+/// The negotiated session interval and who refreshes it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionTimer {
+    pub interval: u32,
+    pub refresher: Refresher,
+}
+
+impl SessionTimer {
+    /// true if we have to send the refreshes
+    pub fn is_local_refresher(&self, role: &TransactionRole) -> bool {
+        matches!(
+            (role, &self.refresher),
+            (TransactionRole::Client, Refresher::Uac) | (TransactionRole::Server, Refresher::Uas)
+        )
+    }
+
+    pub fn header(&self) -> Header {
+        let refresher = match self.refresher {
+            Refresher::Uac => "uac",
+            Refresher::Uas => "uas",
+        };
+        Header::Other(
+            "Session-Expires".to_string(),
+            format!("{};refresher={}", self.interval, refresher),
+        )
+    }
+
+    /// Negotiate the session interval of an incoming INVITE or UPDATE as UAS,
+    /// returns our Min-SE if the requested interval is too small.
+    pub fn negotiate(
+        headers: &rsip::Headers,
+        config: &SessionTimerConfig,
+    ) -> std::result::Result<Self, u32> {
+        match parse_session_expires(headers) {
+            Some((interval, _)) if interval < config.min_se => Err(config.min_se),
+            Some((interval, refresher)) => {
+                let refresher = refresher.unwrap_or(match has_supported(headers, "timer") {
+                    true => Refresher::Uac,
+                    false => Refresher::Uas,
+                });
+                Ok(SessionTimer {
+                    interval,
+                    refresher,
+                })
+            }
+            None => Ok(SessionTimer {
+                interval: config.session_expires.max(min_se(headers).unwrap_or(0)),
+                refresher: Refresher::Uas,
+            }),
+        }
+    }
+}
+
+/// the Session-Expires header, `x` in compact form
+pub fn parse_session_expires(headers: &rsip::Headers) -> Option<(u32, Option<Refresher>)> {
+    let value = headers.iter().find_map(|h| match h {
+        Header::Other(name, value)
+            if name.eq_ignore_ascii_case("Session-Expires") || name.eq_ignore_ascii_case("x") =>
+        {
+            Some(value)
+        }
+        _ => None,
+    })?;
+    let mut parts = value.split(';').map(|p| p.trim());
+    let interval = parts.next()?.parse::<u32>().ok()?;
+    let refresher = parts.find_map(|p| match p.split_once('=') {
+        Some((k, v)) if k.trim().eq_ignore_ascii_case("refresher") => {
+            match v.trim().to_lowercase().as_str() {
+                "uac" => Some(Refresher::Uac),
+                "uas" => Some(Refresher::Uas),
+                _ => None,
+            }
+        }
+        _ => None,
+    });
+    Some((interval, refresher))
+}
+
+pub fn min_se(headers: &rsip::Headers) -> Option<u32> {
+    headers.iter().find_map(|h| match h {
+        Header::Other(name, value) if name.eq_ignore_ascii_case("Min-SE") => {
+            value.split(';').next()?.trim().parse::<u32>().ok()
+        }
+        _ => None,
+    })
+}
+
+pub fn min_se_header(min_se: u32) -> Header {
+    Header::Other("Min-SE".to_string(), min_se.to_string())
+}
+
+/// Keep the session alive until the dialog is cancelled: refresh it at half the
+/// interval if we are the refresher, with UPDATE when the peer allows it and
+/// re-INVITE otherwise, else expect a refresh before the interval is over. The
+/// dialog is terminated when either fails.
+pub(super) fn start_session_timer(inner: DialogInnerRef, timer: SessionTimer) {
+    info!("session timer started: {:?}", timer);
+    inner.session_timer.lock().unwrap().replace(timer);
+    tokio::spawn(
+        async move {
+            if let Err(e) = run_session_timer(inner).await {
+                info!("session timer stopped: {}", e);
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+async fn run_session_timer(inner: DialogInnerRef) -> Result<()> {
+    loop {
+        let timer = match inner.session_timer.lock().unwrap().clone() {
+            Some(timer) => timer,
+            None => return Ok(()),
+        };
+        if inner.state.lock().unwrap().is_terminated() {
+            return Ok(());
+        }
+        let interval = Duration::from_secs(timer.interval as u64);
+        let id = inner.id.lock().unwrap().clone();
+
+        if timer.is_local_refresher(&inner.role) {
+            select! {
+                _ = inner.cancel_token.cancelled() => return Ok(()),
+                _ = inner.endpoint_inner.clock().sleep(interval / 2) => {}
+            }
+            let request = refresh_request(&inner, &timer)?;
+            let resp = inner.do_request(request).await.unwrap_or(None);
+            match resp {
+                Some(resp) if resp.status_code.kind() == StatusCodeKind::Successful => {
+                    if let Some((interval, refresher)) = parse_session_expires(&resp.headers) {
+                        inner.session_timer.lock().unwrap().replace(SessionTimer {
+                            interval,
+                            refresher: refresher.unwrap_or(timer.refresher),
+                        });
+                    }
+                }
+                resp => {
+                    info!(
+                        "session refresh failed: {:?}",
+                        resp.as_ref().map(|r| r.status_code.clone())
+                    );
+                    inner.transition(DialogState::Terminated(
+                        id,
+                        resp.map(|r| r.status_code),
+                        None,
+                    ))?;
+                    return Ok(());
+                }
+            }
+        } else {
+            select! {
+                _ = inner.cancel_token.cancelled() => return Ok(()),
+                _ = inner.session_refreshed.notified() => continue,
+                _ = inner.endpoint_inner.clock().sleep(expiry_wait(interval)) => {}
+            }
+            info!("session expired without refresh");
+            let request = inner.make_request(
+                rsip::Method::Bye,
+                Some(inner.increment_local_seq()),
+                None,
+                None,
+                None,
+            )?;
+            inner.do_request(request).await.ok();
+            inner.transition(DialogState::Terminated(
+                id,
+                Some(StatusCode::RequestTimeout),
+                None,
+            ))?;
+            return Ok(());
+        }
+    }
+}
+
+/// How long the non-refresher waits for a refresh before the BYE, a little
+/// less than the interval so it ends the session ahead of the peer
+/// (RFC 4028 10): `interval - min(32s, interval / 3)`
+fn expiry_wait(interval: Duration) -> Duration {
+    interval - Duration::from_secs(32).min(interval / 3)
+}
+
+/// An UPDATE without offer when the peer allows it, else a re-INVITE with our
+/// session description as it is (RFC 4028 9)
+fn refresh_request(inner: &DialogInnerRef, timer: &SessionTimer) -> Result<rsip::Request> {
+    let mut headers = vec![timer.header()];
+    let (method, body) = match inner.remote_allows(&rsip::Method::Update) {
+        true => (rsip::Method::Update, None),
+        false => {
+            let body = inner.local_sdp().map(|(content_type, body)| {
+                headers.push(Header::ContentType(content_type.into()));
+                body
+            });
+            (rsip::Method::Invite, body)
+        }
+    };
+    inner.make_request(
+        method,
+        Some(inner.increment_local_seq()),
+        None,
+        Some(headers),
+        body,
+    )
+}
+
+#[test]
+fn test_expiry_wait() {
+    assert_eq!(
+        expiry_wait(Duration::from_secs(1800)),
+        Duration::from_secs(1768)
+    );
+    assert_eq!(
+        expiry_wait(Duration::from_secs(90)),
+        Duration::from_secs(60)
+    );
+}
+
+#[test]
+fn test_negotiate_session_timer() {
+    let config = SessionTimerConfig::default();
+    let headers: rsip::Headers = vec![
+        Header::Supported("timer".into()),
+        Header::Other("Session-Expires".to_string(), "600".to_string()),
+    ]
+    .into();
+    assert_eq!(
+        SessionTimer::negotiate(&headers, &config),
+        Ok(SessionTimer {
+            interval: 600,
+            refresher: Refresher::Uac
+        })
+    );
+
+    let headers: rsip::Headers = vec![Header::Other(
+        "x".to_string(),
+        "1800;refresher=uas".to_string(),
+    )]
+    .into();
+    assert_eq!(
+        parse_session_expires(&headers),
+        Some((1800, Some(Refresher::Uas)))
+    );
+
+    let headers: rsip::Headers = vec![Header::Other(
+        "Session-Expires".to_string(),
+        "30".to_string(),
+    )]
+    .into();
+    assert_eq!(SessionTimer::negotiate(&headers, &config), Err(90));
+
+    let headers: rsip::Headers = vec![].into();
+    assert_eq!(
+        SessionTimer::negotiate(&headers, &config).map(|t| t.refresher),
+        Ok(Refresher::Uas)
+    );
+}
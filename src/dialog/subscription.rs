@@ -0,0 +1,478 @@
+use super::{
+    authenticate::Credential,
+    dialog::{Dialog, DialogInner, DialogInnerRef, DialogState, DialogStateSender},
+    dialog_layer::DialogLayer,
+    DialogId,
+};
+use crate::{
+    transaction::{key::TransactionRole, make_tag, transaction::Transaction},
+    Result,
+};
+use rsip::{
+    prelude::{HeadersExt, UntypedHeader},
+    Header, Request, Response, StatusCodeKind,
+};
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, instrument, trace};
+
+pub const DEFAULT_SUBSCRIBE_EXPIRES: u32 = 3600;
+
+/// How [`ClientSubscribeDialog::serve`] retries a failed refresh: a 423 with
+/// Min-Expires is retried at once with the minimum, a 5xx with Retry-After
+/// after the interval given. Any other failure terminates the subscription.
+#[derive(Clone, Debug)]
+pub struct SubscribeRetryPolicy {
+    /// failed refreshes in a row that are retried
+    pub max_retries: u32,
+    /// a longer Retry-After is not waited for
+    pub max_retry_after: Duration,
+}
+
+impl Default for SubscribeRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            max_retry_after: Duration::from_secs(300),
+        }
+    }
+}
+
+pub struct SubscribeOption {
+    pub caller: rsip::Uri,
+    pub callee: rsip::Uri,
+    /// the event package, e.g. `presence` or `dialog`
+    pub event: String,
+    pub accept: Option<String>,
+    pub expires: Option<u32>,
+    pub contact: rsip::Uri,
+    pub credential: Option<Credential>,
+}
+
+/// Subscription-State of a subscription (RFC 6665 4.1.3)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SubscriptionState {
+    Pending(Option<u32>),
+    Active(Option<u32>),
+    /// the reason param, e.g. `timeout` or `rejected`
+    Terminated(Option<String>),
+}
+
+impl SubscriptionState {
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split(';').map(|p| p.trim());
+        let state = parts.next()?.to_lowercase();
+        let mut expires = None;
+        let mut reason = None;
+        for param in parts {
+            match param.split_once('=') {
+                Some((k, v)) if k.trim().eq_ignore_ascii_case("expires") => {
+                    expires = v.trim().parse::<u32>().ok();
+                }
+                Some((k, v)) if k.trim().eq_ignore_ascii_case("reason") => {
+                    reason = Some(v.trim().to_string());
+                }
+                _ => {}
+            }
+        }
+        match state.as_str() {
+            "pending" => Some(SubscriptionState::Pending(expires)),
+            "active" => Some(SubscriptionState::Active(expires)),
+            "terminated" => Some(SubscriptionState::Terminated(reason)),
+            _ => None,
+        }
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        matches!(self, SubscriptionState::Terminated(_))
+    }
+}
+
+impl std::fmt::Display for SubscriptionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubscriptionState::Pending(None) => write!(f, "pending"),
+            SubscriptionState::Pending(Some(e)) => write!(f, "pending;expires={}", e),
+            SubscriptionState::Active(None) => write!(f, "active"),
+            SubscriptionState::Active(Some(e)) => write!(f, "active;expires={}", e),
+            SubscriptionState::Terminated(None) => write!(f, "terminated"),
+            SubscriptionState::Terminated(Some(r)) => write!(f, "terminated;reason={}", r),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ClientSubscribeDialog {
+    pub(super) inner: DialogInnerRef,
+    pub(super) event: String,
+    pub(super) expires: Arc<AtomicU32>,
+    pub(super) subscription_state: Arc<Mutex<SubscriptionState>>,
+    pub(super) retry_policy: Arc<Mutex<SubscribeRetryPolicy>>,
+}
+
+impl DialogLayer {
+    pub fn make_subscribe_request(&self, opt: &SubscribeOption) -> Result<Request> {
+        let last_seq = self.increment_last_seq();
+        let to = rsip::typed::To {
+            display_name: None,
+            uri: opt.callee.clone(),
+            params: vec![],
+        };
+        let recipient = to.uri.clone();
+
+        let form = rsip::typed::From {
+            display_name: None,
+            uri: opt.caller.clone(),
+            params: vec![],
+        }
+        .with_tag(make_tag());
+
+        let via = self.endpoint.get_via(None)?;
+        let mut request =
+            self.endpoint
+                .make_request(rsip::Method::Subscribe, recipient, via, form, to, last_seq);
+
+        let contact = rsip::typed::Contact {
+            display_name: None,
+            uri: opt.contact.clone(),
+            params: vec![],
+        };
+        request
+            .headers
+            .unique_push(rsip::Header::Contact(contact.into()));
+        request
+            .headers
+            .unique_push(rsip::Header::Event(opt.event.clone().into()));
+        request.headers.unique_push(rsip::Header::Expires(
+            opt.expires.unwrap_or(DEFAULT_SUBSCRIBE_EXPIRES).into(),
+        ));
+        if let Some(accept) = &opt.accept {
+            request
+                .headers
+                .unique_push(rsip::Header::Accept(accept.clone().into()));
+        }
+        request
+            .headers
+            .unique_push(rsip::Header::ContentLength(0.into()));
+        Ok(request)
+    }
+
+    /// Send the initial SUBSCRIBE; on 2xx the dialog is confirmed and keyed by the notifier's tag
+    pub async fn do_subscribe(
+        &self,
+        opt: SubscribeOption,
+        state_sender: DialogStateSender,
+    ) -> Result<(ClientSubscribeDialog, Option<Response>)> {
+        let request = self.make_subscribe_request(&opt)?;
+        let id = DialogId::try_from(&request)?;
+        let dlg_inner = DialogInner::new(
+            TransactionRole::Client,
+            id.clone(),
+            request.clone(),
+            self.endpoint.clone(),
+            state_sender,
+            opt.credential,
+            Some(opt.contact),
+        )?;
+
+        let expires = opt.expires.unwrap_or(DEFAULT_SUBSCRIBE_EXPIRES);
+        let dialog = ClientSubscribeDialog {
+            inner: Arc::new(dlg_inner),
+            event: opt.event,
+            expires: Arc::new(AtomicU32::new(expires)),
+            subscription_state: Arc::new(Mutex::new(SubscriptionState::Pending(Some(expires)))),
+            retry_policy: Arc::new(Mutex::new(SubscribeRetryPolicy::default())),
+        };
+        self.insert_dialog(Dialog::ClientSubscribe(dialog.clone()));
+        info!("client subscribe dialog created: {:?}", id);
+
+        let resp = match dialog.inner.do_request(request).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                info!("client subscribe dialog failed: {:?}", e);
+                self.inner.dialogs.write().unwrap().remove(&id);
+                return Err(e);
+            }
+        };
+
+        let resp = match resp {
+            Some(resp) if resp.status_code.kind() == StatusCodeKind::Successful => resp,
+            resp => {
+                self.inner.dialogs.write().unwrap().remove(&id);
+                dialog.inner.transition(DialogState::Terminated(
+                    id,
+                    resp.as_ref().map(|r| r.status_code.clone()),
+                    None,
+                ))?;
+                return Ok((dialog, resp));
+            }
+        };
+
+        let to_tag = resp.to_header()?.tag()?;
+        let tag = to_tag.as_ref().ok_or(crate::Error::DialogError(
+            "to tag not found".to_string(),
+            id.clone(),
+        ))?;
+        dialog.inner.update_remote_tag(tag.value())?;
+        dialog.update_expires(&resp);
+
+        let new_dialog_id = dialog.id();
+        self.inner.dialogs.write().unwrap().remove(&id);
+        self.insert_dialog(Dialog::ClientSubscribe(dialog.clone()));
+        dialog
+            .inner
+            .transition(DialogState::Confirmed(new_dialog_id))?;
+        Ok((dialog, Some(resp)))
+    }
+}
+
+impl ClientSubscribeDialog {
+    pub fn id(&self) -> DialogId {
+        self.inner.id.lock().unwrap().clone()
+    }
+
+    pub fn cancel_token(&self) -> &CancellationToken {
+        &self.inner.cancel_token
+    }
+
+    pub fn event(&self) -> &str {
+        &self.event
+    }
+
+    /// the expires granted by the notifier, updated by 2xx and NOTIFY
+    pub fn expires(&self) -> u32 {
+        self.expires.load(Ordering::Relaxed)
+    }
+
+    pub fn subscription_state(&self) -> SubscriptionState {
+        self.subscription_state.lock().unwrap().clone()
+    }
+
+    /// Replace how the failed refreshes of [`ClientSubscribeDialog::serve`] are retried
+    pub fn set_retry_policy(&self, policy: SubscribeRetryPolicy) {
+        *self.retry_policy.lock().unwrap() = policy;
+    }
+
+    /// Refresh the subscription with the current expires
+    pub async fn refresh(&self) -> Result<Option<Response>> {
+        self.do_subscribe(self.expires()).await
+    }
+
+    /// Remove the subscription with Expires: 0, the notifier answers with a final NOTIFY
+    pub async fn unsubscribe(&self) -> Result<Option<Response>> {
+        self.do_subscribe(0).await
+    }
+
+    /// Refresh the subscription before it expires until it is terminated
+    /// or the dialog is cancelled. A failed refresh is retried as the
+    /// [`SubscribeRetryPolicy`] says, a 481 terminates the subscription.
+    pub async fn serve(&self) -> Result<()> {
+        let mut wait = self.refresh_interval();
+        let mut retries = 0;
+        loop {
+            select! {
+                _ = self.inner.cancel_token.cancelled() => return Ok(()),
+                _ = self.inner.endpoint_inner.clock().sleep(wait) => {}
+            }
+            if self.subscription_state().is_terminated() {
+                return Ok(());
+            }
+            let resp = self.refresh().await?;
+            if self.subscription_state().is_terminated() {
+                return Ok(());
+            }
+            let retry = match resp.as_ref() {
+                Some(resp) if resp.status_code.kind() == StatusCodeKind::Successful => {
+                    retries = 0;
+                    wait = self.refresh_interval();
+                    continue;
+                }
+                Some(resp) => self.retry_after(resp),
+                None => None,
+            };
+            let max_retries = self.retry_policy.lock().unwrap().max_retries;
+            match retry {
+                Some(after) if retries < max_retries => {
+                    retries += 1;
+                    info!(
+                        "subscription refresh failed: {:?}, retry {} after {:?}",
+                        resp.as_ref().map(|r| r.status_code.clone()),
+                        retries,
+                        after
+                    );
+                    wait = after;
+                }
+                _ => {
+                    info!(
+                        "subscription refresh failed: {:?}",
+                        resp.as_ref().map(|r| r.status_code.clone())
+                    );
+                    self.terminate(None, resp.map(|r| r.status_code))?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // at 90% of the expires granted
+    fn refresh_interval(&self) -> Duration {
+        Duration::from_millis(self.expires().max(1) as u64 * 900)
+    }
+
+    // the wait before retrying a failed refresh, `None` if it is not retried
+    fn retry_after(&self, resp: &Response) -> Option<Duration> {
+        match resp.status_code {
+            rsip::StatusCode::IntervalTooBrief => {
+                let min_expires = resp
+                    .min_expires_header()
+                    .and_then(|m| m.value().trim().parse::<u32>().ok())?;
+                self.expires
+                    .store(min_expires.max(self.expires()), Ordering::Relaxed);
+                Some(Duration::ZERO)
+            }
+            ref code if code.kind() == StatusCodeKind::ServerFailure => {
+                // e.g. `120 (I'm in a meeting);duration=3600` (RFC 3261 20.33)
+                let seconds = resp.headers.iter().find_map(|h| match h {
+                    Header::RetryAfter(retry_after) => retry_after
+                        .value()
+                        .split(|c: char| !c.is_ascii_digit())
+                        .next()
+                        .and_then(|s| s.parse::<u64>().ok()),
+                    _ => None,
+                })?;
+                let max_retry_after = self.retry_policy.lock().unwrap().max_retry_after;
+                Some(Duration::from_secs(seconds)).filter(|after| *after <= max_retry_after)
+            }
+            _ => None,
+        }
+    }
+
+    async fn do_subscribe(&self, expires: u32) -> Result<Option<Response>> {
+        if !self.inner.is_confirmed() {
+            return Ok(None);
+        }
+        let headers = vec![
+            Header::Event(self.event.clone().into()),
+            Header::Expires(expires.into()),
+        ];
+        let request = self.inner.make_request(
+            rsip::Method::Subscribe,
+            Some(self.inner.increment_local_seq()),
+            None,
+            Some(headers),
+            None,
+        )?;
+        let resp = self.inner.do_request(request).await?;
+        if let Some(resp) = resp.as_ref() {
+            match resp.status_code.kind() {
+                StatusCodeKind::Successful if expires > 0 => self.update_expires(resp),
+                StatusCodeKind::Successful => {}
+                _ if resp.status_code == rsip::StatusCode::CallTransactionDoesNotExist => {
+                    self.terminate(None, Some(resp.status_code.clone()))?;
+                }
+                _ => {}
+            }
+        }
+        Ok(resp)
+    }
+
+    fn update_expires(&self, resp: &Response) {
+        if let Some(expires) = resp.expires_header().and_then(|e| e.seconds().ok()) {
+            self.expires.store(expires, Ordering::Relaxed);
+        }
+    }
+
+    fn terminate(&self, reason: Option<String>, code: Option<rsip::StatusCode>) -> Result<()> {
+        *self.subscription_state.lock().unwrap() = SubscriptionState::Terminated(reason);
+        self.inner
+            .transition(DialogState::Terminated(self.id(), code, None))
+    }
+
+    #[instrument(name = "client_subscribe_dialog", skip_all, fields(dialog_id = %self.id()))]
+    pub async fn handle(&mut self, mut tx: Transaction) -> Result<()> {
+        trace!(
+            "handle request: {:?} state:{}",
+            tx.original,
+            self.inner.state.lock().unwrap()
+        );
+
+        let cseq = tx.original.cseq_header()?.seq()?;
+        if let Err(status) = self.inner.check_remote_seq(cseq) {
+            tx.reply(status).await?;
+            return Ok(());
+        }
+        self.inner.remote_seq.store(cseq, Ordering::Relaxed);
+
+        match tx.original.method {
+            rsip::Method::Notify => self.handle_notify(tx).await,
+            _ => {
+                info!("invalid request method: {:?}", tx.original.method);
+                tx.reply(rsip::StatusCode::MethodNotAllowed).await?;
+                Err(crate::Error::DialogError(
+                    "invalid request".to_string(),
+                    self.id(),
+                ))
+            }
+        }
+    }
+
+    async fn handle_notify(&mut self, mut tx: Transaction) -> Result<()> {
+        let state = tx
+            .original
+            .headers
+            .iter()
+            .find_map(|h| match h {
+                Header::SubscriptionState(s) => SubscriptionState::parse(s.value()),
+                _ => None,
+            })
+            .unwrap_or(SubscriptionState::Active(None));
+
+        self.inner
+            .transition(DialogState::Notify(self.id(), tx.original.clone()))?;
+        tx.reply(rsip::StatusCode::OK).await?;
+
+        info!("subscription state: {}", state);
+        match &state {
+            SubscriptionState::Pending(Some(expires))
+            | SubscriptionState::Active(Some(expires)) => {
+                self.expires.store(*expires, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+        match state {
+            SubscriptionState::Terminated(reason) => self.terminate(reason, None),
+            state => {
+                *self.subscription_state.lock().unwrap() = state;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse_subscription_state() {
+    assert_eq!(
+        SubscriptionState::parse("active;expires=600"),
+        Some(SubscriptionState::Active(Some(600)))
+    );
+    assert_eq!(
+        SubscriptionState::parse("pending"),
+        Some(SubscriptionState::Pending(None))
+    );
+    assert_eq!(
+        SubscriptionState::parse("terminated;reason=timeout"),
+        Some(SubscriptionState::Terminated(Some("timeout".to_string())))
+    );
+    assert_eq!(
+        SubscriptionState::parse("Terminated; retry-after=30"),
+        Some(SubscriptionState::Terminated(None))
+    );
+    assert_eq!(SubscriptionState::parse("unknown"), None);
+}
@@ -0,0 +1,30 @@
+use crate::{
+    transaction::endpoint::{Endpoint, EndpointOption},
+    transport::{udp::UdpConnection, TransportLayer},
+    EndpointBuilder, Result,
+};
+use tokio_util::sync::CancellationToken;
+
+mod test_client_dialog;
+mod test_dialog_layer;
+mod test_registrar;
+mod test_route_set;
+mod test_server_dialog;
+mod test_subscription;
+
+pub(super) async fn create_test_endpoint() -> Result<Endpoint> {
+    create_test_endpoint_with_option(EndpointOption::default()).await
+}
+
+pub(super) async fn create_test_endpoint_with_option(option: EndpointOption) -> Result<Endpoint> {
+    let token = CancellationToken::new();
+    let tl = TransportLayer::new(token.child_token());
+    let conn = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    tl.add_transport(conn.into());
+    let endpoint = EndpointBuilder::new()
+        .user_agent("rsipstack-test")
+        .transport_layer(tl)
+        .option(option)
+        .build();
+    Ok(endpoint)
+}
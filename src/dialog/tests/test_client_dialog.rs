@@ -0,0 +1,1794 @@
+use crate::dialog::{
+    authenticate::Credential,
+    client_dialog::RedirectPolicy,
+    dialog::{Dialog, DialogState},
+    dialog_layer::{DialogLayer, IncomingHandler},
+    invitation::InviteOption,
+    session_timer::{parse_session_expires, Refresher, SessionTimer, SessionTimerConfig},
+};
+use crate::rsip_ext::Reason;
+use crate::transaction::endpoint::{Endpoint, EndpointOption};
+use crate::transaction::{
+    key::{TransactionKey, TransactionRole},
+    transaction::Transaction,
+};
+use crate::transport::{udp::UdpConnection, TransportEvent};
+use crate::Result;
+use rsip::{
+    prelude::{HeadersExt, UntypedHeader},
+    Header, Request, Response, SipMessage, StatusCode,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::{select, sync::mpsc::unbounded_channel, time::sleep};
+
+fn make_response(req: &Request, status_code: StatusCode, mut headers: Vec<Header>) -> Response {
+    let mut response_headers: Vec<Header> = req
+        .headers
+        .iter()
+        .filter(|h| {
+            matches!(
+                h,
+                Header::Via(_) | Header::From(_) | Header::CallId(_) | Header::CSeq(_)
+            )
+        })
+        .cloned()
+        .collect();
+    let to = req.to_header().expect("to header").value().to_string();
+    let to = match to.contains(";tag=") {
+        true => to,
+        false => format!("{};tag=uas-tag", to),
+    };
+    response_headers.push(rsip::headers::To::new(to).into());
+    response_headers.append(&mut headers);
+    Response {
+        status_code,
+        version: rsip::Version::V2,
+        headers: response_headers.into(),
+        body: vec![],
+    }
+}
+
+fn proxy_authorization(req: &Request) -> Option<String> {
+    req.headers.iter().find_map(|h| match h {
+        Header::ProxyAuthorization(auth) => Some(auth.value().to_string()),
+        _ => None,
+    })
+}
+
+#[tokio::test]
+async fn test_info_preauthorized() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+
+    // a UAS behind an authenticating proxy
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let peer_uri = rsip::Uri::try_from(format!("sip:bob@{}", peer.get_addr().addr))?;
+    let challenges = AtomicUsize::new(0);
+    let info_auth = std::sync::Mutex::new(None);
+    let peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                while let Some(event) = receiver.recv().await {
+                    let (req, connection, from) = match event {
+                        TransportEvent::Incoming(SipMessage::Request(req), connection, from) => {
+                            (req, connection, from)
+                        }
+                        _ => continue,
+                    };
+                    let resp = match (&req.method, proxy_authorization(&req)) {
+                        (rsip::Method::Ack, _) => continue,
+                        (rsip::Method::Invite, None) => {
+                            challenges.fetch_add(1, Ordering::Relaxed);
+                            make_response(
+                                &req,
+                                StatusCode::ProxyAuthenticationRequired,
+                                vec![rsip::headers::ProxyAuthenticate::new(
+                                    "Digest realm=\"atlanta.com\", nonce=\"f84f1cec41e6cbe5aea9c8e88d359\", qop=\"auth\"",
+                                )
+                                .into()],
+                            )
+                        }
+                        (rsip::Method::Info, None) => {
+                            challenges.fetch_add(1, Ordering::Relaxed);
+                            make_response(&req, StatusCode::ProxyAuthenticationRequired, vec![])
+                        }
+                        (method, auth) => {
+                            if *method == rsip::Method::Info {
+                                info_auth.lock().unwrap().replace(auth);
+                            }
+                            let contact = rsip::headers::Contact::new(format!("<{}>", peer_uri));
+                            make_response(&req, StatusCode::OK, vec![contact.into()])
+                        }
+                    };
+                    connection.send(resp.into(), Some(&from)).await.expect("send response");
+                }
+            } => {}
+            _ = peer.serve_loop(sender) => {}
+        }
+    };
+
+    let client_loop = async {
+        let (state_sender, _state_receiver) = unbounded_channel();
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            callee: peer_uri.clone(),
+            content_type: None,
+            offer: None,
+            contact: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            credential: Some(Credential {
+                username: "alice".to_string(),
+                password: "secret".to_string(),
+                ..Default::default()
+            }),
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (dialog, resp) = dialog_layer.do_invite(opt, state_sender).await?;
+        assert_eq!(resp.map(|r| r.status_code), Some(StatusCode::OK));
+        dialog.info().await?;
+        Result::Ok(())
+    };
+
+    select! {
+        r = client_loop => r?,
+        _ = peer_loop => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    }
+
+    // only the INVITE was challenged
+    assert_eq!(challenges.load(Ordering::Relaxed), 1);
+    let info_auth = info_auth
+        .lock()
+        .unwrap()
+        .clone()
+        .flatten()
+        .expect("INFO authorized");
+    assert!(info_auth.contains("nonce=\"f84f1cec41e6cbe5aea9c8e88d359\""));
+    assert!(info_auth.contains("nc=00000002"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_from_tag_stable_across_auth() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let peer_uri = rsip::Uri::try_from(format!("sip:bob@{}", peer.get_addr().addr))?;
+    let from_tags = std::sync::Mutex::new(vec![]);
+    let peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                while let Some(event) = receiver.recv().await {
+                    let (req, connection, from) = match event {
+                        TransportEvent::Incoming(SipMessage::Request(req), connection, from) => {
+                            (req, connection, from)
+                        }
+                        _ => continue,
+                    };
+                    if req.method != rsip::Method::Invite {
+                        continue;
+                    }
+                    let tag = req.from_header().and_then(|f| f.tag()).expect("from tag");
+                    from_tags.lock().unwrap().push(tag.map(|t| t.value().to_string()));
+                    let resp = match req.authorization_header() {
+                        None => make_response(
+                            &req,
+                            StatusCode::Unauthorized,
+                            vec![rsip::headers::WwwAuthenticate::new(
+                                "Digest realm=\"atlanta.com\", nonce=\"f84f1cec41e6cbe5aea9c8e88d359\"",
+                            )
+                            .into()],
+                        ),
+                        Some(_) => {
+                            let contact = rsip::headers::Contact::new(format!("<{}>", peer_uri));
+                            make_response(&req, StatusCode::OK, vec![contact.into()])
+                        }
+                    };
+                    connection.send(resp.into(), Some(&from)).await.expect("send response");
+                }
+            } => {}
+            _ = peer.serve_loop(sender) => {}
+        }
+    };
+
+    let client_loop = async {
+        let (state_sender, _state_receiver) = unbounded_channel();
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            callee: peer_uri.clone(),
+            content_type: None,
+            offer: None,
+            contact: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            credential: Some(Credential {
+                username: "alice".to_string(),
+                password: "secret".to_string(),
+                ..Default::default()
+            }),
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (dialog, resp) = dialog_layer.do_invite(opt, state_sender).await?;
+        assert_eq!(resp.map(|r| r.status_code), Some(StatusCode::OK));
+        Result::Ok(dialog.id())
+    };
+
+    let id = select! {
+        r = client_loop => r?,
+        _ = peer_loop => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+
+    // the authorized INVITE is sent with the tag of the challenged one
+    let from_tags = from_tags.lock().unwrap().clone();
+    assert_eq!(from_tags.len(), 2);
+    assert_eq!(from_tags[0], Some(id.from_tag.clone()));
+    assert_eq!(from_tags[1], from_tags[0]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bye_with_reason_timeout() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+
+    // a UAS that never answers the BYE
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let peer_uri = rsip::Uri::try_from(format!("sip:bob@{}", peer.get_addr().addr))?;
+    let bye_reason = std::sync::Mutex::new(None);
+    let peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                while let Some(event) = receiver.recv().await {
+                    let (req, connection, from) = match event {
+                        TransportEvent::Incoming(SipMessage::Request(req), connection, from) => {
+                            (req, connection, from)
+                        }
+                        _ => continue,
+                    };
+                    match req.method {
+                        rsip::Method::Invite => {
+                            let contact = rsip::headers::Contact::new(format!("<{}>", peer_uri));
+                            let resp = make_response(&req, StatusCode::OK, vec![contact.into()]);
+                            connection.send(resp.into(), Some(&from)).await.expect("send response");
+                        }
+                        rsip::Method::Bye => {
+                            let reason = req.headers.iter().find_map(|h| match h {
+                                Header::Other(name, value) if name == "Reason" => Some(value.clone()),
+                                _ => None,
+                            });
+                            bye_reason.lock().unwrap().replace(reason);
+                        }
+                        _ => {}
+                    }
+                }
+            } => {}
+            _ = peer.serve_loop(sender) => {}
+        }
+    };
+
+    let (state_sender, mut state_receiver) = unbounded_channel();
+    let client_loop = async {
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            callee: peer_uri.clone(),
+            content_type: None,
+            offer: None,
+            contact: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (dialog, resp) = dialog_layer.do_invite(opt, state_sender).await?;
+        assert_eq!(resp.map(|r| r.status_code), Some(StatusCode::OK));
+        dialog
+            .bye_with(Some(Reason::q850(16)), Some(Duration::from_millis(200)))
+            .await?;
+        Result::Ok(())
+    };
+
+    select! {
+        r = client_loop => r?,
+        _ = peer_loop => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    }
+
+    let reason = bye_reason.lock().unwrap().clone().flatten();
+    assert_eq!(reason.as_deref(), Some("Q.850;cause=16"));
+    let mut terminated = None;
+    while let Ok(state) = state_receiver.try_recv() {
+        if let DialogState::Terminated(_, code, _) = state {
+            terminated = Some(code);
+        }
+    }
+    assert_eq!(terminated, Some(Some(StatusCode::RequestTimeout)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_info_timeout_after_trying() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+
+    // a UAS that answers the INFO with 100 Trying only
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let peer_uri = rsip::Uri::try_from(format!("sip:bob@{}", peer.get_addr().addr))?;
+    let peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                while let Some(event) = receiver.recv().await {
+                    let (req, connection, from) = match event {
+                        TransportEvent::Incoming(SipMessage::Request(req), connection, from) => {
+                            (req, connection, from)
+                        }
+                        _ => continue,
+                    };
+                    let resp = match req.method {
+                        rsip::Method::Invite => {
+                            let contact = rsip::headers::Contact::new(format!("<{}>", peer_uri));
+                            make_response(&req, StatusCode::OK, vec![contact.into()])
+                        }
+                        rsip::Method::Info => make_response(&req, StatusCode::Trying, vec![]),
+                        _ => continue,
+                    };
+                    connection.send(resp.into(), Some(&from)).await.expect("send response");
+                }
+            } => {}
+            _ = peer.serve_loop(sender) => {}
+        }
+    };
+
+    let (state_sender, mut state_receiver) = unbounded_channel();
+    let client_loop = async {
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            callee: peer_uri.clone(),
+            content_type: None,
+            offer: None,
+            contact: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (dialog, resp) = dialog_layer.do_invite(opt, state_sender).await?;
+        assert_eq!(resp.map(|r| r.status_code), Some(StatusCode::OK));
+        dialog
+            .info_with_headers(vec![], None, Some(Duration::from_millis(200)))
+            .await?;
+        Result::Ok(())
+    };
+
+    select! {
+        r = client_loop => r?,
+        _ = peer_loop => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    }
+
+    let mut terminated = vec![];
+    while let Ok(state) = state_receiver.try_recv() {
+        if let DialogState::Terminated(_, code, _) = state {
+            terminated.push(code);
+        }
+    }
+    assert_eq!(terminated, vec![Some(StatusCode::RequestTimeout)]);
+    assert!(dialog_layer.is_empty());
+    Ok(())
+}
+
+fn top_via(req: &Request) -> String {
+    req.via_header().expect("via header").value().to_string()
+}
+
+// call cancel() as soon as the dialog is created, before anything is received,
+// the final response and the states of the dialog
+async fn invite_and_cancel(
+    dialog_layer: &DialogLayer,
+    peer_uri: &rsip::Uri,
+) -> Result<(Option<Response>, Vec<DialogState>)> {
+    let (state_sender, mut state_receiver) = unbounded_channel::<DialogState>();
+    let opt = InviteOption {
+        caller: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+        callee: peer_uri.clone(),
+        content_type: None,
+        offer: None,
+        contact: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+        credential: None,
+        session_timer: None,
+        replaces: None,
+        call_id: None,
+        from_tag: None,
+        routes: vec![],
+    };
+    let states = std::sync::Mutex::new(vec![]);
+    let cancel_loop = async {
+        while let Some(state) = state_receiver.recv().await {
+            states.lock().unwrap().push(state.clone());
+            if let DialogState::Calling(id) = state {
+                if let Some(Dialog::ClientInvite(dialog)) = dialog_layer.get_dialog(&id) {
+                    dialog.cancel().await?;
+                }
+            }
+        }
+        Result::Ok(())
+    };
+    let (_, resp) = select! {
+        r = dialog_layer.do_invite(opt, state_sender) => r?,
+        _ = cancel_loop => panic!("must not reach here"),
+    };
+    // the states sent before do_invite returned
+    while let Ok(state) = state_receiver.try_recv() {
+        states.lock().unwrap().push(state);
+    }
+    Ok((resp, states.into_inner().unwrap()))
+}
+
+#[tokio::test]
+async fn test_cancel_after_provisional() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+
+    // a UAS that rings after a while
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let peer_uri = rsip::Uri::try_from(format!("sip:bob@{}", peer.get_addr().addr))?;
+    let ringing_sent = std::sync::atomic::AtomicBool::new(false);
+    let cancel = std::sync::Mutex::new(None);
+    let peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                let mut invite = None;
+                while let Some(event) = receiver.recv().await {
+                    let (req, connection, from) = match event {
+                        TransportEvent::Incoming(SipMessage::Request(req), connection, from) => {
+                            (req, connection, from)
+                        }
+                        _ => continue,
+                    };
+                    match req.method {
+                        rsip::Method::Invite => {
+                            sleep(Duration::from_millis(100)).await;
+                            let resp = make_response(&req, StatusCode::Ringing, vec![]);
+                            ringing_sent.store(true, Ordering::Relaxed);
+                            connection.send(resp.into(), Some(&from)).await.expect("send ringing");
+                            invite = Some(req);
+                        }
+                        rsip::Method::Cancel => {
+                            let ringing = ringing_sent.load(Ordering::Relaxed);
+                            let invite_via = invite.as_ref().map(top_via);
+                            cancel.lock().unwrap().replace((req.clone(), ringing, invite_via));
+                            let resp = make_response(&req, StatusCode::OK, vec![]);
+                            connection.send(resp.into(), Some(&from)).await.expect("send response");
+                            if let Some(invite) = invite.as_ref() {
+                                let resp = make_response(invite, StatusCode::RequestTerminated, vec![]);
+                                connection.send(resp.into(), Some(&from)).await.expect("send response");
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            } => {}
+            _ = peer.serve_loop(sender) => {}
+        }
+    };
+
+    let (resp, states) = select! {
+        r = invite_and_cancel(&dialog_layer, &peer_uri) => r?,
+        _ = peer_loop => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(
+        resp.map(|r| r.status_code),
+        Some(StatusCode::RequestTerminated)
+    );
+    assert!(matches!(states.last(), Some(DialogState::Cancelled(_, _))));
+
+    let (cancel, ringing, invite_via) = cancel.lock().unwrap().clone().expect("CANCEL received");
+    // held back until the 1xx
+    assert!(ringing);
+    assert_eq!(Some(top_via(&cancel)), invite_via);
+    assert_eq!(cancel.cseq_header()?.value(), "1 CANCEL");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cancel_answered_with_bye() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+
+    // a UAS that answers right away without any 1xx
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let peer_uri = rsip::Uri::try_from(format!("sip:bob@{}", peer.get_addr().addr))?;
+    let methods = std::sync::Mutex::new(vec![]);
+    let peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                while let Some(event) = receiver.recv().await {
+                    let (req, connection, from) = match event {
+                        TransportEvent::Incoming(SipMessage::Request(req), connection, from) => {
+                            (req, connection, from)
+                        }
+                        _ => continue,
+                    };
+                    methods.lock().unwrap().push(req.method);
+                    if req.method == rsip::Method::Ack {
+                        continue;
+                    }
+                    let contact = rsip::headers::Contact::new(format!("<{}>", peer_uri));
+                    let resp = make_response(&req, StatusCode::OK, vec![contact.into()]);
+                    connection.send(resp.into(), Some(&from)).await.expect("send response");
+                }
+            } => {}
+            _ = peer.serve_loop(sender) => {}
+        }
+    };
+
+    let (resp, states) = select! {
+        r = invite_and_cancel(&dialog_layer, &peer_uri) => r?,
+        _ = peer_loop => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(resp.map(|r| r.status_code), Some(StatusCode::OK));
+    // the 2xx won the race, the call was answered and hung up
+    assert!(states.iter().any(|s| s.is_confirmed()));
+    assert!(!states
+        .iter()
+        .any(|s| matches!(s, DialogState::Cancelled(_, _))));
+    assert!(matches!(
+        states.last(),
+        Some(DialogState::Terminated(_, _, _))
+    ));
+    assert_eq!(
+        *methods.lock().unwrap(),
+        vec![rsip::Method::Invite, rsip::Method::Ack, rsip::Method::Bye]
+    );
+    Ok(())
+}
+
+fn to_tag(headers: &rsip::Headers) -> Option<String> {
+    let to = headers.iter().find_map(|h| match h {
+        Header::To(to) => Some(to.clone()),
+        _ => None,
+    })?;
+    to.tag().ok()?.map(|tag| tag.value().to_string())
+}
+
+#[tokio::test]
+async fn test_forked_invite() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+
+    // a proxy forking to two UAS, both ring then both answer
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let peer_uri = rsip::Uri::try_from(format!("sip:bob@{}", peer.get_addr().addr))?;
+    let requests = std::sync::Mutex::new(vec![]);
+    let bye_received = tokio::sync::Notify::new();
+    let peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                while let Some(event) = receiver.recv().await {
+                    let (req, connection, from) = match event {
+                        TransportEvent::Incoming(SipMessage::Request(req), connection, from) => {
+                            (req, connection, from)
+                        }
+                        _ => continue,
+                    };
+                    let fork_response = |status_code, tag: &str| {
+                        let contact = rsip::headers::Contact::new(format!("<{}>", peer_uri));
+                        let mut resp = make_response(&req, status_code, vec![contact.into()]);
+                        let to = format!("{};tag={}", req.to_header().expect("to header").value(), tag);
+                        resp.headers.unique_push(rsip::headers::To::new(to).into());
+                        resp
+                    };
+                    match req.method {
+                        rsip::Method::Invite => {
+                            for (status_code, tag) in [
+                                (StatusCode::Ringing, "fork-a"),
+                                (StatusCode::Ringing, "fork-b"),
+                                (StatusCode::OK, "fork-a"),
+                                (StatusCode::OK, "fork-b"),
+                            ] {
+                                let resp = fork_response(status_code, tag);
+                                connection.send(resp.into(), Some(&from)).await.expect("send response");
+                                sleep(Duration::from_millis(20)).await;
+                            }
+                        }
+                        rsip::Method::Ack | rsip::Method::Bye => {
+                            requests.lock().unwrap().push((req.method, to_tag(&req.headers)));
+                            if req.method == rsip::Method::Bye {
+                                let resp = make_response(&req, StatusCode::OK, vec![]);
+                                connection.send(resp.into(), Some(&from)).await.expect("send response");
+                                bye_received.notify_one();
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            } => {}
+            _ = peer.serve_loop(sender) => {}
+        }
+    };
+
+    let (state_sender, mut state_receiver) = unbounded_channel();
+    let client_loop = async {
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            callee: peer_uri.clone(),
+            content_type: None,
+            offer: None,
+            contact: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (dialog, resp) = dialog_layer.do_invite(opt, state_sender).await?;
+        assert_eq!(resp.map(|r| r.status_code), Some(StatusCode::OK));
+        assert_eq!(dialog.id().to_tag, "fork-a");
+        assert!(dialog.early_dialogs().is_empty());
+        bye_received.notified().await;
+        Result::Ok(())
+    };
+
+    select! {
+        r = client_loop => r?,
+        _ = peer_loop => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    }
+
+    let mut early_tags = vec![];
+    while let Ok(state) = state_receiver.try_recv() {
+        if let DialogState::Early(id, _) = state {
+            early_tags.push(id.to_tag);
+        }
+    }
+    assert_eq!(early_tags, vec!["fork-a", "fork-b"]);
+    // the late branch is acknowledged and hung up
+    assert_eq!(
+        *requests.lock().unwrap(),
+        vec![
+            (rsip::Method::Ack, Some("fork-a".to_string())),
+            (rsip::Method::Ack, Some("fork-b".to_string())),
+            (rsip::Method::Bye, Some("fork-b".to_string())),
+        ]
+    );
+    assert_eq!(dialog_layer.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bye_to_contact() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+
+    // the UAS answers with the Contact of another host
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let peer_uri = rsip::Uri::try_from(format!("sip:bob@{}", peer.get_addr().addr))?;
+    let target = UdpConnection::create_connection("127.0.0.2:0".parse()?, None).await?;
+    let target_uri = rsip::Uri::try_from(format!("sip:bob@{}", target.get_addr().addr))?;
+    let peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                while let Some(event) = receiver.recv().await {
+                    if let TransportEvent::Incoming(SipMessage::Request(req), connection, from) = event {
+                        if req.method == rsip::Method::Invite {
+                            let contact = rsip::headers::Contact::new(format!("<{}>", target_uri));
+                            let resp = make_response(&req, StatusCode::OK, vec![contact.into()]);
+                            connection.send(resp.into(), Some(&from)).await.expect("send response");
+                        }
+                    }
+                }
+            } => {}
+            _ = peer.serve_loop(sender) => {}
+        }
+    };
+    let target_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            r = async {
+                while let Some(event) = receiver.recv().await {
+                    if let TransportEvent::Incoming(SipMessage::Request(req), connection, from) = event {
+                        if req.method == rsip::Method::Bye {
+                            let resp = make_response(&req, StatusCode::OK, vec![]);
+                            connection.send(resp.into(), Some(&from)).await.expect("send response");
+                            return req;
+                        }
+                    }
+                }
+                panic!("must not reach here");
+            } => r,
+            _ = target.serve_loop(sender) => panic!("must not reach here"),
+        }
+    };
+
+    let (state_sender, _state_receiver) = unbounded_channel();
+    let client_loop = async {
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            callee: peer_uri.clone(),
+            content_type: None,
+            offer: None,
+            contact: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (dialog, resp) = dialog_layer.do_invite(opt, state_sender).await?;
+        assert_eq!(resp.map(|r| r.status_code), Some(StatusCode::OK));
+        dialog.bye().await?;
+        Result::Ok(())
+    };
+
+    let (client, bye) = select! {
+        r = async { tokio::join!(client_loop, target_loop) } => r,
+        _ = peer_loop => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    client?;
+    assert_eq!(bye.uri, target_uri);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delayed_offer_answer_in_ack() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+
+    // the INVITE has no offer, the UAS makes one in the 2xx
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let peer_uri = rsip::Uri::try_from(format!("sip:bob@{}", peer.get_addr().addr))?;
+    let peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            r = async {
+                while let Some(event) = receiver.recv().await {
+                    let (req, connection, from) = match event {
+                        TransportEvent::Incoming(SipMessage::Request(req), connection, from) => {
+                            (req, connection, from)
+                        }
+                        _ => continue,
+                    };
+                    match req.method {
+                        rsip::Method::Invite => {
+                            let contact = rsip::headers::Contact::new(format!("<{}>", peer_uri));
+                            let mut resp = make_response(
+                                &req,
+                                StatusCode::OK,
+                                vec![
+                                    contact.into(),
+                                    Header::ContentType("application/sdp".into()),
+                                ],
+                            );
+                            resp.body = b"v=0 offer\r\n".to_vec();
+                            connection.send(resp.into(), Some(&from)).await.expect("send response");
+                        }
+                        rsip::Method::Ack => return req,
+                        _ => {}
+                    }
+                }
+                panic!("must not reach here");
+            } => r,
+            _ = peer.serve_loop(sender) => panic!("must not reach here"),
+        }
+    };
+
+    let (state_sender, mut state_receiver) = unbounded_channel();
+    let answer_loop = async {
+        while let Some(state) = state_receiver.recv().await {
+            if let DialogState::WaitAck(id, resp) = state {
+                assert_eq!(resp.body, b"v=0 offer\r\n");
+                match dialog_layer.get_dialog(&id) {
+                    Some(Dialog::ClientInvite(dialog)) => {
+                        dialog.answer_ack(b"v=0 answer\r\n".to_vec())?
+                    }
+                    _ => panic!("client dialog not found"),
+                }
+            }
+        }
+        Result::Ok(())
+    };
+    let client_loop = async {
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            callee: peer_uri.clone(),
+            content_type: None,
+            offer: None,
+            contact: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (dialog, resp) = dialog_layer.do_invite(opt, state_sender).await?;
+        assert_eq!(resp.map(|r| r.status_code), Some(StatusCode::OK));
+        assert!(dialog.inner.is_confirmed());
+        Result::Ok(())
+    };
+
+    let (client, ack) = select! {
+        r = async { tokio::join!(client_loop, peer_loop) } => r,
+        _ = answer_loop => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    client?;
+    assert_eq!(ack.body, b"v=0 answer\r\n");
+    assert_eq!(
+        ack.headers.iter().find_map(|h| match h {
+            Header::ContentType(c) => Some(c.value().to_string()),
+            _ => None,
+        }),
+        Some("application/sdp".to_string())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ack_through_record_route() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+
+    // the proxy on the peer address records the route to a target on another host
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let peer_uri = rsip::Uri::try_from(format!("sip:bob@{}", peer.get_addr().addr))?;
+    let record_route = format!("<sip:{};lr>", peer.get_addr().addr);
+    let target_uri = rsip::Uri::try_from("sip:bob@127.0.0.2:5060")?;
+    let peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        let mut invite = None;
+        select! {
+            r = async {
+                while let Some(event) = receiver.recv().await {
+                    let (req, connection, from) = match event {
+                        TransportEvent::Incoming(SipMessage::Request(req), connection, from) => {
+                            (req, connection, from)
+                        }
+                        _ => continue,
+                    };
+                    match req.method {
+                        rsip::Method::Invite => {
+                            let contact = rsip::headers::Contact::new(format!("<{}>", target_uri));
+                            let resp = make_response(
+                                &req,
+                                StatusCode::OK,
+                                vec![
+                                    contact.into(),
+                                    rsip::headers::RecordRoute::new(record_route.clone()).into(),
+                                ],
+                            );
+                            connection.send(resp.into(), Some(&from)).await.expect("send response");
+                            invite = Some(req);
+                        }
+                        rsip::Method::Ack => return (invite.take().expect("invite"), req),
+                        _ => {}
+                    }
+                }
+                panic!("must not reach here");
+            } => r,
+            _ = peer.serve_loop(sender) => panic!("must not reach here"),
+        }
+    };
+
+    let (state_sender, _state_receiver) = unbounded_channel();
+    let client_loop = async {
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            callee: peer_uri.clone(),
+            content_type: None,
+            offer: None,
+            contact: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (_, resp) = dialog_layer.do_invite(opt, state_sender).await?;
+        assert_eq!(resp.map(|r| r.status_code), Some(StatusCode::OK));
+        Result::Ok(())
+    };
+
+    let (client, (invite, ack)) = select! {
+        r = async { tokio::join!(client_loop, peer_loop) } => r,
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    client?;
+    // the ACK of a 2xx is a new transaction to the remote target through the route set
+    assert_eq!(ack.uri, target_uri);
+    let routes = ack
+        .headers
+        .iter()
+        .filter_map(|h| match h {
+            Header::Route(route) => Some(route.value().to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(routes, vec![record_route]);
+    assert_eq!(ack.cseq_header()?.value(), "1 ACK");
+    assert_ne!(top_via(&ack), top_via(&invite));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_forwarded_and_queued() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let peer_uri = rsip::Uri::try_from(format!("sip:bob@{}", peer.get_addr().addr))?;
+    let peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                while let Some(event) = receiver.recv().await {
+                    let (req, connection, from) = match event {
+                        TransportEvent::Incoming(SipMessage::Request(req), connection, from)
+                            if req.method == rsip::Method::Invite => (req, connection, from),
+                        _ => continue,
+                    };
+                    let contact = rsip::headers::Contact::new(format!("<{}>", peer_uri));
+                    for status in [StatusCode::CallIsBeingForwarded, StatusCode::Queued, StatusCode::OK] {
+                        let resp = make_response(&req, status, vec![contact.clone().into()]);
+                        connection.send(resp.into(), Some(&from)).await.expect("send response");
+                    }
+                }
+            } => {}
+            _ = peer.serve_loop(sender) => {}
+        }
+    };
+
+    let (state_sender, mut state_receiver) = unbounded_channel();
+    let client_loop = async {
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            callee: peer_uri.clone(),
+            content_type: None,
+            offer: Some(b"v=0\r\n".to_vec()),
+            contact: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (_dialog, resp) = dialog_layer.do_invite(opt, state_sender).await?;
+        Result::Ok(resp)
+    };
+
+    let resp = select! {
+        r = client_loop => r?,
+        _ = peer_loop => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(resp.map(|r| r.status_code), Some(StatusCode::OK));
+
+    // both reach the TU as early states with their response
+    let mut early = vec![];
+    while let Ok(state) = state_receiver.try_recv() {
+        if let DialogState::Early(_, resp) = state {
+            early.push(resp.status_code);
+        }
+    }
+    assert_eq!(
+        early,
+        vec![StatusCode::CallIsBeingForwarded, StatusCode::Queued]
+    );
+    Ok(())
+}
+
+/// An INVITE kept ringing is cancelled on Timer C, each 180 restarts it
+#[tokio::test]
+async fn test_timer_c() -> Result<()> {
+    let endpoint = super::create_test_endpoint_with_option(EndpointOption {
+        timer_c: Some(Duration::from_millis(300)),
+        ..Default::default()
+    })
+    .await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let peer_uri = rsip::Uri::try_from(format!("sip:bob@{}", peer.get_addr().addr))?;
+    let started = tokio::time::Instant::now();
+    let peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                let mut invite = None;
+                while let Some(event) = receiver.recv().await {
+                    let (req, connection, from) = match event {
+                        TransportEvent::Incoming(SipMessage::Request(req), connection, from) => {
+                            (req, connection, from)
+                        }
+                        _ => continue,
+                    };
+                    match req.method {
+                        rsip::Method::Invite => {
+                            let ringing = make_response(&req, StatusCode::Ringing, vec![]);
+                            connection.send(ringing.clone().into(), Some(&from)).await.expect("send 180");
+                            sleep(Duration::from_millis(200)).await;
+                            connection.send(ringing.into(), Some(&from)).await.expect("send 180");
+                            invite = Some(req);
+                        }
+                        rsip::Method::Cancel => {
+                            // the second 180 restarted the timer
+                            assert!(started.elapsed() >= Duration::from_millis(450));
+                            let ok = make_response(&req, StatusCode::OK, vec![]);
+                            connection.send(ok.into(), Some(&from)).await.expect("send 200");
+                            let invite = invite.as_ref().expect("invite before cancel");
+                            let terminated = make_response(invite, StatusCode::RequestTerminated, vec![]);
+                            connection.send(terminated.into(), Some(&from)).await.expect("send 487");
+                        }
+                        _ => {}
+                    }
+                }
+            } => {}
+            _ = peer.serve_loop(sender) => {}
+        }
+    };
+
+    let (state_sender, mut state_receiver) = unbounded_channel();
+    let client_loop = async {
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            callee: peer_uri.clone(),
+            content_type: None,
+            offer: Some(b"v=0\r\n".to_vec()),
+            contact: rsip::Uri::try_from("sip:alice@127.0.0.1")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (_dialog, resp) = dialog_layer.do_invite(opt, state_sender).await?;
+        Result::Ok(resp)
+    };
+
+    let resp = select! {
+        r = client_loop => r?,
+        _ = peer_loop => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(
+        resp.map(|r| r.status_code),
+        Some(StatusCode::RequestTerminated)
+    );
+    let mut last = None;
+    while let Ok(state) = state_receiver.try_recv() {
+        last = Some(state);
+    }
+    assert!(matches!(
+        last,
+        Some(DialogState::Terminated(
+            _,
+            Some(StatusCode::RequestTimeout),
+            _
+        ))
+    ));
+    Ok(())
+}
+
+/// With a redirect policy the INVITE follows the Contacts of a 3xx by q
+/// value, skipping the targets already tried
+#[tokio::test(start_paused = true)]
+async fn test_follow_redirects() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    alice
+        .inner
+        .set_redirect_policy(Some(RedirectPolicy::default()));
+    let dialog_layer = DialogLayer::new(alice.inner.clone());
+
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        let mut uris = vec![];
+        while let Some(mut tx) = incoming.recv().await {
+            if tx.original.method != rsip::Method::Invite {
+                continue;
+            }
+            let user = tx.original.uri.user().unwrap_or_default().to_string();
+            uris.push(user.clone());
+            let (status, contacts) = match user.as_str() {
+                "bob" => (
+                    StatusCode::MovedTemporarily,
+                    "<sip:carol@192.0.2.2:5060>;q=0.5, <sip:dave@192.0.2.2:5060>;q=0.9",
+                ),
+                // back to a target already tried
+                "dave" => (StatusCode::MovedTemporarily, "<sip:bob@192.0.2.2:5060>"),
+                _ => (StatusCode::TemporarilyUnavailable, ""),
+            };
+            let headers = match contacts.is_empty() {
+                true => vec![],
+                false => vec![Header::Contact(contacts.into())],
+            };
+            tx.reply_with(status, headers, None).await?;
+            if uris.len() == 3 {
+                return Result::Ok(uris);
+            }
+        }
+        Result::Ok(uris)
+    };
+
+    let (state_sender, mut states) = unbounded_channel();
+    let client_loop = async {
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            content_type: None,
+            offer: Some(b"v=0\r\n".to_vec()),
+            contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (_, resp) = dialog_layer.do_invite(opt, state_sender).await?;
+        Result::Ok(resp)
+    };
+
+    let (uris, resp) = select! {
+        r = async { tokio::try_join!(bob_loop, client_loop) } => r?,
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(uris, vec!["bob", "dave", "carol"]);
+    assert_eq!(
+        resp.map(|r| r.status_code),
+        Some(StatusCode::TemporarilyUnavailable)
+    );
+    let mut last = None;
+    while let Ok(state) = states.try_recv() {
+        last = Some(state);
+    }
+    assert!(matches!(
+        last,
+        Some(DialogState::Terminated(
+            _,
+            Some(StatusCode::TemporarilyUnavailable),
+            _
+        ))
+    ));
+    Ok(())
+}
+
+/// The Contact of a re-INVITE 2xx refreshes the remote target, its
+/// Record-Route leaves the route set alone
+#[tokio::test(start_paused = true)]
+async fn test_reinvite_target_refresh() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let dialog_layer = DialogLayer::new(alice.inner.clone());
+
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(mut tx) = incoming.recv().await {
+            let reinvite = tx.original.to_header()?.tag()?.is_some();
+            let (contact, record_route) = match (&tx.original.method, reinvite) {
+                (rsip::Method::Invite, false) => {
+                    ("<sip:bob@192.0.2.2:5060>", "<sip:192.0.2.2:5060;lr>")
+                }
+                (rsip::Method::Invite, true) => {
+                    ("<sip:bob-moved@192.0.2.2:5060>", "<sip:192.0.2.9;lr>")
+                }
+                (rsip::Method::Bye, _) => {
+                    tx.reply(StatusCode::OK).await?;
+                    return Result::Ok(tx.original.clone());
+                }
+                _ => continue,
+            };
+            let headers = vec![
+                Header::Contact(contact.into()),
+                Header::RecordRoute(record_route.into()),
+            ];
+            tx.reply_with(StatusCode::OK, headers, None).await?;
+        }
+        panic!("no bye");
+    };
+
+    let (state_sender, _states) = unbounded_channel();
+    let client_loop = async {
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            content_type: None,
+            offer: Some(b"v=0\r\n".to_vec()),
+            contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (dialog, _) = dialog_layer.do_invite(opt, state_sender).await?;
+        let dialog = Dialog::ClientInvite(dialog);
+        let before = (dialog.remote_target(), dialog.route_set());
+        if let Dialog::ClientInvite(client) = &dialog {
+            client.reinvite(Some(b"v=0\r\n".to_vec()), None).await?;
+            client.bye().await?;
+        }
+        let after = (dialog.remote_target(), dialog.route_set());
+        Result::Ok((before, after))
+    };
+
+    let (bye, (before, after)) = select! {
+        r = async { tokio::try_join!(bob_loop, client_loop) } => r?,
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(before.0.to_string(), "sip:bob@192.0.2.2:5060");
+    assert_eq!(after.0.to_string(), "sip:bob-moved@192.0.2.2:5060");
+    assert_eq!(before.1, after.1);
+    assert_eq!(after.1.len(), 1);
+    assert_eq!(after.1[0].uri.to_string(), "sip:192.0.2.2:5060;lr");
+    assert_eq!(bye.uri.to_string(), "sip:bob-moved@192.0.2.2:5060");
+    assert_eq!(
+        bye.route_header().map(|r| r.value().to_string()),
+        Some("<sip:192.0.2.2:5060;lr>".to_string())
+    );
+    Ok(())
+}
+
+/// Any provisional response to a re-INVITE is skipped, its final one returned
+#[tokio::test(start_paused = true)]
+async fn test_reinvite_provisional() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let dialog_layer = DialogLayer::new(alice.inner.clone());
+
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(mut tx) = incoming.recv().await {
+            let reinvite = tx.original.to_header()?.tag()?.is_some();
+            match (&tx.original.method, reinvite) {
+                (rsip::Method::Invite, true) => {
+                    for status in [
+                        StatusCode::CallIsBeingForwarded,
+                        StatusCode::Other(199, "Early Dialog Terminated".into()),
+                    ] {
+                        tx.reply(status).await?;
+                    }
+                    let headers = vec![Header::Contact("<sip:bob@192.0.2.2:5060>".into())];
+                    tx.reply_with(StatusCode::OK, headers, None).await?;
+                }
+                (rsip::Method::Invite, false) => {
+                    let headers = vec![Header::Contact("<sip:bob@192.0.2.2:5060>".into())];
+                    tx.reply_with(StatusCode::OK, headers, None).await?;
+                }
+                _ => {}
+            }
+        }
+        Result::Ok(())
+    };
+
+    let (state_sender, _states) = unbounded_channel();
+    let client_loop = async {
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            content_type: None,
+            offer: Some(b"v=0\r\n".to_vec()),
+            contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (dialog, _) = dialog_layer.do_invite(opt, state_sender).await?;
+        let resp = dialog.reinvite(Some(b"v=0\r\n".to_vec()), None).await?;
+        Result::Ok(resp.map(|r| r.status_code))
+    };
+
+    let status = select! {
+        r = client_loop => r?,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(status, Some(StatusCode::OK));
+    Ok(())
+}
+
+/// The in-dialog requests of a terminated dialog are refused
+#[tokio::test(start_paused = true)]
+async fn test_requests_after_bye() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let dialog_layer = DialogLayer::new(alice.inner.clone());
+
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(mut tx) = incoming.recv().await {
+            match tx.original.method {
+                rsip::Method::Invite => {
+                    let headers = vec![Header::Contact("<sip:bob@192.0.2.2:5060>".into())];
+                    tx.reply_with(StatusCode::OK, headers, None).await?;
+                }
+                rsip::Method::Bye => tx.reply(StatusCode::OK).await?,
+                rsip::Method::Ack => {}
+                _ => panic!("{} after the BYE", tx.original.method),
+            }
+        }
+        Result::Ok(())
+    };
+
+    let (state_sender, _states) = unbounded_channel();
+    let client_loop = async {
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            content_type: None,
+            offer: Some(b"v=0\r\n".to_vec()),
+            contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (dialog, _) = dialog_layer.do_invite(opt, state_sender).await?;
+        dialog.bye().await?;
+        let not_confirmed = crate::Error::DialogNotConfirmed(dialog.id());
+        assert_eq!(
+            dialog.update(Some(b"v=0\r\n".to_vec()), None).await,
+            Err(not_confirmed.clone())
+        );
+        let target = rsip::Uri::try_from("sip:carol@192.0.2.3:5060")?;
+        assert_eq!(dialog.refer(target, None).await, Err(not_confirmed.clone()));
+        assert_eq!(
+            dialog
+                .message("text/plain".to_string(), b"hi".to_vec())
+                .await,
+            Err(not_confirmed.clone())
+        );
+        assert_eq!(dialog.info().await, Err(not_confirmed));
+        Result::Ok(())
+    };
+
+    select! {
+        r = client_loop => r?,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    Ok(())
+}
+
+/// A 491 to a re-INVITE is an error of its own, leaving the dialog confirmed,
+/// and a re-INVITE after the BYE is refused
+#[tokio::test(start_paused = true)]
+async fn test_reinvite_request_pending() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let dialog_layer = DialogLayer::new(alice.inner.clone());
+
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(mut tx) = incoming.recv().await {
+            let reinvite = tx.original.to_header()?.tag()?.is_some();
+            match (&tx.original.method, reinvite) {
+                (rsip::Method::Invite, false) => {
+                    let headers = vec![Header::Contact("<sip:bob@192.0.2.2:5060>".into())];
+                    tx.reply_with(StatusCode::OK, headers, None).await?;
+                }
+                (rsip::Method::Invite, true) => tx.reply(StatusCode::RequestPending).await?,
+                (rsip::Method::Bye, _) => tx.reply(StatusCode::OK).await?,
+                _ => {}
+            }
+        }
+        Result::Ok(())
+    };
+
+    let (state_sender, _states) = unbounded_channel();
+    let client_loop = async {
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            content_type: None,
+            offer: Some(b"v=0\r\n".to_vec()),
+            contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (dialog, _) = dialog_layer.do_invite(opt, state_sender).await?;
+        let pending = dialog.reinvite(Some(b"v=0\r\n".to_vec()), None).await;
+        let confirmed = dialog.inner.is_confirmed();
+        dialog.bye().await?;
+        let after_bye = dialog.reinvite(Some(b"v=0\r\n".to_vec()), None).await;
+        Result::Ok((dialog.id(), pending, confirmed, after_bye))
+    };
+
+    let (id, pending, confirmed, after_bye) = select! {
+        r = client_loop => r?,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(pending, Err(crate::Error::RequestPending(id)));
+    assert!(confirmed);
+    assert!(matches!(
+        after_bye,
+        Err(crate::Error::DialogNotConfirmed(_))
+    ));
+    Ok(())
+}
+
+fn session_timer_option() -> Result<InviteOption> {
+    Ok(InviteOption {
+        caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+        callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+        content_type: None,
+        offer: Some(b"v=0\r\n".to_vec()),
+        contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+        credential: None,
+        session_timer: Some(SessionTimerConfig {
+            session_expires: 90,
+            min_se: 90,
+        }),
+        replaces: None,
+        call_id: None,
+        from_tag: None,
+        routes: vec![],
+    })
+}
+
+fn session_expires(refresher: Refresher) -> Header {
+    SessionTimer {
+        interval: 90,
+        refresher,
+    }
+    .header()
+}
+
+/// The re-INVITEs of a UAS refreshing the session are answered and keep the
+/// call up, the BYE goes out `interval - min(32, interval / 3)` after the last
+#[tokio::test(start_paused = true)]
+async fn test_session_refreshed_by_uas_reinvite() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let alice_layer = DialogLayer::new(alice.inner.clone());
+    let handler = IncomingHandler {
+        state_sender: unbounded_channel().0,
+        credential: None,
+        contact: None,
+        invite_sender: unbounded_channel().0,
+        request_sender: unbounded_channel().0,
+    };
+    let alice_loop = async {
+        let mut incoming = alice.incoming_transactions();
+        while let Some(tx) = incoming.recv().await {
+            alice_layer.handle_incoming(tx, &handler).await?;
+        }
+        Result::Ok(())
+    };
+
+    let started = tokio::time::Instant::now();
+    let (dialog_sender, mut dialog_receiver) = unbounded_channel();
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(mut tx) = incoming.recv().await {
+            match tx.original.method {
+                rsip::Method::Invite => {
+                    let headers = vec![
+                        Header::Contact("<sip:bob@192.0.2.2:5060>".into()),
+                        Header::Require("timer".into()),
+                        session_expires(Refresher::Uas),
+                    ];
+                    tx.reply_with(StatusCode::OK, headers, None).await?;
+                    let to = tx.last_response.as_ref().expect("2xx").to_header()?.clone();
+                    dialog_sender.send((tx.original.clone(), to)).ok();
+                }
+                rsip::Method::Bye => {
+                    tx.reply(StatusCode::OK).await?;
+                    return Result::Ok(started.elapsed().as_secs());
+                }
+                _ => {}
+            }
+        }
+        panic!("no bye");
+    };
+    // bob refreshes three times at half the interval, then stops
+    let bob_refresh = async {
+        let (invite, to) = dialog_receiver.recv().await.expect("invite");
+        let mut answers = vec![];
+        for cseq in 1..=3 {
+            sleep(Duration::from_secs(45)).await;
+            let mut request = bob
+                .request_builder(
+                    rsip::Method::Invite,
+                    rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+                )
+                .header(session_expires(Refresher::Uas))
+                .body("application/sdp", b"v=0\r\n".to_vec())
+                .build()?;
+            request
+                .headers
+                .unique_push(Header::From(to.value().to_string().into()));
+            request
+                .headers
+                .unique_push(Header::To(invite.from_header()?.value().to_string().into()));
+            request
+                .headers
+                .unique_push(Header::CallId(invite.call_id_header()?.clone()));
+            request
+                .headers
+                .unique_push(Header::CSeq(format!("{} INVITE", cseq).into()));
+            let key = TransactionKey::from_request(&request, TransactionRole::Client)?;
+            let mut tx = Transaction::new_client(key, request, bob.inner.clone(), None);
+            tx.send().await?;
+            let resp = loop {
+                match tx.receive().await {
+                    Some(SipMessage::Response(resp))
+                        if resp.status_code.kind() != rsip::StatusCodeKind::Provisional =>
+                    {
+                        break resp
+                    }
+                    Some(_) => continue,
+                    None => panic!("re-INVITE not answered"),
+                }
+            };
+            let mut ack = tx.original.clone();
+            ack.method = rsip::Method::Ack;
+            ack.body = vec![];
+            ack.headers.retain(|h| !matches!(h, Header::ContentType(_)));
+            ack.headers
+                .unique_push(Header::Via(bob.inner.get_via(None)?.into()));
+            ack.headers
+                .unique_push(Header::CSeq(format!("{} ACK", cseq).into()));
+            ack.headers
+                .unique_push(Header::To(resp.to_header()?.clone()));
+            ack.headers.unique_push(Header::ContentLength(0.into()));
+            tx.send_ack(ack).await?;
+            answers.push(resp);
+        }
+        Result::Ok(answers)
+    };
+
+    let (state_sender, mut states) = unbounded_channel();
+    let alice_call = async {
+        let (_, resp) = alice_layer
+            .do_invite(session_timer_option()?, state_sender)
+            .await?;
+        Result::Ok(resp.map(|r| r.status_code))
+    };
+
+    let (status, answers, bye_at) = select! {
+        r = async { tokio::try_join!(alice_call, bob_refresh, bob_loop) } => r?,
+        _ = alice_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(600)) => panic!("timeout waiting"),
+    };
+    assert_eq!(status, Some(StatusCode::OK));
+    assert_eq!(answers.len(), 3);
+    for resp in &answers {
+        assert_eq!(resp.status_code, StatusCode::OK);
+        assert_eq!(
+            parse_session_expires(&resp.headers),
+            Some((90, Some(Refresher::Uas)))
+        );
+        assert_eq!(resp.body, b"v=0\r\n");
+    }
+    // the last refresh at 135s, the session ends 60s later
+    assert_eq!(bye_at, 195);
+    let mut updated = 0;
+    while let Ok(state) = states.try_recv() {
+        if matches!(state, DialogState::Updated(_, _)) {
+            updated += 1;
+        }
+    }
+    assert_eq!(updated, 3);
+    Ok(())
+}
+
+// the in-dialog requests of alice, the refresher, in the first 100s with the
+// seconds they came at, bob's 2xx to the INVITE lists `allow`
+async fn session_refreshes(allow: Option<&str>) -> Result<Vec<(u64, rsip::Method)>> {
+    let (alice, bob) = Endpoint::test_pair();
+    let alice_layer = DialogLayer::new(alice.inner.clone());
+    let started = tokio::time::Instant::now();
+    let mut refreshes = vec![];
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(mut tx) = incoming.recv().await {
+            let in_dialog = tx.original.to_header()?.tag()?.is_some();
+            match (&tx.original.method, in_dialog) {
+                (rsip::Method::Invite, false) => {
+                    let mut headers = vec![
+                        Header::Contact("<sip:bob@192.0.2.2:5060>".into()),
+                        session_expires(Refresher::Uac),
+                    ];
+                    if let Some(allow) = allow {
+                        headers.push(Header::Allow(allow.into()));
+                    }
+                    tx.reply_with(StatusCode::OK, headers, None).await?;
+                }
+                (rsip::Method::Invite, true) | (rsip::Method::Update, _) => {
+                    refreshes.push((started.elapsed().as_secs(), tx.original.method));
+                    let headers = vec![session_expires(Refresher::Uac)];
+                    tx.reply_with(StatusCode::OK, headers, None).await?;
+                }
+                _ => {}
+            }
+        }
+        Result::Ok(())
+    };
+    let (state_sender, _states) = unbounded_channel();
+    let alice_call = async {
+        let (_, resp) = alice_layer
+            .do_invite(session_timer_option()?, state_sender)
+            .await?;
+        assert_eq!(resp.map(|r| r.status_code), Some(StatusCode::OK));
+        sleep(Duration::from_secs(100)).await;
+        Result::Ok(())
+    };
+    select! {
+        r = alice_call => r?,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+    };
+    Ok(refreshes)
+}
+
+/// The refresher sends UPDATE to a peer that allows it, else re-INVITE
+#[tokio::test(start_paused = true)]
+async fn test_session_refresh_method() -> Result<()> {
+    assert_eq!(
+        session_refreshes(Some("INVITE, ACK, BYE, CANCEL, UPDATE")).await?,
+        vec![(45, rsip::Method::Update), (90, rsip::Method::Update)]
+    );
+    assert_eq!(
+        session_refreshes(None).await?,
+        vec![(45, rsip::Method::Invite), (90, rsip::Method::Invite)]
+    );
+    Ok(())
+}
+
+// ping a call `pings` times with bob answering the OPTIONS with `status`, or
+// not at all when it is `None`, returns for each ping whether it was answered
+// and the status the dialog had ended with by then
+async fn ping_dead_peer(
+    status: Option<StatusCode>,
+    pings: usize,
+) -> Result<Vec<(bool, Option<Option<StatusCode>>)>> {
+    let (alice, bob) = Endpoint::test_pair();
+    let dialog_layer = DialogLayer::new(alice.inner.clone());
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(mut tx) = incoming.recv().await {
+            match (&tx.original.method, &status) {
+                (rsip::Method::Invite, _) => {
+                    let headers = vec![Header::Contact("<sip:bob@192.0.2.2:5060>".into())];
+                    tx.reply_with(StatusCode::OK, headers, None).await?;
+                }
+                (rsip::Method::Options, Some(status)) => tx.reply(status.clone()).await?,
+                _ => {}
+            }
+        }
+        Result::Ok(())
+    };
+    let (state_sender, mut states) = unbounded_channel();
+    let client_loop = async {
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            content_type: None,
+            offer: Some(b"v=0\r\n".to_vec()),
+            contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (dialog, _) = dialog_layer.do_invite(opt, state_sender).await?;
+        let dialog = Dialog::ClientInvite(dialog);
+        let mut results = vec![];
+        let mut terminated = None;
+        for _ in 0..pings {
+            let ping = dialog.options_ping().await?;
+            while let Ok(state) = states.try_recv() {
+                if let DialogState::Terminated(_, status, _) = state {
+                    terminated = Some(status);
+                }
+            }
+            results.push((ping.is_some(), terminated.clone()));
+        }
+        Result::Ok(results)
+    };
+    select! {
+        r = client_loop => r,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(300)) => panic!("timeout waiting"),
+    }
+}
+
+/// A ping answered 481 ends the dialog at once
+#[tokio::test(start_paused = true)]
+async fn test_ping_dead_peer() -> Result<()> {
+    assert_eq!(
+        ping_dead_peer(Some(StatusCode::CallTransactionDoesNotExist), 1).await?,
+        vec![(false, Some(Some(StatusCode::CallTransactionDoesNotExist)))]
+    );
+    // any other answer shows the peer alive
+    assert_eq!(
+        ping_dead_peer(Some(StatusCode::NotImplemented), 1).await?,
+        vec![(true, None)]
+    );
+    Ok(())
+}
+
+/// Only the third consecutive ping timing out ends the dialog, with a 408
+#[tokio::test(start_paused = true)]
+async fn test_ping_timeouts() -> Result<()> {
+    assert_eq!(
+        ping_dead_peer(None, 3).await?,
+        vec![
+            (false, None),
+            (false, None),
+            (false, Some(Some(StatusCode::RequestTimeout))),
+        ]
+    );
+    // a peer answering 408 times out the same
+    assert_eq!(
+        ping_dead_peer(Some(StatusCode::RequestTimeout), 3).await?,
+        vec![
+            (false, None),
+            (false, None),
+            (false, Some(Some(StatusCode::RequestTimeout))),
+        ]
+    );
+    Ok(())
+}
@@ -0,0 +1,1612 @@
+use crate::dialog::{
+    client_dialog::ClientInviteDialog,
+    dialog::{Dialog, DialogInner, DialogState, DialogStateSender, DialogStateStream, MAX_CSEQ},
+    dialog_layer::{DialogLayer, IncomingHandler},
+    invitation::InviteOption,
+    registration::Registration,
+    server_dialog::ServerInviteDialog,
+    DialogId,
+};
+use crate::rsip_ext::Replaces;
+use crate::transaction::{
+    endpoint::{Endpoint, EndpointBuilder, EndpointOption, ShutdownSummary},
+    key::TransactionRole,
+};
+use crate::transport::{udp::UdpConnection, TransportEvent};
+use crate::Result;
+use futures::StreamExt;
+use rsip::{
+    prelude::{HeadersExt, UntypedHeader},
+    Header, Request, SipMessage,
+};
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tokio::{select, sync::mpsc::unbounded_channel, time::sleep};
+
+fn make_invite() -> Result<Request> {
+    let invite = "INVITE sip:bob@127.0.0.1:5060 SIP/2.0\r\n\
+         Via: SIP/2.0/UDP 127.0.0.1:5070;branch=z9hG4bKlayer\r\n\
+         From: <sip:alice@127.0.0.1>;tag=uac-tag\r\n\
+         To: <sip:bob@127.0.0.1>;tag=uas-tag\r\n\
+         Call-ID: dialog-layer-test\r\n\
+         CSeq: 1 INVITE\r\n\
+         Contact: <sip:alice@127.0.0.1:5070>\r\n\
+         Max-Forwards: 70\r\n\
+         Content-Length: 0\r\n\r\n";
+    match SipMessage::try_from(invite)? {
+        SipMessage::Request(req) => Ok(req),
+        _ => panic!("not a request"),
+    }
+}
+
+fn make_dialog(
+    endpoint: &Endpoint,
+    invite: &Request,
+    state_sender: DialogStateSender,
+) -> Result<Arc<DialogInner>> {
+    let inner = DialogInner::new(
+        TransactionRole::Server,
+        DialogId::try_from(invite)?,
+        invite.clone(),
+        endpoint.inner.clone(),
+        state_sender,
+        None,
+        None,
+    )?;
+    Ok(Arc::new(inner))
+}
+
+#[tokio::test]
+async fn test_dialog_state_subscribers() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+    let mut logger = dialog_layer.subscribe();
+    let mut metrics = dialog_layer.subscribe();
+
+    let invite = make_invite()?;
+    let id = DialogId::try_from(&invite)?;
+    let (state_sender, _state_receiver) = unbounded_channel();
+    let inner = make_dialog(&endpoint, &invite, state_sender)?;
+    dialog_layer.insert_dialog(Dialog::ServerInvite(ServerInviteDialog {
+        inner: inner.clone(),
+    }));
+
+    inner.transition(DialogState::Confirmed(id.clone()))?;
+    assert!(matches!(
+        logger.recv().await,
+        Some(DialogState::Confirmed(_))
+    ));
+    assert!(matches!(
+        metrics.recv().await,
+        Some(DialogState::Confirmed(_))
+    ));
+
+    // more states than a subscriber buffers, the oldest are skipped
+    for _ in 0..1000 {
+        inner.transition(DialogState::Info(id.clone(), invite.clone()))?;
+    }
+    inner.transition(DialogState::Terminated(id.clone(), None, None))?;
+    for subscriber in [&mut logger, &mut metrics] {
+        let mut infos = 0;
+        loop {
+            match subscriber.recv().await {
+                Some(DialogState::Info(_, _)) => infos += 1,
+                Some(DialogState::Terminated(terminated, None, _)) => {
+                    assert_eq!(terminated, id);
+                    break;
+                }
+                _ => panic!("unexpected state"),
+            }
+        }
+        assert!(infos > 0 && infos < 1000);
+    }
+    assert!(dialog_layer.is_empty());
+
+    drop(dialog_layer);
+    assert!(logger.recv().await.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dialog_metrics() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let invite = make_invite()?;
+    let id = DialogId::try_from(&invite)?;
+    let (state_sender, _state_receiver) = unbounded_channel();
+
+    let answered = make_dialog(&endpoint, &invite, state_sender.clone())?;
+    let busy = make_dialog(&endpoint, &invite, state_sender.clone())?;
+    let unanswered = make_dialog(&endpoint, &invite, state_sender.clone())?;
+    let dropped = make_dialog(&endpoint, &invite, state_sender)?;
+    answered.transition(DialogState::Trying(id.clone()))?;
+    answered.transition(DialogState::Confirmed(id.clone()))?;
+    busy.transition(DialogState::Trying(id.clone()))?;
+
+    let metrics = endpoint.dialog_metrics();
+    assert_eq!(metrics.created, 4);
+    assert_eq!(
+        (metrics.calling, metrics.trying, metrics.confirmed),
+        (2, 1, 1)
+    );
+    assert_eq!(metrics.setup_time.count, 1);
+
+    answered.transition(DialogState::Terminated(id.clone(), None, None))?;
+    // the second Terminated of a dialog is not counted
+    answered.transition(DialogState::Terminated(id.clone(), None, None))?;
+    busy.transition(DialogState::Terminated(
+        id.clone(),
+        Some(rsip::StatusCode::BusyHere),
+        None,
+    ))?;
+    unanswered.transition(DialogState::Terminated(
+        id.clone(),
+        Some(rsip::StatusCode::RequestTimeout),
+        None,
+    ))?;
+    drop(dropped);
+
+    let metrics = endpoint.dialog_metrics();
+    assert_eq!(
+        (metrics.calling, metrics.trying, metrics.confirmed),
+        (0, 0, 0)
+    );
+    assert_eq!(metrics.terminated_normal, 1);
+    assert_eq!(metrics.terminated_timeout, 1);
+    assert_eq!(metrics.terminated_failures.get(&486), Some(&1));
+    assert_eq!(metrics.terminated_failures.len(), 1);
+    assert_eq!(metrics.duration.count, 1);
+    assert_eq!(metrics.duration.buckets[0], 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_endpoint_shutdown() -> Result<()> {
+    let endpoint = super::create_test_endpoint_with_option(EndpointOption {
+        t1: Duration::from_millis(8),
+        ..Default::default()
+    })
+    .await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+
+    // one remote party answers the BYE, the other never does
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let silent = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let (state_sender, _state_receiver) = unbounded_channel();
+    let mut dialogs = vec![];
+    for (call_id, remote) in [("answered", &peer), ("silent", &silent)] {
+        let mut invite = make_invite()?;
+        invite.headers.unique_push(Header::CallId(call_id.into()));
+        invite.headers.unique_push(Header::Contact(
+            format!("<sip:alice@{}>", remote.get_addr().addr).into(),
+        ));
+        let id = DialogId::try_from(&invite)?;
+        let inner = make_dialog(&endpoint, &invite, state_sender.clone())?;
+        dialog_layer.insert_dialog(Dialog::ServerInvite(ServerInviteDialog {
+            inner: inner.clone(),
+        }));
+        inner.transition(DialogState::Confirmed(id))?;
+        dialogs.push(inner);
+    }
+
+    let answered = dialogs[0].clone();
+    let peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                while let Some(event) = receiver.recv().await {
+                    if let TransportEvent::Incoming(SipMessage::Request(req), connection, from) = event {
+                        if req.method == rsip::Method::Bye {
+                            let resp = answered.make_response(&req, rsip::StatusCode::OK, None, None);
+                            connection.send(resp.into(), Some(&from)).await.expect("send response");
+                        }
+                    }
+                }
+            } => {}
+            _ = peer.serve_loop(sender) => {}
+        }
+    };
+
+    let (summary, _) = select! {
+        r = async { tokio::join!(endpoint.shutdown(), endpoint.serve()) } => r,
+        _ = peer_loop => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(
+        summary,
+        ShutdownSummary {
+            terminated: 1,
+            forced: 1
+        }
+    );
+    assert!(dialog_layer.is_empty());
+    assert_eq!(endpoint.shutdown().await, ShutdownSummary::default());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_in_dialog_cseq() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let invite = make_invite()?;
+    let (state_sender, _state_receiver) = unbounded_channel();
+    let inner = make_dialog(&endpoint, &invite, state_sender)?;
+    let seq = |req: rsip::Request| -> Result<u32> { Ok(req.cseq_header()?.seq()?) };
+
+    let info = seq(inner.make_request(rsip::Method::Info, None, None, None, None)?)?;
+    // the peer sends requests of its own in between
+    inner.remote_seq.store(20, Ordering::Relaxed);
+    let bye = seq(inner.make_request(rsip::Method::Bye, None, None, None, None)?)?;
+    assert_eq!(bye, info + 1);
+    assert_eq!(inner.get_local_seq(), bye);
+    assert_eq!(inner.get_remote_seq(), 20);
+
+    // an explicit CSeq, e.g. of an ACK, takes no number
+    let ack = seq(inner.make_request(rsip::Method::Ack, Some(info), None, None, None)?)?;
+    assert_eq!(ack, info);
+    assert_eq!(inner.get_local_seq(), bye);
+    assert_eq!(inner.get_remote_seq(), 20);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_remote_cseq_check() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let mut invite = make_invite()?;
+    let (state_sender, _state_receiver) = unbounded_channel();
+    let server = make_dialog(&endpoint, &invite, state_sender.clone())?;
+    // the requests after the INVITE may not go down
+    assert_eq!(server.check_remote_seq(1), Ok(()));
+    assert_eq!(server.check_remote_seq(2), Ok(()));
+    assert_eq!(
+        server.check_remote_seq(0),
+        Err(rsip::StatusCode::ServerInternalError)
+    );
+    // and never reach 2^31, a huge one can not lock the next ones out
+    assert_eq!(
+        server.check_remote_seq(MAX_CSEQ),
+        Err(rsip::StatusCode::BadRequest)
+    );
+    assert_eq!(
+        server.check_remote_seq(u32::MAX),
+        Err(rsip::StatusCode::BadRequest)
+    );
+
+    // the CSeq of our INVITE is no lower bound for the requests of the callee
+    invite.headers.unique_push(Header::CSeq(
+        rsip::typed::CSeq {
+            seq: MAX_CSEQ - 1,
+            method: rsip::Method::Invite,
+        }
+        .into(),
+    ));
+    let client = DialogInner::new(
+        TransactionRole::Client,
+        DialogId::try_from(&invite)?,
+        invite,
+        endpoint.inner.clone(),
+        state_sender,
+        None,
+        None,
+    )?;
+    assert_eq!(client.get_remote_seq(), 0);
+    assert_eq!(client.check_remote_seq(1), Ok(()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_remote_user_agent() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let mut invite = make_invite()?;
+    invite
+        .headers
+        .push(Header::UserAgent("softphone/1.0".into()));
+    let (state_sender, _state_receiver) = unbounded_channel();
+
+    // the UAS takes the User-Agent of the INVITE
+    let server = Dialog::ServerInvite(ServerInviteDialog {
+        inner: make_dialog(&endpoint, &invite, state_sender.clone())?,
+    });
+    assert_eq!(
+        server.remote_user_agent(),
+        Some("softphone/1.0".to_string())
+    );
+
+    // the UAC the Server of the 2xx, ahead of its User-Agent
+    let inner = Arc::new(DialogInner::new(
+        TransactionRole::Client,
+        DialogId::try_from(&invite)?,
+        invite.clone(),
+        endpoint.inner.clone(),
+        state_sender,
+        None,
+        None,
+    )?);
+    let client = Dialog::ClientInvite(ClientInviteDialog {
+        inner: inner.clone(),
+    });
+    assert_eq!(client.remote_user_agent(), None);
+    let resp = inner.make_response(
+        &invite,
+        rsip::StatusCode::OK,
+        Some(vec![Header::Server("pbx/2.0".into())]),
+        None,
+    );
+    inner.update_remote_user_agent(&resp);
+    assert_eq!(client.remote_user_agent(), Some("pbx/2.0".to_string()));
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_endpoint_pair_call() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let alice_layer = DialogLayer::new(alice.inner.clone());
+    let bob_layer = DialogLayer::new(bob.inner.clone());
+
+    let (state_sender, mut state_receiver) = unbounded_channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: Some(rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?),
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(tx) = incoming.recv().await {
+            bob_layer.handle_incoming(tx, &handler).await?;
+        }
+        Result::Ok(())
+    };
+    let accept_loop = async {
+        while let Some(dialog) = invite_receiver.recv().await {
+            dialog.accept(None, Some(b"v=0\r\n".to_vec()))?;
+        }
+        Result::Ok(())
+    };
+    let bob_states = async {
+        let mut states = vec![];
+        while let Some(state) = state_receiver.recv().await {
+            let terminated = matches!(state, DialogState::Terminated(_, _, _));
+            states.push(state);
+            if terminated {
+                break;
+            }
+        }
+        states
+    };
+    let alice_call = async {
+        let (state_sender, _state_receiver) = unbounded_channel();
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            content_type: None,
+            offer: Some(b"v=0\r\n".to_vec()),
+            contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (dialog, resp) = alice_layer.do_invite(opt, state_sender).await?;
+        assert_eq!(resp.map(|r| r.status_code), Some(rsip::StatusCode::OK));
+        // an hour of call in no time
+        sleep(Duration::from_secs(3600)).await;
+        dialog.bye().await?;
+        Result::Ok(())
+    };
+
+    let (r, states) = select! {
+        r = async { tokio::join!(alice_call, bob_states) } => r,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = accept_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+    };
+    r?;
+    assert!(states
+        .iter()
+        .any(|s| matches!(s, DialogState::Confirmed(_))));
+    assert!(matches!(
+        states.last(),
+        Some(DialogState::Terminated(_, None, _))
+    ));
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_invite_manual_ack() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let alice_layer = DialogLayer::new(alice.inner.clone());
+    let bob_layer = DialogLayer::new(bob.inner.clone());
+
+    let (state_sender, mut state_receiver) = unbounded_channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: Some(rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?),
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(tx) = incoming.recv().await {
+            bob_layer.handle_incoming(tx, &handler).await?;
+        }
+        Result::Ok(())
+    };
+    let accept_loop = async {
+        while let Some(dialog) = invite_receiver.recv().await {
+            dialog.accept(None, Some(b"v=0\r\n".to_vec()))?;
+        }
+        Result::Ok(())
+    };
+    let started = tokio::time::Instant::now();
+    // the time the ACK reached bob
+    let bob_acked = async {
+        while let Some(state) = state_receiver.recv().await {
+            if let DialogState::Ack(_, ack) = state {
+                assert_eq!(ack.body, b"v=0\r\n".to_vec());
+                return started.elapsed();
+            }
+        }
+        panic!("must not reach here");
+    };
+    let alice_call = async {
+        let (state_sender, mut state_receiver) = unbounded_channel();
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            content_type: None,
+            offer: None,
+            contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (dialog, resp) = alice_layer.do_invite_manual_ack(opt, state_sender).await?;
+        assert_eq!(resp.map(|r| r.status_code), Some(rsip::StatusCode::OK));
+        let mut last = None;
+        while let Ok(state) = state_receiver.try_recv() {
+            last = Some(state);
+        }
+        assert!(matches!(last, Some(DialogState::WaitAck(_, _))));
+
+        // relayed upstream, acknowledged with the answer of the other side later
+        sleep(Duration::from_secs(2)).await;
+        let headers = vec![Header::ContentType("application/sdp".into())];
+        dialog
+            .send_ack(Some(headers), Some(b"v=0\r\n".to_vec()))
+            .await?;
+        assert!(matches!(
+            state_receiver.recv().await,
+            Some(DialogState::Confirmed(_))
+        ));
+        assert!(dialog.send_ack(None, None).await.is_err());
+        assert!(dialog.suppress_ack().is_err());
+        Result::Ok(())
+    };
+
+    let (r, acked) = select! {
+        r = async { tokio::join!(alice_call, bob_acked) } => r,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = accept_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(20)) => panic!("timeout waiting"),
+    };
+    r?;
+    assert!(acked >= Duration::from_secs(2));
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_endpoint_pair_cancel() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let alice_layer = DialogLayer::new(alice.inner.clone());
+    let bob_layer = DialogLayer::new(bob.inner.clone());
+
+    let (state_sender, mut state_receiver) = unbounded_channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: Some(rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?),
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(tx) = incoming.recv().await {
+            bob_layer.handle_incoming(tx, &handler).await?;
+        }
+        Result::Ok(())
+    };
+    // bob rings and never answers
+    let ring_loop = async {
+        let mut dialogs = vec![];
+        while let Some(dialog) = invite_receiver.recv().await {
+            dialog.reliable_provisional(None, None).await.ok();
+            dialogs.push(dialog);
+        }
+    };
+    let bob_states = async {
+        while let Some(state) = state_receiver.recv().await {
+            if state.is_terminated() {
+                return state;
+            }
+        }
+        panic!("must not reach here");
+    };
+    let alice_call = async {
+        let (state_sender, mut state_receiver) = unbounded_channel();
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            content_type: None,
+            offer: Some(b"v=0\r\n".to_vec()),
+            contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let cancel_loop = async {
+            while let Some(state) = state_receiver.recv().await {
+                if let DialogState::Calling(id) = state {
+                    if let Some(Dialog::ClientInvite(dialog)) = alice_layer.get_dialog(&id) {
+                        dialog.cancel().await?;
+                    }
+                }
+            }
+            Result::Ok(())
+        };
+        let (dialog, resp) = select! {
+            r = alice_layer.do_invite(opt, state_sender) => r?,
+            _ = cancel_loop => panic!("must not reach here"),
+        };
+        assert_eq!(
+            resp.map(|r| r.status_code),
+            Some(rsip::StatusCode::RequestTerminated)
+        );
+        let state = dialog.inner.state.lock().unwrap().clone();
+        Result::Ok(state)
+    };
+
+    let (alice_state, bob_state) = select! {
+        r = async { tokio::join!(alice_call, bob_states) } => r,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = ring_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert!(matches!(alice_state?, DialogState::Cancelled(_, _)));
+    assert!(matches!(bob_state, DialogState::Cancelled(_, _)));
+    // a cancelled call is counted as its 487
+    let metrics = alice.inner.dialog_metrics.snapshot();
+    assert_eq!(metrics.terminated_failures.get(&487), Some(&1));
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_in_dialog_options() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let alice_layer = DialogLayer::new(alice.inner.clone());
+    let bob_layer = DialogLayer::new(bob.inner.clone());
+
+    let (state_sender, mut bob_states) = unbounded_channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: Some(rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?),
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(tx) = incoming.recv().await {
+            bob_layer.handle_incoming(tx, &handler).await?;
+        }
+        Result::Ok(())
+    };
+    let accept_loop = async {
+        while let Some(dialog) = invite_receiver.recv().await {
+            dialog.accept(None, Some(b"v=0\r\n".to_vec()))?;
+        }
+        Result::Ok(())
+    };
+    let alice_call = async {
+        let (state_sender, _state_receiver) = unbounded_channel();
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            content_type: None,
+            offer: Some(b"v=0\r\n".to_vec()),
+            contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (dialog, resp) = alice_layer.do_invite(opt, state_sender).await?;
+        let resp = resp.expect("2xx to the INVITE");
+        assert!(resp.headers.iter().any(|h| matches!(h, Header::Allow(_))));
+        // bob answers in-dialog requests once the ACK is in
+        while let Some(state) = bob_states.recv().await {
+            if state.is_confirmed() {
+                break;
+            }
+        }
+        let ping = Dialog::ClientInvite(dialog.clone())
+            .options_ping()
+            .await?
+            .expect("OPTIONS answered");
+        dialog.bye().await?;
+        Result::Ok(ping)
+    };
+
+    let ping = select! {
+        r = alice_call => r?,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = accept_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert!(ping.allow.is_some_and(|allow| allow.contains("OPTIONS")));
+    assert!(ping
+        .accept
+        .is_some_and(|accept| accept.contains("application/sdp")));
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_endpoint_pair_timeout() -> Result<()> {
+    // bob is never served, alice retransmits until Timer F
+    let (alice, _bob) = Endpoint::test_pair();
+    let started = tokio::time::Instant::now();
+    let request = async {
+        let mut tx = alice
+            .request_builder(
+                rsip::Method::Options,
+                rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            )
+            .send()
+            .await?;
+        while let Some(msg) = tx.receive().await {
+            if let SipMessage::Response(resp) = msg {
+                return Result::Ok(resp);
+            }
+        }
+        panic!("must not reach here");
+    };
+    let resp = select! {
+        r = request => r?,
+        _ = alice.serve() => panic!("must not reach here"),
+    };
+    assert_eq!(resp.status_code, rsip::StatusCode::RequestTimeout);
+    let elapsed = started.elapsed();
+    let t1x64 = alice.inner.t1x64;
+    assert!(elapsed >= t1x64, "timeout too early: {:?}", elapsed);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_dialog_from_tag() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let mut request = make_invite()?;
+    request
+        .headers
+        .unique_push(Header::From("<sip:alice@127.0.0.1>".into()));
+    let id = DialogId {
+        call_id: "dialog-layer-test".to_string(),
+        from_tag: String::new(),
+        to_tag: String::new(),
+    };
+    let dialog = DialogInner::new(
+        TransactionRole::Client,
+        id,
+        request,
+        endpoint.inner.clone(),
+        unbounded_channel().0,
+        None,
+        None,
+    )?;
+
+    // a From without a tag is given one, in the dialog id and the requests
+    let from_tag = dialog.id.lock().unwrap().from_tag.clone();
+    assert!(!from_tag.is_empty());
+    assert_eq!(
+        dialog
+            .initial_request
+            .from_header()?
+            .tag()?
+            .map(|t| t.value().to_string()),
+        Some(from_tag.clone())
+    );
+    let bye = dialog.make_request(rsip::Method::Bye, None, None, None, None)?;
+    assert_eq!(
+        bye.from_header()?.tag()?.map(|t| t.value().to_string()),
+        Some(from_tag)
+    );
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_invite_call_id_and_from_tag() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let alice_layer = DialogLayer::new(alice.inner.clone());
+    let bob_layer = DialogLayer::new(bob.inner.clone());
+    let option = |call_id: &str, from_tag: &str| -> Result<InviteOption> {
+        Ok(InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            content_type: None,
+            offer: Some(b"v=0\r\n".to_vec()),
+            contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: Some(call_id.to_string()),
+            from_tag: Some(from_tag.to_string()),
+            routes: vec![],
+        })
+    };
+    assert!(alice_layer
+        .make_invite_request(&option("", "tag-1")?)
+        .is_err());
+    assert!(alice_layer
+        .make_invite_request(&option("req 42@upstream", "tag-1")?)
+        .is_err());
+    assert!(alice_layer
+        .make_invite_request(&option("req-42@upstream", "tag;1")?)
+        .is_err());
+
+    let (state_sender, _bob_states) = unbounded_channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: Some(rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?),
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(tx) = incoming.recv().await {
+            bob_layer.handle_incoming(tx, &handler).await?;
+        }
+        Result::Ok(())
+    };
+    let accept_loop = async {
+        while let Some(dialog) = invite_receiver.recv().await {
+            dialog.accept(None, Some(b"v=0\r\n".to_vec()))?;
+        }
+        Result::Ok(())
+    };
+    let alice_call = async {
+        let (state_sender, _state_receiver) = unbounded_channel();
+        let opt = option("req-42@upstream", "tag-1")?;
+        alice_layer.do_invite(opt, state_sender).await
+    };
+    let (dialog, resp) = select! {
+        r = alice_call => r?,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = accept_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    let resp = resp.expect("2xx to the INVITE");
+    assert_eq!(resp.call_id_header()?.value(), "req-42@upstream");
+    let id = dialog.id();
+    assert_eq!(id.call_id, "req-42@upstream");
+    assert_eq!(id.from_tag, "tag-1");
+    assert!(alice_layer.get_dialog(&id).is_some());
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_invite_replaces() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let alice_layer = DialogLayer::new(alice.inner.clone());
+    let bob_layer = DialogLayer::new(bob.inner.clone());
+
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let (state_sender, _bob_states) = unbounded_channel();
+    let bob_handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: Some(rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?),
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(tx) = incoming.recv().await {
+            bob_layer.handle_incoming(tx, &bob_handler).await?;
+        }
+        Result::Ok(())
+    };
+    let accept_loop = async {
+        while let Some(dialog) = invite_receiver.recv().await {
+            dialog.accept(None, Some(b"v=0\r\n".to_vec()))?;
+        }
+        Result::Ok(())
+    };
+    // the BYE of the replaced call goes to alice's dialog
+    let (state_sender, _alice_states) = unbounded_channel();
+    let alice_handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: None,
+        invite_sender: unbounded_channel().0,
+        request_sender: unbounded_channel().0,
+    };
+    let alice_loop = async {
+        let mut incoming = alice.incoming_transactions();
+        while let Some(tx) = incoming.recv().await {
+            alice_layer.handle_incoming(tx, &alice_handler).await?;
+        }
+        Result::Ok(())
+    };
+
+    let (state_sender, _states) = unbounded_channel();
+    let invite = |replaces| {
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            content_type: None,
+            offer: Some(b"v=0\r\n".to_vec()),
+            contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            credential: None,
+            session_timer: None,
+            replaces,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        Result::Ok(alice_layer.do_invite(opt, state_sender.clone()))
+    };
+    let alice_calls = async {
+        let (first, resp) = invite(None)?.await?;
+        assert_eq!(resp.map(|r| r.status_code), Some(rsip::StatusCode::OK));
+        let replaces = Dialog::ClientInvite(first.clone()).replaces();
+
+        // an unknown dialog can't be replaced, an early-only replaces not a confirmed one
+        let unknown = Replaces {
+            call_id: "unknown".to_string(),
+            ..replaces.clone()
+        };
+        let (_, resp) = invite(Some(unknown))?.await?;
+        assert_eq!(
+            resp.map(|r| r.status_code),
+            Some(rsip::StatusCode::CallTransactionDoesNotExist)
+        );
+        let early_only = Replaces {
+            early_only: true,
+            ..replaces.clone()
+        };
+        let (_, resp) = invite(Some(early_only))?.await?;
+        assert_eq!(
+            resp.map(|r| r.status_code),
+            Some(rsip::StatusCode::BusyHere)
+        );
+        assert!(first.inner.is_confirmed());
+
+        // the new call is answered and the first one hung up by bob
+        let (second, resp) = invite(Some(replaces))?.await?;
+        assert_eq!(resp.map(|r| r.status_code), Some(rsip::StatusCode::OK));
+        while !matches!(
+            *first.inner.state.lock().unwrap(),
+            DialogState::Terminated(_, _, _)
+        ) {
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert!(second.inner.is_confirmed());
+        Result::Ok(())
+    };
+
+    let r = select! {
+        r = alice_calls => r,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = accept_loop => panic!("must not reach here"),
+        _ = alice_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(10)) => panic!("timeout waiting"),
+    };
+    r?;
+    // bob has the new call only
+    assert_eq!(bob_layer.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_dialog_id_views() -> Result<()> {
+    let invite = make_invite()?;
+    let uas_id = DialogId::new(
+        &TransactionRole::Server,
+        "dialog-layer-test",
+        "uas-tag",
+        "uac-tag",
+    );
+    assert_eq!(DialogId::try_from(&invite)?, uas_id);
+    assert_eq!(uas_id.local_tag(&TransactionRole::Server), "uas-tag");
+    assert_eq!(uas_id.remote_tag(&TransactionRole::Server), "uac-tag");
+    assert_eq!(uas_id.local_tag(&TransactionRole::Client), "uac-tag");
+    assert_eq!(uas_id.remote_tag(&TransactionRole::Client), "uas-tag");
+
+    let ok = "SIP/2.0 200 OK\r\n\
+         Via: SIP/2.0/UDP 127.0.0.1:5070;branch=z9hG4bKlayer\r\n\
+         From: <sip:alice@127.0.0.1>;tag=uac-tag\r\n\
+         To: <sip:bob@127.0.0.1>;tag=uas-tag\r\n\
+         Call-ID: dialog-layer-test\r\n\
+         CSeq: 1 INVITE\r\n\
+         Content-Length: 0\r\n\r\n";
+    let ok = match SipMessage::try_from(ok)? {
+        SipMessage::Response(resp) => resp,
+        _ => panic!("not a response"),
+    };
+    let uac_id = DialogId::new(
+        &TransactionRole::Client,
+        "dialog-layer-test",
+        "uac-tag",
+        "uas-tag",
+    );
+    assert_eq!(DialogId::try_from(&ok)?, uac_id);
+    assert_eq!(uac_id, uas_id);
+
+    // a BYE of the UAS carries the tags the other way round
+    let bye = "BYE sip:alice@127.0.0.1:5070 SIP/2.0\r\n\
+         Via: SIP/2.0/UDP 127.0.0.1:5060;branch=z9hG4bKbye\r\n\
+         From: <sip:bob@127.0.0.1>;tag=uas-tag\r\n\
+         To: <sip:alice@127.0.0.1>;tag=uac-tag\r\n\
+         Call-ID: dialog-layer-test\r\n\
+         CSeq: 1 BYE\r\n\
+         Max-Forwards: 70\r\n\
+         Content-Length: 0\r\n\r\n";
+    let bye = match SipMessage::try_from(bye)? {
+        SipMessage::Request(req) => req,
+        _ => panic!("not a request"),
+    };
+    let bye_id = DialogId::try_from(&bye)?;
+    assert_ne!(bye_id, uas_id);
+    assert_eq!(bye_id.swapped(), uas_id);
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_reap_stale_dialogs() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let alice_layer = DialogLayer::new(alice.inner.clone());
+    let bob_layer = DialogLayer::new(bob.inner.clone());
+
+    let (state_sender, mut state_receiver) = unbounded_channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: Some(rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?),
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(tx) = incoming.recv().await {
+            bob_layer.handle_incoming(tx, &handler).await?;
+        }
+        Result::Ok(())
+    };
+    let accept_loop = async {
+        while let Some(dialog) = invite_receiver.recv().await {
+            dialog.accept(None, Some(b"v=0\r\n".to_vec()))?;
+        }
+        Result::Ok(())
+    };
+    let bob_states = async {
+        while let Some(state) = state_receiver.recv().await {
+            if let DialogState::Terminated(_, code, _) = state {
+                return code;
+            }
+        }
+        panic!("must not reach here");
+    };
+    let alice_call = async {
+        let (state_sender, _state_receiver) = unbounded_channel();
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            content_type: None,
+            offer: Some(b"v=0\r\n".to_vec()),
+            contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (dialog, _) = alice_layer.do_invite(opt, state_sender).await?;
+        sleep(Duration::from_secs(60)).await;
+        // active a minute ago, not stale yet
+        assert!(alice_layer.reap(Duration::from_secs(120)).await.is_empty());
+        assert_eq!(alice_layer.len(), 1);
+
+        sleep(Duration::from_secs(120)).await;
+        let id = dialog.id();
+        assert_eq!(alice_layer.reap(Duration::from_secs(120)).await, vec![id]);
+        assert!(alice_layer.is_empty());
+        assert!(dialog.inner.state.lock().unwrap().is_terminated());
+        Result::Ok(())
+    };
+
+    let (r, bob_code) = select! {
+        r = async { tokio::join!(alice_call, bob_states) } => r,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = accept_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+    };
+    r?;
+    // the reaped dialog was hung up with a BYE
+    assert_eq!(bob_code, None);
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_dialog_state_stream() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let alice_layer = DialogLayer::new(alice.inner.clone());
+    let bob_layer = DialogLayer::new(bob.inner.clone());
+
+    let (state_sender, mut bob_states) = DialogStateStream::channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: Some(rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?),
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(tx) = incoming.recv().await {
+            bob_layer.handle_incoming(tx, &handler).await?;
+        }
+        Result::Ok(())
+    };
+    // the first call is answered, the second one declined
+    let accept_loop = async {
+        let mut calls = 0;
+        while let Some(dialog) = invite_receiver.recv().await {
+            calls += 1;
+            match calls {
+                1 => dialog.accept(None, Some(b"v=0\r\n".to_vec()))?,
+                _ => dialog.reject()?,
+            }
+        }
+        Result::Ok(())
+    };
+    let alice_calls = async {
+        let opt = || -> Result<InviteOption> {
+            Ok(InviteOption {
+                caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+                callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+                content_type: None,
+                offer: Some(b"v=0\r\n".to_vec()),
+                contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+                credential: None,
+                session_timer: None,
+                replaces: None,
+                call_id: None,
+                from_tag: None,
+                routes: vec![],
+            })
+        };
+        let (state_sender, mut states) = DialogStateStream::channel();
+        let (dialog, _) = alice_layer.do_invite(opt()?, state_sender).await?;
+        assert_eq!(states.wait_until_confirmed().await?, dialog.id());
+        // bob has the ACK before the BYE
+        bob_states.wait_until_confirmed().await?;
+        dialog.bye().await?;
+        assert_eq!(
+            states.wait_until_terminated().await?,
+            Some(rsip::StatusCode::OK)
+        );
+
+        let (state_sender, mut states) = DialogStateStream::channel();
+        alice_layer.do_invite(opt()?, state_sender).await?;
+        assert!(matches!(states.next().await, Some(DialogState::Calling(_))));
+        assert_eq!(
+            states.wait_until_terminated().await?,
+            Some(rsip::StatusCode::Decline)
+        );
+
+        let (state_sender, mut states) = DialogStateStream::channel();
+        alice_layer.do_invite(opt()?, state_sender).await?;
+        match states.wait_until_confirmed().await {
+            Err(crate::Error::DialogError(reason, _)) => {
+                assert!(reason.contains("Decline"), "{}", reason)
+            }
+            _ => panic!("a declined call is not confirmed"),
+        }
+        Result::Ok(())
+    };
+
+    select! {
+        r = alice_calls => r?,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = accept_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(10)) => panic!("timeout waiting"),
+    }
+    Ok(())
+}
+
+/// Early media with two 183s, then the 200 of the same early dialog
+#[tokio::test(start_paused = true)]
+async fn test_progress_then_accept() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let alice_layer = DialogLayer::new(alice.inner.clone());
+    let bob_layer = DialogLayer::new(bob.inner.clone());
+
+    let (state_sender, mut bob_states) = DialogStateStream::channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: Some(rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?),
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(tx) = incoming.recv().await {
+            bob_layer.handle_incoming(tx, &handler).await?;
+        }
+        Result::Ok(())
+    };
+    let accept_loop = async {
+        while let Some(dialog) = invite_receiver.recv().await {
+            dialog.progress(b"v=0\r\ns=announcement\r\n".to_vec(), None)?;
+            sleep(Duration::from_millis(100)).await;
+            dialog.progress(b"v=0\r\ns=queue\r\n".to_vec(), None)?;
+            sleep(Duration::from_millis(100)).await;
+            dialog.accept(None, Some(b"v=0\r\ns=answer\r\n".to_vec()))?;
+        }
+        Result::Ok(())
+    };
+    let alice_call = async {
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            content_type: None,
+            offer: Some(b"v=0\r\n".to_vec()),
+            contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (state_sender, mut states) = DialogStateStream::channel();
+        let (dialog, resp) = alice_layer.do_invite(opt, state_sender).await?;
+        let mut early = vec![];
+        while let Some(state) = states.next().await {
+            match state {
+                DialogState::Early(id, resp) => early.push((id, resp)),
+                DialogState::Confirmed(_) => break,
+                _ => {}
+            }
+        }
+        bob_states.wait_until_confirmed().await?;
+        Result::Ok((dialog.id(), resp, early))
+    };
+
+    let (id, resp, early) = select! {
+        r = alice_call => r?,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = accept_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    let resp = resp.expect("final response");
+    assert_eq!(resp.status_code, rsip::StatusCode::OK);
+    assert_eq!(resp.body, b"v=0\r\ns=answer\r\n");
+    assert_eq!(
+        early
+            .iter()
+            .map(|(_, resp)| (resp.status_code.clone(), resp.body.clone()))
+            .collect::<Vec<_>>(),
+        vec![
+            (
+                rsip::StatusCode::SessionProgress,
+                b"v=0\r\ns=announcement\r\n".to_vec()
+            ),
+            (
+                rsip::StatusCode::SessionProgress,
+                b"v=0\r\ns=queue\r\n".to_vec()
+            ),
+        ]
+    );
+    // the 183s have the To tag of the 200
+    assert!(early.iter().all(|(early_id, _)| *early_id == id));
+    Ok(())
+}
+
+/// A full bounded stream drops the oldest states that are not final
+#[tokio::test]
+async fn test_bounded_state_stream() -> Result<()> {
+    let endpoint = EndpointBuilder::new().dialog_state_capacity(2).build();
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+    let (state_sender, mut states) = dialog_layer.state_channel();
+    let id = |n: u32| DialogId {
+        call_id: format!("flood-{}", n),
+        from_tag: "alice".to_string(),
+        to_tag: "bob".to_string(),
+    };
+    state_sender.send(DialogState::Calling(id(0)))?;
+    for n in 1..=5 {
+        state_sender.send(DialogState::Trying(id(n)))?;
+    }
+    state_sender.send(DialogState::Terminated(id(5), None, None))?;
+    state_sender.send(DialogState::Terminated(id(6), None, None))?;
+    // full of final states, the next one that is not final is dropped
+    state_sender.send(DialogState::Trying(id(7)))?;
+    state_sender.send(DialogState::Cancelled(id(8), None))?;
+    drop(state_sender);
+
+    let delivered = states
+        .by_ref()
+        .map(|state| state.to_string())
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(
+        delivered,
+        vec![
+            DialogState::Terminated(id(5), None, None).to_string(),
+            DialogState::Terminated(id(6), None, None).to_string(),
+            DialogState::Cancelled(id(8), None).to_string(),
+        ]
+    );
+    // Calling and the five Trying, then the Trying of a full stream
+    assert_eq!(states.dropped(), 7);
+
+    // unbounded unless the endpoint says otherwise
+    let endpoint = EndpointBuilder::new().build();
+    let (state_sender, mut states) = DialogLayer::new(endpoint.inner.clone()).state_channel();
+    for n in 0..100 {
+        state_sender.send(DialogState::Trying(id(n)))?;
+    }
+    drop(state_sender);
+    assert_eq!(states.by_ref().count().await, 100);
+    assert_eq!(states.dropped(), 0);
+    Ok(())
+}
+
+/// The GRUUs of a registration are the Contact of the dialogs after it and
+/// the remote target keeps their `gr` param (RFC 5627)
+#[tokio::test(start_paused = true)]
+async fn test_registration_gruu() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let alice_layer = DialogLayer::new(alice.inner.clone());
+    let bob_layer = DialogLayer::new(bob.inner.clone());
+    let instance = "<urn:uuid:f81d4fae-7dec-11d0-a765-00a0c91e6bf6>";
+
+    let (state_sender, _bob_states) = DialogStateStream::channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: Some(rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?),
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(mut tx) = incoming.recv().await {
+            if tx.original.method != rsip::Method::Register {
+                bob_layer.handle_incoming(tx, &handler).await?;
+                continue;
+            }
+            let supported = tx
+                .original
+                .headers
+                .iter()
+                .any(|h| matches!(h, Header::Supported(s) if s.value() == "path, gruu"));
+            assert!(supported, "the REGISTER supports path and gruu");
+            let contact = tx.original.contact_header()?.value().to_string();
+            assert!(contact.contains(&format!("+sip.instance=\"{}\"", instance)));
+            let contact = format!(
+                "{};pub-gruu=\"sip:alice@example.com;gr=urn:uuid:f81d4fae-7dec-11d0-a765-00a0c91e6bf6\"\
+                 ;temp-gruu=\"sip:tgruu.7hs==jd7vnzga5w7fajsc7-ajd6fabz0f8g5@example.com;gr\"",
+                contact
+            );
+            tx.reply_with(
+                rsip::StatusCode::OK,
+                vec![
+                    Header::Contact(contact.into()),
+                    Header::Other("Path".into(), "<sip:edge.example.com;lr>".into()),
+                ],
+                None,
+            )
+            .await?;
+        }
+        Result::Ok(())
+    };
+    let accept_loop = async {
+        let dialog = invite_receiver.recv().await.expect("an invite");
+        let contact = dialog
+            .inner
+            .initial_request
+            .contact_header()?
+            .value()
+            .to_string();
+        let remote = dialog.inner.remote_uri.lock().unwrap().to_string();
+        dialog.accept(None, None)?;
+        Result::Ok((contact, remote))
+    };
+    let alice_flow = async {
+        let mut registration = Registration::new(alice.inner.clone(), None);
+        registration.instance = Some(instance.to_string());
+        let resp = registration.register(&"192.0.2.2:5060".to_string()).await?;
+        assert_eq!(resp.status_code, rsip::StatusCode::OK);
+        let gruu = registration.gruu.clone().expect("assigned gruus");
+        assert_eq!(gruu.instance, instance);
+        assert_eq!(
+            gruu.public.as_ref().map(|u| u.to_string()),
+            Some("sip:alice@example.com;gr=urn:uuid:f81d4fae-7dec-11d0-a765-00a0c91e6bf6".into())
+        );
+        assert!(gruu.temporary.is_some());
+        assert_eq!(
+            registration
+                .path
+                .iter()
+                .map(|r| r.to_string())
+                .collect::<Vec<_>>(),
+            vec!["<sip:edge.example.com;lr>"]
+        );
+        assert!(alice.inner.supported().contains(&"gruu".to_string()));
+
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            content_type: None,
+            offer: None,
+            contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (state_sender, _states) = DialogStateStream::channel();
+        let (dialog, _) = alice_layer.do_invite(opt, state_sender).await?;
+        Result::Ok(dialog)
+    };
+
+    let (dialog, (contact, remote)) = select! {
+        r = async { tokio::try_join!(alice_flow, accept_loop) } => r?,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    let gruu = "sip:alice@example.com;gr=urn:uuid:f81d4fae-7dec-11d0-a765-00a0c91e6bf6";
+    assert_eq!(contact, format!("<{}>", gruu));
+    assert_eq!(remote, gruu);
+    assert_eq!(
+        dialog.inner.local_contact.as_ref().map(|u| u.to_string()),
+        Some(gruu.to_string())
+    );
+
+    // a private dialog hides the AOR behind the temporary GRUU
+    let mut headers = rsip::Headers::default();
+    headers.push(Header::Other("Privacy".into(), "id".into()));
+    let temporary = alice.inner.gruu_contact(&headers).expect("temporary gruu");
+    assert!(temporary.to_string().starts_with("sip:tgruu."));
+    alice.inner.set_prefer_temp_gruu(true);
+    assert_eq!(
+        alice.inner.gruu_contact(&rsip::Headers::default()),
+        Some(temporary)
+    );
+    Ok(())
+}
+
+/// The requests after a registration go through its Service-Route (RFC 3608)
+#[tokio::test(start_paused = true)]
+async fn test_service_route() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let alice_layer = DialogLayer::new(alice.inner.clone());
+    let bob_layer = DialogLayer::new(bob.inner.clone());
+
+    let (state_sender, _bob_states) = DialogStateStream::channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: Some(rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?),
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(mut tx) = incoming.recv().await {
+            if tx.original.method != rsip::Method::Register {
+                bob_layer.handle_incoming(tx, &handler).await?;
+                continue;
+            }
+            assert!(tx.original.route_header().is_none());
+            let service_route = "<sip:192.0.2.2:5060;lr>, <sip:orig@scscf.example.com;lr>";
+            tx.reply_with(
+                rsip::StatusCode::OK,
+                vec![
+                    tx.original.contact_header()?.clone().into(),
+                    Header::Other("Service-Route".into(), service_route.into()),
+                ],
+                None,
+            )
+            .await?;
+        }
+        Result::Ok(())
+    };
+    let accept_loop = async {
+        let dialog = invite_receiver.recv().await.expect("an invite");
+        let routes = dialog
+            .inner
+            .initial_request
+            .headers
+            .iter()
+            .filter_map(|h| match h {
+                Header::Route(route) => Some(route.value().to_string()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        dialog.accept(None, None)?;
+        Result::Ok(routes)
+    };
+    let alice_flow = async {
+        let mut registration = Registration::new(alice.inner.clone(), None);
+        registration.register(&"192.0.2.2:5060".to_string()).await?;
+        assert_eq!(registration.service_route.len(), 2);
+
+        let option = || -> Result<InviteOption> {
+            Ok(InviteOption {
+                caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+                callee: rsip::Uri::try_from("sip:carol@198.51.100.7")?,
+                content_type: None,
+                offer: None,
+                contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+                credential: None,
+                session_timer: None,
+                replaces: None,
+                call_id: None,
+                from_tag: None,
+                routes: vec![],
+            })
+        };
+        let (state_sender, _states) = DialogStateStream::channel();
+        alice_layer.do_invite(option()?, state_sender).await?;
+
+        // not honored, the requests go to their request uri
+        alice.inner.set_honor_service_route(false);
+        let request = alice_layer.make_invite_request(&option()?)?;
+        assert!(request.route_header().is_none());
+        Result::Ok(())
+    };
+
+    let (_, routes) = select! {
+        r = async { tokio::try_join!(alice_flow, accept_loop) } => r?,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(
+        routes,
+        vec!["<sip:192.0.2.2:5060;lr>", "<sip:orig@scscf.example.com;lr>"]
+    );
+    Ok(())
+}
+
+/// Any method but the ones moving the dialog state goes through
+/// [`Dialog::send_request`], its final response comes back raw
+#[tokio::test(start_paused = true)]
+async fn test_send_request() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let alice_layer = DialogLayer::new(alice.inner.clone());
+    let bob_layer = DialogLayer::new(bob.inner.clone());
+
+    let (state_sender, mut bob_states) = DialogStateStream::channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: Some(rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?),
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        while let Some(tx) = incoming.recv().await {
+            // the PUBLISH is refused with an error
+            bob_layer.handle_incoming(tx, &handler).await.ok();
+        }
+    };
+    let accept_loop = async {
+        while let Some(dialog) = invite_receiver.recv().await {
+            dialog.accept(None, None)?;
+        }
+        Result::Ok(())
+    };
+    let alice_call = async {
+        let opt = InviteOption {
+            caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            content_type: None,
+            offer: Some(b"v=0\r\n".to_vec()),
+            contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            credential: None,
+            session_timer: None,
+            replaces: None,
+            call_id: None,
+            from_tag: None,
+            routes: vec![],
+        };
+        let (state_sender, _states) = DialogStateStream::channel();
+        let (dialog, _) = alice_layer.do_invite(opt, state_sender).await?;
+        bob_states.wait_until_confirmed().await?;
+        let dialog = Dialog::ClientInvite(dialog);
+        let seq = dialog.inner().get_local_seq();
+
+        let options = dialog
+            .send_request(rsip::Method::Options, vec![], None)
+            .await?;
+        let publish = dialog
+            .send_request(
+                rsip::Method::Publish,
+                vec![
+                    Header::Other("Event".into(), "presence".into()),
+                    Header::ContentType("application/pidf+xml".into()),
+                ],
+                Some(b"<presence/>".to_vec()),
+            )
+            .await?;
+        assert_eq!(dialog.inner().get_local_seq(), seq + 2);
+        assert!(dialog
+            .send_request(rsip::Method::Bye, vec![], None)
+            .await
+            .is_err());
+        Result::Ok((options.status_code, publish.status_code))
+    };
+
+    let (options, publish) = select! {
+        r = alice_call => r?,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = accept_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(options, rsip::StatusCode::OK);
+    assert_eq!(publish, rsip::StatusCode::MethodNotAllowed);
+    Ok(())
+}
@@ -0,0 +1,249 @@
+use crate::dialog::registrar::{address_of_record, Registrar};
+use crate::Result;
+use rsip::{Request, SipMessage, StatusCode};
+use std::time::{Duration, Instant};
+
+fn make_register(
+    call_id: &str,
+    cseq: u32,
+    contacts: &[&str],
+    expires: Option<u32>,
+) -> Result<Request> {
+    let mut register = format!(
+        "REGISTER sip:Restsend.com SIP/2.0\r\n\
+         Via: SIP/2.0/UDP 192.0.2.1:5060;branch=z9hG4bKreg{cseq}\r\n\
+         From: <sip:bob@restsend.com>;tag=reg-tag\r\n\
+         To: <sip:bob@Restsend.com:5060;transport=udp>\r\n\
+         Call-ID: {call_id}\r\n\
+         CSeq: {cseq} REGISTER\r\n\
+         Max-Forwards: 70\r\n"
+    );
+    for contact in contacts {
+        register.push_str(&format!("Contact: {}\r\n", contact));
+    }
+    if let Some(expires) = expires {
+        register.push_str(&format!("Expires: {}\r\n", expires));
+    }
+    register.push_str("Content-Length: 0\r\n\r\n");
+    match SipMessage::try_from(register)? {
+        SipMessage::Request(req) => Ok(req),
+        _ => panic!("not a request"),
+    }
+}
+
+#[test]
+fn test_registrar_bindings() -> Result<()> {
+    let mut registrar = Registrar::new();
+    let now = Instant::now();
+    let register = make_register(
+        "reg-1",
+        1,
+        &[
+            "<sip:bob@192.0.2.1:5060>",
+            "<sip:bob@192.0.2.9:5060>;expires=120",
+        ],
+        Some(600),
+    )?;
+    let aor = address_of_record(&register)?;
+    assert_eq!(aor, "sip:bob@restsend.com");
+
+    let bindings = registrar.apply_register(&register, now).expect("register");
+    assert_eq!(bindings.len(), 2);
+    assert_eq!(bindings[0].remaining(now), 600);
+    assert_eq!(bindings[1].remaining(now), 120);
+    assert_eq!(bindings[0].call_id, "reg-1");
+    assert_eq!(
+        bindings[1]
+            .contact_header(now + Duration::from_secs(20))
+            .to_string(),
+        "Contact: <sip:bob@192.0.2.9:5060>;expires=100"
+    );
+
+    // a refresh, the second binding expires meanwhile
+    let later = now + Duration::from_secs(300);
+    let register = make_register("reg-1", 2, &["<sip:bob@192.0.2.1:5060>"], None)?;
+    let bindings = registrar.apply_register(&register, later).expect("refresh");
+    assert_eq!(bindings.len(), 1);
+    assert_eq!(bindings[0].remaining(later), 3600);
+    assert_eq!(bindings[0].cseq, 2);
+
+    // a query leaves them as they are
+    let register = make_register("reg-2", 1, &[], None)?;
+    assert_eq!(
+        registrar
+            .apply_register(&register, later)
+            .expect("query")
+            .len(),
+        1
+    );
+
+    // expires=0 removes a binding
+    let register = make_register("reg-1", 3, &["<sip:bob@192.0.2.1:5060>;expires=0"], None)?;
+    assert!(registrar
+        .apply_register(&register, later)
+        .expect("remove")
+        .is_empty());
+    assert!(registrar.bindings(&aor, later).is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_registrar_wildcard() -> Result<()> {
+    let mut registrar = Registrar::new();
+    let now = Instant::now();
+    let register = make_register("reg-1", 5, &["<sip:bob@192.0.2.1:5060>"], None)?;
+    let aor = address_of_record(&register)?;
+    registrar.apply_register(&register, now).expect("register");
+    let register = make_register("reg-2", 1, &["<sip:bob@192.0.2.2:5060>"], None)?;
+    registrar.apply_register(&register, now).expect("register");
+
+    // only with Expires: 0 and alone
+    let register = make_register("reg-3", 1, &["*"], None)?;
+    assert_eq!(
+        registrar.apply_register(&register, now).err(),
+        Some(StatusCode::BadRequest)
+    );
+    let register = make_register("reg-3", 1, &["*", "<sip:bob@192.0.2.3:5060>"], Some(0))?;
+    assert_eq!(
+        registrar.apply_register(&register, now).err(),
+        Some(StatusCode::BadRequest)
+    );
+
+    // a binding of a newer REGISTER of the same Call-ID rejects it, nothing
+    // is removed
+    let register = make_register("reg-1", 4, &["*"], Some(0))?;
+    assert_eq!(
+        registrar.apply_register(&register, now).err(),
+        Some(StatusCode::ServerInternalError)
+    );
+    assert_eq!(registrar.bindings(&aor, now).len(), 2);
+    let register = make_register("reg-1", 6, &["*"], Some(0))?;
+    assert!(registrar
+        .apply_register(&register, now)
+        .expect("remove all")
+        .is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_registrar_rejects() -> Result<()> {
+    let mut registrar = Registrar::new();
+    let now = Instant::now();
+    let register = make_register("reg-1", 10, &["<sip:bob@192.0.2.1:5060>"], None)?;
+    registrar.apply_register(&register, now).expect("register");
+
+    // an out of order REGISTER of the same Call-ID changes nothing
+    let register = make_register(
+        "reg-1",
+        10,
+        &[
+            "<sip:bob@192.0.2.2:5060>",
+            "<sip:bob@192.0.2.1:5060>;expires=0",
+        ],
+        None,
+    )?;
+    assert_eq!(
+        registrar.apply_register(&register, now).err(),
+        Some(StatusCode::ServerInternalError)
+    );
+    let bindings = registrar.bindings("sip:bob@restsend.com", now);
+    assert_eq!(bindings.len(), 1);
+    assert_eq!(bindings[0].cseq, 10);
+
+    // too short, and too long which is shortened
+    let register = make_register("reg-2", 1, &["<sip:bob@192.0.2.2:5060>"], Some(10))?;
+    assert_eq!(
+        registrar.apply_register(&register, now).err(),
+        Some(StatusCode::IntervalTooBrief)
+    );
+    let register = make_register("reg-2", 2, &["<sip:bob@192.0.2.2:5060>"], Some(864000))?;
+    let bindings = registrar.apply_register(&register, now).expect("register");
+    assert_eq!(bindings[1].remaining(now), registrar.max_expires);
+    Ok(())
+}
+
+#[test]
+fn test_registrar_path() -> Result<()> {
+    let mut registrar = Registrar::new();
+    let now = Instant::now();
+    let mut register = make_register("reg-1", 1, &["<sip:bob@10.0.0.7:5060>"], None)?;
+    register.headers.push(rsip::Header::Other(
+        "Path".into(),
+        "<sip:edge.example.com;lr>, <sip:p2.example.com;lr>".into(),
+    ));
+    register.headers.push(rsip::Header::Other(
+        "Path".into(),
+        "<sip:p3.example.com;lr>".into(),
+    ));
+
+    let bindings = registrar.apply_register(&register, now).expect("register");
+    let routes = bindings[0]
+        .route_headers()
+        .iter()
+        .map(|h| h.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        routes,
+        vec![
+            "Route: <sip:edge.example.com;lr>",
+            "Route: <sip:p2.example.com;lr>",
+            "Route: <sip:p3.example.com;lr>",
+        ]
+    );
+    assert_eq!(
+        bindings[0].path_headers()[0].to_string(),
+        "Path: <sip:edge.example.com;lr>"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_registrar_reply_expires() -> Result<()> {
+    let mut registrar = Registrar::new();
+    registrar.min_expires = 300;
+    registrar.max_expires = 7200;
+    registrar.default_expires = 60;
+    let now = Instant::now();
+    let headers = |reply: &(StatusCode, Vec<rsip::Header>)| {
+        reply.1.iter().map(|h| h.to_string()).collect::<Vec<_>>()
+    };
+
+    // under the minimum
+    let register = make_register("reg-1", 1, &["<sip:bob@192.0.2.1:5060>"], Some(120))?;
+    let reply = registrar.reply_register(&register, now);
+    assert_eq!(reply.0, StatusCode::IntervalTooBrief);
+    assert_eq!(headers(&reply), vec!["Min-Expires: 300"]);
+    assert!(registrar.bindings("sip:bob@restsend.com", now).is_empty());
+
+    // in the range, granted as asked
+    let register = make_register("reg-1", 2, &["<sip:bob@192.0.2.1:5060>"], Some(600))?;
+    let reply = registrar.reply_register(&register, now);
+    assert_eq!(reply.0, StatusCode::OK);
+    assert_eq!(
+        headers(&reply),
+        vec!["Contact: <sip:bob@192.0.2.1:5060>;expires=600"]
+    );
+
+    // over the maximum, shortened
+    let register = make_register(
+        "reg-1",
+        3,
+        &["<sip:bob@192.0.2.1:5060>;expires=86400"],
+        None,
+    )?;
+    let reply = registrar.reply_register(&register, now);
+    assert_eq!(reply.0, StatusCode::OK);
+    assert_eq!(
+        headers(&reply),
+        vec!["Contact: <sip:bob@192.0.2.1:5060>;expires=7200"]
+    );
+
+    // the default under the minimum is raised to it
+    let register = make_register("reg-1", 4, &["<sip:bob@192.0.2.1:5060>"], None)?;
+    let reply = registrar.reply_register(&register, now);
+    assert_eq!(
+        headers(&reply),
+        vec!["Contact: <sip:bob@192.0.2.1:5060>;expires=300"]
+    );
+    Ok(())
+}
@@ -0,0 +1,231 @@
+use crate::dialog::{dialog::DialogInner, DialogId};
+use crate::rsip_ext::RsipHeadersExt;
+use crate::transaction::endpoint::Endpoint;
+use crate::transaction::key::TransactionRole;
+use crate::Result;
+use rsip::{
+    prelude::{HeadersExt, UntypedHeader},
+    Header, Request, SipMessage,
+};
+use std::time::Duration;
+use tokio::{select, sync::mpsc::unbounded_channel, time::timeout};
+
+fn make_invite(record_route: &str) -> Result<Request> {
+    let invite = format!(
+        "INVITE sip:bob@127.0.0.1:5060 SIP/2.0\r\n\
+         Via: SIP/2.0/UDP 127.0.0.1:5070;branch=z9hG4bKroute\r\n\
+         {record_route}\
+         From: <sip:alice@127.0.0.1>;tag=uac-tag\r\n\
+         To: <sip:bob@127.0.0.1>;tag=uas-tag\r\n\
+         Call-ID: route-set-test\r\n\
+         CSeq: 1 INVITE\r\n\
+         Contact: <sip:alice@10.0.0.1:5070>\r\n\
+         Max-Forwards: 70\r\n\
+         Content-Length: 0\r\n\r\n"
+    );
+    match SipMessage::try_from(invite)? {
+        SipMessage::Request(req) => Ok(req),
+        _ => panic!("not a request"),
+    }
+}
+
+async fn make_dialog(role: TransactionRole, request: Request) -> Result<DialogInner> {
+    let endpoint = super::create_test_endpoint().await?;
+    make_endpoint_dialog(&endpoint, role, request)
+}
+
+fn make_endpoint_dialog(
+    endpoint: &Endpoint,
+    role: TransactionRole,
+    request: Request,
+) -> Result<DialogInner> {
+    let id = DialogId::try_from(&request)?;
+    DialogInner::new(
+        role,
+        id,
+        request,
+        endpoint.inner.clone(),
+        unbounded_channel().0,
+        None,
+        None,
+    )
+}
+
+fn routes(req: &Request) -> Vec<String> {
+    req.headers
+        .iter()
+        .filter_map(|h| match h {
+            Header::Route(route) => Some(route.value().to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_route_set_loose() -> Result<()> {
+    // the UAS keeps the Record-Route order of the INVITE
+    let invite = make_invite(
+        "Record-Route: <sip:p2.example.com;lr>\r\n\
+         Record-Route: <sip:p1.example.com;lr>\r\n",
+    )?;
+    let dialog = make_dialog(TransactionRole::Server, invite).await?;
+    let bye = dialog.make_request(rsip::Method::Bye, None, None, None, None)?;
+    assert_eq!(bye.uri.to_string(), "sip:alice@10.0.0.1:5070");
+    assert_eq!(
+        routes(&bye),
+        vec!["<sip:p2.example.com;lr>", "<sip:p1.example.com;lr>"]
+    );
+
+    // the UAC reverses the Record-Route of the 2xx
+    let invite = make_invite("")?;
+    let dialog = make_dialog(TransactionRole::Client, invite).await?;
+    let resp = match SipMessage::try_from(
+        "SIP/2.0 200 OK\r\n\
+         Via: SIP/2.0/UDP 127.0.0.1:5070;branch=z9hG4bKroute\r\n\
+         Record-Route: <sip:p2.example.com;lr>, <sip:p1.example.com;lr>\r\n\
+         From: <sip:alice@127.0.0.1>;tag=uac-tag\r\n\
+         To: <sip:bob@127.0.0.1>;tag=uas-tag\r\n\
+         Call-ID: route-set-test\r\n\
+         CSeq: 1 INVITE\r\n\
+         Contact: <sip:bob@10.0.0.2:5060>\r\n\
+         Content-Length: 0\r\n\r\n",
+    )? {
+        SipMessage::Response(resp) => resp,
+        _ => panic!("not a response"),
+    };
+    dialog.update_remote_target(&resp)?;
+    dialog.update_route_set(&resp);
+    let bye = dialog.make_request(rsip::Method::Bye, None, None, None, None)?;
+    assert_eq!(bye.uri.to_string(), "sip:bob@10.0.0.2:5060");
+    assert_eq!(
+        routes(&bye),
+        vec!["<sip:p1.example.com;lr>", "<sip:p2.example.com;lr>"]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_route_set_strict() -> Result<()> {
+    let invite = make_invite(
+        "Record-Route: <sip:strict.example.com>\r\n\
+         Record-Route: <sip:p1.example.com;lr>\r\n",
+    )?;
+    let dialog = make_dialog(TransactionRole::Server, invite).await?;
+    let bye = dialog.make_request(rsip::Method::Bye, None, None, None, None)?;
+    // the strict router is the request uri, the remote target the last route
+    assert_eq!(bye.uri.to_string(), "sip:strict.example.com");
+    assert_eq!(
+        routes(&bye),
+        vec!["<sip:p1.example.com;lr>", "<sip:alice@10.0.0.1:5070>"]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_request_extra_headers() -> Result<()> {
+    let invite = make_invite("Record-Route: <sip:p1.example.com;lr>\r\n")?;
+    let dialog = make_dialog(TransactionRole::Server, invite).await?;
+    let headers = vec![
+        Header::Other("X-Carrier".to_string(), "acme".to_string()),
+        Header::CallId("other-call".into()),
+        Header::CSeq("99 BYE".into()),
+        Header::Route(rsip::headers::Route::new("<sip:attacker.example.com;lr>")),
+        Header::Other("Call-ID".to_string(), "other-call".to_string()),
+        Header::MaxForwards(10.into()),
+    ];
+    let bye = dialog.make_request(rsip::Method::Bye, Some(2), None, Some(headers), None)?;
+    let count = |f: fn(&Header) -> bool| bye.headers.iter().filter(|h| f(h)).count();
+
+    // the dialog headers are kept, the others are added or replace the defaults
+    assert_eq!(count(|h| matches!(h, Header::CallId(_))), 1);
+    assert_eq!(bye.call_id_header()?.value(), "route-set-test");
+    assert_eq!(count(|h| matches!(h, Header::CSeq(_))), 1);
+    assert_eq!(bye.cseq_header()?.value(), "2 BYE");
+    assert_eq!(routes(&bye), vec!["<sip:p1.example.com;lr>"]);
+    assert_eq!(
+        count(|h| matches!(h, Header::Other(name, _) if name == "Call-ID")),
+        0
+    );
+    assert_eq!(count(|h| matches!(h, Header::MaxForwards(_))), 1);
+    assert_eq!(bye.max_forwards_header()?.value(), "10");
+    assert_eq!(
+        count(|h| matches!(h, Header::Other(name, _) if name == "X-Carrier")),
+        1
+    );
+    Ok(())
+}
+
+/// Send `request` from `alice` and return it as `bob` received it, answered with 200
+async fn send_to_peer(
+    alice: &Endpoint,
+    bob: &Endpoint,
+    dialog: &DialogInner,
+    request: Request,
+) -> Result<Request> {
+    let peer = async {
+        let mut incoming = bob.incoming_transactions();
+        let mut tx = incoming.recv().await.expect("incoming transaction");
+        tx.reply(rsip::StatusCode::OK).await?;
+        Result::Ok(tx.original.clone())
+    };
+    let r = select! {
+        r = async { tokio::join!(dialog.do_request(request), peer) } => r,
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+    };
+    let (resp, received) = r;
+    assert_eq!(resp?.map(|r| r.status_code), Some(rsip::StatusCode::OK));
+    received
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_do_request_loose_route() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let invite = make_invite("Record-Route: <sip:192.0.2.2:5060;lr>\r\n")?;
+    let dialog = make_endpoint_dialog(&alice, TransactionRole::Server, invite)?;
+    let bye = dialog.make_request(rsip::Method::Bye, None, None, None, None)?;
+
+    // sent to the loose router, which gets the request uri and the route untouched
+    let received = timeout(
+        Duration::from_secs(2),
+        send_to_peer(&alice, &bob, &dialog, bye),
+    )
+    .await
+    .expect("bob must receive the BYE")?;
+    assert_eq!(received.uri.to_string(), "sip:alice@10.0.0.1:5070");
+    assert_eq!(routes(&received), vec!["<sip:192.0.2.2:5060;lr>"]);
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_do_request_strict_route() -> Result<()> {
+    let (alice, bob) = Endpoint::test_pair();
+    let invite = make_invite("Record-Route: <sip:192.0.2.2:5060>\r\n")?;
+    let dialog = make_endpoint_dialog(&alice, TransactionRole::Server, invite)?;
+    let bye = dialog.make_request(rsip::Method::Bye, None, None, None, None)?;
+
+    // sent to the strict router in the request uri, the remote target the last route
+    let received = timeout(
+        Duration::from_secs(2),
+        send_to_peer(&alice, &bob, &dialog, bye),
+    )
+    .await
+    .expect("bob must receive the BYE")?;
+    assert_eq!(received.uri.to_string(), "sip:192.0.2.2:5060");
+    assert_eq!(routes(&received), vec!["<sip:alice@10.0.0.1:5070>"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_do_request_invalid_route() -> Result<()> {
+    let invite = make_invite("")?;
+    let dialog = make_dialog(TransactionRole::Server, invite).await?;
+    let mut bye = dialog.make_request(rsip::Method::Bye, None, None, None, None)?;
+    bye.headers
+        .push_front(Header::Route(rsip::headers::Route::new("<not a uri")));
+    assert!(matches!(
+        dialog.do_request(bye).await,
+        Err(crate::Error::DialogError(..))
+    ));
+    Ok(())
+}
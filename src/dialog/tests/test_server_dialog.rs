@@ -0,0 +1,897 @@
+use crate::dialog::{
+    dialog::{Dialog, DialogState, MAX_HELD_REQUESTS},
+    dialog_layer::{DialogLayer, IncomingHandler},
+    server_dialog::ServerInviteDialog,
+    DialogId,
+};
+use crate::rsip_ext::{DtmfEvent, Reason};
+use crate::transaction::endpoint::{Endpoint, EndpointOption};
+use crate::transport::{udp::UdpConnection, SipAddr, TransportEvent};
+use crate::Result;
+use rsip::{
+    prelude::{HeadersExt, UntypedHeader},
+    Response, SipMessage, StatusCode,
+};
+use std::time::Duration;
+use tokio::{
+    select,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+    time::sleep,
+};
+
+fn make_invite(peer: &SipAddr, target: &SipAddr) -> Result<SipMessage> {
+    let invite = format!(
+        "INVITE sip:bob@{target} SIP/2.0\r\n\
+         Via: SIP/2.0/UDP {peer};branch=z9hG4bKinvite1\r\n\
+         From: <sip:alice@{peer}>;tag=uac-tag\r\n\
+         To: <sip:bob@{target}>\r\n\
+         Call-ID: server-dialog-test\r\n\
+         CSeq: 1 INVITE\r\n\
+         Contact: <sip:alice@{peer}>\r\n\
+         Max-Forwards: 70\r\n\
+         Content-Length: 0\r\n\r\n",
+        peer = peer.addr,
+        target = target.addr,
+    );
+    Ok(SipMessage::try_from(invite)?)
+}
+
+fn make_ack(
+    peer: &SipAddr,
+    target: &SipAddr,
+    cseq: u32,
+    to: &str,
+    body: &str,
+) -> Result<SipMessage> {
+    let ack = format!(
+        "ACK sip:bob@{target} SIP/2.0\r\n\
+         Via: SIP/2.0/UDP {peer};branch=z9hG4bKack{cseq}\r\n\
+         From: <sip:alice@{peer}>;tag=uac-tag\r\n\
+         To: {to}\r\n\
+         Call-ID: server-dialog-test\r\n\
+         CSeq: {cseq} ACK\r\n\
+         Max-Forwards: 70\r\n\
+         Content-Type: application/sdp\r\n\
+         Content-Length: {len}\r\n\r\n{body}",
+        peer = peer.addr,
+        target = target.addr,
+        len = body.len(),
+    );
+    Ok(SipMessage::try_from(ack)?)
+}
+
+fn make_request(peer: &SipAddr, target: &SipAddr, method: &str, to: &str) -> Result<SipMessage> {
+    let request = format!(
+        "{method} sip:bob@{target} SIP/2.0\r\n\
+         Via: SIP/2.0/UDP {peer};branch=z9hG4bK{method}\r\n\
+         From: <sip:alice@{peer}>;tag=uac-tag\r\n\
+         To: {to}\r\n\
+         Call-ID: out-of-dialog-test\r\n\
+         CSeq: 1 {method}\r\n\
+         Max-Forwards: 70\r\n\
+         Content-Length: 0\r\n\r\n",
+        peer = peer.addr,
+        target = target.addr,
+    );
+    Ok(SipMessage::try_from(request)?)
+}
+
+fn make_in_dialog(
+    peer: &SipAddr,
+    target: &SipAddr,
+    method: &str,
+    branch: &str,
+    cseq: u32,
+    to: &str,
+    content: Option<(&str, &str)>,
+) -> Result<SipMessage> {
+    let (content_type, body) = match content {
+        Some((content_type, body)) => (format!("Content-Type: {}\r\n", content_type), body),
+        None => (String::new(), ""),
+    };
+    let request = format!(
+        "{method} sip:bob@{target} SIP/2.0\r\n\
+         Via: SIP/2.0/UDP {peer};branch=z9hG4bK{branch}\r\n\
+         From: <sip:alice@{peer}>;tag=uac-tag\r\n\
+         To: {to}\r\n\
+         Call-ID: server-dialog-test\r\n\
+         CSeq: {cseq} {method}\r\n\
+         Max-Forwards: 70\r\n\
+         {content_type}\
+         Content-Length: {len}\r\n\r\n{body}",
+        peer = peer.addr,
+        target = target.addr,
+        len = body.len(),
+    );
+    Ok(SipMessage::try_from(request)?)
+}
+
+// the next final response to a `method` request, skipping retransmissions of others
+async fn final_response(
+    receiver: &mut UnboundedReceiver<TransportEvent>,
+    method: rsip::Method,
+) -> Result<Response> {
+    while let Some(event) = receiver.recv().await {
+        if let TransportEvent::Incoming(SipMessage::Response(resp), _, _) = event {
+            if resp.status_code.kind() != rsip::StatusCodeKind::Provisional
+                && resp.cseq_header()?.method()? == method
+            {
+                return Ok(resp);
+            }
+        }
+    }
+    panic!("must not reach here");
+}
+
+async fn serve_uas(
+    endpoint: &Endpoint,
+    dialog_layer: &DialogLayer,
+    handler: IncomingHandler,
+) -> Result<()> {
+    let mut incoming = endpoint.incoming_transactions();
+    while let Some(tx) = incoming.recv().await {
+        dialog_layer.handle_incoming(tx, &handler).await?;
+    }
+    Ok(())
+}
+
+// send the INVITE, then ACK every 200 with `ack_cseq` when set
+async fn serve_uac(peer: &UdpConnection, target: &SipAddr, ack_cseq: Option<u32>) -> Result<()> {
+    let (sender, mut receiver) = unbounded_channel();
+    peer.send(make_invite(peer.get_addr(), target)?, Some(target))
+        .await?;
+    let receive_loop = async {
+        while let Some(event) = receiver.recv().await {
+            let resp = match event {
+                TransportEvent::Incoming(SipMessage::Response(resp), _, _) => resp,
+                _ => continue,
+            };
+            if let (StatusCode::OK, Some(cseq)) = (&resp.status_code, ack_cseq) {
+                // the answer comes in the ACK
+                let to = resp.to_header()?.value().to_string();
+                let ack = make_ack(peer.get_addr(), target, cseq, &to, "v=0\r\n")?;
+                peer.send(ack, Some(target)).await?;
+            }
+        }
+        Result::Ok(())
+    };
+    select! {
+        r = receive_loop => r,
+        r = peer.serve_loop(sender) => r,
+    }
+}
+
+// the states of the dialog, then the dialogs left in the layer
+async fn run_uas(
+    option: EndpointOption,
+    ack_cseq: Option<u32>,
+) -> Result<(Vec<DialogState>, Vec<(DialogId, DialogState)>)> {
+    let endpoint = super::create_test_endpoint_with_option(option).await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+    let target = endpoint.get_addrs()[0].clone();
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+
+    let (state_sender, mut state_receiver) = unbounded_channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: None,
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    let accept_loop = async {
+        while let Some(dialog) = invite_receiver.recv().await {
+            dialog.accept(None, None)?;
+        }
+        Result::Ok(())
+    };
+    let state_loop = async {
+        let mut states = vec![];
+        while let Some(state) = state_receiver.recv().await {
+            match &state {
+                DialogState::Confirmed(_) | DialogState::Terminated(_, _, _) => {
+                    states.push(state);
+                    break;
+                }
+                _ => {}
+            }
+            states.push(state);
+        }
+        Result::Ok(states)
+    };
+
+    let states = select! {
+        r = state_loop => r?,
+        _ = accept_loop => panic!("must not reach here"),
+        _ = serve_uas(&endpoint, &dialog_layer, handler) => panic!("must not reach here"),
+        _ = serve_uac(&peer, &target, ack_cseq) => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    Ok((states, dialog_layer.iter().collect()))
+}
+
+#[tokio::test]
+async fn test_server_dialog_ack() -> Result<()> {
+    let (states, dialogs) = run_uas(EndpointOption::default(), Some(1)).await?;
+    match &states[..] {
+        [DialogState::Calling(_), DialogState::Ack(_, ack), DialogState::Confirmed(id)] => {
+            assert_eq!(ack.body, b"v=0\r\n");
+            assert!(matches!(&dialogs[..], [(d, DialogState::Confirmed(_))] if d == id));
+        }
+        _ => panic!(
+            "unexpected states: {}",
+            states
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_dialog_ack_timeout() -> Result<()> {
+    let option = EndpointOption {
+        t1: Duration::from_millis(10),
+        t2: Duration::from_millis(40),
+        t4: Duration::from_millis(50),
+        ..Default::default()
+    };
+    // an ACK for another INVITE does not confirm the dialog
+    let (states, dialogs) = run_uas(option, Some(2)).await?;
+    match states.last() {
+        Some(DialogState::Terminated(_, Some(StatusCode::RequestTimeout), _)) => {}
+        _ => panic!(
+            "unexpected states: {}",
+            states
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+    assert!(!states.iter().any(|s| matches!(s, DialogState::Ack(_, _))));
+    // the terminated dialog is removed from the layer
+    assert!(dialogs.is_empty());
+    Ok(())
+}
+
+/// Without ACK the 2xx is retransmitted until 64*T1, then the dialog is hung up
+#[tokio::test]
+async fn test_server_dialog_no_ack_bye() -> Result<()> {
+    let endpoint = super::create_test_endpoint_with_option(EndpointOption {
+        t1: Duration::from_millis(10),
+        t2: Duration::from_millis(40),
+        t4: Duration::from_millis(50),
+        ..Default::default()
+    })
+    .await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+    let target = endpoint.get_addrs()[0].clone();
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+
+    let (state_sender, mut state_receiver) = unbounded_channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: None,
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    let accept_loop = async {
+        while let Some(dialog) = invite_receiver.recv().await {
+            dialog.accept(None, None)?;
+        }
+        Result::Ok(())
+    };
+    // count the 200s of the INVITE, answer the BYE
+    let (sender, mut receiver) = unbounded_channel();
+    let uac_loop = async {
+        peer.send(make_invite(peer.get_addr(), &target)?, Some(&target))
+            .await?;
+        let mut oks = 0;
+        while let Some(event) = receiver.recv().await {
+            match event {
+                TransportEvent::Incoming(SipMessage::Response(resp), _, _)
+                    if resp.status_code == StatusCode::OK =>
+                {
+                    oks += 1;
+                }
+                TransportEvent::Incoming(SipMessage::Request(req), _, from)
+                    if req.method == rsip::Method::Bye =>
+                {
+                    let ok = endpoint.inner.make_response(&req, StatusCode::OK, None);
+                    peer.send(ok.into(), Some(&from)).await?;
+                    return Result::Ok(oks);
+                }
+                _ => {}
+            }
+        }
+        panic!("must not reach here");
+    };
+    let state_loop = async {
+        while let Some(state) = state_receiver.recv().await {
+            if let DialogState::Terminated(_, reason, _) = state {
+                return reason;
+            }
+        }
+        None
+    };
+
+    let (oks, reason) = select! {
+        r = async { tokio::join!(uac_loop, state_loop) } => r,
+        _ = accept_loop => panic!("must not reach here"),
+        _ = serve_uas(&endpoint, &dialog_layer, handler) => panic!("must not reach here"),
+        _ = peer.serve_loop(sender) => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    // at 0, 10, 30, 70, 110 ms and so on every T2
+    let oks = oks?;
+    assert!(oks >= 10, "{} 200s sent", oks);
+    assert_eq!(reason, Some(StatusCode::RequestTimeout));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handle_incoming_out_of_dialog() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+    let target = endpoint.get_addrs()[0].clone();
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+
+    let (request_sender, mut request_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender: unbounded_channel().0,
+        credential: None,
+        contact: None,
+        invite_sender: unbounded_channel().0,
+        request_sender,
+    };
+    let to = format!("<sip:bob@{}>", target.addr);
+    peer.send(
+        make_request(peer.get_addr(), &target, "OPTIONS", &to)?,
+        Some(&target),
+    )
+    .await?;
+    // an in-dialog request without dialog
+    let to = format!("<sip:bob@{}>;tag=unknown", target.addr);
+    peer.send(
+        make_request(peer.get_addr(), &target, "INFO", &to)?,
+        Some(&target),
+    )
+    .await?;
+
+    let (sender, mut receiver) = unbounded_channel();
+    let uac_loop = async {
+        while let Some(event) = receiver.recv().await {
+            if let TransportEvent::Incoming(SipMessage::Response(resp), _, _) = event {
+                return Result::Ok(resp);
+            }
+        }
+        panic!("must not reach here");
+    };
+    let (options, resp) = select! {
+        r = async { tokio::join!(request_receiver.recv(), uac_loop) } => r,
+        _ = serve_uas(&endpoint, &dialog_layer, handler) => panic!("must not reach here"),
+        _ = peer.serve_loop(sender) => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(
+        options.map(|tx| tx.original.method),
+        Some(rsip::Method::Options)
+    );
+    let resp = resp?;
+    assert_eq!(resp.status_code, StatusCode::CallTransactionDoesNotExist);
+    assert_eq!(resp.cseq_header()?.method()?, rsip::Method::Info);
+    Ok(())
+}
+
+type InDialogRequest = (&'static str, u32, Option<(&'static str, &'static str)>);
+
+// confirm a dialog, then send the INFO `requests` by branch, cseq and content,
+// returns the status codes and the states after Confirmed
+async fn run_info(requests: Vec<InDialogRequest>) -> Result<(Vec<StatusCode>, Vec<DialogState>)> {
+    let endpoint = super::create_test_endpoint().await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+    let target = endpoint.get_addrs()[0].clone();
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+
+    let (state_sender, mut state_receiver) = unbounded_channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: None,
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    let accept_loop = async {
+        while let Some(dialog) = invite_receiver.recv().await {
+            dialog.accept(None, None)?;
+        }
+        Result::Ok(())
+    };
+
+    let (sender, mut receiver) = unbounded_channel();
+    let uac_loop = async {
+        peer.send(make_invite(peer.get_addr(), &target)?, Some(&target))
+            .await?;
+        let to = final_response(&mut receiver, rsip::Method::Invite)
+            .await?
+            .to_header()?
+            .value()
+            .to_string();
+        peer.send(
+            make_ack(peer.get_addr(), &target, 1, &to, "")?,
+            Some(&target),
+        )
+        .await?;
+        while let Some(state) = state_receiver.recv().await {
+            if matches!(state, DialogState::Confirmed(_)) {
+                break;
+            }
+        }
+
+        let mut codes = vec![];
+        for (branch, cseq, content) in requests {
+            let info =
+                make_in_dialog(peer.get_addr(), &target, "INFO", branch, cseq, &to, content)?;
+            peer.send(info, Some(&target)).await?;
+            codes.push(
+                final_response(&mut receiver, rsip::Method::Info)
+                    .await?
+                    .status_code,
+            );
+        }
+        Result::Ok(codes)
+    };
+
+    let codes = select! {
+        r = uac_loop => r?,
+        _ = accept_loop => panic!("must not reach here"),
+        _ = serve_uas(&endpoint, &dialog_layer, handler) => panic!("must not reach here"),
+        _ = peer.serve_loop(sender) => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    let mut states = vec![];
+    while let Ok(state) = state_receiver.try_recv() {
+        states.push(state);
+    }
+    Ok((codes, states))
+}
+
+#[tokio::test]
+async fn test_server_dialog_retransmission() -> Result<()> {
+    // the same INFO in a new transaction, then an older one
+    let (codes, states) = run_info(vec![
+        ("info1", 2, None),
+        ("info2", 2, None),
+        ("info3", 1, None),
+    ])
+    .await?;
+    assert_eq!(
+        codes,
+        vec![
+            StatusCode::OK,
+            StatusCode::OK,
+            StatusCode::ServerInternalError
+        ]
+    );
+    // the retransmission does not reach the application
+    let infos = states
+        .iter()
+        .filter(|s| matches!(s, DialogState::Info(_, _)))
+        .count();
+    assert_eq!(infos, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_dialog_invite_retransmission() -> Result<()> {
+    // no 100 Trying, the retransmission comes before any response
+    let endpoint = super::create_test_endpoint_with_option(EndpointOption {
+        auto_trying: false,
+        ..Default::default()
+    })
+    .await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+    let target = endpoint.get_addrs()[0].clone();
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+
+    let (state_sender, _state_receiver) = unbounded_channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: None,
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    // the INVITE twice, then the same request in another transaction
+    let (sender, mut receiver) = unbounded_channel();
+    let uac_loop = async {
+        let invite = make_invite(peer.get_addr(), &target)?;
+        peer.send(invite.clone(), Some(&target)).await?;
+        peer.send(invite.clone(), Some(&target)).await?;
+        sleep(Duration::from_millis(50)).await;
+        let merged = invite
+            .to_string()
+            .replace("z9hG4bKinvite1", "z9hG4bKinvite2");
+        peer.send(SipMessage::try_from(merged)?, Some(&target))
+            .await?;
+        final_response(&mut receiver, rsip::Method::Invite).await
+    };
+    let resp = select! {
+        r = uac_loop => r?,
+        _ = serve_uas(&endpoint, &dialog_layer, handler) => panic!("must not reach here"),
+        _ = peer.serve_loop(sender) => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(resp.status_code, StatusCode::LoopDetected);
+    assert!(resp.via_header()?.value().contains("z9hG4bKinvite2"));
+    let mut dialogs = vec![];
+    while let Ok(dialog) = invite_receiver.try_recv() {
+        dialogs.push(dialog);
+    }
+    assert_eq!(dialogs.len(), 1);
+    assert_eq!(dialog_layer.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_dialog_dtmf() -> Result<()> {
+    let (codes, states) = run_info(vec![
+        (
+            "dtmf1",
+            2,
+            Some(("application/dtmf-relay", "Signal=5\r\nDuration=160\r\n")),
+        ),
+        // not a valid digit, passed as a plain INFO
+        ("dtmf2", 3, Some(("application/dtmf-relay", "Signal=X\r\n"))),
+    ])
+    .await?;
+    assert_eq!(codes, vec![StatusCode::OK, StatusCode::OK]);
+    match &states[..] {
+        [DialogState::Dtmf(_, event), DialogState::Info(_, info)] => {
+            assert_eq!(
+                event,
+                &DtmfEvent {
+                    digit: '5',
+                    duration: 160
+                }
+            );
+            assert_eq!(info.body, b"Signal=X\r\n");
+        }
+        _ => panic!(
+            "unexpected states: {}",
+            states
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_dialog_bye_reason() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+    let target = endpoint.get_addrs()[0].clone();
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+
+    let (state_sender, mut state_receiver) = unbounded_channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: None,
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    let accept_loop = async {
+        while let Some(dialog) = invite_receiver.recv().await {
+            dialog.accept(None, None)?;
+        }
+        Result::Ok(())
+    };
+
+    let (sender, mut receiver) = unbounded_channel();
+    let uac_loop = async {
+        peer.send(make_invite(peer.get_addr(), &target)?, Some(&target))
+            .await?;
+        let to = final_response(&mut receiver, rsip::Method::Invite)
+            .await?
+            .to_header()?
+            .value()
+            .to_string();
+        peer.send(
+            make_ack(peer.get_addr(), &target, 1, &to, "")?,
+            Some(&target),
+        )
+        .await?;
+        while let Some(state) = state_receiver.recv().await {
+            if matches!(state, DialogState::Confirmed(_)) {
+                break;
+            }
+        }
+        let mut bye = make_in_dialog(peer.get_addr(), &target, "BYE", "bye1", 2, &to, None)?;
+        if let SipMessage::Request(req) = &mut bye {
+            req.headers.push(rsip::Header::Other(
+                "Reason".into(),
+                "Q.850;cause=16;text=\"Normal call clearing\"".into(),
+            ));
+        }
+        peer.send(bye, Some(&target)).await?;
+        let code = final_response(&mut receiver, rsip::Method::Bye)
+            .await?
+            .status_code;
+        while let Some(state) = state_receiver.recv().await {
+            if let DialogState::Terminated(_, status, reason) = state {
+                return Result::Ok((code, status, reason));
+            }
+        }
+        panic!("must not reach here");
+    };
+
+    let (code, status, reason) = select! {
+        r = uac_loop => r?,
+        _ = accept_loop => panic!("must not reach here"),
+        _ = serve_uas(&endpoint, &dialog_layer, handler) => panic!("must not reach here"),
+        _ = peer.serve_loop(sender) => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(code, StatusCode::OK);
+    assert_eq!(status, None);
+    assert_eq!(
+        reason,
+        Some(Reason::q850(16).with_text("Normal call clearing"))
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_dialog_pause() -> Result<()> {
+    let endpoint = super::create_test_endpoint().await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+    let target = endpoint.get_addrs()[0].clone();
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+
+    let (state_sender, mut state_receiver) = unbounded_channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: None,
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+
+    let (sender, mut receiver) = unbounded_channel();
+    let uac_loop = async {
+        peer.send(make_invite(peer.get_addr(), &target)?, Some(&target))
+            .await?;
+        let dialog = invite_receiver.recv().await.expect("invite");
+        dialog.accept(None, None)?;
+        let dialog = Dialog::ServerInvite(dialog);
+        let to = final_response(&mut receiver, rsip::Method::Invite)
+            .await?
+            .to_header()?
+            .value()
+            .to_string();
+        peer.send(
+            make_ack(peer.get_addr(), &target, 1, &to, "")?,
+            Some(&target),
+        )
+        .await?;
+        while let Some(state) = state_receiver.recv().await {
+            if matches!(state, DialogState::Confirmed(_)) {
+                break;
+            }
+        }
+        let info = |cseq: u32| {
+            make_in_dialog(
+                peer.get_addr(),
+                &target,
+                "INFO",
+                &format!("info{}", cseq),
+                cseq,
+                &to,
+                None,
+            )
+        };
+        // the cseq and status of the next INFO response
+        async fn next_info(
+            receiver: &mut UnboundedReceiver<TransportEvent>,
+        ) -> Result<(u32, StatusCode)> {
+            let resp = final_response(receiver, rsip::Method::Info).await?;
+            Ok((resp.cseq_header()?.seq()?, resp.status_code))
+        }
+
+        // held until resumed, then answered in order
+        dialog.pause();
+        for cseq in [2, 3] {
+            peer.send(info(cseq)?, Some(&target)).await?;
+        }
+        select! {
+            _ = next_info(&mut receiver) => panic!("a held request is not answered"),
+            _ = sleep(Duration::from_millis(100)) => {}
+        }
+        dialog.resume().await;
+        assert!(!dialog.is_paused());
+        assert_eq!(next_info(&mut receiver).await?, (2, StatusCode::OK));
+        assert_eq!(next_info(&mut receiver).await?, (3, StatusCode::OK));
+
+        // the one over the bound is answered 500, a BYE is not held and the
+        // held requests are answered 487
+        dialog.pause();
+        let count = MAX_HELD_REQUESTS as u32 + 1;
+        for cseq in 4..4 + count {
+            peer.send(info(cseq)?, Some(&target)).await?;
+        }
+        assert_eq!(
+            next_info(&mut receiver).await?,
+            (3 + count, StatusCode::ServerInternalError)
+        );
+        let bye = make_in_dialog(
+            peer.get_addr(),
+            &target,
+            "BYE",
+            "bye1",
+            4 + count,
+            &to,
+            None,
+        )?;
+        peer.send(bye, Some(&target)).await?;
+        let bye_status = final_response(&mut receiver, rsip::Method::Bye)
+            .await?
+            .status_code;
+        let mut held = vec![];
+        for _ in 1..count {
+            held.push(next_info(&mut receiver).await?);
+        }
+        Result::Ok((bye_status, held, dialog.state()))
+    };
+
+    let (bye_status, held, state) = select! {
+        r = uac_loop => r?,
+        _ = serve_uas(&endpoint, &dialog_layer, handler) => panic!("must not reach here"),
+        _ = peer.serve_loop(sender) => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(bye_status, StatusCode::OK);
+    let expected: Vec<_> = (4..3 + MAX_HELD_REQUESTS as u32 + 1)
+        .map(|cseq| (cseq, StatusCode::RequestTerminated))
+        .collect();
+    assert_eq!(held, expected);
+    assert!(matches!(state, DialogState::Terminated(_, _, _)));
+    Ok(())
+}
+
+// answer the INVITE with `answer`, the final response of the UAC and the last state
+async fn run_reject(
+    answer: impl Fn(&ServerInviteDialog) -> Result<()>,
+) -> Result<(Response, DialogState)> {
+    let endpoint = super::create_test_endpoint().await?;
+    let dialog_layer = DialogLayer::new(endpoint.inner.clone());
+    let target = endpoint.get_addrs()[0].clone();
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+
+    let (state_sender, mut state_receiver) = unbounded_channel();
+    let (invite_sender, mut invite_receiver) = unbounded_channel();
+    let handler = IncomingHandler {
+        state_sender,
+        credential: None,
+        contact: None,
+        invite_sender,
+        request_sender: unbounded_channel().0,
+    };
+    let answer_loop = async {
+        while let Some(dialog) = invite_receiver.recv().await {
+            answer(&dialog)?;
+        }
+        Result::Ok(())
+    };
+    let state_loop = async {
+        while let Some(state) = state_receiver.recv().await {
+            if let DialogState::Terminated(_, _, _) = state {
+                return state;
+            }
+        }
+        panic!("must not reach here");
+    };
+    let uac = async {
+        let (sender, mut receiver) = unbounded_channel();
+        peer.send(make_invite(peer.get_addr(), &target)?, Some(&target))
+            .await?;
+        select! {
+            r = final_response(&mut receiver, rsip::Method::Invite) => r,
+            _ = peer.serve_loop(sender) => panic!("must not reach here"),
+        }
+    };
+
+    let (resp, state) = select! {
+        r = async { tokio::join!(uac, state_loop) } => r,
+        _ = answer_loop => panic!("must not reach here"),
+        _ = serve_uas(&endpoint, &dialog_layer, handler) => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert!(dialog_layer.is_empty());
+    Ok((resp?, state))
+}
+
+fn header_values(resp: &Response, name: &str) -> Vec<String> {
+    resp.headers
+        .iter()
+        .filter_map(|h| {
+            let header = h.to_string();
+            let (header_name, value) = header.split_once(':')?;
+            header_name
+                .eq_ignore_ascii_case(name)
+                .then(|| value.trim().to_string())
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_server_dialog_reject() -> Result<()> {
+    let (resp, state) = run_reject(|dialog| {
+        dialog.reject_with(
+            StatusCode::BusyHere,
+            Some("on another call".to_string()),
+            Some(30),
+        )
+    })
+    .await?;
+    assert_eq!(resp.status_code, StatusCode::BusyHere);
+    assert_eq!(
+        header_values(&resp, "Warning"),
+        vec!["399 rsipstack \"on another call\""]
+    );
+    assert_eq!(header_values(&resp, "Retry-After"), vec!["30"]);
+    assert!(resp.to_header()?.tag()?.is_some());
+    assert!(matches!(
+        state,
+        DialogState::Terminated(_, Some(StatusCode::BusyHere), _)
+    ));
+
+    // no Retry-After on a 403
+    let (resp, _) =
+        run_reject(|dialog| dialog.reject_with(StatusCode::Forbidden, None, Some(30))).await?;
+    assert_eq!(resp.status_code, StatusCode::Forbidden);
+    assert!(header_values(&resp, "Retry-After").is_empty());
+    assert!(header_values(&resp, "Warning").is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_dialog_redirect() -> Result<()> {
+    let contacts = vec![
+        rsip::Uri::try_from("sip:bob@192.0.2.1")?,
+        rsip::Uri::try_from("sip:bob@192.0.2.2")?,
+    ];
+    let (resp, state) = run_reject(|dialog| dialog.redirect(contacts.clone())).await?;
+    assert_eq!(resp.status_code, StatusCode::MovedTemporarily);
+    assert_eq!(
+        header_values(&resp, "Contact"),
+        vec!["<sip:bob@192.0.2.1>", "<sip:bob@192.0.2.2>"]
+    );
+    assert!(resp.to_header()?.tag()?.is_some());
+    assert!(matches!(
+        state,
+        DialogState::Terminated(_, Some(StatusCode::MovedTemporarily), _)
+    ));
+    Ok(())
+}
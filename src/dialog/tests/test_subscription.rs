@@ -0,0 +1,180 @@
+use crate::dialog::{
+    dialog::DialogState,
+    dialog_layer::DialogLayer,
+    subscription::{ClientSubscribeDialog, SubscribeOption},
+};
+use crate::transaction::endpoint::Endpoint;
+use crate::Result;
+use rsip::{prelude::HeadersExt, Header, StatusCode};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    select,
+    sync::mpsc::unbounded_channel,
+    time::{sleep, Instant},
+};
+
+type Reply = (StatusCode, Vec<Header>);
+
+fn ok(expires: u32) -> Reply {
+    (StatusCode::OK, vec![Header::Expires(expires.into())])
+}
+
+// subscribe and serve the refreshes for `duration`, bob answers them with
+// `replies` in order, then with a 200 granting what is asked. Returns the
+// seconds after the first SUBSCRIBE and the Expires of each one, and the
+// states of the dialog
+async fn run_refreshes(
+    replies: Vec<Reply>,
+    duration: Duration,
+) -> Result<(Vec<(u64, u32)>, Vec<DialogState>)> {
+    let (alice, bob) = Endpoint::test_pair();
+    let alice_layer = DialogLayer::new(alice.inner.clone());
+    let received = Arc::new(Mutex::new(vec![]));
+    let mut incoming = bob.incoming_transactions();
+    let bob_loop = async {
+        let start = Instant::now();
+        let mut replies = replies.into_iter();
+        while let Some(mut tx) = incoming.recv().await {
+            let expires = tx.original.expires_header().expect("expires").seconds()?;
+            received
+                .lock()
+                .unwrap()
+                .push((start.elapsed().as_secs(), expires));
+            let (status, headers) = replies.next().unwrap_or_else(|| ok(expires));
+            tx.reply_with(status, headers, None).await?;
+        }
+        Result::Ok(())
+    };
+    let (state_sender, mut state_receiver) = unbounded_channel();
+    let alice_loop = async {
+        let opt = SubscribeOption {
+            caller: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            callee: rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            event: "presence".to_string(),
+            accept: None,
+            expires: Some(60),
+            contact: rsip::Uri::try_from("sip:alice@192.0.2.1:5060")?,
+            credential: None,
+        };
+        let (dialog, resp): (ClientSubscribeDialog, _) =
+            alice_layer.do_subscribe(opt, state_sender).await?;
+        assert_eq!(resp.map(|r| r.status_code), Some(StatusCode::OK));
+        select! {
+            r = dialog.serve() => r?,
+            _ = sleep(duration) => {}
+        }
+        Result::Ok(())
+    };
+    select! {
+        r = alice_loop => r?,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(duration * 2) => panic!("timeout waiting"),
+    };
+    let mut states = vec![];
+    while let Ok(state) = state_receiver.try_recv() {
+        states.push(state);
+    }
+    let received = received.lock().unwrap().clone();
+    Ok((received, states))
+}
+
+fn terminated(states: &[DialogState]) -> Vec<Option<StatusCode>> {
+    states
+        .iter()
+        .filter_map(|s| match s {
+            DialogState::Terminated(_, status, _) => Some(status.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_subscribe_refresh_interval_too_brief() -> Result<()> {
+    let (received, states) = run_refreshes(
+        vec![
+            ok(60),
+            (
+                StatusCode::IntervalTooBrief,
+                vec![Header::MinExpires("120".into())],
+            ),
+        ],
+        Duration::from_secs(200),
+    )
+    .await?;
+    // refreshed at 90% of 60s, retried at once with the minimum, then
+    // refreshed at 90% of 120s
+    assert_eq!(received, vec![(0, 60), (54, 60), (54, 120), (162, 120)]);
+    assert!(terminated(&states).is_empty());
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_subscribe_refresh_retry_after() -> Result<()> {
+    let unavailable = |seconds: &str| {
+        (
+            StatusCode::ServiceUnavailable,
+            vec![Header::RetryAfter(seconds.into())],
+        )
+    };
+    let (received, states) = run_refreshes(
+        vec![ok(60), unavailable("30 (overloaded)")],
+        Duration::from_secs(150),
+    )
+    .await?;
+    // the retry waits for the Retry-After, then the refreshes go on
+    assert_eq!(received, vec![(0, 60), (54, 60), (84, 60), (138, 60)]);
+    assert!(terminated(&states).is_empty());
+
+    // three retries at most, then the subscription ends with the last status
+    let (received, states) = run_refreshes(
+        vec![
+            ok(60),
+            unavailable("10"),
+            unavailable("10"),
+            unavailable("10"),
+            unavailable("10"),
+        ],
+        Duration::from_secs(200),
+    )
+    .await?;
+    let times: Vec<u64> = received.iter().map(|(t, _)| *t).collect();
+    assert_eq!(times, vec![0, 54, 64, 74, 84]);
+    assert_eq!(
+        terminated(&states),
+        vec![Some(StatusCode::ServiceUnavailable)]
+    );
+
+    // a 503 without Retry-After is not retried
+    let (received, states) = run_refreshes(
+        vec![ok(60), (StatusCode::ServiceUnavailable, vec![])],
+        Duration::from_secs(200),
+    )
+    .await?;
+    assert_eq!(received.len(), 2);
+    assert_eq!(
+        terminated(&states),
+        vec![Some(StatusCode::ServiceUnavailable)]
+    );
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_subscribe_refresh_no_subscription() -> Result<()> {
+    let (received, states) = run_refreshes(
+        vec![ok(60), (StatusCode::CallTransactionDoesNotExist, vec![])],
+        Duration::from_secs(200),
+    )
+    .await?;
+    // terminated once, nothing is sent after the 481
+    assert_eq!(received.len(), 2);
+    assert_eq!(
+        terminated(&states),
+        vec![Some(StatusCode::CallTransactionDoesNotExist)]
+    );
+    Ok(())
+}
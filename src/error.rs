@@ -1,16 +1,109 @@
 use crate::{dialog::DialogId, transaction::key::TransactionKey, transport::SipAddr};
-use std::env::VarError;
+use std::{
+    env::VarError,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use wasm_bindgen::prelude::*;
 
+/// What failed below the SIP layer, to tell retriable network failures
+/// from configuration and protocol errors
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum TransportErrorKind {
+    /// the target refused the connection
+    ConnectionRefused,
+    /// the connection was reset or closed while in use
+    ConnectionClosed,
+    /// the target did not answer in time
+    Timeout,
+    /// no route to the target network or host
+    Unreachable,
+    /// the TLS or WebSocket handshake failed
+    Handshake,
+    /// the peer certificate was rejected
+    TlsCertificate,
+    /// the target transport is not supported or not configured
+    Unsupported,
+    Other,
+}
+
+impl TransportErrorKind {
+    /// true if another target, or the same one later, may succeed
+    pub fn is_retriable(&self) -> bool {
+        matches!(
+            self,
+            TransportErrorKind::ConnectionRefused
+                | TransportErrorKind::ConnectionClosed
+                | TransportErrorKind::Timeout
+                | TransportErrorKind::Unreachable
+                | TransportErrorKind::Handshake
+        )
+    }
+}
+
+impl From<&std::io::Error> for TransportErrorKind {
+    fn from(e: &std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::ConnectionRefused => TransportErrorKind::ConnectionRefused,
+            std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::NotConnected
+            | std::io::ErrorKind::UnexpectedEof => TransportErrorKind::ConnectionClosed,
+            std::io::ErrorKind::TimedOut => TransportErrorKind::Timeout,
+            std::io::ErrorKind::HostUnreachable
+            | std::io::ErrorKind::NetworkUnreachable
+            | std::io::ErrorKind::AddrNotAvailable => TransportErrorKind::Unreachable,
+            _ => TransportErrorKind::Other,
+        }
+    }
+}
+
+impl std::fmt::Display for TransportErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self {
+            TransportErrorKind::ConnectionRefused => "connection refused",
+            TransportErrorKind::ConnectionClosed => "connection closed",
+            TransportErrorKind::Timeout => "timeout",
+            TransportErrorKind::Unreachable => "unreachable",
+            TransportErrorKind::Handshake => "handshake failed",
+            TransportErrorKind::TlsCertificate => "certificate rejected",
+            TransportErrorKind::Unsupported => "unsupported",
+            TransportErrorKind::Other => "error",
+        };
+        write!(f, "{}", kind)
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Error {
     SipMessageError(String),
-    DnsResolutionError(String),
-    TransportLayerError(String, SipAddr),
+    /// a received message could not be parsed, `raw` holds its first bytes,
+    /// at most [`parse_error_raw_limit`] of them
+    Parse {
+        message: String,
+        raw: Vec<u8>,
+    },
+    /// the target of a uri could not be resolved (RFC 3263)
+    Resolve(String),
+    /// a connection to `addr` failed, `source` is the underlying error
+    Transport {
+        kind: TransportErrorKind,
+        addr: SipAddr,
+        source: String,
+    },
     TransactionError(String, TransactionKey),
     EndpointError(String),
     DialogError(String, DialogId),
+    /// the peer answered `491 Request Pending` to a re-INVITE of the dialog,
+    /// it may be retried after a backoff (RFC 3261 14.1)
+    RequestPending(DialogId),
+    /// the request can only be sent in a confirmed dialog, or an early one
+    /// for an UPDATE, the dialog is not confirmed yet or already terminated
+    DialogNotConfirmed(DialogId),
     Keepalive,
+    /// a stream carried a message over the size limit, its framing is lost
+    /// and the connection is closed (RFC 3261 18.3)
+    MessageTooLarge,
     Error(String),
 }
 
@@ -18,14 +111,23 @@ impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::SipMessageError(e) => write!(f, "SIP message error: {}", e),
-            Error::DnsResolutionError(e) => write!(f, "DNS resolution error: {}", e),
-            Error::TransportLayerError(e, addr) => {
-                write!(f, "Transport layer error: {}: {}", e, addr)
+            Error::Parse { message, raw } => write!(
+                f,
+                "Parse error: {}: {:?}",
+                message,
+                String::from_utf8_lossy(raw)
+            ),
+            Error::Resolve(e) => write!(f, "DNS resolution error: {}", e),
+            Error::Transport { kind, addr, source } => {
+                write!(f, "Transport error: {}: {}: {}", kind, source, addr)
             }
             Error::TransactionError(e, key) => write!(f, "Transaction error: {}: {}", e, key),
             Error::EndpointError(e) => write!(f, "Endpoint error: {}", e),
             Error::DialogError(e, id) => write!(f, "Dialog error: {}: {}", e, id),
+            Error::RequestPending(id) => write!(f, "Request pending: {}", id),
+            Error::DialogNotConfirmed(id) => write!(f, "Dialog not confirmed: {}", id),
             Error::Keepalive => write!(f, "Keepalive message"),
+            Error::MessageTooLarge => write!(f, "SIP message too large"),
             Error::Error(e) => write!(f, "Error: {}", e),
         }
     }
@@ -34,17 +136,65 @@ impl std::fmt::Display for Error {
 impl Into<JsValue> for Error {
     fn into(self) -> JsValue {
         match self {
-            Error::DnsResolutionError(e) => e.into(),
+            Error::Resolve(e) => e.into(),
             Error::SipMessageError(e) => e.into(),
-            Error::TransportLayerError(e, _) => e.into(),
+            Error::Parse { message, .. } => message.into(),
+            Error::Transport { kind, source, .. } => format!("{}: {}", kind, source).into(),
             Error::TransactionError(e, key) => format!("{}: {}", e, key.to_string()).into(),
             Error::EndpointError(e) => e.into(),
             Error::DialogError(e, id) => format!("{}: {}", e, id.to_string()).into(),
+            Error::RequestPending(id) => format!("request pending: {}", id).into(),
+            Error::DialogNotConfirmed(id) => format!("dialog not confirmed: {}", id).into(),
             Error::Keepalive => "Keepalive message".into(),
+            Error::MessageTooLarge => "SIP message too large".into(),
             Error::Error(e) => e.into(),
         }
     }
 }
+/// the default of [`parse_error_raw_limit`]
+pub const DEFAULT_PARSE_ERROR_RAW_LIMIT: usize = 1024;
+
+static PARSE_ERROR_RAW_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_PARSE_ERROR_RAW_LIMIT);
+
+/// how many bytes of a malformed message an [`Error::Parse`] keeps
+pub fn parse_error_raw_limit() -> usize {
+    PARSE_ERROR_RAW_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Change how many bytes of a malformed message an [`Error::Parse`] keeps, for
+/// all the transports. Zero keeps none.
+pub fn set_parse_error_raw_limit(limit: usize) {
+    PARSE_ERROR_RAW_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+impl Error {
+    /// `raw` failed to parse, only its first bytes are kept
+    pub(crate) fn parse(message: impl std::fmt::Display, raw: &[u8]) -> Self {
+        let limit = raw.len().min(parse_error_raw_limit());
+        Error::Parse {
+            message: message.to_string(),
+            raw: raw[..limit].to_vec(),
+        }
+    }
+
+    /// a failed socket operation on the connection to `addr`
+    pub(crate) fn transport(e: std::io::Error, addr: &SipAddr) -> Self {
+        Error::Transport {
+            kind: TransportErrorKind::from(&e),
+            addr: addr.clone(),
+            source: e.to_string(),
+        }
+    }
+
+    /// true for network failures worth retrying, possibly on another target
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            Error::Transport { kind, .. } => kind.is_retriable(),
+            _ => false,
+        }
+    }
+}
+
 impl From<rsip::Error> for Error {
     fn from(e: rsip::Error) -> Self {
         Error::SipMessageError(e.to_string())
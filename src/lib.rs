@@ -7,5 +7,6 @@ pub mod transaction;
 pub mod transport;
 pub use transaction::EndpointBuilder;
 pub mod rsip_ext;
+pub mod sdp;
 
-const USER_AGENT: &str = "rsipstack/0.1";
+const USER_AGENT: &str = concat!("rsipstack/", env!("CARGO_PKG_VERSION"));
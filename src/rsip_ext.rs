@@ -1,3 +1,5 @@
+use rsip::prelude::{ToTypedHeader, UntypedHeader};
+
 pub trait RsipMessageExt {}
 pub trait RsipHeadersExt {
     fn push_front(&mut self, header: rsip::Header);
@@ -26,7 +28,20 @@ macro_rules! header_pop {
     };
 }
 
+/// The uri of a Contact, a remote target, with its transport and GRUU params only
 pub fn extract_uri_from_contact(line: &str) -> crate::Result<rsip::Uri> {
+    if let Some(uri) = line.split('<').nth(1).and_then(|s| s.split('>').next()) {
+        if uri.contains('[') || uri.contains(";gr") {
+            let mut uri = parse_uri(uri)?;
+            // a GRUU is used as is (RFC 5627 4.5)
+            uri.params.retain(|p| match p {
+                rsip::Param::Transport(_) => true,
+                rsip::Param::Other(name, _) => name.value().eq_ignore_ascii_case("gr"),
+                _ => false,
+            });
+            return Ok(uri);
+        }
+    }
     match rsip::headers::Contact::try_from(line) {
         Ok(contact) => {
             match contact.uri() {
@@ -47,6 +62,1197 @@ pub fn extract_uri_from_contact(line: &str) -> crate::Result<rsip::Uri> {
     }
 }
 
+/// parsed by rsip in place of an IPv6 reference, it parses IPv4 and domain hosts only
+const IPV6_PLACEHOLDER: &str = "ipv6.invalid";
+
+/// `value` with its IPv6 reference (RFC 3261 25.1) replaced by the placeholder,
+/// and the host to put back once parsed
+fn split_ipv6_reference(value: &str) -> Option<(String, rsip::Host)> {
+    let start = value.find('[')?;
+    let end = start + value[start..].find(']')?;
+    let ip_addr = value[start + 1..end].parse::<std::net::Ipv6Addr>().ok()?;
+    let value = format!(
+        "{}{}{}",
+        &value[..start],
+        IPV6_PLACEHOLDER,
+        &value[end + 1..]
+    );
+    let host = crate::transport::sip_addr::bracket_host(std::net::IpAddr::V6(ip_addr).into());
+    Some((value, host))
+}
+
+/// Parse a uri, the host of an IPv6 reference is kept in brackets so it prints
+/// back the same, like the params with a colon in their value, e.g. the
+/// `gr=urn:uuid:...` of a GRUU (RFC 5627)
+pub fn parse_uri(value: &str) -> crate::Result<rsip::Uri> {
+    let (value, colon_params) = match value.split_once(';') {
+        // rsip cuts a param value at the first colon, these are added back
+        Some((base, params)) if params.contains(':') && !params.contains('?') => {
+            let (colon_params, params): (Vec<&str>, Vec<&str>) =
+                params.split(';').partition(|p| p.contains(':'));
+            let value = std::iter::once(base)
+                .chain(params)
+                .collect::<Vec<_>>()
+                .join(";");
+            (value, colon_params)
+        }
+        _ => (value.to_string(), vec![]),
+    };
+    let mut uri = match split_ipv6_reference(&value) {
+        Some((value, host)) => {
+            let mut uri = rsip::Uri::try_from(value)?;
+            uri.host_with_port.host = host;
+            uri
+        }
+        None => rsip::Uri::try_from(value)?,
+    };
+    for param in colon_params {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        uri.params.push(rsip::Param::Other(
+            name.trim().into(),
+            Some(value.trim().into()),
+        ));
+    }
+    Ok(uri)
+}
+
+/// the values of the Contact headers, one per contact when several are
+/// folded in a header with commas
+pub fn contact_values(headers: &rsip::Headers) -> Vec<String> {
+    headers
+        .iter()
+        .filter_map(|h| match h {
+            rsip::Header::Contact(contact) => Some(split_unquoted(contact.value(), ',')),
+            _ => None,
+        })
+        .flatten()
+        .collect()
+}
+
+/// A Contact value typed, also when rsip fails on it: a quoted param value
+/// with a semicolon (the `pub-gruu` and `temp-gruu` of RFC 5627), an IPv6
+/// host or a colon in a uri param
+pub fn parse_contact(value: &str) -> crate::Result<rsip::typed::Contact> {
+    let (start, end) = match value.find('<') {
+        Some(start) => match value[start..].find('>') {
+            Some(end) => (start, start + end),
+            None => return Err(crate::Error::Error(format!("invalid contact: {}", value))),
+        },
+        None => return Ok(rsip::headers::Contact::new(value).typed()?),
+    };
+    let display_name = value[..start].trim().trim_matches('"');
+    let params = split_unquoted(&value[end + 1..], ';')
+        .into_iter()
+        .map(|param| {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim().to_string(), Some(value.trim().to_string())),
+                None => (param, None),
+            };
+            match (name.to_ascii_lowercase().as_str(), value) {
+                ("expires", Some(value)) => rsip::Param::Expires(value.into()),
+                ("q", Some(value)) => rsip::Param::Q(value.into()),
+                (_, value) => rsip::Param::Other(name.into(), value.map(Into::into)),
+            }
+        })
+        .collect();
+    Ok(rsip::typed::Contact {
+        display_name: (!display_name.is_empty()).then(|| display_name.to_string()),
+        uri: parse_uri(&value[start + 1..end])?,
+        params,
+    })
+}
+
+/// The typed Via, with a sent-by or a received address that may be IPv6
+pub fn parse_via(via: &rsip::headers::Via) -> crate::Result<rsip::typed::Via> {
+    let mut parts = via.value().split(';');
+    let sent_by = parts.next().unwrap_or_default();
+    // rsip cuts a param value at the first colon, the IPv6 ones are added back
+    let (ipv6_params, params): (Vec<&str>, Vec<&str>) = parts.partition(|p| p.contains(':'));
+    let (sent_by, host) = match split_ipv6_reference(sent_by) {
+        Some((sent_by, host)) => (sent_by, Some(host)),
+        None => (sent_by.to_string(), None),
+    };
+    let value = std::iter::once(sent_by.as_str())
+        .chain(params)
+        .collect::<Vec<_>>()
+        .join(";");
+    let mut typed = rsip::headers::Via::new(value).typed()?;
+    if let Some(host) = host {
+        typed.uri.host_with_port.host = host;
+    }
+    for param in ipv6_params {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        typed.params.push(match name.trim() {
+            name if name.eq_ignore_ascii_case("received") => {
+                rsip::Param::Received(rsip::param::Received::new(value.trim()))
+            }
+            name => rsip::Param::Other(name.into(), Some(value.trim().into())),
+        });
+    }
+    Ok(typed)
+}
+
+/// true if the Require header lists the option tag, e.g. `100rel`
+pub fn has_required(headers: &rsip::Headers, tag: &str) -> bool {
+    headers.iter().any(|h| match h {
+        rsip::Header::Require(v) => has_option_tag(rsip::headers::UntypedHeader::value(v), tag),
+        _ => false,
+    })
+}
+
+/// true if the Supported or Require header lists the option tag
+pub fn has_supported(headers: &rsip::Headers, tag: &str) -> bool {
+    has_required(headers, tag)
+        || headers.iter().any(|h| match h {
+            rsip::Header::Supported(v) => {
+                has_option_tag(rsip::headers::UntypedHeader::value(v), tag)
+            }
+            _ => false,
+        })
+}
+
+/// the option tags of the Require and Proxy-Require headers missing from
+/// `supported`, to list in the Unsupported header of a 420
+pub fn unsupported_tags(headers: &rsip::Headers, supported: &[String]) -> Vec<String> {
+    let mut tags: Vec<String> = vec![];
+    for value in headers.iter().filter_map(|h| match h {
+        rsip::Header::Require(v) => Some(rsip::headers::UntypedHeader::value(v)),
+        rsip::Header::ProxyRequire(v) => Some(rsip::headers::UntypedHeader::value(v)),
+        _ => None,
+    }) {
+        for tag in value.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            if !supported.iter().any(|s| s.eq_ignore_ascii_case(tag))
+                && !tags.iter().any(|t| t.eq_ignore_ascii_case(tag))
+            {
+                tags.push(tag.to_string());
+            }
+        }
+    }
+    tags
+}
+
+/// the entries of the Path headers in their order (RFC 3327)
+pub fn path_set(headers: &rsip::Headers) -> Vec<rsip::UriWithParams> {
+    route_entries(headers, "Path")
+}
+
+/// the entries of the Service-Route headers in their order (RFC 3608)
+pub fn service_route_set(headers: &rsip::Headers) -> Vec<rsip::UriWithParams> {
+    route_entries(headers, "Service-Route")
+}
+
+// the uris of the headers named `name`, they have the syntax of a Route
+fn route_entries(headers: &rsip::Headers, header: &str) -> Vec<rsip::UriWithParams> {
+    headers
+        .iter()
+        .filter_map(|h| match h {
+            rsip::Header::Other(name, value) if name.eq_ignore_ascii_case(header) => {
+                rsip::typed::Route::try_from(rsip::headers::Route::from(value.clone())).ok()
+            }
+            _ => None,
+        })
+        .flat_map(|route| route.uris().to_vec())
+        .collect()
+}
+
+fn has_option_tag(value: &str, tag: &str) -> bool {
+    value.split(',').any(|t| t.trim().eq_ignore_ascii_case(tag))
+}
+
+/// Refer-To value with an optional embedded Replaces header (RFC 3891)
+pub fn make_refer_to(uri: &rsip::Uri, replaces: Option<&str>) -> String {
+    match replaces {
+        Some(replaces) => format!("<{}?Replaces={}>", uri, escape_uri_header(replaces)),
+        None => format!("<{}>", uri),
+    }
+}
+
+/// parse a Refer-To value into the target uri and the unescaped Replaces header
+pub fn parse_refer_to(value: &str) -> crate::Result<(rsip::Uri, Option<String>)> {
+    let value = value.trim();
+    let addr = match value.split('<').nth(1).and_then(|s| s.split('>').next()) {
+        Some(addr) => addr,
+        None => value.split(';').next().unwrap_or(value),
+    };
+    let (uri, headers) = match addr.split_once('?') {
+        Some((uri, headers)) => (uri, Some(headers)),
+        None => (addr, None),
+    };
+    let replaces = headers.and_then(|headers| {
+        headers.split('&').find_map(|h| match h.split_once('=') {
+            Some((name, value)) if name.eq_ignore_ascii_case("Replaces") => {
+                Some(unescape_uri_header(value))
+            }
+            _ => None,
+        })
+    });
+    Ok((rsip::Uri::try_from(uri)?, replaces))
+}
+
+/// escape a uri header value, keeping unreserved and hnv-unreserved characters (RFC 3261 25.1)
+pub fn escape_uri_header(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => escaped.push(b as char),
+            b'-' | b'_' | b'.' | b'!' | b'~' | b'*' | b'\'' | b'(' | b')' => {
+                escaped.push(b as char)
+            }
+            b'[' | b']' | b'/' | b'?' | b':' | b'+' | b'$' => escaped.push(b as char),
+            _ => escaped.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    escaped
+}
+
+pub fn unescape_uri_header(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                unescaped.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        unescaped.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&unescaped).to_string()
+}
+
+/// the status line of a `message/sipfrag` body, e.g. the NOTIFY of a REFER (RFC 3515 2.4.5)
+pub fn extract_sipfrag_status(body: &[u8]) -> Option<rsip::StatusCode> {
+    let body = std::str::from_utf8(body).ok()?;
+    let line = body.lines().next()?;
+    let mut parts = line.split_whitespace();
+    if !parts.next()?.starts_with("SIP/") {
+        return None;
+    }
+    parts
+        .next()?
+        .parse::<u16>()
+        .ok()
+        .map(rsip::StatusCode::from)
+}
+
+/// The Reason header of a BYE or CANCEL (RFC 3326), e.g. `Q.850;cause=16`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reason {
+    pub protocol: String,
+    pub cause: u16,
+    pub text: Option<String>,
+}
+
+impl Reason {
+    /// an ISDN cause code, 16 is normal call clearing
+    pub fn q850(cause: u16) -> Self {
+        Self {
+            protocol: "Q.850".to_string(),
+            cause,
+            text: None,
+        }
+    }
+
+    /// a SIP status code, e.g. `200` for a call completed elsewhere
+    pub fn sip(status: rsip::StatusCode) -> Self {
+        Self {
+            protocol: "SIP".to_string(),
+            cause: status.code(),
+            text: None,
+        }
+    }
+
+    pub fn with_text(mut self, text: &str) -> Self {
+        self.text = Some(text.to_string());
+        self
+    }
+
+    /// a `protocol;cause=..;text=".."` value, `None` without cause
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = split_unquoted(value, ';').into_iter();
+        let protocol = parts.next()?;
+        let mut cause = None;
+        let mut text = None;
+        for part in parts {
+            match part.split_once('=') {
+                Some((name, value)) if name.trim().eq_ignore_ascii_case("cause") => {
+                    cause = value.trim().parse::<u16>().ok()
+                }
+                Some((name, value)) if name.trim().eq_ignore_ascii_case("text") => {
+                    text = Some(unquote(value.trim()))
+                }
+                _ => {}
+            }
+        }
+        Some(Self {
+            protocol,
+            cause: cause?,
+            text,
+        })
+    }
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{};cause={}", self.protocol, self.cause)?;
+        match &self.text {
+            Some(text) => write!(
+                f,
+                ";text=\"{}\"",
+                text.replace('\\', "\\\\").replace('"', "\\\"")
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+impl From<Reason> for rsip::Header {
+    fn from(reason: Reason) -> Self {
+        rsip::Header::Other("Reason".to_string(), reason.to_string())
+    }
+}
+
+/// the Reason headers of a BYE or CANCEL, one per protocol (RFC 3326 2)
+pub fn reason_headers(headers: &rsip::Headers) -> Vec<Reason> {
+    headers
+        .iter()
+        .filter_map(|h| match h {
+            rsip::Header::Other(name, value) if name.eq_ignore_ascii_case("Reason") => {
+                Some(split_unquoted(value, ','))
+            }
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|value| Reason::parse(&value))
+        .collect()
+}
+
+// the content of a quoted-string with its escapes resolved, a token as it is
+fn unquote(value: &str) -> String {
+    let inner = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => inner,
+        None => return value.to_string(),
+    };
+    let mut text = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.extend(chars.next()),
+            c => text.push(c),
+        }
+    }
+    text
+}
+
+/// The uri of an asserted identity, rsip does not parse tel uris (RFC 3966)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IdentityUri {
+    Sip(rsip::Uri),
+    /// the number and params after `tel:`, e.g. `+14085264000`
+    Tel(String),
+}
+
+impl std::fmt::Display for IdentityUri {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdentityUri::Sip(uri) => write!(f, "{}", uri),
+            IdentityUri::Tel(number) => write!(f, "tel:{}", number),
+        }
+    }
+}
+
+/// A P-Asserted-Identity value (RFC 3325 9.1), the identity of the user as
+/// asserted by a trusted network element. A request carries at most one sip
+/// or sips identity and one tel identity.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssertedIdentity {
+    pub display_name: Option<String>,
+    pub uri: IdentityUri,
+}
+
+impl AssertedIdentity {
+    pub fn sip(uri: rsip::Uri) -> Self {
+        Self {
+            display_name: None,
+            uri: IdentityUri::Sip(uri),
+        }
+    }
+
+    /// `number` without the `tel:` scheme, e.g. `+14085264000`
+    pub fn tel(number: &str) -> Self {
+        Self {
+            display_name: None,
+            uri: IdentityUri::Tel(number.to_string()),
+        }
+    }
+
+    pub fn with_display_name(mut self, display_name: &str) -> Self {
+        self.display_name = Some(display_name.to_string());
+        self
+    }
+
+    /// a `"name" <uri>` or bare uri value, `None` for any other scheme
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let (display_name, uri) = match value.find('<') {
+            Some(start) => {
+                let end = start + value[start..].find('>')?;
+                let name = value[..start].trim();
+                let name = (!name.is_empty()).then(|| unquote(name));
+                (name, &value[start + 1..end])
+            }
+            None => (None, value),
+        };
+        let uri = match uri.split_once(':') {
+            Some((scheme, number)) if scheme.eq_ignore_ascii_case("tel") => {
+                IdentityUri::Tel(number.to_string())
+            }
+            Some((scheme, _))
+                if scheme.eq_ignore_ascii_case("sip") || scheme.eq_ignore_ascii_case("sips") =>
+            {
+                IdentityUri::Sip(parse_uri(uri).ok()?)
+            }
+            _ => return None,
+        };
+        Some(Self { display_name, uri })
+    }
+}
+
+impl std::fmt::Display for AssertedIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(name) = &self.display_name {
+            write!(
+                f,
+                "\"{}\" ",
+                name.replace('\\', "\\\\").replace('"', "\\\"")
+            )?;
+        }
+        write!(f, "<{}>", self.uri)
+    }
+}
+
+impl From<AssertedIdentity> for rsip::Header {
+    fn from(identity: AssertedIdentity) -> Self {
+        rsip::Header::Other(P_ASSERTED_IDENTITY.to_string(), identity.to_string())
+    }
+}
+
+pub const P_ASSERTED_IDENTITY: &str = "P-Asserted-Identity";
+
+/// the P-Asserted-Identity values of a request, whoever sent it; see
+/// [`EndpointInner::asserted_identities`](crate::transaction::endpoint::EndpointInner::asserted_identities)
+/// for the ones of a trusted peer only
+pub fn asserted_identities(headers: &rsip::Headers) -> Vec<AssertedIdentity> {
+    headers
+        .iter()
+        .filter_map(|h| match h {
+            rsip::Header::Other(name, value) if name.eq_ignore_ascii_case(P_ASSERTED_IDENTITY) => {
+                Some(split_unquoted(value, ','))
+            }
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|value| AssertedIdentity::parse(&value))
+        .collect()
+}
+
+/// A priv-value of the Privacy header (RFC 3323 4.2, RFC 3325 9.3)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Privacy {
+    Header,
+    Session,
+    User,
+    None,
+    Critical,
+    /// the P-Asserted-Identity is not passed out of the trust domain
+    Id,
+}
+
+impl Privacy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Privacy::Header => "header",
+            Privacy::Session => "session",
+            Privacy::User => "user",
+            Privacy::None => "none",
+            Privacy::Critical => "critical",
+            Privacy::Id => "id",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [
+            Privacy::Header,
+            Privacy::Session,
+            Privacy::User,
+            Privacy::None,
+            Privacy::Critical,
+            Privacy::Id,
+        ]
+        .into_iter()
+        .find(|p| p.as_str().eq_ignore_ascii_case(value.trim()))
+    }
+}
+
+/// a Privacy header with `values`, e.g. `id` or `header;user`
+pub fn privacy_header(values: &[Privacy]) -> rsip::Header {
+    let values: Vec<&str> = values.iter().map(|p| p.as_str()).collect();
+    rsip::Header::Other("Privacy".to_string(), values.join(";"))
+}
+
+/// the known priv-values of the Privacy headers, the others are skipped
+pub fn privacy_values(headers: &rsip::Headers) -> Vec<Privacy> {
+    headers
+        .iter()
+        .filter_map(|h| match h {
+            rsip::Header::Other(name, value) if name.eq_ignore_ascii_case("Privacy") => Some(
+                value
+                    .split(';')
+                    .filter_map(Privacy::parse)
+                    .collect::<Vec<_>>(),
+            ),
+            _ => None,
+        })
+        .flatten()
+        .collect()
+}
+
+/// The Replaces header of an INVITE taking over a dialog (RFC 3891), e.g.
+/// `425928@bobster.example.org;to-tag=7743;from-tag=6472`. The to-tag is the
+/// local tag of the dialog at the UA receiving the INVITE, the from-tag its remote tag.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Replaces {
+    pub call_id: String,
+    pub to_tag: String,
+    pub from_tag: String,
+    /// only an early dialog may be replaced
+    pub early_only: bool,
+}
+
+impl Replaces {
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split(';').map(|p| p.trim());
+        let call_id = parts.next().filter(|c| !c.is_empty())?.to_string();
+        let mut to_tag = None;
+        let mut from_tag = None;
+        let mut early_only = false;
+        for part in parts {
+            match part.split_once('=') {
+                Some((name, tag)) if name.trim().eq_ignore_ascii_case("to-tag") => {
+                    to_tag = Some(tag.trim().to_string())
+                }
+                Some((name, tag)) if name.trim().eq_ignore_ascii_case("from-tag") => {
+                    from_tag = Some(tag.trim().to_string())
+                }
+                None if part.eq_ignore_ascii_case("early-only") => early_only = true,
+                _ => {}
+            }
+        }
+        Some(Self {
+            call_id,
+            to_tag: to_tag?,
+            from_tag: from_tag?,
+            early_only,
+        })
+    }
+}
+
+impl std::fmt::Display for Replaces {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{};to-tag={};from-tag={}",
+            self.call_id, self.to_tag, self.from_tag
+        )?;
+        if self.early_only {
+            write!(f, ";early-only")?;
+        }
+        Ok(())
+    }
+}
+
+impl From<Replaces> for rsip::Header {
+    fn from(replaces: Replaces) -> Self {
+        rsip::Header::Other("Replaces".to_string(), replaces.to_string())
+    }
+}
+
+/// the Replaces header of a request, `Some(None)` when it can't be parsed
+pub fn replaces_header(headers: &rsip::Headers) -> Option<Option<Replaces>> {
+    headers.iter().find_map(|h| match h {
+        rsip::Header::Other(name, value) if name.eq_ignore_ascii_case("Replaces") => {
+            Some(Replaces::parse(value))
+        }
+        _ => None,
+    })
+}
+
+/// a `token` of RFC 3261 25.1, e.g. a tag
+pub fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-.!%*_+`'~".contains(c))
+}
+
+/// a Call-ID, `word [ "@" word ]` of RFC 3261 25.1
+pub fn is_call_id(value: &str) -> bool {
+    let is_word = |word: &str| {
+        !word.is_empty()
+            && word
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-.!%*_+`'~()<>:\\\"/[]?{}".contains(c))
+    };
+    match value.split_once('@') {
+        Some((local, host)) => is_word(local) && is_word(host),
+        None => is_word(value),
+    }
+}
+
+/// The compact forms of header names, RFC 3261 7.3.3 and the extensions that
+/// define one
+const COMPACT_FORMS: &[(&str, &str)] = &[
+    ("Call-ID", "i"),
+    ("Contact", "m"),
+    ("Content-Encoding", "e"),
+    ("Content-Length", "l"),
+    ("Content-Type", "c"),
+    ("From", "f"),
+    ("Subject", "s"),
+    ("Supported", "k"),
+    ("To", "t"),
+    ("Via", "v"),
+    ("Event", "o"),
+    ("Allow-Events", "u"),
+    ("Refer-To", "r"),
+    ("Referred-By", "b"),
+    ("Session-Expires", "x"),
+];
+
+/// Expand the compact header names (e.g. `v:` for Via) and split the Vias
+/// folded into one line by commas, so each hop is a Via of its own
+pub fn normalize_headers(headers: &mut rsip::Headers) {
+    let mut normalized = Vec::new();
+    for header in headers.iter().cloned() {
+        let header = match header {
+            rsip::Header::Other(name, value) => expand_compact(name, value),
+            header => header,
+        };
+        match header {
+            rsip::Header::Via(via) if via.value().contains(',') => normalized.extend(
+                split_unquoted(via.value(), ',')
+                    .into_iter()
+                    .map(|value| rsip::Header::Via(value.into())),
+            ),
+            header => normalized.push(header),
+        }
+    }
+    *headers = normalized.into();
+}
+
+/// Write the headers that have one with their compact name
+pub fn compact_headers(headers: &mut rsip::Headers) {
+    for header in headers.iter_mut() {
+        let line = header.to_string();
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if let Some((_, compact)) = COMPACT_FORMS
+            .iter()
+            .find(|(full, _)| full.eq_ignore_ascii_case(name.trim()))
+        {
+            *header = rsip::Header::Other(compact.to_string(), value.trim().to_string());
+        }
+    }
+}
+
+fn expand_compact(name: String, value: String) -> rsip::Header {
+    let Some((full, _)) = COMPACT_FORMS
+        .iter()
+        .find(|(_, compact)| compact.eq_ignore_ascii_case(name.trim()))
+    else {
+        return rsip::Header::Other(name, value);
+    };
+    match *full {
+        "Call-ID" => rsip::Header::CallId(value.into()),
+        "Contact" => rsip::Header::Contact(value.into()),
+        "Content-Encoding" => rsip::Header::ContentEncoding(value.into()),
+        "Content-Length" => rsip::Header::ContentLength(value.into()),
+        "Content-Type" => rsip::Header::ContentType(value.into()),
+        "From" => rsip::Header::From(value.into()),
+        "Subject" => rsip::Header::Subject(value.into()),
+        "Supported" => rsip::Header::Supported(value.into()),
+        "To" => rsip::Header::To(value.into()),
+        "Via" => rsip::Header::Via(value.into()),
+        "Event" => rsip::Header::Event(value.into()),
+        _ => rsip::Header::Other(full.to_string(), value),
+    }
+}
+
+// the values of a list separated by `separator`, the ones in quoted strings
+// and in `<>` stay
+fn split_unquoted(value: &str, separator: char) -> Vec<String> {
+    let mut values = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut bracketed = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '<' if !quoted => bracketed = true,
+            '>' if !quoted => bracketed = false,
+            c if c == separator && !quoted && !bracketed => {
+                values.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    values.push(current.trim().to_string());
+    values.retain(|v| !v.is_empty());
+    values
+}
+
+pub const DTMF_RELAY: &str = "application/dtmf-relay";
+
+/// A DTMF digit sent in an INFO body of type `application/dtmf-relay`,
+/// e.g. `Signal=5\r\nDuration=160`, the duration is in milliseconds
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DtmfEvent {
+    pub digit: char,
+    pub duration: u32,
+}
+
+impl DtmfEvent {
+    /// `digit` is one of 0-9, *, # or A-D
+    pub fn new(digit: char, duration: u32) -> crate::Result<Self> {
+        let digit = digit.to_ascii_uppercase();
+        match digit {
+            '0'..='9' | '*' | '#' | 'A'..='D' => Ok(Self { digit, duration }),
+            _ => Err(crate::Error::Error(format!(
+                "invalid dtmf digit: {}",
+                digit
+            ))),
+        }
+    }
+
+    /// the event of a dtmf-relay body, the duration defaults to 0 when missing
+    pub fn parse(body: &[u8]) -> Option<Self> {
+        let body = std::str::from_utf8(body).ok()?;
+        let mut digit = None;
+        let mut duration = 0;
+        for line in body.lines() {
+            let (name, value) = match line.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => continue,
+            };
+            if name.eq_ignore_ascii_case("Signal") {
+                let mut chars = value.chars();
+                digit = match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some(c),
+                    _ => return None,
+                };
+            } else if name.eq_ignore_ascii_case("Duration") {
+                duration = value.parse().ok()?;
+            }
+        }
+        Self::new(digit?, duration).ok()
+    }
+}
+
+impl std::fmt::Display for DtmfEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Signal={}\r\nDuration={}\r\n", self.digit, self.duration)
+    }
+}
+
+/// the content type and body of a message, or its `application/sdp` part when
+/// the body is multipart (RFC 5621)
+pub fn extract_sdp(headers: &rsip::Headers, body: &[u8]) -> Option<(String, Vec<u8>)> {
+    if body.is_empty() {
+        return None;
+    }
+    let content_type = headers.iter().find_map(|h| match h {
+        rsip::Header::ContentType(v) => Some(rsip::headers::UntypedHeader::value(v).to_string()),
+        _ => None,
+    })?;
+    let sdp = MultipartBody::parse(&content_type, body)
+        .and_then(|multipart| multipart.part("application/sdp").cloned());
+    match sdp {
+        Some(sdp) => Some((sdp.content_type, sdp.body)),
+        None => Some((content_type, body.to_vec())),
+    }
+}
+
+/// A part of a [`MultipartBody`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BodyPart {
+    pub content_type: String,
+    /// e.g. `signal;handling=optional` for an ISUP part (RFC 3204)
+    pub disposition: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl BodyPart {
+    pub fn new(content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            content_type: content_type.to_string(),
+            disposition: None,
+            body,
+        }
+    }
+
+    pub fn with_disposition(mut self, disposition: &str) -> Self {
+        self.disposition = Some(disposition.to_string());
+        self
+    }
+
+    // the media type without params, in lower case
+    fn media_type(&self) -> String {
+        media_type(&self.content_type)
+    }
+}
+
+/// A `multipart/mixed` body (RFC 5621), e.g. the SDP and ISUP of a call
+/// from a PSTN gateway.
+///
+/// The parts are binary safe, the headers of a part other than Content-Type
+/// and Content-Disposition are not kept.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MultipartBody {
+    pub boundary: String,
+    pub parts: Vec<BodyPart>,
+}
+
+impl Default for MultipartBody {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MultipartBody {
+    /// an empty body with a random boundary
+    pub fn new() -> Self {
+        Self::with_boundary(&format!("boundary-{}", crate::transaction::random_text(16)))
+    }
+
+    pub fn with_boundary(boundary: &str) -> Self {
+        Self {
+            boundary: boundary.to_string(),
+            parts: vec![],
+        }
+    }
+
+    pub fn add_part(self, content_type: &str, body: Vec<u8>) -> Self {
+        self.with_part(BodyPart::new(content_type, body))
+    }
+
+    pub fn with_part(mut self, part: BodyPart) -> Self {
+        self.parts.push(part);
+        self
+    }
+
+    /// the first part of `content_type`, its params are not compared
+    pub fn part(&self, content_type: &str) -> Option<&BodyPart> {
+        let content_type = media_type(content_type);
+        self.parts.iter().find(|p| p.media_type() == content_type)
+    }
+
+    /// `multipart/mixed` with the boundary param
+    pub fn content_type(&self) -> String {
+        match is_token(&self.boundary) {
+            true => format!("multipart/mixed;boundary={}", self.boundary),
+            false => format!("multipart/mixed;boundary=\"{}\"", self.boundary),
+        }
+    }
+
+    pub fn content_type_header(&self) -> rsip::Header {
+        rsip::Header::ContentType(self.content_type().into())
+    }
+
+    /// each part after its delimiter line, then the close delimiter
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = vec![];
+        for part in &self.parts {
+            body.extend_from_slice(format!("--{}\r\n", self.boundary).as_bytes());
+            body.extend_from_slice(format!("Content-Type: {}\r\n", part.content_type).as_bytes());
+            if let Some(disposition) = &part.disposition {
+                body.extend_from_slice(
+                    format!("Content-Disposition: {}\r\n", disposition).as_bytes(),
+                );
+            }
+            body.extend_from_slice(b"\r\n");
+            body.extend_from_slice(&part.body);
+            // the line break before a delimiter belongs to it (RFC 2046 5.1.1)
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+        body
+    }
+
+    /// The parts of a body of a `multipart/*` content type, `None` for
+    /// another type or a body without delimiter. The preamble and epilogue
+    /// are skipped, the line breaks may be LF only and a missing close
+    /// delimiter ends the last part at the end of the body.
+    pub fn parse(content_type: &str, body: &[u8]) -> Option<Self> {
+        if !media_type(content_type).starts_with("multipart/") {
+            return None;
+        }
+        let boundary = content_type
+            .split(';')
+            .skip(1)
+            .find_map(|p| match p.split_once('=') {
+                Some((name, value)) if name.trim().eq_ignore_ascii_case("boundary") => {
+                    Some(value.trim().trim_matches('"').to_string())
+                }
+                _ => None,
+            })?;
+        let delimiter = format!("--{}", boundary).into_bytes();
+        let mut parts = vec![];
+        let (_, mut next, mut closed) = find_delimiter(body, &delimiter, 0)?;
+        while !closed {
+            let (end, after, close) = match find_delimiter(body, &delimiter, next) {
+                Some(found) => found,
+                None => {
+                    let rest = &body[next..];
+                    let rest = rest
+                        .strip_suffix(b"\r\n")
+                        .or_else(|| rest.strip_suffix(b"\n"))
+                        .unwrap_or(rest);
+                    (next + rest.len(), body.len(), true)
+                }
+            };
+            parts.push(parse_part(&body[next..end]));
+            next = after;
+            closed = close;
+        }
+        Some(Self { boundary, parts })
+    }
+}
+
+// the media type of a Content-Type without params, in lower case
+fn media_type(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+// The next delimiter line from `from`: where the part before it ends, with
+// the line break, where the part after it starts and if it is the close
+// delimiter. A delimiter starts a line and is followed by `--` or by spaces
+// up to the line break, else it is part of the content.
+fn find_delimiter(body: &[u8], delimiter: &[u8], from: usize) -> Option<(usize, usize, bool)> {
+    let mut i = from;
+    while i + delimiter.len() <= body.len() {
+        let line_start = i == 0 || body[i - 1] == b'\n';
+        if !line_start || !body[i..].starts_with(delimiter) {
+            i += 1;
+            continue;
+        }
+        let end = match i {
+            0 => 0,
+            _ if i >= 2 && body[i - 2] == b'\r' => i - 2,
+            _ => i - 1,
+        };
+        let end = end.max(from);
+        let mut after = i + delimiter.len();
+        if body[after..].starts_with(b"--") {
+            return Some((end, body.len(), true));
+        }
+        while after < body.len() && matches!(body[after], b' ' | b'\t') {
+            after += 1;
+        }
+        if body[after..].starts_with(b"\r\n") {
+            return Some((end, after + 2, false));
+        }
+        if body[after..].starts_with(b"\n") || after == body.len() {
+            return Some((end, (after + 1).min(body.len()), false));
+        }
+        i += 1;
+    }
+    None
+}
+
+// the Content-Type, text/plain when missing (RFC 2046 5.1), and the
+// Content-Disposition of a part with its content
+fn parse_part(part: &[u8]) -> BodyPart {
+    let (headers, content) = if part.starts_with(b"\r\n") {
+        (&part[..0], &part[2..])
+    } else if part.starts_with(b"\n") {
+        (&part[..0], &part[1..])
+    } else {
+        let crlf = part.windows(4).position(|w| w == b"\r\n\r\n");
+        let lf = part.windows(2).position(|w| w == b"\n\n");
+        match (crlf, lf) {
+            (Some(crlf), Some(lf)) if lf < crlf => (&part[..lf], &part[lf + 2..]),
+            (Some(crlf), _) => (&part[..crlf], &part[crlf + 4..]),
+            (None, Some(lf)) => (&part[..lf], &part[lf + 2..]),
+            (None, None) => (part, &part[part.len()..]),
+        }
+    };
+    let mut body_part = BodyPart::new("text/plain", content.to_vec());
+    for line in String::from_utf8_lossy(headers).lines() {
+        match line.split_once(':') {
+            Some((name, value))
+                if name.trim().eq_ignore_ascii_case("content-type")
+                    || name.trim().eq_ignore_ascii_case("c") =>
+            {
+                body_part.content_type = value.trim().to_string();
+            }
+            Some((name, value)) if name.trim().eq_ignore_ascii_case("content-disposition") => {
+                body_part.disposition = Some(value.trim().to_string());
+            }
+            _ => {}
+        }
+    }
+    body_part
+}
+
+/// A body the TU can answer with, [`select_variant`] picks the one the
+/// request accepts best
+#[derive(Clone, Debug)]
+pub struct Variant {
+    pub content_type: String,
+    /// the language of a localized body, sent as Content-Language
+    pub language: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl Variant {
+    pub fn new(content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            content_type: content_type.to_string(),
+            language: None,
+            body,
+        }
+    }
+
+    pub fn with_language(mut self, language: &str) -> Self {
+        self.language = Some(language.to_string());
+        self
+    }
+
+    /// the Content-Type and Content-Language of a response with the body
+    pub fn headers(&self) -> Vec<rsip::Header> {
+        let mut headers = vec![rsip::Header::ContentType(self.content_type.clone().into())];
+        if let Some(language) = &self.language {
+            headers.push(rsip::Header::ContentLanguage(language.clone().into()));
+        }
+        headers
+    }
+}
+
+/// the media ranges of the Accept headers, highest q value first
+pub fn accept_types(headers: &rsip::Headers) -> Vec<(String, f32)> {
+    q_values(headers.iter().filter_map(|h| match h {
+        rsip::Header::Accept(v) => Some(v.value()),
+        _ => None,
+    }))
+}
+
+/// the language ranges of the Accept-Language headers, highest q value first
+pub fn accept_languages(headers: &rsip::Headers) -> Vec<(String, f32)> {
+    q_values(headers.iter().filter_map(|h| match h {
+        rsip::Header::AcceptLanguage(v) => Some(v.value()),
+        _ => None,
+    }))
+}
+
+/// The variant with the best accepted content type, among those the one in
+/// the best accepted language, the first of `variants` on a tie.
+///
+/// Without Accept every content type is accepted, without Accept-Language
+/// every language. A language not accepted is a worse match but not a
+/// rejection, `None` means no content type is accepted and the answer is 406.
+pub fn select_variant<'a>(headers: &rsip::Headers, variants: &'a [Variant]) -> Option<&'a Variant> {
+    let types = accept_types(headers);
+    let languages = accept_languages(headers);
+    let mut best: Option<(&Variant, f32, f32)> = None;
+    for variant in variants {
+        let type_q = match types.is_empty() {
+            true => 1.0,
+            false => media_range_q(&types, &variant.content_type),
+        };
+        if type_q <= 0.0 {
+            continue;
+        }
+        let language_q = match (&variant.language, languages.is_empty()) {
+            (_, true) | (None, _) => 1.0,
+            (Some(language), false) => language_range_q(&languages, language),
+        };
+        if best.is_none_or(|(_, t, l)| (type_q, language_q) > (t, l)) {
+            best = Some((variant, type_q, language_q));
+        }
+    }
+    best.map(|(variant, _, _)| variant)
+}
+
+// the comma separated entries of the values with their q param, 1 without,
+// in the order of their q values
+fn q_values<'a>(values: impl Iterator<Item = &'a str>) -> Vec<(String, f32)> {
+    let mut entries = vec![];
+    for entry in values.flat_map(|v| v.split(',')) {
+        let mut params = entry.split(';');
+        let range = params.next().unwrap_or_default().trim();
+        if range.is_empty() {
+            continue;
+        }
+        let q = params
+            .filter_map(|p| p.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .and_then(|(_, q)| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        entries.push((range.to_ascii_lowercase(), q));
+    }
+    entries.sort_by(|a, b| b.1.total_cmp(&a.1));
+    entries
+}
+
+// the q value of the most specific media range matching `content_type`
+fn media_range_q(ranges: &[(String, f32)], content_type: &str) -> f32 {
+    let content_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let main_type = content_type.split('/').next().unwrap_or_default();
+    let find = |range: &str| ranges.iter().find(|(r, _)| r == range).map(|(_, q)| *q);
+    find(&content_type)
+        .or_else(|| find(&format!("{}/*", main_type)))
+        .or_else(|| find("*/*"))
+        .unwrap_or(0.0)
+}
+
+// the q value of the longest language range matching `language` (RFC 3261 20.3)
+fn language_range_q(ranges: &[(String, f32)], language: &str) -> f32 {
+    let language = language.trim().to_ascii_lowercase();
+    ranges
+        .iter()
+        .filter(|(range, _)| {
+            range == "*"
+                || language == *range
+                || language
+                    .strip_prefix(range.as_str())
+                    .is_some_and(|rest| rest.starts_with('-'))
+        })
+        .max_by_key(|(range, _)| if range == "*" { 0 } else { range.len() })
+        .map(|(_, q)| *q)
+        .unwrap_or(0.0)
+}
+
 #[test]
 fn test_rsip_headers_ext() {
     use rsip::{Header, Headers};
@@ -71,4 +1277,397 @@ fn test_rsip_headers_ext() {
             &Header::Via("SIP/2.0/WSS".into())
         ]
     );
+
+    // only the first of the matching headers goes, wherever it is
+    let mut headers: Headers = vec![
+        Header::MaxForwards(70.into()),
+        Header::Route("<sip:p1.example.com;lr>".into()),
+        Header::Route("<sip:p2.example.com;lr>".into()),
+    ]
+    .into();
+    header_pop!(headers, Header::Route);
+    assert_eq!(
+        headers.iter().collect::<Vec<_>>(),
+        vec![
+            &Header::MaxForwards(70.into()),
+            &Header::Route("<sip:p2.example.com;lr>".into()),
+        ]
+    );
+}
+
+#[test]
+fn test_refer_to() {
+    let uri = rsip::Uri::try_from("sip:bob@example.com").unwrap();
+    let replaces = "12345@192.168.1.1;to-tag=abc;from-tag=xyz";
+    let refer_to = make_refer_to(&uri, Some(replaces));
+    assert_eq!(
+        refer_to,
+        "<sip:bob@example.com?Replaces=12345%40192.168.1.1%3Bto-tag%3Dabc%3Bfrom-tag%3Dxyz>"
+    );
+    let (parsed, parsed_replaces) = parse_refer_to(&refer_to).unwrap();
+    assert_eq!(parsed, uri);
+    assert_eq!(parsed_replaces.as_deref(), Some(replaces));
+
+    let (parsed, parsed_replaces) = parse_refer_to("sip:alice@example.com").unwrap();
+    assert_eq!(parsed.to_string(), "sip:alice@example.com");
+    assert_eq!(parsed_replaces, None);
+
+    assert_eq!(
+        extract_sipfrag_status(b"SIP/2.0 180 Ringing\r\n"),
+        Some(rsip::StatusCode::Ringing)
+    );
+    assert_eq!(extract_sipfrag_status(b"garbage"), None);
+}
+
+#[test]
+fn test_option_tags() {
+    use rsip::{Header, Headers};
+    let headers: Headers = vec![
+        Header::Supported("timer, 100rel".into()),
+        Header::Require("replaces".into()),
+    ]
+    .into();
+    assert!(has_supported(&headers, "100rel"));
+    assert!(has_supported(&headers, "replaces"));
+    assert!(!has_required(&headers, "100rel"));
+    assert!(has_required(&headers, "replaces"));
+    assert!(!has_supported(&headers, "path"));
+
+    let headers: Headers = vec![
+        Header::Require("100rel, gruu".into()),
+        Header::ProxyRequire("GRUU, path".into()),
+    ]
+    .into();
+    let supported = vec!["100rel".to_string(), "timer".to_string()];
+    assert_eq!(unsupported_tags(&headers, &supported), vec!["gruu", "path"]);
+}
+
+#[test]
+fn test_extract_sdp() {
+    use rsip::{Header, Headers};
+    let headers: Headers = vec![Header::ContentType("application/sdp".into())].into();
+    assert_eq!(
+        extract_sdp(&headers, b"v=0\r\n"),
+        Some(("application/sdp".to_string(), b"v=0\r\n".to_vec()))
+    );
+    assert_eq!(extract_sdp(&headers, b""), None);
+
+    let headers: Headers = vec![Header::ContentType(
+        "multipart/mixed;boundary=\"unique\"".into(),
+    )]
+    .into();
+    let body = b"--unique\r\n\
+Content-Type: application/resource-lists+xml\r\n\r\n\
+<resource-lists/>\r\n\
+--unique\r\n\
+Content-Type: application/sdp\r\n\r\n\
+v=0\r\no=- 1 1 IN IP4 127.0.0.1\r\n\r\n\
+--unique--\r\n";
+    assert_eq!(
+        extract_sdp(&headers, body),
+        Some((
+            "application/sdp".to_string(),
+            b"v=0\r\no=- 1 1 IN IP4 127.0.0.1\r\n".to_vec()
+        ))
+    );
+}
+
+#[test]
+fn test_multipart_body() {
+    use rsip::Headers;
+    // an ISUP IAM is binary, with line breaks and dashes in it
+    let isup = vec![0x01, 0x0d, 0x0a, b'-', b'-', 0x00, 0xff, 0x0a];
+    let body = MultipartBody::with_boundary("unique-boundary-1")
+        .add_part("application/sdp", b"v=0\r\n".to_vec())
+        .with_part(
+            BodyPart::new("application/ISUP;version=itu-t92+", isup.clone())
+                .with_disposition("signal;handling=optional"),
+        );
+    assert_eq!(
+        body.content_type(),
+        "multipart/mixed;boundary=unique-boundary-1"
+    );
+    let bytes = body.to_bytes();
+    assert!(bytes
+        .starts_with(b"--unique-boundary-1\r\nContent-Type: application/sdp\r\n\r\nv=0\r\n\r\n"));
+    assert!(bytes.ends_with(b"\r\n--unique-boundary-1--\r\n"));
+    let parsed = MultipartBody::parse(&body.content_type(), &bytes).expect("multipart");
+    assert_eq!(parsed, body);
+    assert_eq!(
+        parsed.part("application/isup").map(|p| &p.body),
+        Some(&isup)
+    );
+    assert!(parsed.part("application/xml").is_none());
+
+    let headers: Headers = vec![body.content_type_header()].into();
+    assert_eq!(
+        extract_sdp(&headers, &bytes),
+        Some(("application/sdp".to_string(), b"v=0\r\n".to_vec()))
+    );
+
+    // a quoted boundary, a preamble and an epilogue, LF line breaks, padding
+    // after a delimiter and a line starting with the boundary in a part
+    let body = "preamble\n\
+--b1 \n\
+Content-Type: application/xml\n\n\
+<a/>\n\
+--b1x is not a delimiter\n\
+--b1\n\
+\n\
+plain\n\
+--b1--\n\
+epilogue";
+    let parsed = MultipartBody::parse("Multipart/Mixed; boundary=\"b1\"", body.as_bytes())
+        .expect("multipart");
+    assert_eq!(parsed.parts.len(), 2);
+    assert_eq!(parsed.parts[0].content_type, "application/xml");
+    assert_eq!(
+        parsed.parts[0].body,
+        b"<a/>\n--b1x is not a delimiter".to_vec()
+    );
+    assert_eq!(
+        parsed.parts[1],
+        BodyPart::new("text/plain", b"plain".to_vec())
+    );
+
+    // the last part ends with the body when the close delimiter is missing
+    let parsed = MultipartBody::parse(
+        "multipart/mixed;boundary=b2",
+        b"--b2\r\nContent-Type: application/sdp\r\n\r\nv=0\r\n",
+    )
+    .expect("multipart");
+    assert_eq!(parsed.parts[0].body, b"v=0".to_vec());
+
+    assert!(MultipartBody::parse("application/sdp", b"v=0\r\n").is_none());
+    assert!(MultipartBody::parse("multipart/mixed;boundary=b3", b"v=0\r\n").is_none());
+    assert!(MultipartBody::new().boundary.len() > 16);
+}
+
+#[test]
+fn test_reason() {
+    assert_eq!(Reason::q850(16).to_string(), "Q.850;cause=16");
+    assert_eq!(
+        Reason::sip(rsip::StatusCode::OK)
+            .with_text("Call completed elsewhere")
+            .to_string(),
+        "SIP;cause=200;text=\"Call completed elsewhere\""
+    );
+    for reason in [
+        Reason::q850(16).with_text("Normal"),
+        Reason::sip(rsip::StatusCode::BusyHere).with_text("Busy \"Here\"; now"),
+        Reason::q850(17),
+    ] {
+        assert_eq!(Reason::parse(&reason.to_string()), Some(reason));
+    }
+    assert_eq!(Reason::parse("SIP;text=\"no cause\""), None);
+    let headers: rsip::Headers = vec![rsip::Header::Other(
+        "Reason".into(),
+        "SIP ;cause=200 ;text=\"Call completed, elsewhere\", Q.850;cause=16".into(),
+    )]
+    .into();
+    assert_eq!(
+        reason_headers(&headers),
+        vec![
+            Reason::sip(rsip::StatusCode::OK).with_text("Call completed, elsewhere"),
+            Reason::q850(16),
+        ]
+    );
+}
+
+#[test]
+fn test_asserted_identity() {
+    let headers: rsip::Headers = vec![
+        rsip::Header::Other(
+            "P-Asserted-Identity".into(),
+            "\"Cullen, Jennings\" <sip:fluffy@cisco.com>, <tel:+14085264000>".into(),
+        ),
+        rsip::Header::Other("p-asserted-identity".into(), "mailto:a@b.com".into()),
+        rsip::Header::Other("Privacy".into(), "id; critical;foo".into()),
+    ]
+    .into();
+    let identities = asserted_identities(&headers);
+    assert_eq!(
+        identities,
+        vec![
+            AssertedIdentity::sip(rsip::Uri::try_from("sip:fluffy@cisco.com").unwrap())
+                .with_display_name("Cullen, Jennings"),
+            AssertedIdentity::tel("+14085264000"),
+        ]
+    );
+    assert_eq!(
+        identities[0].to_string(),
+        "\"Cullen, Jennings\" <sip:fluffy@cisco.com>"
+    );
+    assert_eq!(
+        rsip::Header::from(identities[1].clone()).to_string(),
+        "P-Asserted-Identity: <tel:+14085264000>"
+    );
+    assert_eq!(
+        privacy_values(&headers),
+        vec![Privacy::Id, Privacy::Critical]
+    );
+    assert_eq!(
+        privacy_header(&[Privacy::Header, Privacy::User]).to_string(),
+        "Privacy: header;user"
+    );
+}
+
+#[test]
+fn test_replaces() {
+    let replaces =
+        Replaces::parse("425928@bobster.example.org;from-tag=6472;to-tag=7743;early-only").unwrap();
+    assert_eq!(replaces.call_id, "425928@bobster.example.org");
+    assert_eq!(replaces.to_tag, "7743");
+    assert_eq!(replaces.from_tag, "6472");
+    assert!(replaces.early_only);
+    assert_eq!(
+        replaces.to_string(),
+        "425928@bobster.example.org;to-tag=7743;from-tag=6472;early-only"
+    );
+    // both tags are required
+    assert_eq!(
+        Replaces::parse("425928@bobster.example.org;to-tag=7743"),
+        None
+    );
+
+    let headers: rsip::Headers = vec![rsip::Header::from(Replaces {
+        early_only: false,
+        ..replaces
+    })]
+    .into();
+    assert_eq!(
+        replaces_header(&headers).flatten().map(|r| r.to_string()),
+        Some("425928@bobster.example.org;to-tag=7743;from-tag=6472".to_string())
+    );
+}
+
+#[test]
+fn test_dtmf_event() {
+    let event = DtmfEvent::new('5', 160).unwrap();
+    assert_eq!(event.to_string(), "Signal=5\r\nDuration=160\r\n");
+    assert_eq!(DtmfEvent::parse(event.to_string().as_bytes()), Some(event));
+    assert_eq!(
+        DtmfEvent::parse(b"signal= #\nduration= 250"),
+        Some(DtmfEvent {
+            digit: '#',
+            duration: 250
+        })
+    );
+    assert_eq!(DtmfEvent::new('a', 100).map(|e| e.digit).ok(), Some('A'));
+    assert!(DtmfEvent::new('E', 100).is_err());
+    assert!(DtmfEvent::parse(b"Signal=12\r\nDuration=160").is_none());
+    assert!(DtmfEvent::parse(b"Duration=160").is_none());
+}
+
+#[test]
+fn test_token_and_call_id() {
+    assert!(is_token("a1b2-C3.x~"));
+    assert!(!is_token(""));
+    assert!(!is_token("tag with space"));
+    assert!(!is_token("tag;x"));
+    assert!(is_call_id(
+        "f81d4fae-7dec-11d0-a765-00a0c91e6bf6@foo.bar.com"
+    ));
+    assert!(is_call_id("upstream:req/42"));
+    assert!(!is_call_id(""));
+    assert!(!is_call_id("a@b@c"));
+    assert!(!is_call_id("@host"));
+    assert!(!is_call_id("id with space"));
+    assert!(!is_call_id("id\r\nVia: x"));
+}
+
+#[test]
+fn test_compact_headers() {
+    use rsip::Header;
+    let mut headers: rsip::Headers = vec![
+        Header::Via("SIP/2.0/UDP 192.0.2.1:5060;branch=z9hG4bK-a".into()),
+        Header::CallId("abc@192.0.2.1".into()),
+        Header::MaxForwards(70.into()),
+        Header::Other("Session-Expires".into(), "1800".into()),
+    ]
+    .into();
+    let full = headers.clone();
+    compact_headers(&mut headers);
+    assert_eq!(
+        headers.to_string(),
+        "v: SIP/2.0/UDP 192.0.2.1:5060;branch=z9hG4bK-a\r\n\
+         i: abc@192.0.2.1\r\n\
+         Max-Forwards: 70\r\n\
+         x: 1800\r\n"
+    );
+    normalize_headers(&mut headers);
+    assert_eq!(headers, full);
+
+    let mut headers: rsip::Headers = vec![Header::Other(
+        "V".into(),
+        "SIP/2.0/UDP a.example.com;branch=z9hG4bK-1 , SIP/2.0/TCP b.example.com;branch=z9hG4bK-2"
+            .into(),
+    )]
+    .into();
+    normalize_headers(&mut headers);
+    assert_eq!(
+        headers.iter().collect::<Vec<_>>(),
+        vec![
+            &Header::Via("SIP/2.0/UDP a.example.com;branch=z9hG4bK-1".into()),
+            &Header::Via("SIP/2.0/TCP b.example.com;branch=z9hG4bK-2".into()),
+        ]
+    );
+}
+
+#[test]
+fn test_parse_gruu_contact() {
+    let contact = parse_contact(
+        "<sip:alice@192.0.2.1:5060>;+sip.instance=\"<urn:uuid:f81d>\";expires=3600\
+         ;pub-gruu=\"sip:alice@example.com;gr=urn:uuid:f81d\";temp-gruu=\"sip:tgruu.7hs@example.com;gr\"",
+    )
+    .unwrap();
+    assert_eq!(contact.uri.to_string(), "sip:alice@192.0.2.1:5060");
+    assert_eq!(
+        contact.expires().map(|e| e.value().to_string()),
+        Some("3600".into())
+    );
+    assert!(contact.params.iter().any(|p| matches!(
+        p,
+        rsip::Param::Other(name, Some(value))
+            if name.value() == "pub-gruu" && value.value() == "\"sip:alice@example.com;gr=urn:uuid:f81d\""
+    )));
+
+    // the gr of a remote target keeps its colons
+    let uri =
+        extract_uri_from_contact("<sip:alice@example.com;gr=urn:uuid:f81d;lr>;expires=60").unwrap();
+    assert_eq!(uri.to_string(), "sip:alice@example.com;gr=urn:uuid:f81d");
+}
+
+#[test]
+fn test_select_variant() {
+    let variants = [
+        Variant::new("text/plain", b"hello".to_vec()).with_language("en"),
+        Variant::new("text/plain", b"bonjour".to_vec()).with_language("fr"),
+        Variant::new("text/html", b"<p>hello</p>".to_vec()).with_language("en"),
+    ];
+    let headers: rsip::Headers = vec![
+        rsip::Header::Accept("text/html;q=0.5, text/*".into()),
+        rsip::Header::AcceptLanguage("fr-CA;q=0.2, fr;q=0.9, en;q=0.8".into()),
+    ]
+    .into();
+    let variant = select_variant(&headers, &variants).unwrap();
+    assert_eq!(variant.language.as_deref(), Some("fr"));
+    assert_eq!(
+        variant.headers(),
+        vec![
+            rsip::Header::ContentType("text/plain".into()),
+            rsip::Header::ContentLanguage("fr".into()),
+        ]
+    );
+
+    // an unknown language still gets a body, the first one
+    let headers: rsip::Headers = vec![rsip::Header::AcceptLanguage("de".into())].into();
+    assert_eq!(select_variant(&headers, &variants).unwrap().body, b"hello");
+
+    // q=0 excludes the media range
+    let headers: rsip::Headers = vec![rsip::Header::Accept(
+        "text/plain;q=0, application/sdp".into(),
+    )]
+    .into();
+    assert!(select_variant(&headers, &variants).is_none());
 }
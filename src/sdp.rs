@@ -0,0 +1,375 @@
+use crate::{Error, Result};
+
+/// The media direction attribute of a session or media (RFC 3264 5.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+impl Direction {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "sendrecv" => Some(Direction::SendRecv),
+            "sendonly" => Some(Direction::SendOnly),
+            "recvonly" => Some(Direction::RecvOnly),
+            "inactive" => Some(Direction::Inactive),
+            _ => None,
+        }
+    }
+
+    /// the direction of a stream put on hold: it is no longer received (RFC 3264 8.4)
+    pub fn hold(self) -> Self {
+        match self {
+            Direction::SendRecv | Direction::SendOnly => Direction::SendOnly,
+            Direction::RecvOnly | Direction::Inactive => Direction::Inactive,
+        }
+    }
+
+    /// the direction answering an offer with this one (RFC 3264 6.1)
+    pub fn answer(self) -> Self {
+        match self {
+            Direction::SendOnly => Direction::RecvOnly,
+            Direction::RecvOnly => Direction::SendOnly,
+            d => d,
+        }
+    }
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let direction = match self {
+            Direction::SendRecv => "sendrecv",
+            Direction::SendOnly => "sendonly",
+            Direction::RecvOnly => "recvonly",
+            Direction::Inactive => "inactive",
+        };
+        write!(f, "{}", direction)
+    }
+}
+
+/// An `m=` section, the lines after the media line are kept as they were
+/// received, in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaDescription {
+    pub media: String,
+    /// the port with its `/<count>` suffix if any
+    pub port: String,
+    pub proto: String,
+    /// the payload types of RTP media
+    pub formats: Vec<String>,
+    pub lines: Vec<String>,
+}
+
+impl MediaDescription {
+    fn parse(line: &str) -> Result<Self> {
+        let mut fields = line.split_ascii_whitespace();
+        let (media, port, proto) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(media), Some(port), Some(proto)) => (media, port, proto),
+            _ => return Err(Error::Error(format!("invalid sdp media line: m={}", line))),
+        };
+        Ok(Self {
+            media: media.to_string(),
+            port: port.to_string(),
+            proto: proto.to_string(),
+            formats: fields.map(|f| f.to_string()).collect(),
+            lines: vec![],
+        })
+    }
+
+    /// the value of the first `a=<name>:<value>` line, empty for `a=<name>`
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        attribute(&self.lines, name)
+    }
+
+    /// the direction attribute of this media, `None` when it has its session's
+    pub fn direction(&self) -> Option<Direction> {
+        direction(&self.lines)
+    }
+
+    /// replace the direction attribute, in place of the first one if any
+    pub fn set_direction(&mut self, direction: Direction) {
+        set_direction(&mut self.lines, Some(direction));
+    }
+
+    /// the `a=rtpmap` encoding of a payload type, e.g. `PCMU/8000`
+    pub fn rtpmap(&self, format: &str) -> Option<&str> {
+        self.lines
+            .iter()
+            .find_map(|line| format_attribute(line, "rtpmap", format))
+    }
+
+    /// Keep the formats `f` returns true for, given their rtpmap encoding. The
+    /// `rtpmap`, `fmtp` and `rtcp-fb` lines of the others are removed as well.
+    pub fn retain_formats<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&str, Option<&str>) -> bool,
+    {
+        let removed: Vec<String> = self
+            .formats
+            .iter()
+            .filter(|format| !f(format, self.rtpmap(format)))
+            .cloned()
+            .collect();
+        self.formats.retain(|format| !removed.contains(format));
+        self.lines.retain(|line| {
+            !["rtpmap", "fmtp", "rtcp-fb"].iter().any(|name| {
+                removed
+                    .iter()
+                    .any(|format| format_attribute(line, name, format).is_some())
+            })
+        });
+    }
+}
+
+impl std::fmt::Display for MediaDescription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "m={} {} {}", self.media, self.port, self.proto)?;
+        for format in &self.formats {
+            write!(f, " {}", format)?;
+        }
+        write!(f, "\r\n")?;
+        for line in &self.lines {
+            write!(f, "{}\r\n", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// A minimal `application/sdp` body (RFC 4566) for offer/answer: the media
+/// sections are parsed, every other line is kept verbatim so a body edited
+/// with the helpers below serializes back with its unknown lines untouched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionDescription {
+    /// the session level lines, `v=` to the first `m=`
+    pub lines: Vec<String>,
+    pub media: Vec<MediaDescription>,
+}
+
+impl SessionDescription {
+    pub fn parse(sdp: &str) -> Result<Self> {
+        let mut session = Self {
+            lines: vec![],
+            media: vec![],
+        };
+        for line in sdp.lines() {
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(media) = line.strip_prefix("m=") {
+                session.media.push(MediaDescription::parse(media)?);
+                continue;
+            }
+            match session.media.last_mut() {
+                Some(media) => media.lines.push(line.to_string()),
+                None => session.lines.push(line.to_string()),
+            }
+        }
+        if !session.lines.first().is_some_and(|l| l.starts_with("v=")) {
+            return Err(Error::Error("invalid sdp: no version line".to_string()));
+        }
+        Ok(session)
+    }
+
+    /// the session level value of the first `a=<name>:<value>` line
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        attribute(&self.lines, name)
+    }
+
+    /// the direction of a media, its own or the session's, sendrecv by default
+    pub fn media_direction(&self, index: usize) -> Direction {
+        self.media
+            .get(index)
+            .and_then(|m| m.direction())
+            .or_else(|| direction(&self.lines))
+            .unwrap_or(Direction::SendRecv)
+    }
+
+    /// Set the direction of every media, the session level one is removed
+    pub fn set_direction(&mut self, direction: Direction) {
+        set_direction(&mut self.lines, None);
+        for media in self.media.iter_mut() {
+            media.set_direction(direction);
+        }
+    }
+
+    /// Put every media on hold, see [`Direction::hold`]
+    pub fn hold(&mut self) {
+        for index in 0..self.media.len() {
+            let direction = self.media_direction(index).hold();
+            self.media[index].set_direction(direction);
+        }
+        set_direction(&mut self.lines, None);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+}
+
+impl TryFrom<&[u8]> for SessionDescription {
+    type Error = Error;
+
+    fn try_from(body: &[u8]) -> Result<Self> {
+        let sdp =
+            std::str::from_utf8(body).map_err(|e| Error::Error(format!("invalid sdp: {}", e)))?;
+        Self::parse(sdp)
+    }
+}
+
+impl std::fmt::Display for SessionDescription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in &self.lines {
+            write!(f, "{}\r\n", line)?;
+        }
+        for media in &self.media {
+            write!(f, "{}", media)?;
+        }
+        Ok(())
+    }
+}
+
+fn attribute<'a>(lines: &'a [String], name: &str) -> Option<&'a str> {
+    lines.iter().find_map(|line| {
+        let attr = line.strip_prefix("a=")?;
+        match attr.split_once(':') {
+            Some((n, value)) if n == name => Some(value),
+            None if attr == name => Some(""),
+            _ => None,
+        }
+    })
+}
+
+fn direction(lines: &[String]) -> Option<Direction> {
+    lines
+        .iter()
+        .find_map(|line| line.strip_prefix("a=").and_then(Direction::parse))
+}
+
+// replace the first direction line and drop the others, `None` drops them all
+fn set_direction(lines: &mut Vec<String>, direction: Option<Direction>) {
+    let is_direction = |line: &String| line.strip_prefix("a=").and_then(Direction::parse).is_some();
+    let position = lines.iter().position(is_direction);
+    lines.retain(|line| !is_direction(line));
+    if let Some(direction) = direction {
+        let line = format!("a={}", direction);
+        match position {
+            Some(position) => lines.insert(position, line),
+            None => lines.push(line),
+        }
+    }
+}
+
+// the value of `a=<name>:<format> <value>`
+fn format_attribute<'a>(line: &'a str, name: &str, format: &str) -> Option<&'a str> {
+    let value = line
+        .strip_prefix("a=")?
+        .strip_prefix(name)?
+        .strip_prefix(':')?;
+    match value.split_once(' ') {
+        Some((f, value)) if f == format => Some(value),
+        None if value == format => Some(""),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+const TEST_SDP: &str = "v=0\r\n\
+    o=alice 2890844526 2890844526 IN IP4 192.0.2.1\r\n\
+    s=-\r\n\
+    c=IN IP4 192.0.2.1\r\n\
+    t=0 0\r\n\
+    a=x-session-unknown:42\r\n\
+    m=audio 49170 RTP/AVP 0 8 101\r\n\
+    a=rtpmap:0 PCMU/8000\r\n\
+    a=rtpmap:8 PCMA/8000\r\n\
+    a=rtpmap:101 telephone-event/8000\r\n\
+    a=fmtp:101 0-16\r\n\
+    a=x-media-unknown\r\n\
+    a=ptime:20\r\n\
+    m=video 51372/2 RTP/AVP 96\r\n\
+    b=AS:512\r\n\
+    a=rtpmap:96 H264/90000\r\n\
+    a=fmtp:96 profile-level-id=42e01f\r\n\
+    a=rtcp-fb:96 nack\r\n\
+    a=recvonly\r\n";
+
+#[test]
+fn test_sdp_round_trip() {
+    let sdp = SessionDescription::parse(TEST_SDP).expect("parse sdp");
+    assert_eq!(sdp.to_string(), TEST_SDP);
+    assert_eq!(sdp.attribute("x-session-unknown"), Some("42"));
+    assert_eq!(sdp.media.len(), 2);
+    let audio = &sdp.media[0];
+    assert_eq!(audio.media, "audio");
+    assert_eq!(audio.formats, vec!["0", "8", "101"]);
+    assert_eq!(audio.rtpmap("8"), Some("PCMA/8000"));
+    assert_eq!(audio.attribute("x-media-unknown"), Some(""));
+    assert_eq!(sdp.media[1].port, "51372/2");
+
+    // LF only line endings and a trailing blank line are accepted
+    let lf = TEST_SDP.replace("\r\n", "\n") + "\n";
+    let sdp = SessionDescription::try_from(lf.as_bytes()).expect("parse sdp");
+    assert_eq!(sdp.to_bytes(), TEST_SDP.as_bytes());
+
+    assert!(SessionDescription::parse("o=- 0 0 IN IP4 0.0.0.0\r\n").is_err());
+    assert!(SessionDescription::parse("v=0\r\nm=audio\r\n").is_err());
+}
+
+#[test]
+fn test_sdp_direction() {
+    let mut sdp = SessionDescription::parse(TEST_SDP).expect("parse sdp");
+    assert_eq!(sdp.media_direction(0), Direction::SendRecv);
+    assert_eq!(sdp.media_direction(1), Direction::RecvOnly);
+    assert_eq!(Direction::SendOnly.answer(), Direction::RecvOnly);
+
+    sdp.hold();
+    assert_eq!(sdp.media[0].direction(), Some(Direction::SendOnly));
+    assert_eq!(sdp.media[1].direction(), Some(Direction::Inactive));
+    // the attribute is replaced in place, the other lines are untouched
+    assert_eq!(
+        sdp.media[1].lines.last().map(|l| l.as_str()),
+        Some("a=inactive")
+    );
+    assert_eq!(
+        sdp.media[0].lines.last().map(|l| l.as_str()),
+        Some("a=sendonly")
+    );
+
+    // a session level direction applies to every media until it is set
+    let mut sdp =
+        SessionDescription::parse(&TEST_SDP.replace("a=x-session-unknown:42", "a=inactive"))
+            .expect("parse sdp");
+    assert_eq!(sdp.media_direction(0), Direction::Inactive);
+    sdp.set_direction(Direction::SendRecv);
+    assert_eq!(sdp.attribute("inactive"), None);
+    assert_eq!(sdp.media_direction(0), Direction::SendRecv);
+    assert_eq!(sdp.media_direction(1), Direction::SendRecv);
+}
+
+#[test]
+fn test_sdp_retain_formats() {
+    let mut sdp = SessionDescription::parse(TEST_SDP).expect("parse sdp");
+    // keep PCMA and the DTMF events
+    sdp.media[0].retain_formats(|format, rtpmap| {
+        format == "101" || rtpmap.is_some_and(|r| r.starts_with("PCMA/"))
+    });
+    assert_eq!(sdp.media[0].formats, vec!["8", "101"]);
+    assert_eq!(
+        sdp.media[0].lines,
+        vec![
+            "a=rtpmap:8 PCMA/8000",
+            "a=rtpmap:101 telephone-event/8000",
+            "a=fmtp:101 0-16",
+            "a=x-media-unknown",
+            "a=ptime:20",
+        ]
+    );
+    sdp.media[1].retain_formats(|_, _| false);
+    assert!(sdp.media[1].formats.is_empty());
+    assert_eq!(sdp.media[1].lines, vec!["b=AS:512", "a=recvonly"]);
+    assert!(sdp.to_string().contains("m=audio 49170 RTP/AVP 8 101\r\n"));
+}
@@ -0,0 +1,86 @@
+use std::{fmt, future::Future, pin::Pin, time::Duration};
+use tokio::{sync::watch, time::Instant};
+
+/// A future ready once its duration has passed on a [`Clock`]
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// The time the timers of an endpoint run on: the transaction and dialog
+/// timers, the session timers, the refreshes and the transport keepalives,
+/// see [`EndpointOption::clock`](super::endpoint::EndpointOption::clock)
+pub trait Clock: Send + Sync + fmt::Debug {
+    fn now(&self) -> Instant;
+
+    fn sleep(&self, duration: Duration) -> Sleep;
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        self.sleep(deadline.saturating_duration_since(self.now()))
+    }
+}
+
+/// The tokio clock, the default. Tests may pause and advance it with
+/// `tokio::time` too
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// A clock standing still until it is advanced, the sleeps whose deadline
+/// it passes are woken at once
+#[derive(Debug)]
+pub struct MockClock {
+    now: watch::Sender<Instant>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            now: watch::Sender::new(Instant::now()),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.send_modify(|now| *now += duration);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.borrow()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        let deadline = self.now() + duration;
+        let mut now = self.now.subscribe();
+        Box::pin(async move {
+            now.wait_for(|now| *now >= deadline).await.ok();
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_mock_clock() {
+    let clock = MockClock::new();
+    let start = clock.now();
+    let mut sleep = clock.sleep(Duration::from_secs(10));
+    clock.advance(Duration::from_secs(9));
+    assert!(futures::poll!(&mut sleep).is_pending());
+    clock.advance(Duration::from_secs(1));
+    assert!(futures::poll!(&mut sleep).is_ready());
+    assert_eq!(clock.now() - start, Duration::from_secs(10));
+    // a sleep already due is ready at once
+    clock.sleep(Duration::ZERO).await;
+}
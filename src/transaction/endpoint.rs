@@ -1,49 +1,235 @@
 use super::{
-    key::TransactionKey,
-    make_via_branch,
+    clock::{Clock, TokioClock},
+    key::{TransactionKey, TransactionRole},
+    make_tag,
+    message::RequestBuilder,
+    random_text,
     timer::Timer,
     transaction::{Transaction, TransactionEvent, TransactionEventSender},
-    SipConnection, TransactionReceiver, TransactionSender, TransactionTimer,
+    SipConnection, TransactionReceiver, TransactionSender, TransactionTimer, BRANCH_LEN,
 };
 use crate::{
-    transport::{SipAddr, TransportEvent, TransportLayer},
+    dialog::{
+        client_dialog::RedirectPolicy,
+        dialog_layer::DialogLayerInner,
+        metrics::{DialogMetrics, DialogMetricsSnapshot},
+        registration::Gruu,
+    },
+    rsip_ext::{
+        asserted_identities, compact_headers, unsupported_tags, AssertedIdentity, DTMF_RELAY,
+        P_ASSERTED_IDENTITY,
+    },
+    transport::{
+        loopback::LoopbackNetwork,
+        sip_addr::host_ip,
+        tls::TlsConfig,
+        tracer::{Direction, MessageTracer},
+        SipAddr, TransportEvent, TransportLayer, TransportRef,
+    },
     Error, Result, USER_AGENT,
 };
-use rsip::SipMessage;
+use futures::future::join_all;
+use rsip::{
+    prelude::{HasHeaders, HeadersExt},
+    Request, Response, SipMessage, StatusCodeKind,
+};
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
 };
 use tokio::{
     select,
     sync::mpsc::{error, unbounded_channel},
-    time::sleep,
+    time::Instant,
 };
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, info, trace, warn};
 
+/// most digest nonces we keep counting at once
+const MAX_NONCE_COUNTS: usize = 1024;
+
+/// the option tags the stack implements, `timer` is only advertised by the
+/// dialogs with a session timer
+pub const DEFAULT_SUPPORTED: [&str; 3] = ["100rel", "replaces", "timer"];
+
+/// the methods the dialogs handle, sent as the Allow header of INVITEs, their
+/// 2xx and the OPTIONS responses
+pub const ALLOW_METHODS: [rsip::Method; 11] = [
+    rsip::Method::Invite,
+    rsip::Method::Ack,
+    rsip::Method::Bye,
+    rsip::Method::Cancel,
+    rsip::Method::Options,
+    rsip::Method::Info,
+    rsip::Method::Update,
+    rsip::Method::PRack,
+    rsip::Method::Refer,
+    rsip::Method::Notify,
+    rsip::Method::Message,
+];
+
+/// the body types the dialogs understand, the Accept header of the OPTIONS responses
+pub const ACCEPT_TYPES: [&str; 3] = ["application/sdp", DTMF_RELAY, "message/sipfrag"];
+
 pub struct EndpointInner {
     pub user_agent: String,
     pub timers: Timer<TransactionTimer>,
     pub transport_layer: TransportLayer,
     pub finished_transactions: Mutex<HashMap<TransactionKey, Option<SipMessage>>>,
     pub transactions: Mutex<HashMap<TransactionKey, TransactionEventSender>>,
+    /// when each transaction was attached, for the sweep
+    attached_at: Mutex<HashMap<TransactionKey, Instant>>,
+    /// the last nonce-count sent for each digest nonce (RFC 7616 3.4)
+    nonce_counts: Mutex<HashMap<String, u32>>,
+    branch_seq: AtomicU64,
+    pub(crate) dialog_metrics: DialogMetrics,
+    /// the dialog layers created on the endpoint, their dialogs are hung up on shutdown
+    pub(crate) dialog_layers: Mutex<Vec<Weak<DialogLayerInner>>>,
+    shutting_down: AtomicBool,
+    contact_builder: Mutex<Option<ContactBuilder>>,
+    outgoing_hook: Mutex<Option<MessageHook>>,
+    incoming_hook: Mutex<Option<MessageHook>>,
+    /// logs the messages in full, off when `None`
+    message_tracer: Mutex<Option<MessageTracer>>,
+    /// the option tags of the Supported header, a request requiring any other is answered 420
+    supported: Mutex<Vec<String>>,
+    /// write the headers with their compact names, e.g. `v` for Via
+    compact_headers: AtomicBool,
+    /// the states waiting in a [`DialogLayer::state_channel`](crate::dialog::dialog_layer::DialogLayer::state_channel),
+    /// unbounded when `None`
+    dialog_state_capacity: Mutex<Option<usize>>,
+    /// the GRUUs of the last registration, the Contact of new dialogs
+    gruu: Mutex<Option<Gruu>>,
+    /// use the temporary GRUU for every dialog, not only the ones with Privacy
+    prefer_temp_gruu: AtomicBool,
+    /// the Service-Route of the last registration
+    service_route: Mutex<Vec<rsip::UriWithParams>>,
+    /// prepend the Service-Route to the requests out of a dialog
+    honor_service_route: AtomicBool,
+    /// follow the Contacts of a 3xx to an INVITE, off when `None`
+    redirect_policy: Mutex<Option<RedirectPolicy>>,
+    /// the addresses of the trust domain, the only sources of an asserted identity
+    trusted_peers: Mutex<Vec<IpAddr>>,
+    /// remove the P-Asserted-Identity of the requests to untrusted targets
+    strip_asserted_identity: AtomicBool,
     incoming_sender: Mutex<Option<TransactionSender>>,
     cancel_token: CancellationToken,
     timer_interval: Duration,
+    transaction_sweep: Option<Duration>,
+    clock: Arc<dyn Clock>,
 
     pub t1: Duration,
+    pub t2: Duration,
     pub t4: Duration,
     pub t1x64: Duration,
+    pub timer_c: Option<Duration>,
+    pub auto_trying: bool,
 }
 pub type EndpointInnerRef = Arc<EndpointInner>;
 
+/// What a [`ContactBuilder`] is given to build the Contact of a request
+pub struct ContactContext<'a> {
+    pub method: &'a rsip::Method,
+    /// the transport the request is sent on
+    pub transport: rsip::Transport,
+    /// the local address of the connection, the external one when behind a NAT
+    pub addr: &'a SipAddr,
+    /// the Contact the request was built with
+    pub contact: &'a rsip::typed::Contact,
+}
+
+/// Builds the Contact of each outgoing request once its connection is known
+pub type ContactBuilder = Arc<dyn Fn(&ContactContext) -> rsip::typed::Contact + Send + Sync>;
+
+/// Rewrites a message on its way out or in, e.g. to add a tracing header.
+///
+/// The Via headers are put back as they were after the hook, their branch
+/// matches the message to its transaction. The Call-ID, tags and CSeq are the
+/// hook's to keep, the dialogs are matched on them. A request must stay a
+/// request and a response a response, else the message is left unchanged.
+pub type MessageHook = Arc<dyn Fn(&mut SipMessage) + Send + Sync>;
+
+/// RFC 3261 timer values, see Table 4
+#[derive(Clone, Debug)]
+pub struct EndpointOption {
+    pub t1: Duration,
+    pub t2: Duration,
+    pub t4: Duration,
+    /// how long an INVITE waits for its final response, restarted on each
+    /// provisional one but 100, before it is cancelled; `None` waits forever
+    pub timer_c: Option<Duration>,
+    /// answer a new INVITE with 100 Trying before the TU gets it, so that
+    /// its retransmissions stop while the TU decides (RFC 3261 17.2.1)
+    pub auto_trying: bool,
+    /// how often the non-INVITE transactions attached for more than 64*T1
+    /// and T4 are ended with a timeout, a safety net for the ones whose timers
+    /// never end them; the INVITE ones last as long as the TU lets them ring.
+    /// `None` turns the sweep off
+    pub transaction_sweep: Option<Duration>,
+    /// the clock of the transaction and dialog timers, the session timers,
+    /// the refreshes and the keepalives of the transport layer, a
+    /// [`MockClock`](super::clock::MockClock) lets the tests advance it by hand
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for EndpointOption {
+    fn default() -> Self {
+        EndpointOption {
+            t1: Duration::from_millis(500),
+            t2: Duration::from_secs(4),
+            t4: Duration::from_secs(5),
+            // more than 3 minutes (RFC 3261 16.6 step 11)
+            timer_c: Some(Duration::from_secs(180)),
+            auto_trying: true,
+            transaction_sweep: Some(Duration::from_secs(5)),
+            clock: Arc::new(TokioClock),
+        }
+    }
+}
+
+impl EndpointOption {
+    /// 64*T1, the timeout of the transactions (Timer B, F and H) and of the
+    /// dialog requests
+    pub fn t1x64(&self) -> Duration {
+        self.t1 * 64
+    }
+}
+
 pub struct EndpointBuilder {
     user_agent: String,
     transport_layer: Option<TransportLayer>,
     cancel_token: Option<CancellationToken>,
     timer_interval: Option<Duration>,
+    option: Option<EndpointOption>,
+    tls_config: Option<TlsConfig>,
+    contact_builder: Option<ContactBuilder>,
+    outgoing_hook: Option<MessageHook>,
+    incoming_hook: Option<MessageHook>,
+    message_tracer: Option<MessageTracer>,
+    supported: Option<Vec<String>>,
+    max_message_size: Option<usize>,
+    compact_headers: bool,
+    dialog_state_capacity: Option<usize>,
+    prefer_temp_gruu: bool,
+    honor_service_route: bool,
+    redirect_policy: Option<RedirectPolicy>,
+    trusted_peers: Vec<IpAddr>,
+    strip_asserted_identity: bool,
+    transports: Vec<TransportRef>,
+}
+
+/// How the dialogs ended on [`Endpoint::shutdown`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// the dialogs that got a final response to their BYE, CANCEL or reject
+    pub terminated: usize,
+    /// the dialogs terminated locally without a final response in time
+    pub forced: usize,
 }
 
 pub struct Endpoint {
@@ -56,19 +242,48 @@ impl EndpointInner {
         transport_layer: TransportLayer,
         cancel_token: CancellationToken,
         timer_interval: Option<Duration>,
+        option: Option<EndpointOption>,
     ) -> Arc<Self> {
+        let option = option.unwrap_or_default();
+        transport_layer.set_clock(option.clock.clone());
+        let t1x64 = option.t1x64();
         Arc::new(EndpointInner {
             user_agent,
-            timers: Timer::new(),
+            timers: Timer::with_clock(option.clock.clone()),
             transport_layer,
             transactions: Mutex::new(HashMap::new()),
+            attached_at: Mutex::new(HashMap::new()),
             finished_transactions: Mutex::new(HashMap::new()),
+            nonce_counts: Mutex::new(HashMap::new()),
+            branch_seq: AtomicU64::new(0),
+            dialog_metrics: DialogMetrics::default(),
+            dialog_layers: Mutex::new(vec![]),
+            shutting_down: AtomicBool::new(false),
+            contact_builder: Mutex::new(None),
+            outgoing_hook: Mutex::new(None),
+            incoming_hook: Mutex::new(None),
+            message_tracer: Mutex::new(None),
+            supported: Mutex::new(DEFAULT_SUPPORTED.iter().map(|t| t.to_string()).collect()),
+            compact_headers: AtomicBool::new(false),
+            dialog_state_capacity: Mutex::new(None),
+            gruu: Mutex::new(None),
+            prefer_temp_gruu: AtomicBool::new(false),
+            service_route: Mutex::new(vec![]),
+            honor_service_route: AtomicBool::new(true),
+            redirect_policy: Mutex::new(None),
+            trusted_peers: Mutex::new(vec![]),
+            strip_asserted_identity: AtomicBool::new(false),
             timer_interval: timer_interval.unwrap_or(Duration::from_millis(20)),
+            transaction_sweep: option.transaction_sweep,
+            clock: option.clock,
             cancel_token,
             incoming_sender: Mutex::new(None),
-            t1: Duration::from_millis(500),
-            t4: Duration::from_secs(4),
-            t1x64: Duration::from_millis(64 * 500),
+            t1: option.t1,
+            t2: option.t2,
+            t4: option.t4,
+            t1x64,
+            timer_c: option.timer_c,
+            auto_trying: option.auto_trying,
         })
     }
 
@@ -82,10 +297,54 @@ impl EndpointInner {
             r = self.clone().process_transport_layer() => {
                 _ = r?
             },
+            _ = self.process_sweep() => {},
         }
         Ok(())
     }
 
+    async fn process_sweep(&self) {
+        let interval = match self.transaction_sweep {
+            Some(interval) => interval,
+            None => return std::future::pending().await,
+        };
+        loop {
+            self.clock.sleep(interval).await;
+            self.sweep_transactions(self.clock.now());
+        }
+    }
+
+    /// End the non-INVITE transactions attached for more than 64*T1 and T4
+    /// before `now`, their TU gets a timeout. Returns how many were ended
+    fn sweep_transactions(&self, now: Instant) -> usize {
+        let max_age = self.t1x64 + self.t4;
+        let expired: Vec<TransactionKey> = self
+            .attached_at
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, at)| !key.is_invite() && now.duration_since(**at) > max_age)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            info!("transaction left for too long, expiring {}", key);
+            self.attached_at.lock().unwrap().remove(key);
+            if let Some(tu) = self.transactions.lock().unwrap().remove(key) {
+                tu.send(TransactionEvent::Expired).ok();
+            }
+        }
+        expired.len()
+    }
+
+    /// the clock the timers of the endpoint run on
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// the transactions in progress
+    pub fn transaction_count(&self) -> usize {
+        self.transactions.lock().unwrap().len()
+    }
+
     // process transport layer, receive message from transport layer
     async fn process_transport_layer(self: Arc<Self>) -> Result<()> {
         let (transport_tx, mut transport_rx) = unbounded_channel();
@@ -94,6 +353,9 @@ impl EndpointInner {
         while let Some(event) = transport_rx.recv().await {
             match event {
                 TransportEvent::Incoming(msg, connection, from) => {
+                    if let Some(tracer) = self.message_tracer() {
+                        tracer.trace(Direction::Incoming, Some(&from), &msg);
+                    }
                     match self.on_received_message(msg, connection).await {
                         Ok(()) => {}
                         Err(e) => {
@@ -101,11 +363,24 @@ impl EndpointInner {
                         }
                     }
                 }
+                TransportEvent::TooLarge(msg, connection, from) => {
+                    if let Some(tracer) = self.message_tracer() {
+                        tracer.trace(Direction::Incoming, Some(&from), &msg);
+                    }
+                    if let Err(e) = self.on_message_too_large(msg, connection).await {
+                        warn!("on_message_too_large error:{} {:?}", from, e);
+                    }
+                }
                 TransportEvent::New(t) => {
                     trace!("new connection {} ", t);
                 }
                 TransportEvent::Closed(t) => {
                     trace!("connection closed {} ", t);
+                    if t.is_reliable() {
+                        for tu in self.transactions.lock().unwrap().values() {
+                            tu.send(TransactionEvent::ConnectionClosed(t.clone())).ok();
+                        }
+                    }
                 }
             }
         }
@@ -114,11 +389,12 @@ impl EndpointInner {
 
     pub async fn process_timer(self: Arc<Self>) -> Result<()> {
         while !self.cancel_token.is_cancelled() {
-            for t in self.timers.poll(Instant::now()) {
+            for t in self.timers.poll(self.clock.now()) {
                 match t {
                     TransactionTimer::TimerCleanup(key) => {
                         debug!("TimerCleanup {}", key);
                         self.transactions.lock().unwrap().remove(&key);
+                        self.attached_at.lock().unwrap().remove(&key);
                         self.finished_transactions.lock().unwrap().remove(&key);
                         continue;
                     }
@@ -137,21 +413,296 @@ impl EndpointInner {
                     }
                 }
             }
-            sleep(self.timer_interval).await;
+            self.clock.sleep(self.timer_interval).await;
         }
         Ok(())
     }
 
+    /// Replace the Contact builder, `None` keeps the Contact the requests
+    /// were built with
+    pub fn set_contact_builder(&self, builder: Option<ContactBuilder>) {
+        *self.contact_builder.lock().unwrap() = builder;
+    }
+
+    pub(super) fn contact_builder(&self) -> Option<ContactBuilder> {
+        self.contact_builder.lock().unwrap().clone()
+    }
+
+    /// Replace the hook run on each message before it is sent, `None` sends
+    /// them as built
+    pub fn set_outgoing_hook(&self, hook: Option<MessageHook>) {
+        *self.outgoing_hook.lock().unwrap() = hook;
+    }
+
+    /// Replace the hook run on each received message before it is matched to
+    /// a transaction
+    pub fn set_incoming_hook(&self, hook: Option<MessageHook>) {
+        *self.incoming_hook.lock().unwrap() = hook;
+    }
+
+    /// Replace the tracer logging each message sent or received, `None`
+    /// (the default) logs none
+    pub fn set_message_tracer(&self, tracer: Option<MessageTracer>) {
+        *self.message_tracer.lock().unwrap() = tracer;
+    }
+
+    fn message_tracer(&self) -> Option<MessageTracer> {
+        self.message_tracer.lock().unwrap().clone()
+    }
+
+    /// a request once the outgoing hook has run on it, the transactions keep
+    /// this one for their retransmissions
+    pub(super) fn rewrite_request(&self, request: Request) -> Request {
+        let hook = self.outgoing_hook.lock().unwrap().clone();
+        match run_hook(hook, request.into()) {
+            SipMessage::Request(request) => request,
+            SipMessage::Response(_) => unreachable!("a hook keeps the kind of message"),
+        }
+    }
+
+    pub(super) fn rewrite_response(&self, response: Response) -> Response {
+        let hook = self.outgoing_hook.lock().unwrap().clone();
+        match run_hook(hook, response.into()) {
+            SipMessage::Response(response) => response,
+            SipMessage::Request(_) => unreachable!("a hook keeps the kind of message"),
+        }
+    }
+
+    /// Replace the size of the largest message accepted,
+    /// [`MAX_MESSAGE_SIZE`](crate::transport::connection::MAX_MESSAGE_SIZE) by
+    /// default. Checked by the transports on the bytes received, larger
+    /// requests are answered 513 and larger responses dropped
+    pub fn set_max_message_size(&self, size: usize) {
+        self.transport_layer.set_max_message_size(size);
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.transport_layer.max_message_size()
+    }
+
+    /// Send the headers with their compact names (RFC 3261 7.3.3), to save
+    /// bytes on UDP or WebSocket
+    pub fn set_compact_headers(&self, compact: bool) {
+        self.compact_headers.store(compact, Ordering::Relaxed);
+    }
+
+    pub fn compact_headers(&self) -> bool {
+        self.compact_headers.load(Ordering::Relaxed)
+    }
+
+    /// a message as it is written on the wire, the transactions keep the one
+    /// with the full header names to match on
+    pub(super) fn wire_message(&self, msg: impl Into<SipMessage>) -> SipMessage {
+        let mut msg = msg.into();
+        if self.compact_headers() {
+            compact_headers(msg.headers_mut());
+        }
+        msg
+    }
+
+    /// Send `msg` on `connection` as written on the wire, to `destination`
+    /// or where the message says when `None`
+    pub(super) async fn send_wire(
+        &self,
+        connection: &SipConnection,
+        msg: impl Into<SipMessage>,
+        destination: Option<&SipAddr>,
+    ) -> Result<()> {
+        let msg = self.wire_message(msg);
+        if let Some(tracer) = self.message_tracer() {
+            let peer = match destination {
+                Some(destination) => Some(destination.clone()),
+                None => SipConnection::get_destination(&msg)
+                    .ok()
+                    .map(|addr| SipAddr {
+                        r#type: connection.get_addr().r#type,
+                        addr: addr.into(),
+                    }),
+            };
+            tracer.trace(Direction::Outgoing, peer.as_ref(), &msg);
+        }
+        connection.send(msg, destination).await
+    }
+
+    /// Bound the state channels of the dialog layers to `capacity` states,
+    /// see [`DialogStateStream::bounded`](crate::dialog::dialog::DialogStateStream::bounded)
+    /// for what is dropped, `None` leaves them unbounded
+    pub fn set_dialog_state_capacity(&self, capacity: Option<usize>) {
+        *self.dialog_state_capacity.lock().unwrap() = capacity;
+    }
+
+    pub fn dialog_state_capacity(&self) -> Option<usize> {
+        *self.dialog_state_capacity.lock().unwrap()
+    }
+
+    /// The GRUUs the dialogs take their Contact from, set by a
+    /// [`Registration`](crate::dialog::registration::Registration) with an
+    /// instance; `gruu` is added to the supported option tags (RFC 5627 4.1)
+    pub fn set_gruu(&self, gruu: Option<Gruu>) {
+        if gruu.is_some() {
+            let mut supported = self.supported.lock().unwrap();
+            if !supported.iter().any(|t| t.eq_ignore_ascii_case("gruu")) {
+                supported.push("gruu".to_string());
+            }
+        }
+        *self.gruu.lock().unwrap() = gruu;
+    }
+
+    pub fn gruu(&self) -> Option<Gruu> {
+        self.gruu.lock().unwrap().clone()
+    }
+
+    /// Use the temporary GRUU for all dialogs, by default only the requests
+    /// with a Privacy header other than `none` get it (RFC 5627 3.2)
+    pub fn set_prefer_temp_gruu(&self, prefer: bool) {
+        self.prefer_temp_gruu.store(prefer, Ordering::Relaxed);
+    }
+
+    /// the GRUU to put in the Contact of a dialog created with `headers`
+    pub(crate) fn gruu_contact(&self, headers: &rsip::Headers) -> Option<rsip::Uri> {
+        let gruu = self.gruu()?;
+        let private = headers.iter().any(|h| match h {
+            rsip::Header::Other(name, value) if name.eq_ignore_ascii_case("Privacy") => {
+                !value.trim().eq_ignore_ascii_case("none")
+            }
+            _ => false,
+        });
+        gruu.contact_uri(private || self.prefer_temp_gruu.load(Ordering::Relaxed))
+    }
+
+    /// The route set of the requests out of a dialog but REGISTER, set by a
+    /// [`Registration`](crate::dialog::registration::Registration) from the
+    /// Service-Route of its 2xx (RFC 3608)
+    pub fn set_service_route(&self, routes: Vec<rsip::UriWithParams>) {
+        *self.service_route.lock().unwrap() = routes;
+    }
+
+    /// the Service-Route new requests go through, empty when it is not honored
+    pub fn service_route(&self) -> Vec<rsip::UriWithParams> {
+        match self.honor_service_route.load(Ordering::Relaxed) {
+            true => self.service_route.lock().unwrap().clone(),
+            false => vec![],
+        }
+    }
+
+    /// Prepend the Service-Route of the registration to the requests out of
+    /// a dialog, on by default
+    pub fn set_honor_service_route(&self, honor: bool) {
+        self.honor_service_route.store(honor, Ordering::Relaxed);
+    }
+
+    /// Retry an INVITE answered with a 3xx toward its Contacts, `None`
+    /// terminates the dialog with the 3xx
+    pub fn set_redirect_policy(&self, policy: Option<RedirectPolicy>) {
+        *self.redirect_policy.lock().unwrap() = policy;
+    }
+
+    pub fn redirect_policy(&self) -> Option<RedirectPolicy> {
+        self.redirect_policy.lock().unwrap().clone()
+    }
+
+    /// Replace the addresses of the trust domain (RFC 3325 2.3), the peers
+    /// whose P-Asserted-Identity is taken
+    pub fn set_trusted_peers(&self, peers: Vec<IpAddr>) {
+        *self.trusted_peers.lock().unwrap() = peers;
+    }
+
+    pub fn is_trusted(&self, addr: &SipAddr) -> bool {
+        match host_ip(&addr.addr.host) {
+            Some(ip) => self.trusted_peers.lock().unwrap().contains(&ip),
+            None => false,
+        }
+    }
+
+    /// Remove the P-Asserted-Identity of the requests leaving the trust
+    /// domain, off by default (RFC 3325 5)
+    pub fn set_strip_asserted_identity(&self, strip: bool) {
+        self.strip_asserted_identity.store(strip, Ordering::Relaxed);
+    }
+
+    /// The asserted identities of an incoming `request`, empty unless the
+    /// source of the request, as stamped on its top Via, is a trusted peer
+    pub fn asserted_identities(&self, request: &Request) -> Vec<AssertedIdentity> {
+        let trusted = request
+            .via_header()
+            .ok()
+            .and_then(|via| SipConnection::parse_target_from_via(via).ok())
+            .map(|source| self.is_trusted(&source.into()))
+            .unwrap_or(false);
+        match trusted {
+            true => asserted_identities(&request.headers),
+            false => vec![],
+        }
+    }
+
+    /// drop the P-Asserted-Identity of a request sent to an untrusted
+    /// `target`, the request uri when `None`
+    pub(super) fn strip_untrusted_identity(&self, request: &mut Request, target: Option<&SipAddr>) {
+        if !self.strip_asserted_identity.load(Ordering::Relaxed) {
+            return;
+        }
+        let trusted = match target {
+            Some(target) => self.is_trusted(target),
+            None => self.is_trusted(&request.uri.host_with_port.clone().into()),
+        };
+        if !trusted {
+            request.headers.retain(|h| {
+                !matches!(h, rsip::Header::Other(name, _) if name.eq_ignore_ascii_case(P_ASSERTED_IDENTITY))
+            });
+        }
+    }
+
+    /// Replace the option tags the endpoint supports
+    pub fn set_supported(&self, tags: Vec<String>) {
+        *self.supported.lock().unwrap() = tags;
+    }
+
+    pub fn supported(&self) -> Vec<String> {
+        self.supported.lock().unwrap().clone()
+    }
+
+    pub fn allow_header(&self) -> rsip::headers::Allow {
+        let methods: Vec<String> = ALLOW_METHODS.iter().map(|m| m.to_string()).collect();
+        methods.join(", ").into()
+    }
+
+    /// Allow, Accept and Supported, what an OPTIONS is answered with (RFC 3261 11.2)
+    pub fn capability_headers(&self) -> Vec<rsip::Header> {
+        let mut headers = vec![
+            self.allow_header().into(),
+            rsip::Header::Accept(ACCEPT_TYPES.join(", ").into()),
+        ];
+        let supported = self.supported();
+        if !supported.is_empty() {
+            headers.push(rsip::Header::Supported(supported.join(", ").into()));
+        }
+        headers
+    }
+
     pub fn attach_incoming_sender(&self, sender: Option<TransactionSender>) {
         *self.incoming_sender.lock().unwrap() = sender;
     }
 
+    /// Answer a request over the size limit with 513, a response is dropped
+    async fn on_message_too_large(&self, msg: SipMessage, connection: SipConnection) -> Result<()> {
+        match msg {
+            SipMessage::Request(req) if req.method != rsip::Method::Ack => {
+                let resp = self.make_response(&req, rsip::StatusCode::MessageTooLarge, None);
+                self.send_wire(&connection, self.rewrite_response(resp), None)
+                    .await
+            }
+            _ => Ok(()),
+        }
+    }
+
     // receive message from transport layer
     pub async fn on_received_message(
         self: &Arc<Self>,
         msg: SipMessage,
         connection: SipConnection,
     ) -> Result<()> {
+        let hook = self.incoming_hook.lock().unwrap().clone();
+        let msg = run_hook(hook, msg);
         let mut key = match &msg {
             SipMessage::Request(req) => {
                 TransactionKey::from_request(req, super::key::TransactionRole::Server)?
@@ -171,7 +722,14 @@ impl EndpointInner {
             .flatten();
 
         if let Some(last_message) = last_message {
-            connection.send(last_message, None).await?;
+            let reply = match &msg {
+                // a CANCEL of an answered INVITE is answered, not the INVITE again
+                SipMessage::Request(req) if req.method == rsip::Method::Cancel => self
+                    .rewrite_response(self.make_response(req, rsip::StatusCode::OK, None))
+                    .into(),
+                _ => last_message,
+            };
+            self.send_wire(&connection, reply, None).await?;
             return Ok(());
         }
 
@@ -194,7 +752,8 @@ impl EndpointInner {
 
         if self.incoming_sender.lock().unwrap().is_none() {
             let resp = self.make_response(&request, rsip::StatusCode::ServiceUnavailable, None);
-            connection.send(resp.into(), None).await?;
+            self.send_wire(&connection, self.rewrite_response(resp), None)
+                .await?;
             return Err(Error::TransactionError(
                 "incoming_sender not set".to_string(),
                 key,
@@ -206,8 +765,26 @@ impl EndpointInner {
                 TransactionKey::from_ack_or_cancel(&request, super::key::TransactionRole::Server)?;
         }
 
-        let tx =
+        if !matches!(request.method, rsip::Method::Ack | rsip::Method::Cancel) {
+            let unsupported = unsupported_tags(&request.headers, &self.supported());
+            if !unsupported.is_empty() {
+                debug!("unsupported option tags {:?} {}", unsupported, key);
+                let mut resp = self.make_response(&request, rsip::StatusCode::BadExtension, None);
+                resp.headers
+                    .push(rsip::Header::Unsupported(unsupported.join(", ").into()));
+                self.send_wire(&connection, self.rewrite_response(resp), None)
+                    .await?;
+                return Ok(());
+            }
+        }
+
+        let mut tx =
             Transaction::new_server(key.clone(), request.clone(), self.clone(), Some(connection));
+        if request.method == rsip::Method::Invite && self.auto_trying {
+            if let Err(e) = tx.send_trying().await {
+                info!("failed to send 100 Trying {} {}", key, e);
+            }
+        }
 
         self.incoming_sender
             .lock()
@@ -223,21 +800,22 @@ impl EndpointInner {
             .lock()
             .unwrap()
             .insert(key.clone(), tu_sender);
+        self.attached_at
+            .lock()
+            .unwrap()
+            .insert(key.clone(), self.clock.now());
     }
 
     pub fn detach_transaction(&self, key: &TransactionKey, last_message: Option<SipMessage>) {
         trace!("detach_transaction {}", key);
         self.transactions.lock().unwrap().remove(key);
+        self.attached_at.lock().unwrap().remove(key);
 
         if let Some(msg) = last_message {
-            let timer_k_duration = if msg.is_request() {
-                self.t4
-            } else {
-                self.t1x64
-            };
-
+            // the ACK of an INVITE is resent until Timer D, a non-INVITE
+            // response until Timer J
             self.timers.timeout(
-                timer_k_duration,
+                self.t1x64,
                 TransactionTimer::TimerCleanup(key.clone()), // maybe use TimerK ???
             );
 
@@ -265,6 +843,67 @@ impl EndpointInner {
         }]);
         Ok(rr.into())
     }
+    /// Send an out-of-dialog MESSAGE (RFC 3428) and wait for the final response
+    pub async fn send_message(
+        self: &Arc<Self>,
+        from: rsip::Uri,
+        to: rsip::Uri,
+        content_type: &str,
+        body: Vec<u8>,
+    ) -> Result<Option<Response>> {
+        let from = rsip::typed::From {
+            display_name: None,
+            uri: from,
+            params: vec![],
+        }
+        .with_tag(make_tag());
+        let to = rsip::typed::To {
+            display_name: None,
+            uri: to,
+            params: vec![],
+        };
+        let via = self.get_via(None)?;
+        let mut request =
+            self.make_request(rsip::Method::Message, to.uri.clone(), via, from, to, 1);
+        request
+            .headers
+            .push(rsip::Header::ContentType(content_type.into()));
+        request.body = body;
+
+        let key = TransactionKey::from_request(&request, TransactionRole::Client)?;
+        let mut tx = Transaction::new_client(key, request, self.clone(), None);
+        tx.send().await?;
+        while let Some(msg) = tx.receive().await {
+            match msg {
+                SipMessage::Response(resp)
+                    if resp.status_code.kind() != StatusCodeKind::Provisional =>
+                {
+                    return Ok(Some(resp));
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// the nonce-count for the next request answering `nonce`, starting at 1
+    pub fn next_nonce_count(&self, nonce: &str) -> u32 {
+        let mut nonce_counts = self.nonce_counts.lock().unwrap();
+        if nonce_counts.len() >= MAX_NONCE_COUNTS && !nonce_counts.contains_key(nonce) {
+            nonce_counts.clear();
+        }
+        let nc = nonce_counts.entry(nonce.to_string()).or_insert(0);
+        *nc += 1;
+        *nc
+    }
+
+    /// A new Via branch: the magic cookie (RFC 3261 8.1.1.7), random text and a
+    /// counter so no two branches of this endpoint are ever the same
+    pub fn generate_branch(&self) -> rsip::Param {
+        let seq = self.branch_seq.fetch_add(1, Ordering::Relaxed);
+        rsip::Param::Branch(format!("z9hG4bK{}{:x}", random_text(BRANCH_LEN), seq).into())
+    }
+
     pub fn get_via(&self, branch: Option<rsip::Param>) -> Result<rsip::typed::Via> {
         let first_addr = self
             .transport_layer
@@ -273,12 +912,14 @@ impl EndpointInner {
             .ok_or(Error::EndpointError("not sipaddrs".to_string()))
             .cloned()?;
 
+        let transport = first_addr.r#type.unwrap_or_default();
+        let sent_by: rsip::HostWithPort = first_addr.into();
         let via = rsip::typed::Via {
             version: rsip::Version::V2,
-            transport: first_addr.r#type.unwrap_or_default(),
-            uri: first_addr.addr.into(),
+            transport,
+            uri: sent_by.into(),
             params: vec![
-                branch.unwrap_or(make_via_branch()),
+                branch.unwrap_or_else(|| self.generate_branch()),
                 rsip::Param::Other("rport".into(), None),
             ]
             .into(),
@@ -294,6 +935,22 @@ impl EndpointBuilder {
             transport_layer: None,
             cancel_token: None,
             timer_interval: None,
+            option: None,
+            tls_config: None,
+            contact_builder: None,
+            outgoing_hook: None,
+            incoming_hook: None,
+            message_tracer: None,
+            supported: None,
+            max_message_size: None,
+            compact_headers: false,
+            dialog_state_capacity: None,
+            prefer_temp_gruu: false,
+            honor_service_route: true,
+            redirect_policy: None,
+            trusted_peers: vec![],
+            strip_asserted_identity: false,
+            transports: vec![],
         }
     }
 
@@ -317,6 +974,121 @@ impl EndpointBuilder {
         self
     }
 
+    pub fn option(&mut self, option: EndpointOption) -> &mut Self {
+        self.option.replace(option);
+        self
+    }
+
+    /// roots, client certificate and SNI used for outgoing TLS connections
+    pub fn tls_config(&mut self, tls_config: TlsConfig) -> &mut Self {
+        self.tls_config.replace(tls_config);
+        self
+    }
+
+    /// build the Contact of each request from the transport it is sent on,
+    /// e.g. `transport=ws` and a `+sip.instance` for WebSocket clients
+    pub fn contact_builder<F>(&mut self, builder: F) -> &mut Self
+    where
+        F: Fn(&ContactContext) -> rsip::typed::Contact + Send + Sync + 'static,
+    {
+        self.contact_builder.replace(Arc::new(builder));
+        self
+    }
+
+    /// rewrite each message before it is sent, see [`MessageHook`] for what
+    /// it must leave alone. It runs once per message, the retransmissions are
+    /// the rewritten one, and again on a request failing over to another target.
+    pub fn on_outgoing<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&mut SipMessage) + Send + Sync + 'static,
+    {
+        self.outgoing_hook.replace(Arc::new(hook));
+        self
+    }
+
+    /// rewrite each received message before it is matched to a transaction or
+    /// dialog, e.g. to normalize the headers of a peer
+    pub fn on_incoming<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&mut SipMessage) + Send + Sync + 'static,
+    {
+        self.incoming_hook.replace(Arc::new(hook));
+        self
+    }
+
+    /// log each message sent or received in full at TRACE level, see
+    /// [`MessageTracer`] for what is hidden
+    pub fn message_tracer(&mut self, tracer: MessageTracer) -> &mut Self {
+        self.message_tracer.replace(tracer);
+        self
+    }
+
+    /// add a transport of our own, e.g. a [`LoopbackTransport`](crate::transport::loopback::LoopbackTransport)
+    /// for tests, it sends to every target of its transport type
+    pub fn transport(&mut self, transport: TransportRef) -> &mut Self {
+        self.transports.push(transport);
+        self
+    }
+
+    /// the option tags of the Supported header, [`DEFAULT_SUPPORTED`] if unset
+    pub fn supported(&mut self, tags: &[&str]) -> &mut Self {
+        self.supported
+            .replace(tags.iter().map(|t| t.to_string()).collect());
+        self
+    }
+
+    /// the size of the largest message accepted,
+    /// [`MAX_MESSAGE_SIZE`](crate::transport::connection::MAX_MESSAGE_SIZE) if unset
+    pub fn max_message_size(&mut self, size: usize) -> &mut Self {
+        self.max_message_size.replace(size);
+        self
+    }
+
+    /// send the headers with their compact names, e.g. `v` for Via and `i`
+    /// for Call-ID
+    pub fn compact_headers(&mut self, compact: bool) -> &mut Self {
+        self.compact_headers = compact;
+        self
+    }
+
+    /// bound the state channels of the dialog layers, unbounded if unset
+    pub fn dialog_state_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.dialog_state_capacity.replace(capacity);
+        self
+    }
+
+    /// use the temporary GRUU of the registration for all dialogs
+    pub fn prefer_temp_gruu(&mut self, prefer: bool) -> &mut Self {
+        self.prefer_temp_gruu = prefer;
+        self
+    }
+
+    /// route the requests out of a dialog through the Service-Route of the
+    /// registration, on if unset
+    pub fn honor_service_route(&mut self, honor: bool) -> &mut Self {
+        self.honor_service_route = honor;
+        self
+    }
+
+    /// follow the 3xx to the INVITEs, they end the dialog if unset
+    pub fn follow_redirects(&mut self, policy: RedirectPolicy) -> &mut Self {
+        self.redirect_policy.replace(policy);
+        self
+    }
+
+    /// the addresses of the trust domain, the P-Asserted-Identity of the
+    /// requests from other sources is ignored
+    pub fn trusted_peers(&mut self, peers: &[IpAddr]) -> &mut Self {
+        self.trusted_peers = peers.to_vec();
+        self
+    }
+
+    /// remove the P-Asserted-Identity of the requests to untrusted targets
+    pub fn strip_asserted_identity(&mut self, strip: bool) -> &mut Self {
+        self.strip_asserted_identity = strip;
+        self
+    }
+
     pub fn build(&mut self) -> Endpoint {
         let cancel_token = self.cancel_token.take().unwrap_or_default();
 
@@ -324,19 +1096,67 @@ impl EndpointBuilder {
             .transport_layer
             .take()
             .unwrap_or(TransportLayer::new(cancel_token.child_token()));
+        if let Some(tls_config) = self.tls_config.take() {
+            transport_layer.set_tls_config(tls_config);
+        }
+        for transport in self.transports.drain(..) {
+            transport_layer.add_transport(transport.into());
+        }
 
         let core = EndpointInner::new(
             self.user_agent.clone(),
             transport_layer,
             cancel_token,
             self.timer_interval,
+            self.option.take(),
         );
+        core.set_contact_builder(self.contact_builder.take());
+        core.set_outgoing_hook(self.outgoing_hook.take());
+        core.set_incoming_hook(self.incoming_hook.take());
+        core.set_message_tracer(self.message_tracer.take());
+        if let Some(tags) = self.supported.take() {
+            core.set_supported(tags);
+        }
+        if let Some(size) = self.max_message_size.take() {
+            core.set_max_message_size(size);
+        }
+        core.set_compact_headers(self.compact_headers);
+        core.set_dialog_state_capacity(self.dialog_state_capacity.take());
+        core.set_prefer_temp_gruu(self.prefer_temp_gruu);
+        core.set_honor_service_route(self.honor_service_route);
+        core.set_redirect_policy(self.redirect_policy.take());
+        core.set_trusted_peers(std::mem::take(&mut self.trusted_peers));
+        core.set_strip_asserted_identity(self.strip_asserted_identity);
 
         Endpoint { inner: core }
     }
 }
 
 impl Endpoint {
+    /// Two endpoints sending to each other over a [`LoopbackNetwork`], at
+    /// `192.0.2.1:5060` and `192.0.2.2:5060`, for tests without sockets.
+    ///
+    /// The timers of the endpoints and their dialogs run on the default
+    /// [`Clock`], the tokio clock. Pause it with
+    /// `#[tokio::test(start_paused = true)]` or `tokio::time::pause()` (the
+    /// `test-util` feature of tokio) and the retransmissions fire as soon as
+    /// the endpoints are idle, or step them with `tokio::time::advance()`. The
+    /// endpoints poll their timers every 20ms of that clock. Neither endpoint
+    /// is served yet, run `serve()` on both.
+    pub fn test_pair() -> (Endpoint, Endpoint) {
+        let network = LoopbackNetwork::new();
+        let endpoint = |addr: [u8; 4]| {
+            let cancel_token = CancellationToken::new();
+            let transport = network.bind(SocketAddr::from((addr, 5060)));
+            EndpointBuilder::new()
+                .transport_layer(TransportLayer::new(cancel_token.child_token()))
+                .cancel_token(cancel_token)
+                .transport(Arc::new(transport))
+                .build()
+        };
+        (endpoint([192, 0, 2, 1]), endpoint([192, 0, 2, 2]))
+    }
+
     pub async fn serve(&self) {
         let inner = self.inner.clone();
         match inner.serve().await {
@@ -349,9 +1169,37 @@ impl Endpoint {
         }
     }
 
-    pub fn shutdown(&self) {
+    /// Hang up the dialogs of all the dialog layers, then close the transports.
+    ///
+    /// Confirmed dialogs are sent a BYE, INVITEs in progress a CANCEL, and the
+    /// final responses are awaited up to T1*64. Calling it again returns an
+    /// empty summary.
+    pub async fn shutdown(&self) -> ShutdownSummary {
+        let mut summary = ShutdownSummary::default();
+        if self.inner.shutting_down.swap(true, Ordering::Relaxed) {
+            return summary;
+        }
         info!("endpoint shutdown requested");
+        let layers = self.inner.dialog_layers.lock().unwrap().clone();
+        let dialogs = layers
+            .iter()
+            .filter_map(|layer| layer.upgrade())
+            .flat_map(|layer| layer.dialogs())
+            .collect::<Vec<_>>();
+        let timeout = self.inner.t1x64;
+        let results = join_all(dialogs.iter().map(|d| d.shutdown(timeout))).await;
+        for clean in results {
+            match clean {
+                true => summary.terminated += 1,
+                false => summary.forced += 1,
+            }
+        }
+        info!(
+            "endpoint shutdown: {} dialogs terminated, {} forced",
+            summary.terminated, summary.forced
+        );
         self.inner.cancel_token.cancel();
+        summary
     }
 
     //
@@ -366,4 +1214,43 @@ impl Endpoint {
     pub fn get_addrs(&self) -> Vec<SipAddr> {
         self.inner.transport_layer.get_addrs()
     }
+
+    /// The dialog counts by state, setup times, durations and termination reasons
+    pub fn dialog_metrics(&self) -> DialogMetricsSnapshot {
+        self.inner.dialog_metrics.snapshot()
+    }
+
+    /// The transactions in progress, see [`EndpointOption::transaction_sweep`]
+    pub fn transaction_count(&self) -> usize {
+        self.inner.transaction_count()
+    }
+
+    /// A request outside of any dialog, e.g. an OPTIONS ping or a MESSAGE
+    pub fn request_builder(&self, method: rsip::Method, to: rsip::Uri) -> RequestBuilder {
+        RequestBuilder::new(self.inner.clone(), method, to)
+    }
+}
+
+fn run_hook(hook: Option<MessageHook>, msg: SipMessage) -> SipMessage {
+    let hook = match hook {
+        Some(hook) => hook,
+        None => return msg,
+    };
+    let mut rewritten = msg.clone();
+    hook(&mut rewritten);
+    if rewritten.is_request() != msg.is_request() {
+        warn!("message hook changed the kind of message, sending it unchanged");
+        return msg;
+    }
+    let is_via = |h: &&rsip::Header| matches!(h, rsip::Header::Via(_));
+    let mut headers: Vec<rsip::Header> = msg.headers().iter().filter(is_via).cloned().collect();
+    headers.extend(rewritten.headers().iter().filter(|h| !is_via(h)).cloned());
+    if rewritten.body().len() != msg.body().len() {
+        headers.retain(|h| !matches!(h, rsip::Header::ContentLength(_)));
+        headers.push(rsip::Header::ContentLength(
+            (rewritten.body().len() as u32).into(),
+        ));
+    }
+    *rewritten.headers_mut() = headers.into();
+    rewritten
 }
@@ -1,11 +1,7 @@
-use crate::{Error, Result};
+use crate::{rsip_ext::parse_via, Error, Result};
 use rsip::headers::UntypedHeader;
 use rsip::typed::Via;
-use rsip::{
-    param::Tag,
-    prelude::{HeadersExt, ToTypedHeader},
-    Method,
-};
+use rsip::{param::Tag, prelude::HeadersExt, Method};
 use rsip::{Request, Response};
 use std::fmt::Write;
 use std::hash::Hash;
@@ -35,8 +31,15 @@ impl std::fmt::Display for TransactionKey {
 }
 
 impl TransactionKey {
+    /// an INVITE transaction or the ACK of its non-2xx, not a CANCEL
+    pub fn is_invite(&self) -> bool {
+        self.0
+            .split_once('.')
+            .is_some_and(|(_, rest)| rest.starts_with("INVITE_"))
+    }
+
     pub fn from_ack_or_cancel(req: &Request, role: TransactionRole) -> Result<Self> {
-        let via = req.via_header()?.typed()?;
+        let via = parse_via(req.via_header()?)?;
         let method = req.method().clone();
         let from_tag = req
             .from_header()?
@@ -48,7 +51,7 @@ impl TransactionKey {
     }
 
     pub fn from_request(req: &Request, role: TransactionRole) -> Result<Self> {
-        let via = req.via_header()?.typed()?;
+        let via = parse_via(req.via_header()?)?;
         let mut method = req.method().clone();
 
         if matches!(method, Method::Ack | Method::Cancel) {
@@ -65,7 +68,7 @@ impl TransactionKey {
     }
 
     pub fn from_response(resp: &Response, role: TransactionRole) -> Result<Self> {
-        let via = resp.via_header()?.typed()?;
+        let via = parse_via(resp.via_header()?)?;
         let cseq = resp.cseq_header()?;
         let method = cseq.method()?;
         let from_tag = resp
@@ -87,6 +90,16 @@ impl TransactionKey {
     ) -> Result<Self> {
         let mut key = String::new();
         match via.branch() {
+            // a server matches the sent-by too, a retransmission comes from the
+            // same client, a request of another one with the same branch does
+            // not (RFC 3261 17.2.3)
+            Some(branch) if role == TransactionRole::Server => {
+                write!(
+                    &mut key,
+                    "{}.{}_{}_{}_{}_{}_{}",
+                    role, method, cseq, call_id, from_tag, branch, via.uri.host_with_port
+                )
+            }
             Some(branch) => {
                 write!(
                     &mut key,
@@ -150,7 +163,7 @@ fn test_transaction_key() -> Result<()> {
     assert_eq!(
         key,
         TransactionKey(
-            "s.REGISTER_2_1j9FpLxk3uxtm8tn@sip.restsend.com_ja743ks76zlflH_z9hG4bKnashd92"
+            "s.REGISTER_2_1j9FpLxk3uxtm8tn@sip.restsend.com_ja743ks76zlflH_z9hG4bKnashd92_client.sip.restsend.com:5061"
                 .to_string()
         )
     );
@@ -163,9 +176,23 @@ fn test_transaction_key() -> Result<()> {
     assert_eq!(
         key,
         TransactionKey(
-            "s.INVITE_2_1j9FpLxk3uxtm8tn@sip.restsend.com_ja743ks76zlflH_z9hG4bKnashd92"
+            "s.INVITE_2_1j9FpLxk3uxtm8tn@sip.restsend.com_ja743ks76zlflH_z9hG4bKnashd92_sip.restsend.com:5061"
                 .to_string()
         )
     );
+
+    // the same branch from another client is another transaction
+    let mut other_req = register_req.clone();
+    other_req
+        .headers
+        .unique_push(Via::new("SIP/2.0/TLS other.restsend.com:5061;branch=z9hG4bKnashd92").into());
+    assert_ne!(
+        TransactionKey::from_request(&other_req, TransactionRole::Server)?,
+        TransactionKey::from_request(&register_req, TransactionRole::Server)?
+    );
+    assert_eq!(
+        TransactionKey::from_request(&other_req, TransactionRole::Client)?,
+        TransactionKey::from_request(&register_req, TransactionRole::Client)?
+    );
     Ok(())
 }
@@ -1,5 +1,18 @@
-use super::{endpoint::EndpointInner, make_call_id};
-use rsip::{Header, Request, Response, StatusCode};
+use super::{
+    endpoint::{EndpointInner, EndpointInnerRef},
+    key::{TransactionKey, TransactionRole},
+    make_call_id, make_tag, random_text,
+    transaction::Transaction,
+};
+use crate::{
+    rsip_ext::{parse_via, privacy_header, AssertedIdentity, Privacy, RsipHeadersExt},
+    Error,
+};
+use rsip::{
+    prelude::{HeadersExt, UntypedHeader},
+    Header, Param, Request, Response, StatusCode, StatusCodeKind,
+};
+use std::hash::{DefaultHasher, Hash, Hasher};
 
 impl EndpointInner {
     pub fn make_request(
@@ -11,7 +24,14 @@ impl EndpointInner {
         to: rsip::typed::To,
         seq: u32,
     ) -> rsip::Request {
-        let headers = vec![
+        // ask for rport so the response comes back through our NAT binding (RFC 3581)
+        let mut via = via;
+        if !via.params.iter().any(
+            |p| matches!(p, rsip::Param::Other(key, _) if key.value().eq_ignore_ascii_case("rport")),
+        ) {
+            via.params.push(rsip::Param::Other("rport".into(), None));
+        }
+        let mut headers = vec![
             Header::Via(via.into()),
             Header::CallId(make_call_id(None)),
             Header::From(from.into()),
@@ -20,6 +40,13 @@ impl EndpointInner {
             Header::MaxForwards(70.into()),
             Header::UserAgent(self.user_agent.clone().into()),
         ];
+        // the Service-Route is the pre-loaded route set, a REGISTER goes
+        // without it (RFC 3608 6.1)
+        if method != rsip::Method::Register {
+            for route in self.service_route() {
+                headers.push(Header::Route(route.to_string().into()));
+            }
+        }
         rsip::Request {
             method,
             uri: req_uri,
@@ -48,6 +75,16 @@ impl EndpointInner {
             )
         });
         headers.unique_push(Header::UserAgent(self.user_agent.clone().into()));
+        if req.method == rsip::Method::Options && status_code.kind() == StatusCodeKind::Successful {
+            headers.extend(self.capability_headers());
+        }
+        // a 405 lists the methods we do handle (RFC 3261 8.2.1), a 501 as well
+        if matches!(
+            status_code,
+            StatusCode::MethodNotAllowed | StatusCode::NotImplemented
+        ) {
+            headers.unique_push(Header::Allow(self.allow_header()));
+        }
         Response {
             status_code,
             version: req.version().clone(),
@@ -55,4 +92,231 @@ impl EndpointInner {
             body: body.unwrap_or_default(),
         }
     }
+
+    /// The ACK of a non-2xx final response to an INVITE, sent by the client
+    /// transaction itself with the branch of the INVITE (RFC 3261 17.1.1.3)
+    pub fn make_error_ack(&self, invite: &Request, resp: &Response) -> crate::Result<Request> {
+        let mut headers = vec![Header::Via(invite.via_header()?.clone())];
+        headers.extend(
+            invite
+                .headers
+                .iter()
+                .filter(|h| {
+                    matches!(
+                        h,
+                        Header::CallId(_)
+                            | Header::From(_)
+                            | Header::Route(_)
+                            | Header::MaxForwards(_)
+                    )
+                })
+                .cloned(),
+        );
+        headers.push(Header::To(resp.to_header()?.clone()));
+        headers.push(Header::CSeq(
+            rsip::typed::CSeq {
+                seq: invite.cseq_header()?.seq()?,
+                method: rsip::Method::Ack,
+            }
+            .into(),
+        ));
+        headers.push(Header::ContentLength(0.into()));
+        Ok(Request {
+            method: rsip::Method::Ack,
+            uri: invite.uri.clone(),
+            headers: headers.into(),
+            body: vec![],
+            version: rsip::Version::V2,
+        })
+    }
+
+    /// Copy an incoming request to relay it, with Max-Forwards decremented and our
+    /// Via on top. Fails with 483 once Max-Forwards runs out (RFC 3261 16.3) and
+    /// with 482 when the request comes back to us unchanged (RFC 3261 16.3 4).
+    pub fn make_forward_request(&self, req: &Request) -> Result<Request, StatusCode> {
+        let max_forwards = match req.max_forwards_header() {
+            Ok(max_forwards) => max_forwards.num().map_err(|_| StatusCode::BadRequest)?,
+            Err(_) => 70,
+        };
+        if max_forwards == 0 {
+            return Err(StatusCode::TooManyHops);
+        }
+
+        // a spiral changes the request uri and so the branch, a loop doesn't
+        let branch_prefix = format!("z9hG4bK{:016x}", forward_hash(req));
+        // our sent-by as written in the Via
+        let addrs = self
+            .get_addrs()
+            .into_iter()
+            .map(Into::into)
+            .collect::<Vec<rsip::HostWithPort>>();
+        let looped = req.headers.iter().any(|h| {
+            let via = match h {
+                Header::Via(via) => parse_via(via),
+                _ => return false,
+            };
+            via.is_ok_and(|via| {
+                addrs.contains(&via.uri.host_with_port)
+                    && via
+                        .branch()
+                        .is_some_and(|b| b.value().starts_with(&branch_prefix))
+            })
+        });
+        if looped {
+            return Err(StatusCode::LoopDetected);
+        }
+
+        let branch = Param::Branch(format!("{}{}", branch_prefix, random_text(8)).into());
+        let via = self
+            .get_via(Some(branch))
+            .map_err(|_| StatusCode::ServerInternalError)?;
+        let mut forward = req.clone();
+        forward
+            .headers
+            .unique_push(Header::MaxForwards((max_forwards - 1).into()));
+        forward.headers.push_front(Header::Via(via.into()));
+        Ok(forward)
+    }
+}
+
+/// An out-of-dialog request such as OPTIONS or MESSAGE, see `Endpoint::request_builder`.
+///
+/// It gets a new Call-ID, a From tag and CSeq 1, From and Contact default to the
+/// first local address.
+pub struct RequestBuilder {
+    endpoint: EndpointInnerRef,
+    method: rsip::Method,
+    to: rsip::Uri,
+    from: Option<rsip::Uri>,
+    contact: Option<rsip::Uri>,
+    headers: Vec<Header>,
+    body: Vec<u8>,
+    transport: Option<rsip::Transport>,
+}
+
+impl RequestBuilder {
+    pub fn new(endpoint: EndpointInnerRef, method: rsip::Method, to: rsip::Uri) -> Self {
+        RequestBuilder {
+            endpoint,
+            method,
+            to,
+            from: None,
+            contact: None,
+            headers: vec![],
+            body: vec![],
+            transport: None,
+        }
+    }
+
+    pub fn from(&mut self, from: rsip::Uri) -> &mut Self {
+        self.from = Some(from);
+        self
+    }
+
+    pub fn contact(&mut self, contact: rsip::Uri) -> &mut Self {
+        self.contact = Some(contact);
+        self
+    }
+
+    pub fn header(&mut self, header: Header) -> &mut Self {
+        self.headers.push(header);
+        self
+    }
+
+    /// Send the request on `transport` whatever the `transport` param of
+    /// the uri says
+    pub fn transport(&mut self, transport: rsip::Transport) -> &mut Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Add a P-Asserted-Identity, up to one sip and one tel identity for a
+    /// request in a trust domain (RFC 3325 9.1)
+    pub fn asserted_identity(&mut self, identity: AssertedIdentity) -> &mut Self {
+        self.headers.push(identity.into());
+        self
+    }
+
+    /// Add a Privacy header, e.g. `id` to keep the asserted identity in the
+    /// trust domain (RFC 3325 9.3)
+    pub fn privacy(&mut self, values: &[Privacy]) -> &mut Self {
+        self.headers.push(privacy_header(values));
+        self
+    }
+
+    pub fn body(&mut self, content_type: &str, body: Vec<u8>) -> &mut Self {
+        self.headers.push(Header::ContentType(content_type.into()));
+        self.body = body;
+        self
+    }
+
+    pub fn build(&self) -> crate::Result<Request> {
+        let local = self
+            .endpoint
+            .get_addrs()
+            .first()
+            .cloned()
+            .ok_or(Error::EndpointError("not sipaddrs".to_string()))?;
+        let local_uri = rsip::Uri {
+            scheme: Some(rsip::Scheme::Sip),
+            host_with_port: local.clone().into(),
+            params: match local.r#type {
+                Some(rsip::Transport::Udp) | None => vec![],
+                Some(transport) => vec![Param::Transport(transport)],
+            },
+            ..Default::default()
+        };
+        let from = rsip::typed::From {
+            display_name: None,
+            uri: self.from.clone().unwrap_or(local_uri.clone()),
+            params: vec![],
+        }
+        .with_tag(make_tag());
+        let to = rsip::typed::To {
+            display_name: None,
+            uri: self.to.clone(),
+            params: vec![],
+        };
+        let via = self.endpoint.get_via(None)?;
+        let mut request =
+            self.endpoint
+                .make_request(self.method, self.to.clone(), via, from, to, 1);
+        let contact = self.contact.clone().unwrap_or(local_uri);
+        request
+            .headers
+            .push(rsip::typed::Contact::from(contact).into());
+        for header in &self.headers {
+            request.headers.push(header.clone());
+        }
+        request
+            .headers
+            .push(Header::ContentLength((self.body.len() as u32).into()));
+        request.body = self.body.clone();
+        Ok(request)
+    }
+
+    /// Send the request through a new client transaction, the responses come from its `receive`
+    pub async fn send(&self) -> crate::Result<Transaction> {
+        let request = self.build()?;
+        let key = TransactionKey::from_request(&request, TransactionRole::Client)?;
+        let mut tx = Transaction::new_client(key, request, self.endpoint.clone(), None);
+        tx.transport = self.transport;
+        tx.send().await?;
+        Ok(tx)
+    }
+}
+
+/// the part of a forwarded branch that identifies the request (RFC 3261 16.6 8)
+fn forward_hash(req: &Request) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    req.uri.to_string().hash(&mut hasher);
+    for value in [
+        req.call_id_header().map(|h| h.value().to_string()),
+        req.from_header().map(|h| h.value().to_string()),
+        req.to_header().map(|h| h.value().to_string()),
+        req.cseq_header().map(|h| h.value().to_string()),
+    ] {
+        value.ok().hash(&mut hasher);
+    }
+    hasher.finish()
 }
@@ -5,6 +5,7 @@ use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use transaction::Transaction;
 use uuid::Uuid;
 
+pub mod clock;
 pub mod endpoint;
 pub mod key;
 pub mod message;
@@ -49,10 +50,11 @@ pub enum TransactionTimer {
     TimerA(TransactionKey, Duration),
     TimerB(TransactionKey),
     TimerD(TransactionKey),
-    TimerE(TransactionKey),
+    TimerE(TransactionKey, Duration),
     TimerF(TransactionKey),
     TimerK(TransactionKey),
     TimerG(TransactionKey, Duration),
+    TimerJ(TransactionKey),
     TimerCleanup(TransactionKey),
 }
 
@@ -62,10 +64,11 @@ impl TransactionTimer {
             TransactionTimer::TimerA(key, _) => key,
             TransactionTimer::TimerB(key) => key,
             TransactionTimer::TimerD(key) => key,
-            TransactionTimer::TimerE(key) => key,
+            TransactionTimer::TimerE(key, _) => key,
             TransactionTimer::TimerF(key) => key,
             TransactionTimer::TimerG(key, _) => key,
             TransactionTimer::TimerK(key) => key,
+            TransactionTimer::TimerJ(key) => key,
             TransactionTimer::TimerCleanup(key) => key,
         }
     }
@@ -79,12 +82,15 @@ impl std::fmt::Display for TransactionTimer {
             }
             TransactionTimer::TimerB(key) => write!(f, "TimerB: {}", key),
             TransactionTimer::TimerD(key) => write!(f, "TimerD: {}", key),
-            TransactionTimer::TimerE(key) => write!(f, "TimerE: {}", key),
+            TransactionTimer::TimerE(key, duration) => {
+                write!(f, "TimerE: {} {}", key, duration.as_millis())
+            }
             TransactionTimer::TimerF(key) => write!(f, "TimerF: {}", key),
             TransactionTimer::TimerG(key, duration) => {
                 write!(f, "TimerG: {} {}", key, duration.as_millis())
             }
             TransactionTimer::TimerK(key) => write!(f, "TimerK: {}", key),
+            TransactionTimer::TimerJ(key) => write!(f, "TimerJ: {}", key),
             TransactionTimer::TimerCleanup(key) => write!(f, "TimerCleanup: {}", key),
         }
     }
@@ -52,4 +52,19 @@ mod tests {
             "sip:bob@restsend.com;transport=UDP"
         );
     }
+
+    #[test]
+    fn test_ipv6_contact() {
+        let line = "<sip:alice@[2001:db8::1]:5070;transport=tcp;ob>;expires=3600";
+        let contact_uri = extract_uri_from_contact(line).expect("failed to parse contact");
+        assert_eq!(
+            contact_uri.to_string(),
+            "sip:alice@[2001:db8::1]:5070;transport=TCP"
+        );
+        let addr = crate::transport::SipAddr::try_from(&contact_uri).expect("sip addr");
+        assert_eq!(
+            addr.get_socketaddr().expect("socket addr"),
+            "[2001:db8::1]:5070".parse().unwrap()
+        );
+    }
 }
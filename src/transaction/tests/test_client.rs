@@ -1,10 +1,16 @@
+use crate::transaction::endpoint::{ContactContext, EndpointOption};
 use crate::transaction::key::{TransactionKey, TransactionRole};
 use crate::transaction::transaction::Transaction;
+use crate::transport::keepalive::KeepaliveConfig;
+use crate::transport::transport_layer::TransportConfig;
 use crate::transport::udp::UdpConnection;
-use crate::{transport::TransportEvent, Result};
-use rsip::{headers::*, SipMessage};
-use std::time::Duration;
-use tokio::{select, sync::mpsc::unbounded_channel, time::sleep};
+use crate::transport::TransportLayer;
+use crate::{transport::TransportEvent, EndpointBuilder, Result};
+use rsip::{headers::*, prelude::HeadersExt, SipMessage};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::{io::AsyncReadExt, select, sync::mpsc::unbounded_channel, time::sleep};
+use tokio_util::sync::CancellationToken;
 use tracing::info;
 
 #[tokio::test]
@@ -108,3 +114,965 @@ async fn test_client_transaction() -> Result<()> {
     }
     Ok(())
 }
+
+#[tokio::test]
+async fn test_client_non_invite_timeout() -> Result<()> {
+    let token = CancellationToken::new();
+    let tl = TransportLayer::new(token.child_token());
+    let conn = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    tl.add_transport(conn.into());
+
+    let t1 = Duration::from_millis(10);
+    let endpoint = EndpointBuilder::new()
+        .user_agent("rsipstack-test")
+        .transport_layer(tl)
+        .timer_interval(Duration::from_millis(2))
+        .option(EndpointOption {
+            t1,
+            t2: Duration::from_millis(40),
+            t4: Duration::from_millis(50),
+            ..Default::default()
+        })
+        .build();
+
+    // peer never responds, only counts retransmissions
+    let peer_server = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let received = AtomicUsize::new(0);
+    let peer_server_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                while let Some(TransportEvent::Incoming(..)) = receiver.recv().await {
+                    received.fetch_add(1, Ordering::Relaxed);
+                }
+            } => {}
+            _ = peer_server.serve_loop(sender) => {}
+        }
+    };
+
+    let send_loop = async {
+        let register_req = rsip::message::Request {
+            method: rsip::method::Method::Register,
+            uri: rsip::Uri {
+                scheme: Some(rsip::Scheme::Sip),
+                host_with_port: peer_server.get_addr().addr.clone(),
+                ..Default::default()
+            },
+            headers: vec![
+                Via::new("SIP/2.0/UDP restsend.com:5060;branch=z9hG4bKnashd93").into(),
+                CSeq::new("1 REGISTER").into(),
+                From::new("Bob <sip:bob@restsend.com>;tag=ja743ks76zlflH").into(),
+                CallId::new("1j9FpLxk3uxtm8tn@restsend.com").into(),
+            ]
+            .into(),
+            version: rsip::Version::V2,
+            body: Default::default(),
+        };
+
+        let key = TransactionKey::from_request(&register_req, TransactionRole::Client)
+            .expect("client_transaction");
+        let mut tx = Transaction::new_client(key, register_req, endpoint.inner.clone(), None);
+        let start = Instant::now();
+        tx.send().await.expect("send request");
+        match tx.receive().await {
+            Some(SipMessage::Response(resp)) => {
+                assert_eq!(resp.status_code, rsip::StatusCode::RequestTimeout);
+            }
+            _ => panic!("must receive timeout"),
+        }
+        start.elapsed()
+    };
+
+    select! {
+        elapsed = send_loop => {
+            // Timer F fires at 64*T1
+            assert!(elapsed >= t1 * 64, "timeout too early: {:?}", elapsed);
+            // Timer E retransmits over UDP
+            assert!(received.load(Ordering::Relaxed) > 1);
+        }
+        _ = peer_server_loop => {
+            panic!("must not reach here");
+        }
+        _ = endpoint.serve() => {
+            panic!("must not reach here");
+        }
+        _ = sleep(Duration::from_secs(2)) => {
+            panic!("timeout waiting");
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_failover() -> Result<()> {
+    let token = CancellationToken::new();
+    let tl = TransportLayer::new(token.child_token());
+    let conn = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    tl.add_transport(conn.into());
+
+    let t1 = Duration::from_millis(10);
+    let endpoint = EndpointBuilder::new()
+        .user_agent("rsipstack-test")
+        .transport_layer(tl)
+        .timer_interval(Duration::from_millis(2))
+        .option(EndpointOption {
+            t1,
+            t2: Duration::from_millis(40),
+            t4: Duration::from_millis(50),
+            ..Default::default()
+        })
+        .build();
+
+    // the first target never answers, the second one does
+    let dead_peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let alive_peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let dead_branches = std::sync::Mutex::new(vec![]);
+    let dead_peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                while let Some(TransportEvent::Incoming(SipMessage::Request(req), ..)) = receiver.recv().await {
+                    dead_branches.lock().unwrap().push(req.via_header().unwrap().value().to_string());
+                }
+            } => {}
+            _ = dead_peer.serve_loop(sender) => {}
+        }
+    };
+    let alive_peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                while let Some(TransportEvent::Incoming(SipMessage::Request(req), connection, from)) = receiver.recv().await {
+                    let branch = req.via_header().unwrap().value().to_string();
+                    assert!(!dead_branches.lock().unwrap().contains(&branch), "branch must change");
+                    let response = SipMessage::Response(rsip::message::Response {
+                        version: rsip::Version::V2,
+                        status_code: rsip::StatusCode::OK,
+                        headers: req.headers.clone(),
+                        body: Default::default(),
+                    });
+                    connection.send(response, Some(&from)).await.expect("send Ok");
+                }
+            } => {}
+            _ = alive_peer.serve_loop(sender) => {}
+        }
+    };
+
+    let send_loop = async {
+        let register_req = rsip::message::Request {
+            method: rsip::method::Method::Register,
+            uri: rsip::Uri {
+                scheme: Some(rsip::Scheme::Sip),
+                host_with_port: dead_peer.get_addr().addr.clone(),
+                ..Default::default()
+            },
+            headers: vec![
+                Via::new("SIP/2.0/UDP restsend.com:5060;branch=z9hG4bKnashd94").into(),
+                CSeq::new("1 REGISTER").into(),
+                From::new("Bob <sip:bob@restsend.com>;tag=ja743ks76zlflH").into(),
+                CallId::new("1j9FpLxk3uxtm8tn@restsend.com").into(),
+            ]
+            .into(),
+            version: rsip::Version::V2,
+            body: Default::default(),
+        };
+
+        let key = TransactionKey::from_request(&register_req, TransactionRole::Client)
+            .expect("client_transaction");
+        let mut tx = Transaction::new_client(key, register_req, endpoint.inner.clone(), None);
+        tx.destinations = vec![
+            dead_peer.get_addr().to_owned(),
+            alive_peer.get_addr().to_owned(),
+        ];
+        tx.send().await.expect("send request");
+        match tx.receive().await {
+            Some(SipMessage::Response(resp)) => {
+                assert_eq!(resp.status_code, rsip::StatusCode::OK);
+            }
+            _ => panic!("must receive response"),
+        }
+        assert_eq!(tx.attempted_targets(), 2);
+        assert_eq!(tx.destination(), Some(alive_peer.get_addr()));
+    };
+
+    select! {
+        _ = send_loop => {
+            assert!(!dead_branches.lock().unwrap().is_empty());
+        }
+        _ = dead_peer_loop => {
+            panic!("must not reach here");
+        }
+        _ = alive_peer_loop => {
+            panic!("must not reach here");
+        }
+        _ = endpoint.serve() => {
+            panic!("must not reach here");
+        }
+        _ = sleep(Duration::from_secs(2)) => {
+            panic!("timeout waiting");
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_request_builder_options() -> Result<()> {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0")).await?;
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let peer_uri = rsip::Uri {
+        scheme: Some(rsip::Scheme::Sip),
+        host_with_port: peer.get_addr().addr.clone(),
+        ..Default::default()
+    };
+
+    let peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            r = async {
+                while let Some(event) = receiver.recv().await {
+                    if let TransportEvent::Incoming(SipMessage::Request(req), connection, from) = event {
+                        let resp = endpoint.inner.make_response(&req, rsip::StatusCode::OK, None);
+                        connection.send(resp.into(), Some(&from)).await.expect("send response");
+                        return req;
+                    }
+                }
+                panic!("must not reach here");
+            } => r,
+            _ = peer.serve_loop(sender) => panic!("must not reach here"),
+        }
+    };
+    let client_loop = async {
+        let mut tx = endpoint
+            .request_builder(rsip::Method::Options, peer_uri.clone())
+            .header(rsip::Header::Accept("application/sdp".into()))
+            .send()
+            .await?;
+        while let Some(msg) = tx.receive().await {
+            if let SipMessage::Response(resp) = msg {
+                return Result::Ok(resp);
+            }
+        }
+        panic!("must not reach here");
+    };
+
+    let (req, resp) = select! {
+        r = async { tokio::join!(peer_loop, client_loop) } => r,
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(resp?.status_code, rsip::StatusCode::OK);
+    assert_eq!(req.method, rsip::Method::Options);
+    assert_eq!(req.uri, peer_uri);
+    assert_eq!(req.cseq_header()?.seq()?, 1);
+    assert!(req.from_header()?.tag()?.is_some());
+    assert!(req.to_header()?.tag()?.is_none());
+    assert!(req.contact_header().is_ok());
+    assert!(req
+        .headers
+        .iter()
+        .any(|h| matches!(h, rsip::Header::Accept(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_contact_builder() -> Result<()> {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0")).await?;
+    endpoint
+        .inner
+        .set_contact_builder(Some(std::sync::Arc::new(|ctx: &ContactContext| {
+            let mut contact = ctx.contact.clone();
+            contact.uri.host_with_port = ctx.addr.addr.clone();
+            contact.uri.params = vec![rsip::Param::Transport(ctx.transport)];
+            contact.params.push(rsip::Param::Other(
+                "+sip.instance".into(),
+                Some(format!("\"<urn:uuid:{}>\"", ctx.method).into()),
+            ));
+            contact
+        })));
+    let local_addr = endpoint.get_addrs()[0].addr.clone();
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let peer_uri = rsip::Uri {
+        scheme: Some(rsip::Scheme::Sip),
+        host_with_port: peer.get_addr().addr.clone(),
+        ..Default::default()
+    };
+
+    let peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            r = async {
+                while let Some(event) = receiver.recv().await {
+                    if let TransportEvent::Incoming(SipMessage::Request(req), connection, from) = event {
+                        let resp = endpoint.inner.make_response(&req, rsip::StatusCode::OK, None);
+                        connection.send(resp.into(), Some(&from)).await.expect("send response");
+                        return req;
+                    }
+                }
+                panic!("must not reach here");
+            } => r,
+            _ = peer.serve_loop(sender) => panic!("must not reach here"),
+        }
+    };
+    let client_loop = async {
+        let mut tx = endpoint
+            .request_builder(rsip::Method::Options, peer_uri.clone())
+            .contact(rsip::Uri {
+                scheme: Some(rsip::Scheme::Sip),
+                host_with_port: rsip::Domain::from("static.invalid").into(),
+                ..Default::default()
+            })
+            .send()
+            .await?;
+        while let Some(msg) = tx.receive().await {
+            if let SipMessage::Response(resp) = msg {
+                return Result::Ok(resp);
+            }
+        }
+        panic!("must not reach here");
+    };
+
+    let (req, resp) = select! {
+        r = async { tokio::join!(peer_loop, client_loop) } => r,
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(resp?.status_code, rsip::StatusCode::OK);
+    let contact = req.contact_header()?.to_string();
+    let uri = crate::rsip_ext::extract_uri_from_contact(&contact)?;
+    assert_eq!(uri.host_with_port, local_addr);
+    assert_eq!(
+        uri.params,
+        vec![rsip::Param::Transport(rsip::Transport::Udp)]
+    );
+    assert!(contact.contains("+sip.instance=\"<urn:uuid:OPTIONS>\""));
+    Ok(())
+}
+
+/// the log lines of a test, for the spans they are logged in
+#[derive(Clone, Default)]
+struct LogBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_transaction_span() -> Result<()> {
+    let logs = LogBuffer::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .with_max_level(tracing::Level::DEBUG)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let (alice, bob) = crate::transaction::Endpoint::test_pair();
+    let mut incoming = bob.incoming_transactions();
+    let bob_loop = async {
+        let mut tx = incoming.recv().await.expect("incoming transaction");
+        tx.reply(rsip::StatusCode::OK).await.expect("reply");
+    };
+    let alice_loop = async {
+        let mut tx = alice
+            .request_builder(
+                rsip::Method::Options,
+                rsip::Uri::try_from("sip:bob@192.0.2.2")?,
+            )
+            .send()
+            .await?;
+        while let Some(msg) = tx.receive().await {
+            if let SipMessage::Response(_) = msg {
+                break;
+            }
+        }
+        Result::Ok(tx.original.clone())
+    };
+    let ((), sent) = select! {
+        r = async { tokio::join!(bob_loop, alice_loop) } => r,
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    let sent = sent?;
+    let span = format!(
+        "transaction{{call_id={} branch={} method=OPTIONS cseq=1}}",
+        sent.call_id_header()?.value(),
+        sent.via_header()?.typed()?.branch().expect("branch")
+    );
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).expect("utf8");
+    // logged after the request went out, the span is still entered
+    assert!(
+        logs.lines()
+            .any(|l| l.contains(&format!("{}:send", span)) && l.contains("Calling -> Trying")),
+        "{}",
+        logs
+    );
+    // the server transaction of bob carries the same fields
+    assert!(
+        logs.lines()
+            .any(|l| l.contains(&format!("{}:respond", span))),
+        "{}",
+        logs
+    );
+    Ok(())
+}
+
+/// A request with a pre-loaded route set goes to its first loose router
+#[tokio::test(start_paused = true)]
+async fn test_preloaded_route() -> Result<()> {
+    let (alice, bob) = crate::transaction::Endpoint::test_pair();
+    let mut incoming = bob.incoming_transactions();
+    let bob_loop = async {
+        let mut tx = incoming.recv().await.expect("incoming transaction");
+        tx.reply(rsip::StatusCode::OK).await.expect("reply");
+        tx.original.clone()
+    };
+    let alice_loop = async {
+        let mut tx = alice
+            .request_builder(
+                rsip::Method::Options,
+                rsip::Uri::try_from("sip:carol@198.51.100.7")?,
+            )
+            .header(Route::new("<sip:192.0.2.2:5060;lr>").into())
+            .send()
+            .await?;
+        while let Some(msg) = tx.receive().await {
+            if let SipMessage::Response(resp) = msg {
+                return Result::Ok(resp.status_code);
+            }
+        }
+        panic!("no response");
+    };
+    let (received, status) = select! {
+        r = async { tokio::join!(bob_loop, alice_loop) } => r,
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(status?, rsip::StatusCode::OK);
+    assert_eq!(received.uri.to_string(), "sip:carol@198.51.100.7");
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_message_hooks() -> Result<()> {
+    let (alice, bob) = crate::transaction::Endpoint::test_pair();
+    let trace = || rsip::Header::Other("X-Trace".into(), "abc".into());
+    alice
+        .inner
+        .set_outgoing_hook(Some(std::sync::Arc::new(move |msg: &mut SipMessage| {
+            if let SipMessage::Request(req) = msg {
+                req.headers.push(trace());
+                // the branch is put back, the response still matches
+                req.headers.retain(|h| !matches!(h, rsip::Header::Via(_)));
+                req.headers
+                    .push(Via::new("SIP/2.0/UDP 192.0.2.1:5060;branch=z9hG4bKmangled").into());
+            }
+        })));
+    bob.inner
+        .set_incoming_hook(Some(std::sync::Arc::new(|msg: &mut SipMessage| {
+            if let SipMessage::Request(req) = msg {
+                req.headers
+                    .push(rsip::Header::Other("X-Normalized".into(), "1".into()));
+            }
+        })));
+
+    let mut incoming = bob.incoming_transactions();
+    let bob_loop = async {
+        let mut tx = incoming.recv().await.expect("incoming transaction");
+        tx.reply(rsip::StatusCode::OK).await.expect("reply");
+        tx.original.clone()
+    };
+    let alice_loop = async {
+        let mut tx = alice
+            .request_builder(
+                rsip::Method::Options,
+                rsip::Uri::try_from("sip:bob@192.0.2.2")?,
+            )
+            .send()
+            .await?;
+        while let Some(msg) = tx.receive().await {
+            if let SipMessage::Response(resp) = msg {
+                return Result::Ok((tx.original.clone(), resp));
+            }
+        }
+        panic!("must not reach here");
+    };
+    let (received, sent) = select! {
+        r = async { tokio::join!(bob_loop, alice_loop) } => r,
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    let (sent, resp) = sent?;
+    assert_eq!(resp.status_code, rsip::StatusCode::OK);
+    let has = |req: &rsip::Request, name: &str| {
+        req.headers
+            .iter()
+            .any(|h| matches!(h, rsip::Header::Other(n, _) if n == name))
+    };
+    assert!(has(&sent, "X-Trace"));
+    assert!(!sent.via_header()?.value().contains("mangled"));
+    assert!(has(&received, "X-Trace"));
+    assert!(has(&received, "X-Normalized"));
+    assert_eq!(
+        received.via_header()?.typed()?.branch(),
+        sent.via_header()?.typed()?.branch()
+    );
+    Ok(())
+}
+
+/// Both ends send compact headers, the requests and responses still match
+#[tokio::test(start_paused = true)]
+async fn test_compact_headers() -> Result<()> {
+    let (alice, bob) = crate::transaction::Endpoint::test_pair();
+    alice.inner.set_compact_headers(true);
+    bob.inner.set_compact_headers(true);
+
+    let mut incoming = bob.incoming_transactions();
+    let bob_loop = async {
+        let mut tx = incoming.recv().await.expect("incoming transaction");
+        tx.reply(rsip::StatusCode::OK).await.expect("reply");
+        tx.original.clone()
+    };
+    let alice_loop = async {
+        let mut tx = alice
+            .request_builder(
+                rsip::Method::Options,
+                rsip::Uri::try_from("sip:bob@192.0.2.2")?,
+            )
+            .send()
+            .await?;
+        while let Some(msg) = tx.receive().await {
+            if let SipMessage::Response(resp) = msg {
+                return Result::Ok((tx.original.clone(), resp));
+            }
+        }
+        panic!("must not reach here");
+    };
+    let (received, sent) = select! {
+        r = async { tokio::join!(bob_loop, alice_loop) } => r,
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    let (sent, resp) = sent?;
+    assert_eq!(resp.status_code, rsip::StatusCode::OK);
+    assert_eq!(resp.call_id_header()?, sent.call_id_header()?);
+    assert_eq!(received.call_id_header()?, sent.call_id_header()?);
+    assert_eq!(
+        received.via_header()?.typed()?.branch(),
+        sent.via_header()?.typed()?.branch()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_invite_error_ack() -> Result<()> {
+    let token = CancellationToken::new();
+    let tl = TransportLayer::new(token.child_token());
+    let conn = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    tl.add_transport(conn.into());
+
+    let t1 = Duration::from_millis(10);
+    let endpoint = EndpointBuilder::new()
+        .user_agent("rsipstack-test")
+        .transport_layer(tl)
+        .timer_interval(Duration::from_millis(2))
+        .option(EndpointOption {
+            t1,
+            t2: Duration::from_millis(40),
+            t4: Duration::from_millis(50),
+            ..Default::default()
+        })
+        .build();
+
+    // the peer answers the first retransmission with a 486, sent twice
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let invites = AtomicUsize::new(0);
+    let acks = std::sync::Mutex::new(vec![]);
+    let peer_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                while let Some(event) = receiver.recv().await {
+                    let (req, connection, from) = match event {
+                        TransportEvent::Incoming(SipMessage::Request(req), connection, from) => {
+                            (req, connection, from)
+                        }
+                        _ => continue,
+                    };
+                    match req.method {
+                        rsip::Method::Invite => {
+                            if invites.fetch_add(1, Ordering::Relaxed) != 1 {
+                                continue;
+                            }
+                            let mut resp = endpoint.inner.make_response(&req, rsip::StatusCode::BusyHere, None);
+                            resp.to_header_mut().unwrap().mut_tag("busy1".into()).unwrap();
+                            for _ in 0..2 {
+                                connection.send(resp.clone().into(), Some(&from)).await.expect("send response");
+                                sleep(Duration::from_millis(20)).await;
+                            }
+                        }
+                        rsip::Method::Ack => acks.lock().unwrap().push(req),
+                        _ => {}
+                    }
+                }
+            } => {}
+            _ = peer.serve_loop(sender) => {}
+        }
+    };
+
+    let send_loop = async {
+        let invite_req = rsip::message::Request {
+            method: rsip::method::Method::Invite,
+            uri: rsip::Uri {
+                scheme: Some(rsip::Scheme::Sip),
+                host_with_port: peer.get_addr().addr.clone(),
+                ..Default::default()
+            },
+            headers: vec![
+                Via::new("SIP/2.0/UDP restsend.com:5060;branch=z9hG4bKerrorack").into(),
+                CSeq::new("1 INVITE").into(),
+                From::new("Bob <sip:bob@restsend.com>;tag=ja743ks76zlflH").into(),
+                To::new("Alice <sip:alice@restsend.com>").into(),
+                CallId::new("errorack@restsend.com").into(),
+            ]
+            .into(),
+            version: rsip::Version::V2,
+            body: Default::default(),
+        };
+
+        let key = TransactionKey::from_request(&invite_req, TransactionRole::Client)
+            .expect("client_transaction");
+        let mut tx = Transaction::new_client(key, invite_req, endpoint.inner.clone(), None);
+        tx.send().await.expect("send request");
+        let mut responses = vec![];
+        while let Some(SipMessage::Response(resp)) = tx.receive().await {
+            responses.push(resp.status_code);
+        }
+        // the endpoint acknowledges the retransmitted 486 once the transaction is dropped
+        drop(tx);
+        sleep(Duration::from_millis(100)).await;
+        responses
+    };
+
+    let responses = select! {
+        r = send_loop => r,
+        _ = peer_loop => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    // Timer A retransmitted the INVITE, the TU only sees the 486 once
+    assert!(invites.load(Ordering::Relaxed) >= 2);
+    assert_eq!(responses, vec![rsip::StatusCode::BusyHere]);
+
+    // each 486 is acknowledged within the INVITE transaction
+    let acks = acks.lock().unwrap();
+    assert_eq!(acks.len(), 2);
+    let ack = &acks[0];
+    assert!(ack
+        .via_header()?
+        .value()
+        .starts_with("SIP/2.0/UDP restsend.com:5060;branch=z9hG4bKerrorack"));
+    assert_eq!(ack.cseq_header()?.value(), "1 ACK");
+    assert_eq!(
+        ack.to_header()?.tag()?.map(|t| t.value().to_string()),
+        Some("busy1".to_string())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_connection_lost() -> Result<()> {
+    let token = CancellationToken::new();
+    let config = TransportConfig {
+        keepalive: Some(KeepaliveConfig {
+            interval: Duration::from_millis(20),
+            max_misses: 2,
+        }),
+        ..Default::default()
+    };
+    let tl = TransportLayer::with_config(token.child_token(), config);
+    let conn = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    tl.add_transport(conn.into());
+    let endpoint = EndpointBuilder::new()
+        .user_agent("rsipstack-test")
+        .transport_layer(tl)
+        .build();
+
+    // a server that reads everything and answers nothing, not even the pings
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = listener.local_addr()?;
+    let peer_loop = async {
+        let (mut stream, _) = listener.accept().await.expect("accept");
+        let mut buf = [0u8; 2048];
+        while stream.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+        std::future::pending::<()>().await;
+    };
+
+    let send_loop = async {
+        // the endpoint is serving before the connection is made
+        sleep(Duration::from_millis(10)).await;
+        let options = rsip::message::Request {
+            method: rsip::method::Method::Options,
+            uri: rsip::Uri::try_from(format!("sip:{};transport=tcp", server_addr))?,
+            headers: vec![
+                Via::new("SIP/2.0/TCP restsend.com:5060;branch=z9hG4bKlost").into(),
+                CSeq::new("1 OPTIONS").into(),
+                From::new("Bob <sip:bob@restsend.com>;tag=ja743ks76zlflH").into(),
+                To::new("Alice <sip:alice@restsend.com>").into(),
+                CallId::new("lost@restsend.com").into(),
+            ]
+            .into(),
+            version: rsip::Version::V2,
+            body: Default::default(),
+        };
+        let key = TransactionKey::from_request(&options, TransactionRole::Client)?;
+        let mut tx = Transaction::new_client(key, options, endpoint.inner.clone(), None);
+        tx.send().await?;
+        let mut responses = vec![];
+        while let Some(SipMessage::Response(resp)) = tx.receive().await {
+            responses.push(resp.status_code);
+        }
+        Result::Ok(responses)
+    };
+
+    // the failed connection ends the transaction long before Timer F
+    let responses = select! {
+        r = send_loop => r?,
+        _ = peer_loop => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(responses, vec![rsip::StatusCode::ServiceUnavailable]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_large_request_over_tcp() -> Result<()> {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0")).await?;
+    let token = CancellationToken::new();
+    let peer_tl = TransportLayer::new(token.child_token());
+    let (tcp_sender, mut tcp_receiver) = unbounded_channel();
+    let peer_addr = peer_tl
+        .add_tcp_listener("127.0.0.1:0".parse()?, tcp_sender)
+        .await?;
+    // the UDP peer on the same port gets the small requests
+    let udp_peer = UdpConnection::create_connection(peer_addr.get_socketaddr()?, None).await?;
+    let peer_uri = rsip::Uri {
+        scheme: Some(rsip::Scheme::Sip),
+        host_with_port: peer_addr.addr.clone(),
+        ..Default::default()
+    };
+
+    let peer_loop = async {
+        let (udp_sender, mut udp_receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                loop {
+                    let event = select! {
+                        Some(event) = tcp_receiver.recv() => event,
+                        Some(event) = udp_receiver.recv() => event,
+                    };
+                    if let TransportEvent::Incoming(SipMessage::Request(req), connection, from) = event {
+                        let resp = endpoint.inner.make_response(&req, rsip::StatusCode::OK, None);
+                        connection.send(resp.into(), Some(&from)).await.expect("send response");
+                    }
+                }
+            } => {}
+            _ = udp_peer.serve_loop(udp_sender) => {}
+        }
+    };
+    let send = |size: usize| {
+        let endpoint = &endpoint;
+        let peer_uri = peer_uri.clone();
+        async move {
+            let mut tx = endpoint
+                .request_builder(rsip::Method::Message, peer_uri)
+                .body("text/plain", vec![b'a'; size])
+                .send()
+                .await?;
+            while let Some(msg) = tx.receive().await {
+                if let SipMessage::Response(resp) = msg {
+                    return Result::Ok((resp, tx.original.clone()));
+                }
+            }
+            panic!("must not reach here");
+        }
+    };
+    let client_loop = async { Result::Ok((send(100).await?, send(2000).await?)) };
+
+    let ((small, small_req), (large, large_req)) = select! {
+        r = client_loop => r?,
+        _ = peer_loop => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    token.cancel();
+    assert_eq!(small.status_code, rsip::StatusCode::OK);
+    assert_eq!(
+        small_req.via_header()?.typed()?.transport,
+        rsip::Transport::Udp
+    );
+    // over the UDP threshold the request moves to TCP (RFC 3261 18.1.1)
+    assert_eq!(large.status_code, rsip::StatusCode::OK);
+    assert_eq!(
+        large_req.via_header()?.typed()?.transport,
+        rsip::Transport::Tcp
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_forced_transport() -> Result<()> {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0")).await?;
+    let token = CancellationToken::new();
+    let peer_tl = TransportLayer::new(token.child_token());
+    let (tcp_sender, mut tcp_receiver) = unbounded_channel();
+    let peer_addr = peer_tl
+        .add_tcp_listener("127.0.0.1:0".parse()?, tcp_sender)
+        .await?;
+    // the UDP peer on the same port is where the uri points to
+    let udp_peer = UdpConnection::create_connection(peer_addr.get_socketaddr()?, None).await?;
+    let peer_uri = rsip::Uri::try_from(format!("sip:{};transport=udp", peer_addr.addr))?;
+
+    let peer_loop = async {
+        let (udp_sender, mut udp_receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                loop {
+                    let (event, transport) = select! {
+                        Some(event) = tcp_receiver.recv() => (event, rsip::Transport::Tcp),
+                        Some(event) = udp_receiver.recv() => (event, rsip::Transport::Udp),
+                    };
+                    if let TransportEvent::Incoming(SipMessage::Request(req), connection, from) = event {
+                        let mut resp = endpoint.inner.make_response(&req, rsip::StatusCode::OK, None);
+                        resp.headers.push(rsip::Header::Other("X-Received".into(), transport.to_string()));
+                        connection.send(resp.into(), Some(&from)).await.expect("send response");
+                    }
+                }
+            } => {}
+            _ = udp_peer.serve_loop(udp_sender) => {}
+        }
+    };
+    let client_loop = async {
+        let mut tx = endpoint
+            .request_builder(rsip::Method::Options, peer_uri)
+            .transport(rsip::Transport::Tcp)
+            .send()
+            .await?;
+        while let Some(msg) = tx.receive().await {
+            if let SipMessage::Response(resp) = msg {
+                return Result::Ok((resp, tx));
+            }
+        }
+        panic!("must not reach here");
+    };
+
+    let (resp, tx) = select! {
+        r = client_loop => r?,
+        _ = peer_loop => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    token.cancel();
+    assert_eq!(resp.status_code, rsip::StatusCode::OK);
+    assert!(resp.headers.iter().any(
+        |h| matches!(h, rsip::Header::Other(name, value) if name == "X-Received" && value == "TCP")
+    ));
+    assert_eq!(
+        tx.original.via_header()?.typed()?.transport,
+        rsip::Transport::Tcp
+    );
+    assert_eq!(
+        tx.connection.as_ref().and_then(|c| c.get_addr().r#type),
+        Some(rsip::Transport::Tcp)
+    );
+    Ok(())
+}
+
+/// The transaction timers run on the clock of the endpoint: on a mock clock
+/// Timer E retransmits and Timer F fires as it is advanced, with no sleep
+#[tokio::test(start_paused = true)]
+async fn test_timers_mock_clock() -> Result<()> {
+    use crate::transaction::clock::{Clock, MockClock};
+    use crate::transport::{custom::Transport, loopback::LoopbackNetwork};
+    use std::sync::Arc;
+
+    let clock = Arc::new(MockClock::new());
+    let network = LoopbackNetwork::new();
+    let cancel_token = CancellationToken::new();
+    let alice = EndpointBuilder::new()
+        .transport_layer(TransportLayer::new(cancel_token.child_token()))
+        .cancel_token(cancel_token)
+        .transport(Arc::new(network.bind("192.0.2.1:5060".parse()?)))
+        .option(EndpointOption {
+            clock: clock.clone(),
+            ..Default::default()
+        })
+        .build();
+    let start = clock.now();
+    // bob never answers, only tells when each request reached it
+    let bob = network.bind("192.0.2.2:5060".parse()?);
+    let (sent_sender, mut sent) = unbounded_channel();
+    let bob_loop = async {
+        while bob.recv().await.is_some() {
+            sent_sender.send(clock.now() - start).ok();
+        }
+    };
+    let mut tx = alice
+        .request_builder(
+            rsip::Method::Options,
+            rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+        )
+        .send()
+        .await?;
+    // the request sent at 0s, then at 0.5s, 1.5s, 3.5s and every T2 after
+    // that, Timer F at 64*T1
+    let mut expected = vec![0, 500, 1500, 3500];
+    expected.extend((7500..32000).step_by(4000));
+    let timer_f = Duration::from_secs(32);
+    // the transaction handles its timers while it is received from
+    let request = async {
+        while let Some(msg) = tx.receive().await {
+            if let SipMessage::Response(resp) = msg {
+                return (resp.status_code, clock.now() - start);
+            }
+        }
+        panic!("no response");
+    };
+    let driver = async {
+        let mut times = vec![];
+        for at in &expected {
+            // only the clock moving on sends the next one
+            clock.advance(start + Duration::from_millis(*at) - clock.now());
+            times.push(sent.recv().await.expect("request sent"));
+        }
+        clock.advance(start + timer_f - clock.now());
+        times
+    };
+    // the paused tokio clock never moves while a task can run, it only ends
+    // the test when the driver is stuck
+    let ((status, elapsed), times) = select! {
+        r = async { tokio::join!(request, driver) } => r,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(60)) => panic!("timeout waiting"),
+    };
+    let expected: Vec<_> = expected.into_iter().map(Duration::from_millis).collect();
+    assert_eq!(times, expected);
+    assert_eq!(status, rsip::StatusCode::RequestTimeout);
+    assert_eq!(elapsed, timer_f);
+    // no retransmission after the timeout
+    assert!(sent.try_recv().is_err());
+    Ok(())
+}
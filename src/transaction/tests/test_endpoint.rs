@@ -1,4 +1,5 @@
 use rsip::headers::*;
+use rsip::prelude::{HeadersExt, UntypedHeader};
 use std::time::Duration;
 use tokio::{select, time::sleep};
 
@@ -10,7 +11,7 @@ async fn test_endpoint_serve() {
     select! {
         _ = async {
             sleep(Duration::from_millis(10)).await;
-            endpoint.shutdown();
+            endpoint.shutdown().await;
             sleep(Duration::from_secs(1)).await;
         } => {
             assert!(false, "must not reach here");
@@ -86,3 +87,221 @@ async fn test_endpoint_recvrequests() {
         }
     }
 }
+
+#[tokio::test]
+async fn test_endpoint_forward_request() {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0"))
+        .await
+        .expect("create_test_endpoint");
+    let parse = |max_forwards: u32| match rsip::SipMessage::try_from(format!(
+        "INVITE sip:bob@restsend.com SIP/2.0\r\n\
+         Via: SIP/2.0/UDP 127.0.0.1:5070;branch=z9hG4bKforward\r\n\
+         From: <sip:alice@restsend.com>;tag=alice-tag\r\n\
+         To: <sip:bob@restsend.com>\r\n\
+         Call-ID: forward-test\r\n\
+         CSeq: 1 INVITE\r\n\
+         Max-Forwards: {max_forwards}\r\n\
+         Content-Length: 0\r\n\r\n"
+    )) {
+        Ok(rsip::SipMessage::Request(req)) => req,
+        _ => panic!("invalid request"),
+    };
+    assert_eq!(
+        endpoint.inner.make_forward_request(&parse(0)).err(),
+        Some(rsip::StatusCode::TooManyHops)
+    );
+
+    let forward = endpoint
+        .inner
+        .make_forward_request(&parse(70))
+        .expect("forward");
+    assert_eq!(forward.max_forwards_header().unwrap().value(), "69");
+    let via = forward.via_header().unwrap().typed().unwrap();
+    assert_eq!(via.uri.host_with_port, endpoint.get_addrs()[0].addr);
+    assert_eq!(
+        forward
+            .headers
+            .iter()
+            .filter(|h| matches!(h, rsip::Header::Via(_)))
+            .count(),
+        2
+    );
+
+    // the same request coming back is a loop
+    assert_eq!(
+        endpoint.inner.make_forward_request(&forward).err(),
+        Some(rsip::StatusCode::LoopDetected)
+    );
+    // sent to another target it is a spiral
+    let mut spiral = forward.clone();
+    spiral.uri = rsip::Uri::try_from("sip:bob@192.168.1.2").unwrap();
+    assert!(endpoint.inner.make_forward_request(&spiral).is_ok());
+}
+
+#[tokio::test]
+async fn test_endpoint_generate_branch() {
+    let endpoint = super::create_test_endpoint(None)
+        .await
+        .expect("create_test_endpoint");
+    let first = endpoint.inner.generate_branch();
+    let second = endpoint.inner.generate_branch();
+    assert_ne!(first, second);
+    for branch in [first, second] {
+        match branch {
+            rsip::Param::Branch(branch) => assert!(branch.value().starts_with("z9hG4bK")),
+            _ => panic!("not a branch"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_endpoint_ipv6_via() {
+    let endpoint = super::create_test_endpoint(Some("[::1]:0"))
+        .await
+        .expect("create_test_endpoint");
+    let port = endpoint.get_addrs()[0].get_socketaddr().unwrap().port();
+    let via = endpoint.inner.get_via(None).expect("get_via");
+    assert!(via
+        .to_string()
+        .starts_with(&format!("SIP/2.0/UDP [::1]:{};branch=", port)));
+
+    // the Via parses back to the same address
+    let via: rsip::headers::Via = via.into();
+    let typed = crate::rsip_ext::parse_via(&via).expect("parse_via");
+    let addr = crate::transport::SipAddr::from(typed.uri.host_with_port);
+    assert_eq!(
+        addr.get_socketaddr().unwrap(),
+        endpoint.get_addrs()[0].get_socketaddr().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_endpoint_bad_extension() {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0"))
+        .await
+        .expect("create_test_endpoint");
+    let addr = endpoint.get_addrs()[0].to_owned();
+    let mut incoming = endpoint.incoming_transactions();
+    let test_conn = crate::transport::udp::UdpConnection::create_connection(
+        "127.0.0.1:0".parse().unwrap(),
+        None,
+    )
+    .await
+    .expect("create_connection");
+
+    let invite_req = rsip::message::Request {
+        method: rsip::method::Method::Invite,
+        uri: rsip::Uri::try_from("sip:bob@restsend.com").expect("uri"),
+        headers: vec![
+            Via::new("SIP/2.0/UDP restsend.com:5060;branch=z9hG4bKbadext").into(),
+            CSeq::new("1 INVITE").into(),
+            From::new("Alice <sip:alice@restsend.com>;tag=badext1").into(),
+            To::new("Bob <sip:bob@restsend.com>").into(),
+            CallId::new("badext@restsend.com").into(),
+            rsip::Header::Require("100rel, gruu".into()),
+            rsip::Header::ProxyRequire("sec-agree".into()),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    let buf: String = invite_req.into();
+    test_conn
+        .send_raw(buf.as_bytes(), &addr)
+        .await
+        .expect("send_raw");
+
+    let recv_loop = async {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        select! {
+            event = receiver.recv() => event,
+            _ = test_conn.serve_loop(sender) => None,
+        }
+    };
+    let resp = select! {
+        event = recv_loop => match event {
+            Some(crate::transport::TransportEvent::Incoming(rsip::SipMessage::Response(resp), _, _)) => resp,
+            _ => panic!("expected a response"),
+        },
+        _ = incoming.recv() => panic!("the INVITE must not reach the TU"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(resp.status_code, rsip::StatusCode::BadExtension);
+    let unsupported = resp
+        .headers
+        .iter()
+        .find_map(|h| match h {
+            rsip::Header::Unsupported(v) => Some(v.value().to_string()),
+            _ => None,
+        })
+        .expect("Unsupported header");
+    assert_eq!(unsupported, "gruu, sec-agree");
+}
+
+#[tokio::test]
+async fn test_endpoint_options_capabilities() {
+    let endpoint = super::create_test_endpoint(None)
+        .await
+        .expect("create_test_endpoint");
+    let mut options = rsip::message::Request {
+        method: rsip::method::Method::Options,
+        uri: rsip::Uri::try_from("sip:bob@restsend.com").expect("uri"),
+        headers: vec![
+            Via::new("SIP/2.0/UDP restsend.com:5060;branch=z9hG4bKcaps").into(),
+            CSeq::new("1 OPTIONS").into(),
+            From::new("Alice <sip:alice@restsend.com>;tag=caps1").into(),
+            To::new("Bob <sip:bob@restsend.com>").into(),
+            CallId::new("caps@restsend.com").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    let resp = endpoint
+        .inner
+        .make_response(&options, rsip::StatusCode::OK, None);
+    let header = |resp: &rsip::Response, name: &str| {
+        resp.headers.iter().find_map(|h| match h {
+            rsip::Header::Allow(v) if name == "Allow" => Some(v.value().to_string()),
+            rsip::Header::Accept(v) if name == "Accept" => Some(v.value().to_string()),
+            rsip::Header::Supported(v) if name == "Supported" => Some(v.value().to_string()),
+            rsip::Header::UserAgent(v) if name == "User-Agent" => Some(v.value().to_string()),
+            _ => None,
+        })
+    };
+    assert_eq!(
+        header(&resp, "Allow").as_deref(),
+        Some("INVITE, ACK, BYE, CANCEL, OPTIONS, INFO, UPDATE, PRACK, REFER, NOTIFY, MESSAGE")
+    );
+    assert_eq!(
+        header(&resp, "Accept").as_deref(),
+        Some("application/sdp, application/dtmf-relay, message/sipfrag")
+    );
+    assert_eq!(
+        header(&resp, "Supported").as_deref(),
+        Some("100rel, replaces, timer")
+    );
+    assert_eq!(
+        header(&resp, "User-Agent").as_deref(),
+        Some(endpoint.inner.user_agent.as_str())
+    );
+
+    // an error or another method carries none of them
+    let resp = endpoint
+        .inner
+        .make_response(&options, rsip::StatusCode::BusyHere, None);
+    assert_eq!(header(&resp, "Allow"), None);
+    options.method = rsip::method::Method::Message;
+    let resp = endpoint
+        .inner
+        .make_response(&options, rsip::StatusCode::OK, None);
+    assert_eq!(header(&resp, "Accept"), None);
+
+    // the default User-Agent names the crate version
+    let endpoint = crate::EndpointBuilder::new().build();
+    assert_eq!(
+        endpoint.inner.user_agent,
+        format!("rsipstack/{}", env!("CARGO_PKG_VERSION"))
+    );
+}
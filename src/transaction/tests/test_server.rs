@@ -1,9 +1,13 @@
-use crate::transport::SipConnection;
+use crate::transaction::endpoint::EndpointOption;
+use crate::transport::{udp::UdpConnection, SipConnection};
 use crate::{
     transport::{channel::ChannelConnection, SipAddr, TransportEvent, TransportLayer},
     EndpointBuilder,
 };
-use rsip::headers::*;
+use rsip::{
+    headers::*,
+    prelude::{HeadersExt, ToTypedHeader},
+};
 use std::time::Duration;
 use tokio::{select, sync::mpsc::unbounded_channel, time::sleep};
 use tokio_util::sync::CancellationToken;
@@ -144,3 +148,732 @@ async fn test_server_transaction() {
         }
     }
 }
+
+#[tokio::test]
+async fn test_server_response_to_source() -> crate::Result<()> {
+    let token = CancellationToken::new();
+    let tl = TransportLayer::new(token.child_token());
+    let conn = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let server_addr = conn.get_addr().to_owned();
+    tl.add_transport(conn.into());
+    let endpoint = EndpointBuilder::new()
+        .user_agent("rsipstack-test")
+        .transport_layer(tl)
+        .build();
+
+    // the client is behind a NAT, its Via carries an address we can't reach
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let peer_addr = peer.get_addr().get_socketaddr()?;
+    let register_req = rsip::message::Request {
+        method: rsip::method::Method::Register,
+        uri: rsip::Uri {
+            scheme: Some(rsip::Scheme::Sip),
+            host_with_port: server_addr.addr.clone(),
+            ..Default::default()
+        },
+        headers: vec![
+            Via::new("SIP/2.0/UDP 10.0.0.1:5070;rport;branch=z9hG4bKnashd95").into(),
+            CSeq::new("1 REGISTER").into(),
+            From::new("Bob <sip:bob@restsend.com>;tag=ja743ks76zlflH").into(),
+            To::new("Bob <sip:bob@restsend.com>").into(),
+            CallId::new("1j9FpLxk3uxtm8tn@restsend.com").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+
+    let send_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        peer.send(register_req.into(), Some(&server_addr))
+            .await
+            .expect("send request");
+        select! {
+            event = receiver.recv() => match event {
+                Some(TransportEvent::Incoming(rsip::SipMessage::Response(resp), ..)) => {
+                    assert_eq!(resp.status_code, rsip::StatusCode::OK);
+                    let via = resp.via_header().expect("via_header").typed().expect("typed via");
+                    assert_eq!(
+                        via.received().expect("received").map(|r| r.to_string()),
+                        Some(peer_addr.ip().to_string())
+                    );
+                    assert!(via.params.iter().any(|p| matches!(p,
+                        rsip::Param::Other(k, Some(v))
+                            if k.value() == "rport" && v.value() == peer_addr.port().to_string()
+                    )));
+                }
+                _ => panic!("unexpected event"),
+            },
+            _ = peer.serve_loop(sender) => {}
+        }
+    };
+
+    let incoming_loop = async {
+        let mut incoming = endpoint.incoming_transactions();
+        let mut tx = incoming.recv().await.expect("incoming");
+        tx.reply(rsip::StatusCode::OK).await.expect("reply 200");
+        sleep(Duration::from_secs(2)).await;
+    };
+
+    select! {
+        _ = send_loop => {}
+        _ = endpoint.serve() => {
+            panic!("must not reach here");
+        }
+        _ = incoming_loop => {
+            panic!("must not reach here");
+        }
+        _ = sleep(Duration::from_secs(1)) => {
+            panic!("timeout waiting");
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_absorb_retransmission() -> crate::Result<()> {
+    let token = CancellationToken::new();
+    let tl = TransportLayer::new(token.child_token());
+    let conn = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let server_addr = conn.get_addr().to_owned();
+    tl.add_transport(conn.into());
+    let endpoint = EndpointBuilder::new()
+        .user_agent("rsipstack-test")
+        .transport_layer(tl)
+        .option(EndpointOption {
+            t1: Duration::from_millis(10),
+            t2: Duration::from_millis(40),
+            t4: Duration::from_millis(50),
+            ..Default::default()
+        })
+        .build();
+
+    // a monitoring system resending its OPTIONS
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let options_req = rsip::message::Request {
+        method: rsip::method::Method::Options,
+        uri: rsip::Uri {
+            scheme: Some(rsip::Scheme::Sip),
+            host_with_port: server_addr.addr.clone(),
+            ..Default::default()
+        },
+        headers: vec![
+            Via::new(format!(
+                "SIP/2.0/UDP {};branch=z9hG4bKnashd96",
+                peer.get_addr().addr
+            ))
+            .into(),
+            CSeq::new("1 OPTIONS").into(),
+            From::new("Monitor <sip:monitor@restsend.com>;tag=ja743ks76zlflH").into(),
+            To::new("<sip:restsend.com>").into(),
+            CallId::new("options-retransmission@restsend.com").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+
+    let send_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        let receive_loop = async {
+            let mut responses = 0;
+            for _ in 0..3 {
+                peer.send(options_req.clone().into(), Some(&server_addr))
+                    .await
+                    .expect("send request");
+                match receiver.recv().await {
+                    Some(TransportEvent::Incoming(rsip::SipMessage::Response(resp), ..)) => {
+                        assert_eq!(resp.status_code, rsip::StatusCode::OK);
+                        responses += 1;
+                    }
+                    _ => panic!("unexpected event"),
+                }
+            }
+            responses
+        };
+        select! {
+            responses = receive_loop => responses,
+            _ = peer.serve_loop(sender) => panic!("must not reach here"),
+        }
+    };
+
+    let mut incoming = endpoint.incoming_transactions();
+    let incoming_loop = async {
+        let mut tx = incoming.recv().await.expect("incoming");
+        tx.reply(rsip::StatusCode::OK).await.expect("reply 200");
+        // the retransmissions never reach the TU, Timer J ends the transaction
+        assert!(tx.receive().await.is_none());
+        assert!(tx.is_terminated());
+        tx
+    };
+
+    let (responses, tx) = select! {
+        r = async { tokio::join!(send_loop, incoming_loop) } => r,
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(responses, 3);
+    assert!(incoming.try_recv().is_err());
+    // terminated by Timer J, nothing is left to answer for it
+    assert!(!tx
+        .endpoint_inner
+        .finished_transactions
+        .lock()
+        .unwrap()
+        .contains_key(&tx.key));
+    Ok(())
+}
+
+// the CSeq method and status of the next response received
+async fn next_response(
+    receiver: &mut tokio::sync::mpsc::UnboundedReceiver<TransportEvent>,
+) -> (rsip::Method, rsip::StatusCode) {
+    match receiver.recv().await {
+        Some(TransportEvent::Incoming(rsip::SipMessage::Response(resp), ..)) => {
+            let method = resp.cseq_header().unwrap().method().unwrap();
+            (method, resp.status_code)
+        }
+        _ => panic!("unexpected event"),
+    }
+}
+
+#[tokio::test]
+async fn test_server_cancel() -> crate::Result<()> {
+    let token = CancellationToken::new();
+    let tl = TransportLayer::new(token.child_token());
+    let conn = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let server_addr = conn.get_addr().to_owned();
+    tl.add_transport(conn.into());
+    let endpoint = EndpointBuilder::new()
+        .user_agent("rsipstack-test")
+        .transport_layer(tl)
+        .option(EndpointOption {
+            t1: Duration::from_millis(10),
+            t2: Duration::from_millis(40),
+            t4: Duration::from_millis(50),
+            ..Default::default()
+        })
+        .build();
+
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let make_request = |method: rsip::Method| rsip::message::Request {
+        method,
+        uri: rsip::Uri {
+            scheme: Some(rsip::Scheme::Sip),
+            host_with_port: server_addr.addr.clone(),
+            ..Default::default()
+        },
+        headers: vec![
+            Via::new(format!(
+                "SIP/2.0/UDP {};branch=z9hG4bKcancel1",
+                peer.get_addr().addr
+            ))
+            .into(),
+            CSeq::new(format!("1 {}", method)).into(),
+            From::new("Alice <sip:alice@restsend.com>;tag=cancel-tag").into(),
+            To::new("<sip:bob@restsend.com>").into(),
+            CallId::new("server-cancel@restsend.com").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+
+    let send_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        let receive_loop = async {
+            peer.send(
+                make_request(rsip::Method::Invite).into(),
+                Some(&server_addr),
+            )
+            .await
+            .expect("send invite");
+            while next_response(&mut receiver).await
+                != (rsip::Method::Invite, rsip::StatusCode::Ringing)
+            {}
+
+            // the CANCEL is answered and the INVITE gets its 487
+            peer.send(
+                make_request(rsip::Method::Cancel).into(),
+                Some(&server_addr),
+            )
+            .await
+            .expect("send cancel");
+            let mut responses = vec![];
+            while responses.len() < 2 {
+                let response = next_response(&mut receiver).await;
+                if !responses.contains(&response) {
+                    responses.push(response);
+                }
+            }
+            responses.sort_by_key(|(_, status)| status.code());
+            assert_eq!(
+                responses,
+                vec![
+                    (rsip::Method::Cancel, rsip::StatusCode::OK),
+                    (rsip::Method::Invite, rsip::StatusCode::RequestTerminated),
+                ]
+            );
+
+            // a CANCEL after the final response is still answered 200
+            peer.send(
+                make_request(rsip::Method::Cancel).into(),
+                Some(&server_addr),
+            )
+            .await
+            .expect("send cancel");
+            loop {
+                match next_response(&mut receiver).await {
+                    (rsip::Method::Cancel, status) => return status,
+                    // the 487 retransmissions, no ACK is sent
+                    (rsip::Method::Invite, rsip::StatusCode::RequestTerminated) => {}
+                    r => panic!("unexpected response: {:?}", r),
+                }
+            }
+        };
+        select! {
+            status = receive_loop => status,
+            _ = peer.serve_loop(sender) => panic!("must not reach here"),
+        }
+    };
+
+    let mut incoming = endpoint.incoming_transactions();
+    let incoming_loop = async {
+        let mut tx = incoming.recv().await.expect("incoming");
+        assert_eq!(tx.original.method, rsip::Method::Invite);
+        tx.reply(rsip::StatusCode::Ringing)
+            .await
+            .expect("reply 180");
+        let mut cancels = 0;
+        while let Some(msg) = tx.receive().await {
+            if let rsip::SipMessage::Request(req) = msg {
+                assert_eq!(req.method, rsip::Method::Cancel);
+                cancels += 1;
+                tx.reply(rsip::StatusCode::RequestTerminated)
+                    .await
+                    .expect("reply 487");
+            }
+        }
+        cancels
+    };
+
+    let (status, cancels) = select! {
+        r = async { tokio::join!(send_loop, incoming_loop) } => r,
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(status, rsip::StatusCode::OK);
+    // only the CANCEL ahead of the final response reaches the TU
+    assert_eq!(cancels, 1);
+    assert!(incoming.try_recv().is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_message_too_large() -> crate::Result<()> {
+    let token = CancellationToken::new();
+    let tl = TransportLayer::new(token.child_token());
+    let conn = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let server_addr = conn.get_addr().to_owned();
+    tl.add_transport(conn.into());
+    let endpoint = EndpointBuilder::new()
+        .transport_layer(tl)
+        .max_message_size(2048)
+        .build();
+    let mut incoming = endpoint.incoming_transactions();
+
+    let peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let make_request = |branch: &str, body: Vec<u8>| rsip::message::Request {
+        method: rsip::Method::Message,
+        uri: rsip::Uri {
+            scheme: Some(rsip::Scheme::Sip),
+            host_with_port: server_addr.addr.clone(),
+            ..Default::default()
+        },
+        headers: vec![
+            Via::new(format!(
+                "SIP/2.0/UDP {};branch={}",
+                peer.get_addr().addr,
+                branch
+            ))
+            .into(),
+            CSeq::new("1 MESSAGE").into(),
+            From::new("Alice <sip:alice@restsend.com>;tag=large-tag").into(),
+            To::new("<sip:bob@restsend.com>").into(),
+            CallId::new(format!("{}@restsend.com", branch)).into(),
+            ContentType::new("text/plain").into(),
+            ContentLength::new(body.len().to_string()).into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body,
+    };
+
+    let send_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        let receive_loop = async {
+            // the body alone is over the limit, the TU never sees it
+            peer.send(
+                make_request("z9hG4bKlarge", vec![b'a'; 3000]).into(),
+                Some(&server_addr),
+            )
+            .await
+            .expect("send large");
+            let large = next_response(&mut receiver).await;
+            peer.send(
+                make_request("z9hG4bKsmall", b"hello".to_vec()).into(),
+                Some(&server_addr),
+            )
+            .await
+            .expect("send small");
+            (large, next_response(&mut receiver).await)
+        };
+        select! {
+            r = receive_loop => r,
+            _ = peer.serve_loop(sender) => panic!("must not reach here"),
+        }
+    };
+    let incoming_loop = async {
+        let mut tx = incoming.recv().await.expect("incoming");
+        assert!(tx.original.body().len() < 2048);
+        tx.reply(rsip::StatusCode::OK).await.expect("reply");
+        std::future::pending::<()>().await
+    };
+
+    let (large, small) = select! {
+        r = send_loop => r,
+        _ = incoming_loop => panic!("must not reach here"),
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(
+        large,
+        (rsip::Method::Message, rsip::StatusCode::MessageTooLarge)
+    );
+    assert_eq!(small, (rsip::Method::Message, rsip::StatusCode::OK));
+    Ok(())
+}
+
+/// A new INVITE is answered with 100 Trying before the TU sees it
+#[tokio::test(start_paused = true)]
+async fn test_auto_trying() -> crate::Result<()> {
+    let (alice, bob) = crate::transaction::Endpoint::test_pair();
+    // the responses as bob sends them
+    let sent = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let sent_ref = sent.clone();
+    bob.inner.set_outgoing_hook(Some(std::sync::Arc::new(
+        move |msg: &mut rsip::SipMessage| {
+            if let rsip::SipMessage::Response(resp) = msg {
+                let to_tag = resp.to_header().and_then(|to| to.tag()).ok().flatten();
+                sent_ref
+                    .lock()
+                    .unwrap()
+                    .push((resp.status_code.clone(), to_tag));
+            }
+        },
+    )));
+    let mut incoming = bob.incoming_transactions();
+    let bob_loop = async {
+        let mut tx = incoming.recv().await.expect("incoming transaction");
+        assert_eq!(sent.lock().unwrap().len(), 1);
+        // the TU takes its time to decide
+        sleep(Duration::from_millis(300)).await;
+        tx.reply(rsip::StatusCode::BusyHere).await.expect("reply");
+        while tx.receive().await.is_some() {}
+    };
+    let alice_loop = async {
+        let mut tx = alice
+            .request_builder(
+                rsip::Method::Invite,
+                rsip::Uri::try_from("sip:bob@192.0.2.2")?,
+            )
+            .send()
+            .await?;
+        while let Some(msg) = tx.receive().await {
+            if let rsip::SipMessage::Response(resp) = msg {
+                return crate::Result::Ok(resp.status_code);
+            }
+        }
+        panic!("no response");
+    };
+    let status = select! {
+        r = alice_loop => r?,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(status, rsip::StatusCode::BusyHere);
+    let sent = sent.lock().unwrap().clone();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[0], (rsip::StatusCode::Trying, None));
+    assert_eq!(sent[1].0, rsip::StatusCode::BusyHere);
+    assert!(sent[1].1.is_some());
+    Ok(())
+}
+
+/// The reply body is the variant the request accepts, or none with 406
+#[tokio::test(start_paused = true)]
+async fn test_reply_variant() -> crate::Result<()> {
+    use crate::rsip_ext::Variant;
+    let (alice, bob) = crate::transaction::Endpoint::test_pair();
+    let variants = [
+        Variant::new("text/plain", b"closed".to_vec()).with_language("en"),
+        Variant::new("text/plain", b"geschlossen".to_vec()).with_language("de"),
+    ];
+    let mut incoming = bob.incoming_transactions();
+    let bob_loop = async {
+        while let Some(mut tx) = incoming.recv().await {
+            tx.reply_variant(rsip::StatusCode::OK, vec![], &variants)
+                .await
+                .expect("reply");
+        }
+    };
+    let send = |headers: Vec<rsip::Header>| {
+        let alice = &alice;
+        async move {
+            let mut builder = alice.request_builder(
+                rsip::Method::Options,
+                rsip::Uri::try_from("sip:bob@192.0.2.2")?,
+            );
+            for header in headers {
+                builder.header(header);
+            }
+            let mut tx = builder.send().await?;
+            while let Some(msg) = tx.receive().await {
+                if let rsip::SipMessage::Response(resp) = msg {
+                    return crate::Result::Ok(resp);
+                }
+            }
+            panic!("no response");
+        }
+    };
+    let alice_loop = async {
+        let german = send(vec![
+            rsip::Header::Accept("text/plain".into()),
+            rsip::Header::AcceptLanguage("de-AT, de;q=0.9, en;q=0.5".into()),
+        ])
+        .await?;
+        let html = send(vec![rsip::Header::Accept("text/html".into())]).await?;
+        crate::Result::Ok((german, html))
+    };
+    let (german, html) = select! {
+        r = alice_loop => r?,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(german.status_code, rsip::StatusCode::OK);
+    assert_eq!(german.body, b"geschlossen");
+    assert!(german
+        .headers
+        .iter()
+        .any(|h| h == &rsip::Header::ContentLanguage("de".into())));
+    assert_eq!(html.status_code, rsip::StatusCode::NotAcceptable);
+    assert!(html
+        .headers
+        .iter()
+        .any(|h| h == &rsip::Header::Accept("text/plain".into())));
+    Ok(())
+}
+
+/// The asserted identity of a trusted peer is taken, it does not leave the
+/// trust domain once stripping is on
+#[tokio::test(start_paused = true)]
+async fn test_asserted_identity() -> crate::Result<()> {
+    use crate::rsip_ext::{asserted_identities, privacy_values, AssertedIdentity, Privacy};
+    let (alice, bob) = crate::transaction::Endpoint::test_pair();
+    let identities = vec![
+        AssertedIdentity::sip(rsip::Uri::try_from("sip:alice@example.com")?)
+            .with_display_name("Alice"),
+        AssertedIdentity::tel("+14085264000"),
+    ];
+    let mut incoming = bob.incoming_transactions();
+    let bob_loop = async {
+        let mut received = vec![];
+        while let Some(mut tx) = incoming.recv().await {
+            received.push((
+                bob.inner.asserted_identities(&tx.original),
+                asserted_identities(&tx.original.headers),
+                privacy_values(&tx.original.headers),
+            ));
+            tx.reply(rsip::StatusCode::OK).await.expect("reply");
+            if received.len() == 3 {
+                return received;
+            }
+        }
+        panic!("must not reach here");
+    };
+    let alice_loop = async {
+        for (trusted, strip) in [(true, false), (false, false), (false, true)] {
+            let peers = match trusted {
+                true => vec!["192.0.2.1".parse()?],
+                false => vec![],
+            };
+            bob.inner.set_trusted_peers(peers);
+            alice.inner.set_strip_asserted_identity(strip);
+            let mut builder = alice.request_builder(
+                rsip::Method::Options,
+                rsip::Uri::try_from("sip:bob@192.0.2.2")?,
+            );
+            for identity in &identities {
+                builder.asserted_identity(identity.clone());
+            }
+            let mut tx = builder.privacy(&[Privacy::Id]).send().await?;
+            while let Some(msg) = tx.receive().await {
+                if matches!(msg, rsip::SipMessage::Response(_)) {
+                    break;
+                }
+            }
+        }
+        crate::Result::Ok(())
+    };
+    let received = select! {
+        r = async { tokio::join!(bob_loop, alice_loop) } => { r.1?; r.0 }
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    // from a trusted peer
+    assert_eq!(received[0].0, identities);
+    assert_eq!(received[0].2, vec![Privacy::Id]);
+    // from an untrusted one the header is there but not taken
+    assert!(received[1].0.is_empty());
+    assert_eq!(received[1].1, identities);
+    // stripped on its way to an untrusted target
+    assert!(received[2].1.is_empty());
+    assert_eq!(received[2].2, vec![Privacy::Id]);
+    Ok(())
+}
+
+/// The transactions no timer ends are expired by the sweep, their TU is told
+#[tokio::test(start_paused = true)]
+async fn test_transaction_sweep() -> crate::Result<()> {
+    use crate::transaction::{
+        key::{TransactionKey, TransactionRole},
+        transaction::Transaction,
+    };
+    use tokio::time::Instant;
+    let (alice, bob) = crate::transaction::Endpoint::test_pair();
+    let started = Instant::now();
+    let mut incoming = bob.incoming_transactions();
+    // bob never answers, alice gives up on Timer F
+    let bob_loop = async {
+        let mut tx = incoming.recv().await.expect("incoming transaction");
+        assert_eq!(bob.transaction_count(), 1);
+        assert!(tx.receive().await.is_none());
+        started.elapsed()
+    };
+    let alice_loop = async {
+        let mut tx = alice
+            .request_builder(
+                rsip::Method::Options,
+                rsip::Uri::try_from("sip:bob@192.0.2.2")?,
+            )
+            .send()
+            .await?;
+        while tx.receive().await.is_some() {}
+        crate::Result::Ok(())
+    };
+    // a transaction never sent, none of its timers run
+    let stuck = async {
+        let request = match rsip::SipMessage::try_from(
+            "MESSAGE sip:bob@192.0.2.2 SIP/2.0\r\n\
+             Via: SIP/2.0/UDP 192.0.2.1:5060;branch=z9hG4bKstuck\r\n\
+             From: <sip:alice@192.0.2.1>;tag=stuck\r\n\
+             To: <sip:bob@192.0.2.2>\r\n\
+             Call-ID: stuck-transaction\r\n\
+             CSeq: 1 MESSAGE\r\n\
+             Content-Length: 0\r\n\r\n",
+        )? {
+            rsip::SipMessage::Request(request) => request,
+            _ => panic!("not a request"),
+        };
+        let key = TransactionKey::from_request(&request, TransactionRole::Client)?;
+        let mut tx = Transaction::new_client(key, request, alice.inner.clone(), None);
+        let resp = match tx.receive().await {
+            Some(rsip::SipMessage::Response(resp)) => resp,
+            _ => panic!("no timeout response"),
+        };
+        assert!(tx.receive().await.is_none());
+        crate::Result::Ok((resp.status_code, started.elapsed()))
+    };
+    let (bob_elapsed, alice_done, stuck) = select! {
+        r = async { tokio::join!(bob_loop, alice_loop, stuck) } => r,
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(120)) => panic!("timeout waiting"),
+    };
+    alice_done?;
+    let (status, stuck_elapsed) = stuck?;
+    assert_eq!(status, rsip::StatusCode::RequestTimeout);
+    // not before 64*T1 and T4
+    for elapsed in [bob_elapsed, stuck_elapsed] {
+        assert!(elapsed > Duration::from_secs(37), "{:?}", elapsed);
+    }
+    assert_eq!(alice.transaction_count(), 0);
+    assert_eq!(bob.transaction_count(), 0);
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_reply_lists_allow() -> crate::Result<()> {
+    let (alice, bob) = crate::transaction::Endpoint::test_pair();
+    let mut incoming = bob.incoming_transactions();
+    let bob_loop = async {
+        while let Some(mut tx) = incoming.recv().await {
+            let status = match tx.original.method {
+                rsip::Method::Publish => rsip::StatusCode::MethodNotAllowed,
+                _ => rsip::StatusCode::NotImplemented,
+            };
+            tx.reply(status).await.expect("reply");
+        }
+    };
+    let send = |method: rsip::Method| {
+        let alice = &alice;
+        async move {
+            let mut tx = alice
+                .request_builder(method, rsip::Uri::try_from("sip:bob@192.0.2.2")?)
+                .send()
+                .await?;
+            while let Some(msg) = tx.receive().await {
+                if let rsip::SipMessage::Response(resp) = msg {
+                    return crate::Result::Ok(resp);
+                }
+            }
+            panic!("no response");
+        }
+    };
+    let alice_loop = async {
+        let not_allowed = send(rsip::Method::Publish).await?;
+        let not_implemented = send(rsip::Method::Info).await?;
+        crate::Result::Ok((not_allowed, not_implemented))
+    };
+    let (not_allowed, not_implemented) = select! {
+        r = alice_loop => r?,
+        _ = bob_loop => panic!("must not reach here"),
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    let allow = |resp: &rsip::Response| {
+        resp.headers
+            .iter()
+            .filter_map(|h| match h {
+                rsip::Header::Allow(allow) => Some(allow.value().to_string()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(not_allowed.status_code, rsip::StatusCode::MethodNotAllowed);
+    let methods = allow(&not_allowed);
+    assert_eq!(methods.len(), 1);
+    assert!(methods[0].contains("INVITE") && methods[0].contains("BYE"));
+    assert_eq!(
+        not_implemented.status_code,
+        rsip::StatusCode::NotImplemented
+    );
+    assert_eq!(allow(&not_implemented), methods);
+    Ok(())
+}
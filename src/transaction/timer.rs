@@ -1,11 +1,13 @@
+use super::clock::{Clock, TokioClock};
 use std::{
     collections::{BTreeMap, HashMap},
     sync::{
         atomic::{AtomicU64, Ordering},
-        RwLock,
+        Arc, RwLock,
     },
-    time::{Duration, Instant},
+    time::Duration,
 };
+use tokio::time::Instant;
 
 #[derive(Debug, PartialOrd, PartialEq, Eq, Clone)]
 struct TimerKey {
@@ -23,14 +25,21 @@ pub struct Timer<T> {
     tasks: RwLock<BTreeMap<TimerKey, T>>,
     id_to_tasks: RwLock<HashMap<u64, Instant>>,
     last_task_id: AtomicU64,
+    clock: Arc<dyn Clock>,
 }
 
 impl<T> Timer<T> {
     pub fn new() -> Self {
+        Self::with_clock(Arc::new(TokioClock))
+    }
+
+    /// the timeouts are counted on `clock`
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
         Timer {
             tasks: RwLock::new(BTreeMap::new()),
             id_to_tasks: RwLock::new(HashMap::new()),
             last_task_id: AtomicU64::new(1),
+            clock,
         }
     }
 
@@ -39,7 +48,7 @@ impl<T> Timer<T> {
     }
 
     pub fn timeout(&self, duration: Duration, value: T) -> u64 {
-        self.timeout_at(Instant::now() + duration, value)
+        self.timeout_at(self.clock.now() + duration, value)
     }
 
     pub fn timeout_at(&self, execute_at: Instant, value: T) -> u64 {
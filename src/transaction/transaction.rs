@@ -1,15 +1,18 @@
-use super::endpoint::EndpointInnerRef;
-use super::key::TransactionKey;
+use super::endpoint::{ContactContext, EndpointInnerRef};
+use super::key::{TransactionKey, TransactionRole};
 use super::{SipConnection, TransactionState, TransactionTimer, TransactionType};
 use crate::transaction::make_tag;
-use crate::transport::SipAddr;
-use crate::{Error, Result};
-use rsip::prelude::HeadersExt;
+use crate::dialog::{dialog::is_loose_route, registration::is_gruu};
+use crate::rsip_ext::{parse_contact, parse_via, select_variant, RsipHeadersExt, Variant};
+use crate::transport::{connection::UDP_MTU_THRESHOLD, SipAddr};
+use crate::{header_pop, Error, Result};
+use rsip::prelude::{HeadersExt, ToTypedHeader, UntypedHeader};
 use rsip::headers::ContentLength;
 use rsip::message::HasHeaders;
 use rsip::{Header, Method, Request, Response, SipMessage, StatusCode};
+use std::time::Duration;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-use tracing::{debug, info, instrument, span, Level, Span};
+use tracing::{debug, info, info_span, instrument, Span};
 
 pub type TransactionEventReceiver = UnboundedReceiver<TransactionEvent>;
 pub type TransactionEventSender = UnboundedSender<TransactionEvent>;
@@ -17,14 +20,23 @@ pub enum TransactionEvent {
     Received(SipMessage, Option<SipConnection>),
     Timer(TransactionTimer),
     Respond(Response),
+    /// a reliable connection failed, the transactions over it can't go on
+    ConnectionClosed(SipConnection),
     Terminate,
+    /// removed by the sweep of the endpoint, see `EndpointOption::transaction_sweep`
+    Expired,
 }
 
 pub struct Transaction {
     pub transaction_type: TransactionType,
     pub key: TransactionKey,
     pub original: Request,
-    pub destination: Option<SipAddr>,
+    /// the resolved targets of a client transaction, tried in order
+    pub destinations: Vec<SipAddr>,
+    /// the transport a client transaction is sent on, over the `transport`
+    /// param of its uri
+    pub transport: Option<rsip::Transport>,
+    pub target_index: usize,
     pub state: TransactionState,
     pub endpoint_inner: EndpointInnerRef,
     pub connection: Option<SipConnection>,
@@ -35,8 +47,11 @@ pub struct Transaction {
     pub timer_a: Option<u64>,
     pub timer_b: Option<u64>,
     pub timer_d: Option<u64>,
-    pub timer_k: Option<u64>, // server invite only
+    pub timer_e: Option<u64>, // client non-invite only
+    pub timer_f: Option<u64>, // client non-invite only
+    pub timer_k: Option<u64>,
     pub timer_g: Option<u64>, // server invite only
+    pub timer_j: Option<u64>, // server non-invite only
     span: Span,
     is_cleaned_up: bool,
 }
@@ -50,7 +65,7 @@ impl Transaction {
         endpoint_inner: EndpointInnerRef,
     ) -> Self {
         let (tu_sender, tu_receiver) = unbounded_channel();
-        let span = span!(Level::INFO, "transaction", key = %key);
+        let span = transaction_span(&original);
         info!("transaction created {:?} {}", transaction_type, key);
         let tx = Self {
             transaction_type,
@@ -58,15 +73,20 @@ impl Transaction {
             connection,
             key,
             original,
-            destination: None,
+            destinations: vec![],
+            transport: None,
+            target_index: 0,
             state: TransactionState::Calling,
             last_response: None,
             last_ack: None,
             timer_a: None,
             timer_b: None,
             timer_d: None,
+            timer_e: None,
+            timer_f: None,
             timer_k: None,
             timer_g: None,
+            timer_j: None,
             tu_receiver,
             tu_sender,
             span,
@@ -103,10 +123,8 @@ impl Transaction {
         Transaction::new(tx_type, key, original, connection, endpoint_inner)
     }
     // send client request
-    #[instrument(skip(self))]
+    #[instrument(parent = &self.span, skip(self))]
     pub async fn send(&mut self) -> Result<()> {
-        let span = self.span.clone();
-        let _enter = span.enter();
         match self.transaction_type {
             TransactionType::ClientInvite | TransactionType::ClientNonInvite => {}
             _ => {
@@ -117,38 +135,282 @@ impl Transaction {
             }
         }
 
+        loop {
+            match self.send_to_destination().await {
+                Ok(()) => return self.transition(TransactionState::Trying).map(|_| ()),
+                Err(e) => {
+                    info!("send to {:?} failed: {}", self.destination(), e);
+                    if !self.next_destination() {
+                        return Err(self.attempts_error(e));
+                    }
+                }
+            }
+        }
+    }
+
+    /// the target the request is sent to, `None` for a connection given by the caller
+    pub fn destination(&self) -> Option<&SipAddr> {
+        self.destinations.get(self.target_index)
+    }
+
+    /// how many of the resolved targets were tried
+    pub fn attempted_targets(&self) -> usize {
+        (self.target_index + 1).min(self.destinations.len().max(1))
+    }
+
+    async fn send_to_destination(&mut self) -> Result<()> {
         if let None = self.connection {
-            let connection = self
+            if self.destinations.is_empty() {
+                // a pre-loaded route set sends the request to its first loose router (RFC 3261 8.1.2)
+                let next_hop = self
+                    .original
+                    .route_header()
+                    .and_then(|route| route.typed().ok())
+                    .and_then(|route| route.uris().first().filter(|u| is_loose_route(u)).cloned())
+                    .map(|route| route.uri)
+                    .unwrap_or_else(|| self.original.uri.clone());
+                self.destinations = self
+                    .endpoint_inner
+                    .transport_layer
+                    .resolve(&with_transport(next_hop, self.transport))
+                    .await?;
+            }
+            let target = self.destination().cloned().ok_or(Error::TransactionError(
+                "no destination found".to_string(),
+                self.key.clone(),
+            ))?;
+            let mut connection = self
                 .endpoint_inner
                 .transport_layer
-                .lookup(&self.original.uri)
+                .connect_target(
+                    &with_transport(self.original.uri.clone(), self.transport),
+                    &target,
+                )
                 .await?;
-            self.connection.replace(connection.clone());
+            // a forced transport is never changed for the size of the request
+            if !connection.is_reliable()
+                && self.transport.is_none()
+                && self.original.to_string().len() > UDP_MTU_THRESHOLD
+            {
+                if let Some((reliable, target)) = self.lookup_reliable().await {
+                    connection = reliable;
+                    self.destinations[self.target_index] = target;
+                }
+            }
+            self.connection.replace(connection);
         }
 
-        let connection = self.connection.as_ref().ok_or(Error::TransactionError(
+        let connection = self.connection.clone().ok_or(Error::TransactionError(
             "no connection found".to_string(),
             self.key.clone(),
         ))?;
+        self.update_transport(&connection);
         let content_length_header = Header::ContentLength(ContentLength::from(self.original.body().len() as u32));
         self.original.headers_mut().unique_push(content_length_header);
-        connection
-            .send(self.original.to_owned().into(), self.destination.as_ref())
-            .await?;
-        self.transition(TransactionState::Trying).map(|_| ())
+        let target = self.destination().cloned();
+        self.endpoint_inner
+            .strip_untrusted_identity(&mut self.original, target.as_ref());
+        self.original = self.endpoint_inner.rewrite_request(self.original.to_owned());
+        let sent = self
+            .endpoint_inner
+            .send_wire(&connection, self.original.to_owned(), self.destination())
+            .await;
+        // a dead pooled connection is not reused, the next attempt reconnects
+        if let (Err(_), true, Some(target)) =
+            (&sent, connection.is_reliable(), self.destination())
+        {
+            self.endpoint_inner
+                .transport_layer
+                .evict_connection(target, &connection)
+                .await;
+            self.connection = None;
+        }
+        sent
+    }
+
+    /// Move on to the next resolved target. It is a new client transaction
+    /// with a new branch (RFC 3263 4.3), false if no target is left.
+    fn next_destination(&mut self) -> bool {
+        if self.target_index + 1 >= self.destinations.len() {
+            return false;
+        }
+        self.target_index += 1;
+        self.connection = None;
+        self.cleanup_timer();
+        if let Some(mut via) = self
+            .original
+            .via_header()
+            .ok()
+            .and_then(|via| parse_via(via).ok())
+        {
+            via.params.retain(|p| !matches!(p, rsip::Param::Branch(_)));
+            via.params.push(self.endpoint_inner.generate_branch());
+            if let Some(branch) = via.branch() {
+                self.span.record("branch", branch.to_string());
+            }
+            header_pop!(self.original.headers, Header::Via);
+            self.original.headers.push_front(via.into());
+        }
+        match TransactionKey::from_request(&self.original, TransactionRole::Client) {
+            Ok(key) => {
+                self.endpoint_inner.detach_transaction(&self.key, None);
+                self.key = key;
+                self.endpoint_inner
+                    .attach_transaction(&self.key, self.tu_sender.clone());
+            }
+            Err(e) => info!("failed to rekey transaction: {}", e),
+        }
+        self.state = TransactionState::Calling;
+        true
+    }
+
+    /// retry the next target when the current one did not answer in time
+    async fn failover(&mut self) -> bool {
+        if self.state != TransactionState::Trying || !self.next_destination() {
+            return false;
+        }
+        match self.send().await {
+            Ok(()) => true,
+            Err(e) => {
+                info!("failover failed: {}", e);
+                false
+            }
+        }
+    }
+
+    fn attempts_error(&self, e: Error) -> Error {
+        match (self.attempted_targets(), e) {
+            (1, e) => e,
+            // keep the kind of the last failure for the retry logic above
+            (n, Error::Transport { kind, addr, source }) => Error::Transport {
+                kind,
+                addr,
+                source: format!("{} ({} targets tried)", source, n),
+            },
+            (n, e) => {
+                Error::TransactionError(format!("{} ({} targets tried)", e, n), self.key.clone())
+            }
+        }
+    }
+
+    /// A failed connection is a transport error (RFC 3261 8.1.3.1), a pending
+    /// client transaction ends with a local 503
+    fn on_connection_closed(&mut self) -> Option<SipMessage> {
+        info!("connection closed, terminating");
+        let pending = matches!(
+            self.transaction_type,
+            TransactionType::ClientInvite | TransactionType::ClientNonInvite
+        ) && matches!(
+            self.state,
+            TransactionState::Calling | TransactionState::Trying | TransactionState::Proceeding
+        );
+        self.transition(TransactionState::Terminated).ok();
+        if !pending {
+            return None;
+        }
+        let resp = self.endpoint_inner.make_response(
+            &self.original,
+            rsip::StatusCode::ServiceUnavailable,
+            None,
+        );
+        self.last_response.replace(resp.clone());
+        Some(SipMessage::Response(resp))
+    }
+
+    /// the 408 for the TU, with the number of targets tried when more than one
+    fn timeout_response(&self) -> Response {
+        let mut resp = self.endpoint_inner.make_response(
+            &self.original,
+            rsip::StatusCode::RequestTimeout,
+            None,
+        );
+        let attempts = self.attempted_targets();
+        if attempts > 1 {
+            resp.headers.push(Header::Warning(
+                format!("399 rsipstack \"{} targets tried\"", attempts).into(),
+            ));
+        }
+        resp
+    }
+
+    /// lookup a TCP connection for a large request
+    async fn lookup_reliable(&mut self) -> Option<(SipConnection, SipAddr)> {
+        let mut uri = self.original.uri.clone();
+        uri.params.retain(|p| !matches!(p, rsip::Param::Transport(_)));
+        uri.params
+            .push(rsip::Param::Transport(rsip::Transport::Tcp));
+        match self.endpoint_inner.transport_layer.lookup_destination(&uri).await {
+            Ok((connection, target)) if connection.is_reliable() => Some((connection, target)),
+            _ => {
+                info!("no reliable transport for large request, sending over udp");
+                None
+            }
+        }
+    }
+
+    /// make the Via and Contact transport match the connection, so responses
+    /// and in-dialog requests come back over it
+    fn update_transport(&mut self, connection: &SipConnection) {
+        let transport = match connection.get_addr().r#type {
+            Some(transport) => transport,
+            None => return,
+        };
+        if let Some(mut via) = self
+            .original
+            .via_header()
+            .ok()
+            .and_then(|via| parse_via(via).ok())
+        {
+            if via.transport != transport {
+                via.transport = transport;
+                header_pop!(self.original.headers, Header::Via);
+                self.original.headers.push_front(via.into());
+            }
+        }
+        let mut contact = match self
+            .original
+            .contact_header()
+            .ok()
+            .and_then(|contact| parse_contact(contact.value()).ok())
+        {
+            Some(contact) => contact,
+            None => return,
+        };
+        if let Some(builder) = self.endpoint_inner.contact_builder() {
+            let contact = builder(&ContactContext {
+                method: &self.original.method,
+                transport,
+                addr: connection.get_addr(),
+                contact: &contact,
+            });
+            self.original.headers.unique_push(contact.into());
+            return;
+        }
+        // a GRUU routes to us through the registrar whatever the transport
+        if transport == rsip::Transport::Udp || is_gruu(&contact.uri) {
+            return;
+        }
+        if contact.uri.scheme == Some(rsip::Scheme::Sip)
+            && !contact
+                .uri
+                .params
+                .iter()
+                .any(|p| matches!(p, rsip::Param::Transport(_)))
+        {
+            contact.uri.params.push(rsip::Param::Transport(transport));
+            self.original.headers.unique_push(contact.into());
+        }
     }
 
+    #[instrument(parent = &self.span, skip(self, headers, body))]
     pub async fn reply_with(
         &mut self,
         status_code: StatusCode,
         headers: Vec<rsip::Header>,
         body: Option<Vec<u8>>,
     ) -> Result<()> {
-        let mut resp = self
-            .endpoint_inner
-            .make_response(&self.original, status_code, body);
-        resp.headers.extend(headers);
-        match resp.status_code.kind() {
+        // tag the request first, the final response copies its To
+        match status_code.kind() {
             rsip::StatusCodeKind::Provisional => {}
             _ => {
                 let to = self.original.to_header()?;
@@ -159,18 +421,49 @@ impl Transaction {
                 }
             }
         }
+        let mut resp = self
+            .endpoint_inner
+            .make_response(&self.original, status_code, body);
+        resp.headers.extend(headers);
         self.respond(resp).await
     }
+    /// Reply with the variant the request accepts best, see
+    /// [`select_variant`], or with 406 and the offered types in Accept
+    /// when it accepts none.
+    pub async fn reply_variant(
+        &mut self,
+        status_code: StatusCode,
+        mut headers: Vec<rsip::Header>,
+        variants: &[Variant],
+    ) -> Result<()> {
+        match select_variant(&self.original.headers, variants) {
+            Some(variant) => {
+                headers.extend(variant.headers());
+                self.reply_with(status_code, headers, Some(variant.body.clone()))
+                    .await
+            }
+            None => {
+                let mut offered: Vec<&str> = vec![];
+                for variant in variants {
+                    if !offered.contains(&variant.content_type.as_str()) {
+                        offered.push(&variant.content_type);
+                    }
+                }
+                let offered = offered.join(", ");
+                let accept = rsip::Header::Accept(offered.into());
+                self.reply_with(StatusCode::NotAcceptable, vec![accept], None)
+                    .await
+            }
+        }
+    }
     /// Quick reply with status code
-    #[instrument(skip(self))]
+    #[instrument(parent = &self.span, skip(self))]
     pub async fn reply(&mut self, status_code: StatusCode) -> Result<()> {
         self.reply_with(status_code, vec![], None).await
     }
     // send server response
-    #[instrument(skip(self, response))]
+    #[instrument(parent = &self.span, skip(self, response))]
     pub async fn respond(&mut self, response: Response) -> Result<()> {
-        let span = self.span.clone();
-        let _enter = span.or_current();
         match self.transaction_type {
             TransactionType::ServerInvite | TransactionType::ServerNonInvite => {}
             _ => {
@@ -186,9 +479,11 @@ impl Transaction {
                 rsip::StatusCode::Trying => TransactionState::Trying,
                 _ => TransactionState::Proceeding,
             },
-            _ => match self.transaction_type {
-                TransactionType::ServerInvite => TransactionState::Completed,
-                _ => TransactionState::Terminated,
+            _ => match (&self.transaction_type, &self.connection) {
+                (TransactionType::ServerInvite, _) => TransactionState::Completed,
+                // Timer J is zero on reliable transports (RFC 3261 17.2.2)
+                (_, Some(connection)) if connection.is_reliable() => TransactionState::Terminated,
+                _ => TransactionState::Completed,
             },
         };
         // check an transition to new state
@@ -198,9 +493,10 @@ impl Transaction {
             "no connection found".to_string(),
             self.key.clone(),
         ))?;
+        let response = self.endpoint_inner.rewrite_response(response);
         debug!("responding with {}", response);
-        connection
-            .send(response.to_owned().into(), self.destination.as_ref())
+        self.endpoint_inner
+            .send_wire(connection, response.to_owned(), self.destination())
             .await?;
         self.last_response.replace(response);
         self.transition(new_state).map(|_| ())
@@ -217,6 +513,7 @@ impl Transaction {
             | (&TransactionState::Trying, &TransactionState::Completed)
             | (&TransactionState::Trying, &TransactionState::Confirmed)
             | (&TransactionState::Trying, &TransactionState::Terminated)
+            | (&TransactionState::Proceeding, &TransactionState::Proceeding) // another provisional
             | (&TransactionState::Proceeding, &TransactionState::Completed)
             | (&TransactionState::Proceeding, &TransactionState::Confirmed)
             | (&TransactionState::Proceeding, &TransactionState::Terminated)
@@ -234,10 +531,8 @@ impl Transaction {
             }
         }
     }
-    #[instrument(skip(self, cancel))]
+    #[instrument(parent = &self.span, skip(self, cancel))]
     pub async fn send_cancel(&mut self, cancel: Request) -> Result<()> {
-        let span = self.span.clone();
-        let _enter = span.or_current();
         if self.transaction_type != TransactionType::ClientInvite {
             return Err(Error::TransactionError(
                 "send_cancel is only valid for client invite transactions".to_string(),
@@ -248,8 +543,9 @@ impl Transaction {
         match self.state {
             TransactionState::Calling | TransactionState::Trying | TransactionState::Proceeding => {
                 if let Some(connection) = &self.connection {
-                    connection
-                        .send(cancel.to_owned().into(), self.destination.as_ref())
+                    let cancel = self.endpoint_inner.rewrite_request(cancel);
+                    self.endpoint_inner
+                        .send_wire(connection, cancel.to_owned(), self.destination())
                         .await?;
                 }
                 self.transition(TransactionState::Terminated).map(|_| ())
@@ -262,10 +558,8 @@ impl Transaction {
             }
         }
     }
-    #[instrument(skip(self, ack))]
+    #[instrument(parent = &self.span, skip(self, ack))]
     pub async fn send_ack(&mut self, ack: Request) -> Result<()> {
-        let span = self.span.clone();
-        let _enter = span.or_current();
         if self.transaction_type != TransactionType::ClientInvite {
             return Err(Error::TransactionError(
                 "send_ack is only valid for client invite transactions".to_string(),
@@ -278,6 +572,15 @@ impl Transaction {
             self.key.clone(),
         ))?;
 
+        if self
+            .last_response
+            .as_ref()
+            .is_some_and(|r| r.status_code.code() >= 300)
+        {
+            // the transaction has acknowledged the non-2xx itself
+            return Ok(());
+        }
+
         match self.state {
             TransactionState::Completed => {} // must be in completed state, to send ACK
             _ => {
@@ -288,17 +591,42 @@ impl Transaction {
             }
         }
 
-        connection
-            .send(ack.to_owned().into(), self.destination.as_ref())
+        let ack = self.endpoint_inner.rewrite_request(ack);
+        self.endpoint_inner
+            .send_wire(connection, ack.to_owned(), self.destination())
             .await?;
         self.last_ack.replace(ack);
+        let accepted = self
+            .last_response
+            .as_ref()
+            .is_some_and(|r| r.status_code.kind() == rsip::StatusCodeKind::Successful);
+        if accepted {
+            // the 2xx of other branches of a forked INVITE still go to the TU (RFC 6026 7.2),
+            // until Timer D
+            return Ok(());
+        }
         // client send ack and transition to Terminated
         self.transition(TransactionState::Terminated).map(|_| ())
     }
 
+    #[instrument(parent = &self.span, skip(self))]
     pub async fn receive(&mut self) -> Option<SipMessage> {
-        let span = self.span.clone();
-        let _enter = span.enter();
+        if self.transaction_type == TransactionType::ClientNonInvite
+            && self.state == TransactionState::Completed
+        {
+            // final response already delivered, only absorbing retransmissions until Timer K
+            return None;
+        }
+        if self.transaction_type == TransactionType::ClientInvite
+            && self.state == TransactionState::Completed
+            && self
+                .last_response
+                .as_ref()
+                .is_some_and(|r| r.status_code.code() >= 300)
+        {
+            // the non-2xx is delivered and acknowledged, retransmissions get the ACK until Timer D
+            return None;
+        }
         while let Some(event) = self.tu_receiver.recv().await {
             match event {
                 TransactionEvent::Received(msg, connection) => {
@@ -315,18 +643,37 @@ impl Transaction {
                 TransactionEvent::Respond(response) => {
                     self.respond(response).await.ok();
                 }
+                TransactionEvent::ConnectionClosed(connection) => {
+                    if self
+                        .connection
+                        .as_ref()
+                        .is_some_and(|c| c.same_connection(&connection))
+                    {
+                        return self.on_connection_closed();
+                    }
+                }
                 TransactionEvent::Terminate => {
                     info!("received terminate event");
                     return None;
                 }
+                TransactionEvent::Expired => {
+                    info!("expired by the endpoint sweep");
+                    let timeout = match self.transaction_type {
+                        TransactionType::ClientInvite | TransactionType::ClientNonInvite => {
+                            Some(self.timeout_response().into())
+                        }
+                        _ => None,
+                    };
+                    self.transition(TransactionState::Terminated).ok();
+                    return timeout;
+                }
             }
         }
         None
     }
 
+    #[instrument(parent = &self.span, skip(self))]
     pub async fn send_trying(&mut self) -> Result<()> {
-        let span = self.span.clone();
-        let _enter = span.or_current();
         let response =
             self.endpoint_inner
                 .make_response(&self.original, rsip::StatusCode::Trying, None);
@@ -366,16 +713,24 @@ impl Transaction {
             match self.state {
                 TransactionState::Proceeding
                 | TransactionState::Trying
-                | TransactionState::Completed => {
+                | TransactionState::Completed
+                | TransactionState::Confirmed => {
                     if let Some(connection) = &self.connection {
                         let resp = self
                             .endpoint_inner
                             .make_response(&req, StatusCode::OK, None);
-                        connection
-                            .send(resp.into(), self.destination.as_ref())
+                        let resp = self.endpoint_inner.rewrite_response(resp);
+                        self.endpoint_inner
+                            .send_wire(connection, resp, self.destination())
                             .await
                             .ok();
                     }
+                    // the final response is sent, the CANCEL has no effect (RFC 3261 9.2)
+                    if self.last_response.as_ref().is_some_and(|r| {
+                        r.status_code.kind() != rsip::StatusCodeKind::Provisional
+                    }) {
+                        return None;
+                    }
                     return Some(req.into()); // into dialog
                 }
                 _ => {
@@ -385,8 +740,9 @@ impl Transaction {
                             StatusCode::CallTransactionDoesNotExist,
                             None,
                         );
-                        connection
-                            .send(resp.into(), self.destination.as_ref())
+                        let resp = self.endpoint_inner.rewrite_response(resp);
+                        self.endpoint_inner
+                            .send_wire(connection, resp, self.destination())
                             .await
                             .ok();
                     }
@@ -397,9 +753,27 @@ impl Transaction {
 
         match self.state {
             TransactionState::Trying | TransactionState::Proceeding => {
-                // retransmission of last response
-                if let Some(last_response) = &self.last_response {
-                    self.respond(last_response.to_owned()).await.ok();
+                // retransmission of last response, sent as it was rewritten
+                if let (Some(last_response), Some(connection)) =
+                    (&self.last_response, &self.connection)
+                {
+                    self.endpoint_inner
+                        .send_wire(connection, last_response.to_owned(), self.destination())
+                        .await
+                        .ok();
+                }
+            }
+            TransactionState::Completed
+                if self.transaction_type == TransactionType::ServerNonInvite =>
+            {
+                // absorb the retransmission with the final response until Timer J
+                if let (Some(last_response), Some(connection)) =
+                    (&self.last_response, &self.connection)
+                {
+                    self.endpoint_inner
+                        .send_wire(connection, last_response.to_owned(), self.destination())
+                        .await
+                        .ok();
                 }
             }
             TransactionState::Completed => {
@@ -427,28 +801,98 @@ impl Transaction {
                     TransactionState::Proceeding
                 }
             }
-            _ => {
-                if self.transaction_type == TransactionType::ClientInvite {
-                    TransactionState::Completed
-                } else {
-                    TransactionState::Terminated
-                }
-            }
+            _ => match self.transaction_type {
+                TransactionType::ClientInvite => TransactionState::Completed,
+                _ => match &self.connection {
+                    // wait Timer K to absorb response retransmissions
+                    Some(connection) if !connection.is_reliable() => TransactionState::Completed,
+                    _ => TransactionState::Terminated,
+                },
+            },
         };
 
+        if self.transaction_type == TransactionType::ClientInvite
+            && self.state == TransactionState::Completed
+            && self.last_ack.is_some()
+            && resp.status_code.kind() == rsip::StatusCodeKind::Successful
+        {
+            // a retransmitted or forked 2xx, the TU acknowledges it
+            return Some(SipMessage::Response(resp));
+        }
+
         self.can_transition(&new_state).ok()?;
         if self.state == new_state {
+            // every 1xx goes to the TU, other branches of a fork ring as well (RFC 3261 17.1)
+            if new_state == TransactionState::Proceeding {
+                self.last_response.replace(resp.clone());
+                return Some(SipMessage::Response(resp));
+            }
+            // a retransmitted non-2xx is acknowledged again, not passed to the TU
+            if self.transaction_type == TransactionType::ClientInvite
+                && resp.status_code.code() >= 300
+            {
+                self.resend_ack().await;
+            }
             // ignore duplicate response
             return None;
         }
 
         self.last_response.replace(resp.clone());
+        if self.transaction_type == TransactionType::ClientInvite && resp.status_code.code() >= 300
+        {
+            match self.endpoint_inner.make_error_ack(&self.original, &resp) {
+                Ok(ack) => {
+                    self.last_ack
+                        .replace(self.endpoint_inner.rewrite_request(ack));
+                    self.resend_ack().await;
+                }
+                Err(e) => info!("failed to make ack for {}: {}", resp.status_code, e),
+            }
+        }
         self.transition(new_state).ok();
         return Some(SipMessage::Response(resp));
     }
 
+    async fn resend_ack(&self) {
+        if let (Some(ack), Some(connection)) = (&self.last_ack, &self.connection) {
+            self.endpoint_inner
+                .send_wire(connection, ack.to_owned(), self.destination())
+                .await
+                .map_err(|e| info!("failed to send ack: {}", e))
+                .ok();
+        }
+    }
+
     async fn on_timer(&mut self, timer: TransactionTimer) -> Result<()> {
         match self.state {
+            TransactionState::Trying | TransactionState::Proceeding
+                if self.transaction_type == TransactionType::ClientNonInvite =>
+            {
+                if let TransactionTimer::TimerE(key, duration) = timer {
+                    // Resend the request
+                    if let Some(connection) = &self.connection {
+                        self.endpoint_inner
+                            .send_wire(connection, self.original.to_owned(), self.destination())
+                            .await?;
+                    }
+                    // Restart Timer E, doubling up to T2, or T2 once a provisional was received
+                    let duration = match self.state {
+                        TransactionState::Proceeding => self.endpoint_inner.t2,
+                        _ => (duration * 2).min(self.endpoint_inner.t2),
+                    };
+                    let timer_e = self
+                        .endpoint_inner
+                        .timers
+                        .timeout(duration, TransactionTimer::TimerE(key, duration));
+                    self.timer_e.replace(timer_e);
+                } else if let TransactionTimer::TimerF(_) = timer {
+                    if self.failover().await {
+                        return Ok(());
+                    }
+                    // Inform TU about timeout
+                    self.inform_tu_response(self.timeout_response())?;
+                }
+            }
             TransactionState::Trying => {
                 if matches!(
                     self.transaction_type,
@@ -457,8 +901,12 @@ impl Transaction {
                     if let TransactionTimer::TimerA(key, duration) = timer {
                         // Resend the INVITE request
                         if let Some(connection) = &self.connection {
-                            connection
-                                .send(self.original.to_owned().into(), self.destination.as_ref())
+                            self.endpoint_inner
+                                .send_wire(
+                                    connection,
+                                    self.original.to_owned(),
+                                    self.destination(),
+                                )
                                 .await?;
                         }
                         // Restart Timer A with an upper limit
@@ -469,25 +917,18 @@ impl Transaction {
                             .timeout(duration, TransactionTimer::TimerA(key, duration));
                         self.timer_a.replace(timer_a);
                     } else if let TransactionTimer::TimerB(_) = timer {
+                        if self.failover().await {
+                            return Ok(());
+                        }
                         // Inform TU about timeout
-                        let timeout_response = self.endpoint_inner.make_response(
-                            &self.original,
-                            rsip::StatusCode::RequestTimeout,
-                            None,
-                        );
-                        self.inform_tu_response(timeout_response)?;
+                        self.inform_tu_response(self.timeout_response())?;
                     }
                 }
             }
             TransactionState::Proceeding => {
                 if let TransactionTimer::TimerB(_) = timer {
                     // Inform TU about timeout
-                    let timeout_response = self.endpoint_inner.make_response(
-                        &self.original,
-                        rsip::StatusCode::RequestTimeout,
-                        None,
-                    );
-                    self.inform_tu_response(timeout_response)?;
+                    self.inform_tu_response(self.timeout_response())?;
                 }
             }
             TransactionState::Completed => {
@@ -495,13 +936,17 @@ impl Transaction {
                     // resend the response
                     if let Some(last_response) = &self.last_response {
                         if let Some(connection) = &self.connection {
-                            connection
-                                .send(last_response.to_owned().into(), self.destination.as_ref())
+                            self.endpoint_inner
+                                .send_wire(
+                                    connection,
+                                    last_response.to_owned(),
+                                    self.destination(),
+                                )
                                 .await?;
                         }
                     }
-                    // restart Timer G with an upper limit
-                    let duration = (duration * 2).min(self.endpoint_inner.t1x64);
+                    // restart Timer G, doubling up to T2 (RFC 3261 13.3.1.4, 17.2.1)
+                    let duration = (duration * 2).min(self.endpoint_inner.t2);
                     let timer_g = self
                         .endpoint_inner
                         .timers
@@ -509,6 +954,12 @@ impl Transaction {
                     self.timer_g.replace(timer_g);
                 } else if let TransactionTimer::TimerD(_) = timer {
                     self.transition(TransactionState::Terminated)?;
+                } else if let TransactionTimer::TimerK(_) = timer {
+                    self.transition(TransactionState::Terminated)?;
+                } else if let TransactionTimer::TimerJ(_) = timer {
+                    // the retransmission window is over, nothing left to answer
+                    self.last_response.take();
+                    self.transition(TransactionState::Terminated)?;
                 }
             }
             TransactionState::Confirmed => {
@@ -535,28 +986,49 @@ impl Transaction {
                     self.key.clone(),
                 ))?;
 
-                if matches!(
-                    self.transaction_type,
-                    TransactionType::ClientInvite | TransactionType::ClientNonInvite
-                ) {
-                    if !connection.is_reliable() {
-                        self.timer_a
+                match self.transaction_type {
+                    TransactionType::ClientNonInvite => {
+                        // Timer E for retransmission on unreliable transports only, Timer F always
+                        if !connection.is_reliable() && self.timer_e.is_none() {
+                            self.timer_e.replace(self.endpoint_inner.timers.timeout(
+                                self.endpoint_inner.t1,
+                                TransactionTimer::TimerE(self.key.clone(), self.endpoint_inner.t1),
+                            ));
+                        }
+                        if self.timer_f.is_none() {
+                            self.timer_f.replace(self.endpoint_inner.timers.timeout(
+                                self.endpoint_inner.t1x64,
+                                TransactionTimer::TimerF(self.key.clone()),
+                            ));
+                        }
+                    }
+                    _ => {
+                        if self.transaction_type == TransactionType::ClientInvite
+                            && !connection.is_reliable()
+                        {
+                            self.timer_a
+                                .take()
+                                .map(|id| self.endpoint_inner.timers.cancel(id));
+                            self.timer_a.replace(self.endpoint_inner.timers.timeout(
+                                self.endpoint_inner.t1,
+                                TransactionTimer::TimerA(self.key.clone(), self.endpoint_inner.t1),
+                            ));
+                        }
+
+                        self.timer_b
                             .take()
                             .map(|id| self.endpoint_inner.timers.cancel(id));
-                        self.timer_a.replace(self.endpoint_inner.timers.timeout(
-                            self.endpoint_inner.t1,
-                            TransactionTimer::TimerA(self.key.clone(), self.endpoint_inner.t1),
+                        self.timer_b.replace(self.endpoint_inner.timers.timeout(
+                            self.endpoint_inner.t1x64,
+                            TransactionTimer::TimerB(self.key.clone()),
                         ));
                     }
                 }
-
-                self.timer_b
-                    .take()
-                    .map(|id| self.endpoint_inner.timers.cancel(id));
-                self.timer_b.replace(self.endpoint_inner.timers.timeout(
-                    self.endpoint_inner.t1x64,
-                    TransactionTimer::TimerB(self.key.clone()),
-                ));
+            }
+            TransactionState::Proceeding
+                if self.transaction_type == TransactionType::ClientNonInvite =>
+            {
+                // Timer E and F keep running
             }
             TransactionState::Proceeding => {
                 self.timer_a
@@ -583,7 +1055,13 @@ impl Transaction {
                         "no connection found".to_string(),
                         self.key.clone(),
                     ))?;
-                    if !connection.is_reliable() {
+                    // a 2xx is retransmitted on every transport until the ACK, it may be
+                    // lost behind a proxy (RFC 3261 13.3.1.4)
+                    let accepted = self
+                        .last_response
+                        .as_ref()
+                        .is_some_and(|r| r.status_code.kind() == rsip::StatusCodeKind::Successful);
+                    if accepted || !connection.is_reliable() {
                         let timer_g = self.endpoint_inner.timers.timeout(
                             self.endpoint_inner.t1,
                             TransactionTimer::TimerG(self.key.clone(), self.endpoint_inner.t1),
@@ -592,12 +1070,43 @@ impl Transaction {
                     }
                 }
 
-                // start Timer D
-                let timer_d = self.endpoint_inner.timers.timeout(
-                    self.endpoint_inner.t1x64,
-                    TransactionTimer::TimerD(self.key.clone()),
-                );
-                self.timer_d.replace(timer_d);
+                if self.transaction_type == TransactionType::ClientNonInvite {
+                    self.timer_e
+                        .take()
+                        .map(|id| self.endpoint_inner.timers.cancel(id));
+                    self.timer_f
+                        .take()
+                        .map(|id| self.endpoint_inner.timers.cancel(id));
+                    // start Timer K
+                    let timer_k = self.endpoint_inner.timers.timeout(
+                        self.endpoint_inner.t4,
+                        TransactionTimer::TimerK(self.key.clone()),
+                    );
+                    self.timer_k.replace(timer_k);
+                } else if self.transaction_type == TransactionType::ServerNonInvite {
+                    // start Timer J, request retransmissions are answered until it fires
+                    let timer_j = self.endpoint_inner.timers.timeout(
+                        self.endpoint_inner.t1x64,
+                        TransactionTimer::TimerJ(self.key.clone()),
+                    );
+                    self.timer_j.replace(timer_j);
+                } else {
+                    // start Timer D, zero for a non-2xx on reliable transports (RFC 3261 17.1.1.2),
+                    // a 2xx waits for the retransmissions and forks (RFC 6026 7.2)
+                    let failed = self
+                        .last_response
+                        .as_ref()
+                        .is_some_and(|r| r.status_code.code() >= 300);
+                    let duration = match &self.connection {
+                        Some(connection) if failed && connection.is_reliable() => Duration::ZERO,
+                        _ => self.endpoint_inner.t1x64,
+                    };
+                    let timer_d = self
+                        .endpoint_inner
+                        .timers
+                        .timeout(duration, TransactionTimer::TimerD(self.key.clone()));
+                    self.timer_d.replace(timer_d);
+                }
             }
             TransactionState::Confirmed => {
                 self.cleanup_timer();
@@ -628,12 +1137,21 @@ impl Transaction {
         self.timer_d
             .take()
             .map(|id| self.endpoint_inner.timers.cancel(id));
+        self.timer_e
+            .take()
+            .map(|id| self.endpoint_inner.timers.cancel(id));
+        self.timer_f
+            .take()
+            .map(|id| self.endpoint_inner.timers.cancel(id));
         self.timer_k
             .take()
             .map(|id| self.endpoint_inner.timers.cancel(id));
         self.timer_g
             .take()
             .map(|id| self.endpoint_inner.timers.cancel(id));
+        self.timer_j
+            .take()
+            .map(|id| self.endpoint_inner.timers.cancel(id));
     }
 
     fn cleanup(&mut self) {
@@ -641,23 +1159,50 @@ impl Transaction {
             return;
         }
         self.is_cleaned_up = true;
-        self.cleanup_timer();
         let last_message = {
             match self.transaction_type {
                 TransactionType::ClientInvite => {
                     self.last_ack.take().map(|r| SipMessage::Request(r))
                 }
+                // the endpoint answers the retransmissions once the TU dropped the transaction
                 TransactionType::ServerNonInvite => {
                     self.last_response.take().map(|r| SipMessage::Response(r))
                 }
                 _ => None,
             }
         };
+        self.cleanup_timer();
         self.endpoint_inner
             .detach_transaction(&self.key, last_message);
     }
 }
 
+/// the span of a transaction, the Call-ID joins it to the other
+/// transactions and the dialog of the call
+fn transaction_span(original: &Request) -> Span {
+    let call_id = original
+        .call_id_header()
+        .map(|c| c.value().to_string())
+        .unwrap_or_default();
+    let branch = original
+        .via_header()
+        .ok()
+        .and_then(|via| parse_via(via).ok())
+        .and_then(|via| via.branch().map(|b| b.to_string()))
+        .unwrap_or_default();
+    let cseq = original
+        .cseq_header()
+        .and_then(|c| c.seq())
+        .unwrap_or_default();
+    info_span!(
+        "transaction",
+        call_id = %call_id,
+        branch = %branch,
+        method = %original.method,
+        cseq = cseq
+    )
+}
+
 impl Drop for Transaction {
     fn drop(&mut self) {
         self.cleanup();
@@ -665,3 +1210,12 @@ impl Drop for Transaction {
         info!("transaction dropped");
     }
 }
+
+// the uri with its transport param replaced by `transport`, if any
+fn with_transport(mut uri: rsip::Uri, transport: Option<rsip::Transport>) -> rsip::Uri {
+    if let Some(transport) = transport {
+        uri.params.retain(|p| !matches!(p, rsip::Param::Transport(_)));
+        uri.params.push(rsip::Param::Transport(transport));
+    }
+    uri
+}
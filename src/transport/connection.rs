@@ -1,21 +1,31 @@
 use super::{
-    channel::ChannelConnection, sip_addr::SipAddr, stream::StreamConnection, tcp::TcpConnection,
+    channel::ChannelConnection,
+    custom::{CustomConnection, TransportRef},
+    sip_addr::{bracket_host, SipAddr},
+    stream::StreamConnection,
+    tcp::TcpConnection,
     udp::UdpConnection,
 };
+use crate::rsip_ext::{normalize_headers, parse_via};
 use crate::transport::tls::TlsConnection;
 use crate::transport::websocket::WebSocketConnection;
 use crate::Result;
-use rsip::{
-    prelude::{HeadersExt, ToTypedHeader},
-    Param, SipMessage,
+use rsip::{prelude::HeadersExt, Param, SipMessage};
+use std::{
+    fmt,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
 };
-use std::{fmt, net::SocketAddr};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tracing::debug;
 
 #[derive(Debug)]
 pub enum TransportEvent {
     Incoming(SipMessage, SipConnection, SipAddr),
+    /// a datagram over the size limit of its connection, the endpoint
+    /// answers a request with 513 (RFC 3261 18.1.2), a stream connection
+    /// is closed instead
+    TooLarge(SipMessage, SipConnection, SipAddr),
     New(SipConnection),
     Closed(SipConnection),
 }
@@ -25,6 +35,10 @@ pub type TransportSender = UnboundedSender<TransportEvent>;
 
 pub const KEEPALIVE_REQUEST: &[u8] = b"\r\n\r\n";
 pub const KEEPALIVE_RESPONSE: &[u8] = b"\r\n";
+/// requests larger than this go over TCP if available (RFC 3261 18.1.1)
+pub const UDP_MTU_THRESHOLD: usize = 1300;
+/// the largest message the transports read, the payload of a UDP datagram
+pub const MAX_MESSAGE_SIZE: usize = 65535;
 
 #[derive(Clone, Debug)]
 pub enum SipConnection {
@@ -35,15 +49,30 @@ pub enum SipConnection {
     Tls(TlsConnection),
     #[cfg(feature = "websocket")]
     WebSocket(WebSocketConnection),
+    Custom(CustomConnection),
 }
 
 impl SipConnection {
     pub fn is_reliable(&self) -> bool {
         match self {
             SipConnection::Udp(_) => false,
+            SipConnection::Custom(transport) => transport.is_reliable(),
             _ => true,
         }
     }
+    /// true for two handles of one connection
+    pub fn same_connection(&self, other: &SipConnection) -> bool {
+        match (self, other) {
+            (SipConnection::Tcp(a), SipConnection::Tcp(b)) => Arc::ptr_eq(&a.inner, &b.inner),
+            #[cfg(feature = "rustls")]
+            (SipConnection::Tls(a), SipConnection::Tls(b)) => Arc::ptr_eq(&a.inner, &b.inner),
+            #[cfg(feature = "websocket")]
+            (SipConnection::WebSocket(a), SipConnection::WebSocket(b)) => {
+                Arc::ptr_eq(&a.inner, &b.inner)
+            }
+            _ => self.get_addr() == other.get_addr(),
+        }
+    }
     pub fn get_addr(&self) -> &SipAddr {
         match self {
             SipConnection::Udp(transport) => transport.get_addr(),
@@ -53,9 +82,24 @@ impl SipConnection {
             SipConnection::Tls(transport) => transport.get_addr(),
             #[cfg(feature = "websocket")]
             SipConnection::WebSocket(transport) => transport.get_addr(),
+            SipConnection::Custom(transport) => transport.get_addr(),
+        }
+    }
+    /// the stream connection, `None` for datagram, channel and custom transports
+    pub fn stream(&self) -> Option<&dyn StreamConnection> {
+        match self {
+            SipConnection::Tcp(transport) => Some(transport),
+            #[cfg(feature = "rustls")]
+            SipConnection::Tls(transport) => Some(transport),
+            #[cfg(feature = "websocket")]
+            SipConnection::WebSocket(transport) => Some(transport),
+            _ => None,
         }
     }
     pub async fn send(&self, msg: rsip::SipMessage, destination: Option<&SipAddr>) -> Result<()> {
+        if let Some(stream) = self.stream() {
+            stream.keepalive().on_message();
+        }
         match self {
             SipConnection::Udp(transport) => transport.send(msg, destination).await,
             SipConnection::Channel(transport) => transport.send(msg).await,
@@ -81,6 +125,20 @@ impl SipConnection {
                 }
                 transport.send_message(msg).await
             }
+            SipConnection::Custom(transport) => transport.send(msg, destination).await,
+        }
+    }
+    /// Replace the size of the largest message read from the connection,
+    /// [`MAX_MESSAGE_SIZE`] by default. The channel and custom transports
+    /// keep their own
+    pub fn set_max_message_size(&self, size: usize) {
+        match self {
+            SipConnection::Udp(transport) => transport.set_max_message_size(size),
+            _ => {
+                if let Some(stream) = self.stream() {
+                    stream.max_message_size().store(size, Ordering::Relaxed);
+                }
+            }
         }
     }
     pub async fn serve_loop(&self, sender: TransportSender) -> Result<()> {
@@ -92,6 +150,7 @@ impl SipConnection {
             SipConnection::Tls(transport) => transport.serve_loop(sender).await,
             #[cfg(feature = "websocket")]
             SipConnection::WebSocket(transport) => transport.serve_loop(sender).await,
+            SipConnection::Custom(transport) => transport.serve_loop(sender).await,
         }
     }
 
@@ -99,6 +158,7 @@ impl SipConnection {
         match self {
             SipConnection::Udp(_) => Ok(()),     // UDP has no connection state
             SipConnection::Channel(_) => Ok(()), // Channel doesn't need to be closed
+            SipConnection::Custom(_) => Ok(()),
             SipConnection::Tcp(transport) => transport.close().await,
             #[cfg(feature = "rustls")]
             SipConnection::Tls(transport) => transport.close().await,
@@ -109,33 +169,42 @@ impl SipConnection {
 }
 
 impl SipConnection {
+    /// Expand the compact headers of a message off the wire and stamp the
+    /// top Via of a request with its source
     pub fn update_msg_received(msg: SipMessage, addr: SocketAddr) -> Result<SipMessage> {
         match msg {
             SipMessage::Request(mut req) => {
+                normalize_headers(&mut req.headers);
                 let via = req.via_header_mut()?;
                 Self::build_via_received(via, addr)?;
                 Ok(req.into())
             }
-            SipMessage::Response(_) => Ok(msg),
+            SipMessage::Response(mut resp) => {
+                normalize_headers(&mut resp.headers);
+                Ok(resp.into())
+            }
         }
     }
 
+    /// Stamp the top Via with the source of the request (RFC 3581 4), so the
+    /// response goes back to where the request came from, not the Via address
     pub fn build_via_received(via: &mut rsip::headers::Via, addr: SocketAddr) -> Result<()> {
-        let received = addr.into();
-        let mut typed_via = via.typed()?;
-        if typed_via.uri.host_with_port == received {
+        let received: rsip::HostWithPort = SipAddr::from(addr).into();
+        let mut typed_via = parse_via(via)?;
+        let rport_requested = typed_via.params.iter().any(|param| {
+            matches!(param, Param::Other(key, None) if key.value().eq_ignore_ascii_case("rport"))
+        });
+        if typed_via.uri.host_with_port == received && !rport_requested {
             return Ok(());
         }
-        typed_via.params.retain(|param| {
-            if let Param::Other(key, _) = param {
-                !key.value().eq_ignore_ascii_case("rport")
-            } else {
-                true
-            }
+        typed_via.params.retain(|param| match param {
+            Param::Other(key, _) => !key.value().eq_ignore_ascii_case("rport"),
+            Param::Received(_) => false,
+            _ => true,
         });
         *via = typed_via
             .with_param(Param::Received(rsip::param::Received::new(
-                received.host.to_string(),
+                addr.ip().to_string(),
             )))
             .with_param(Param::Other(
                 rsip::param::OtherParam::new("rport"),
@@ -146,22 +215,21 @@ impl SipConnection {
     }
 
     pub fn parse_target_from_via(via: &rsip::headers::untyped::Via) -> Result<rsip::HostWithPort> {
-        let mut host_with_port = via.uri()?.host_with_port;
-        if let Ok(params) = via.params().as_ref() {
-            for param in params {
-                match param {
-                    Param::Received(v) => {
-                        if let Ok(addr) = v.parse() {
-                            host_with_port.host = addr.into();
-                        }
+        let via = parse_via(via)?;
+        let mut host_with_port = via.uri.host_with_port;
+        for param in via.params.iter() {
+            match param {
+                Param::Received(v) => {
+                    if let Ok(addr) = v.parse() {
+                        host_with_port.host = addr.into();
                     }
-                    Param::Other(key, Some(value)) if key.value().eq_ignore_ascii_case("rport") => {
-                        if let Ok(port) = value.value().try_into() {
-                            host_with_port.port = Some(port);
-                        }
+                }
+                Param::Other(key, Some(value)) if key.value().eq_ignore_ascii_case("rport") => {
+                    if let Ok(port) = value.value().try_into() {
+                        host_with_port.port = Some(port);
                     }
-                    _ => {}
                 }
+                _ => {}
             }
         }
         Ok(host_with_port)
@@ -170,9 +238,24 @@ impl SipConnection {
     pub fn get_destination(msg: &rsip::SipMessage) -> Result<SocketAddr> {
         let host_with_port = match msg {
             rsip::SipMessage::Request(req) => req.uri().host_with_port.clone(),
-            rsip::SipMessage::Response(res) => Self::parse_target_from_via(res.via_header()?)?,
+            rsip::SipMessage::Response(res) => match res.via_header() {
+                Ok(via) => Self::parse_target_from_via(via)?,
+                // written with compact headers, the top Via is a `v`
+                Err(e) => {
+                    let mut headers = res.headers.clone();
+                    normalize_headers(&mut headers);
+                    let via = headers
+                        .iter()
+                        .find_map(|h| match h {
+                            rsip::Header::Via(via) => Some(via.clone()),
+                            _ => None,
+                        })
+                        .ok_or(e)?;
+                    Self::parse_target_from_via(&via)?
+                }
+            },
         };
-        host_with_port.try_into().map_err(Into::into)
+        SipAddr::from(host_with_port).get_socketaddr()
     }
 }
 
@@ -186,6 +269,7 @@ impl fmt::Display for SipConnection {
             SipConnection::Tls(t) => write!(f, "{}", t),
             #[cfg(feature = "websocket")]
             SipConnection::WebSocket(t) => write!(f, "{}", t),
+            SipConnection::Custom(t) => write!(f, "CUSTOM {}", t),
         }
     }
 }
@@ -202,6 +286,12 @@ impl From<ChannelConnection> for SipConnection {
     }
 }
 
+impl From<TransportRef> for SipConnection {
+    fn from(transport: TransportRef) -> Self {
+        SipConnection::Custom(CustomConnection::new(transport))
+    }
+}
+
 impl From<TcpConnection> for SipConnection {
     fn from(connection: TcpConnection) -> Self {
         SipConnection::Tcp(connection)
@@ -220,9 +310,13 @@ impl From<WebSocketConnection> for SipConnection {
     }
 }
 
+// the address as written in a SIP message, an IPv6 address in brackets
 impl Into<rsip::HostWithPort> for SipAddr {
     fn into(self) -> rsip::HostWithPort {
-        self.addr
+        rsip::HostWithPort {
+            host: bracket_host(self.addr.host),
+            port: self.addr.port,
+        }
     }
 }
 impl Into<rsip::Uri> for SipAddr {
@@ -235,7 +329,7 @@ impl Into<rsip::Uri> for SipAddr {
         };
         rsip::Uri {
             scheme: Some(scheme),
-            host_with_port: self.addr,
+            host_with_port: self.into(),
             ..Default::default()
         }
     }
@@ -0,0 +1,87 @@
+use super::{connection::TransportSender, SipAddr, SipConnection, TransportEvent};
+use crate::Result;
+use rsip::SipMessage;
+use std::{net::SocketAddr, sync::Arc};
+use tracing::info;
+
+/// A user supplied message transport, e.g. an in-memory channel for tests or
+/// a tunnel of its own. The built-in [`UdpConnection`](super::udp::UdpConnection)
+/// implements it as well.
+#[async_trait::async_trait]
+pub trait Transport: Send + Sync {
+    /// the local address, its transport type goes in the Via of the requests sent
+    fn get_addr(&self) -> &SipAddr;
+
+    /// reliable transports don't retransmit (RFC 3261 17.1.1.2)
+    fn is_reliable(&self) -> bool {
+        false
+    }
+
+    async fn send(&self, msg: SipMessage, target: SocketAddr) -> Result<()>;
+
+    /// the next inbound message and its source, `None` once the transport is closed
+    async fn recv(&self) -> Option<(SipMessage, SocketAddr)>;
+}
+
+pub type TransportRef = Arc<dyn Transport>;
+
+/// A [`Transport`] as a connection of the transport layer
+#[derive(Clone)]
+pub struct CustomConnection {
+    transport: TransportRef,
+}
+
+impl CustomConnection {
+    pub fn new(transport: TransportRef) -> Self {
+        Self { transport }
+    }
+
+    pub fn get_addr(&self) -> &SipAddr {
+        self.transport.get_addr()
+    }
+
+    pub fn is_reliable(&self) -> bool {
+        self.transport.is_reliable()
+    }
+
+    pub async fn send(&self, msg: SipMessage, destination: Option<&SipAddr>) -> Result<()> {
+        let target = match destination {
+            Some(addr) => addr.get_socketaddr(),
+            None => SipConnection::get_destination(&msg),
+        }?;
+        self.transport.send(msg, target).await
+    }
+
+    pub async fn serve_loop(&self, sender: TransportSender) -> Result<()> {
+        while let Some((msg, addr)) = self.transport.recv().await {
+            let msg = match SipConnection::update_msg_received(msg, addr) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    info!("error updating SIP via from: {} error: {:?}", addr, e);
+                    continue;
+                }
+            };
+            sender.send(TransportEvent::Incoming(
+                msg,
+                SipConnection::Custom(self.clone()),
+                SipAddr {
+                    r#type: self.get_addr().r#type,
+                    addr: addr.into(),
+                },
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for CustomConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.get_addr())
+    }
+}
+
+impl std::fmt::Debug for CustomConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.get_addr())
+    }
+}
@@ -0,0 +1,124 @@
+use super::{connection::KEEPALIVE_REQUEST, SipAddr, SipConnection};
+use crate::{
+    error::TransportErrorKind,
+    transaction::clock::{Clock, TokioClock},
+    Result,
+};
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// CRLF keepalive of the outgoing stream connections (RFC 5626 4.4.1)
+#[derive(Clone, Debug)]
+pub struct KeepaliveConfig {
+    /// between two pings
+    pub interval: Duration,
+    /// pings in a row without a pong before the connection is failed
+    pub max_misses: u32,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        // RFC 5626 4.4.1 recommends 95 to 120 seconds on connection-oriented transports
+        Self {
+            interval: Duration::from_secs(95),
+            max_misses: 3,
+        }
+    }
+}
+
+/// The pings of a connection not answered yet and its last message
+#[derive(Debug)]
+pub struct KeepaliveState {
+    misses: AtomicU32,
+    active_at: Mutex<Instant>,
+    clock: Mutex<Arc<dyn Clock>>,
+}
+
+impl Default for KeepaliveState {
+    fn default() -> Self {
+        Self {
+            misses: AtomicU32::new(0),
+            active_at: Mutex::new(Instant::now()),
+            clock: Mutex::new(Arc::new(TokioClock)),
+        }
+    }
+}
+
+impl KeepaliveState {
+    pub fn on_pong(&self) {
+        self.misses.store(0, Ordering::Relaxed);
+    }
+
+    pub fn misses(&self) -> u32 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// a message was sent or received, pings and pongs are not counted
+    pub fn on_message(&self) {
+        *self.active_at.lock().unwrap() = self.clock().now();
+    }
+
+    /// the time since the last message
+    pub fn idle(&self) -> Duration {
+        let active_at = *self.active_at.lock().unwrap();
+        self.clock().now().saturating_duration_since(active_at)
+    }
+
+    /// Count the pings and the idle time on `clock`, the tokio clock by
+    /// default. The connection counts as active from now on
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.active_at.lock().unwrap() = clock.now();
+        *self.clock.lock().unwrap() = clock;
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.lock().unwrap().clone()
+    }
+}
+
+/// Ping a stream connection every `interval` until `max_misses` pings in a row
+/// are left unanswered, other connections are never pinged
+pub async fn keepalive_loop(
+    connection: &SipConnection,
+    config: &KeepaliveConfig,
+    target: &SipAddr,
+) -> Result<()> {
+    let stream = match connection.stream() {
+        Some(stream) => stream,
+        None => return std::future::pending().await,
+    };
+    let state = stream.keepalive();
+    loop {
+        state.clock().sleep(config.interval).await;
+        if state.misses.fetch_add(1, Ordering::Relaxed) >= config.max_misses {
+            return Err(crate::Error::Transport {
+                kind: TransportErrorKind::Timeout,
+                addr: target.clone(),
+                source: format!("{} keepalive pings unanswered", config.max_misses),
+            });
+        }
+        stream.send_raw(KEEPALIVE_REQUEST).await?;
+    }
+}
+
+/// Return once a stream connection carried no message for `timeout`, other
+/// connections never return
+pub async fn idle_loop(connection: &SipConnection, timeout: Duration) {
+    let state = match connection.stream() {
+        Some(stream) => stream.keepalive(),
+        None => return std::future::pending().await,
+    };
+    loop {
+        let idle = state.idle();
+        if idle >= timeout {
+            return;
+        }
+        state.clock().sleep(timeout - idle).await;
+    }
+}
@@ -0,0 +1,90 @@
+use super::{custom::Transport, SipAddr};
+use crate::{error::TransportErrorKind, Result};
+use rsip::SipMessage;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+type LoopbackSender = UnboundedSender<(SipMessage, SocketAddr)>;
+
+/// An in-memory network of [`LoopbackTransport`]s, a message sent to an
+/// address is received by the transport bound to it
+#[derive(Clone, Default)]
+pub struct LoopbackNetwork {
+    peers: Arc<Mutex<HashMap<SocketAddr, LoopbackSender>>>,
+}
+
+impl LoopbackNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// a transport of the network at `addr`, replacing the one bound before
+    pub fn bind(&self, addr: SocketAddr) -> LoopbackTransport {
+        let (sender, receiver) = unbounded_channel();
+        self.peers.lock().unwrap().insert(addr, sender.clone());
+        LoopbackTransport {
+            addr: SipAddr {
+                r#type: Some(rsip::transport::Transport::Udp),
+                addr: addr.into(),
+            },
+            socket_addr: addr,
+            network: self.clone(),
+            sender,
+            incoming: tokio::sync::Mutex::new(receiver),
+        }
+    }
+}
+
+/// A datagram [`Transport`] without sockets, for running endpoints against
+/// each other in tests
+pub struct LoopbackTransport {
+    addr: SipAddr,
+    socket_addr: SocketAddr,
+    network: LoopbackNetwork,
+    // tells our binding apart from a later one of the same address
+    sender: LoopbackSender,
+    incoming: tokio::sync::Mutex<UnboundedReceiver<(SipMessage, SocketAddr)>>,
+}
+
+#[async_trait::async_trait]
+impl Transport for LoopbackTransport {
+    fn get_addr(&self) -> &SipAddr {
+        &self.addr
+    }
+
+    async fn send(&self, msg: SipMessage, target: SocketAddr) -> Result<()> {
+        let peer = self.network.peers.lock().unwrap().get(&target).cloned();
+        let sent = peer.is_some_and(|peer| peer.send((msg, self.socket_addr)).is_ok());
+        if !sent {
+            return Err(crate::Error::Transport {
+                kind: TransportErrorKind::Unreachable,
+                addr: SipAddr {
+                    r#type: self.addr.r#type,
+                    addr: target.into(),
+                },
+                source: "no loopback transport bound".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    async fn recv(&self) -> Option<(SipMessage, SocketAddr)> {
+        self.incoming.lock().await.recv().await
+    }
+}
+
+impl Drop for LoopbackTransport {
+    fn drop(&mut self) {
+        let mut peers = self.network.peers.lock().unwrap();
+        if peers
+            .get(&self.socket_addr)
+            .is_some_and(|peer| peer.same_channel(&self.sender))
+        {
+            peers.remove(&self.socket_addr);
+        }
+    }
+}
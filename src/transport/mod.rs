@@ -1,15 +1,21 @@
 pub mod channel;
 pub mod connection;
+pub mod custom;
+pub mod keepalive;
+pub mod loopback;
+pub mod resolver;
 pub mod sip_addr;
 pub mod stream;
 pub mod tcp;
 pub mod tls;
+pub mod tracer;
 pub mod transport_layer;
 pub mod udp;
 pub mod websocket;
 
 pub use connection::SipConnection;
 pub use connection::TransportEvent;
+pub use custom::{Transport, TransportRef};
 pub use sip_addr::SipAddr;
 pub use transport_layer::TransportLayer;
 
@@ -0,0 +1,208 @@
+use super::{sip_addr::host_ip, SipAddr};
+use crate::Result;
+use rsip::HostWithPort;
+use rsip_dns::{
+    records::{AddrRecord, NaptrEntry, NaptrRecord, SrvDomain, SrvEntry, SrvRecord},
+    trust_dns_resolver::{proto::rr::RecordType, TokioAsyncResolver},
+    DnsClient, ResolvableExt,
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+// the tokio clock like the timers, so paused time in tests expires the cache
+use tokio::time::Instant;
+use tracing::debug;
+
+/// most candidates a single resolution returns
+const MAX_TARGETS: usize = 16;
+
+/// Resolves a SIP URI into the ordered list of targets to try (RFC 3263)
+#[async_trait::async_trait]
+pub trait Resolver: Send + Sync {
+    async fn resolve(&self, uri: &rsip::Uri) -> Result<Vec<SipAddr>>;
+}
+
+pub type ResolverRef = Arc<dyn Resolver>;
+
+/// NAPTR, SRV then A/AAAA lookups, the records are cached until their TTL expires
+#[derive(Clone)]
+pub struct DnsResolver {
+    client: CachedDnsClient,
+}
+
+impl DnsResolver {
+    pub fn new() -> Result<Self> {
+        let resolver = TokioAsyncResolver::tokio(Default::default(), Default::default())
+            .map_err(|e| crate::Error::Resolve(e.to_string()))?;
+        Ok(Self {
+            client: CachedDnsClient {
+                resolver,
+                cache: Arc::new(Mutex::new(HashMap::new())),
+            },
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Resolver for DnsResolver {
+    async fn resolve(&self, uri: &rsip::Uri) -> Result<Vec<SipAddr>> {
+        // an IPv6 reference is an address, not a domain to look up
+        let mut uri = uri.clone();
+        if let Some(ip_addr) = host_ip(&uri.host_with_port.host) {
+            uri.host_with_port.host = ip_addr.into();
+        }
+        let context = rsip_dns::Context::initialize_from(
+            uri.clone(),
+            self.client.clone(),
+            rsip_dns::SupportedTransports::any(),
+        )?;
+
+        let mut lookup = rsip_dns::Lookup::from(context);
+        let mut targets = vec![];
+        while let Some(mut target) = lookup.resolve_next().await {
+            // rsip-dns uses the default port for ip hosts
+            if let (rsip::Host::IpAddr(_), Some(port)) =
+                (&uri.host_with_port.host, uri.host_with_port.port)
+            {
+                target.port = port;
+            }
+            let addr = SipAddr {
+                r#type: Some(target.transport),
+                addr: HostWithPort::from(SocketAddr::new(target.ip_addr, u16::from(target.port))),
+            };
+            if !targets.contains(&addr) {
+                targets.push(addr);
+            }
+            if targets.len() >= MAX_TARGETS {
+                break;
+            }
+        }
+        debug!("resolved {} -> {:?}", uri, targets);
+        if targets.is_empty() {
+            return Err(crate::Error::Resolve(format!("no target: {}", uri)));
+        }
+        Ok(targets)
+    }
+}
+
+#[derive(Clone, Hash, Eq, PartialEq)]
+enum CacheKey {
+    Naptr(String),
+    Srv(String),
+    Ip(String),
+}
+
+#[derive(Clone)]
+enum CacheEntry {
+    Naptr(Vec<NaptrEntry>),
+    Srv(Vec<SrvEntry>),
+    Ip(Vec<IpAddr>),
+}
+
+#[derive(Clone)]
+struct CachedDnsClient {
+    resolver: TokioAsyncResolver,
+    cache: Arc<Mutex<HashMap<CacheKey, (Instant, CacheEntry)>>>,
+}
+
+impl CachedDnsClient {
+    fn get(&self, key: &CacheKey) -> Option<CacheEntry> {
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(key) {
+            Some((valid_until, entry)) if *valid_until > Instant::now() => Some(entry.clone()),
+            Some(_) => {
+                cache.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn put(&self, key: CacheKey, valid_until: Instant, entry: CacheEntry) {
+        self.cache.lock().unwrap().insert(key, (valid_until, entry));
+    }
+
+    // the TTL of a lookup, given on the system clock, on the tokio one
+    fn put_lookup(&self, key: CacheKey, valid_until: std::time::Instant, entry: CacheEntry) {
+        let ttl = valid_until.saturating_duration_since(std::time::Instant::now());
+        self.put(key, Instant::now() + ttl, entry);
+    }
+}
+
+#[async_trait::async_trait]
+impl DnsClient for CachedDnsClient {
+    async fn naptr_lookup(&self, domain: rsip::Domain) -> Option<NaptrRecord> {
+        let key = CacheKey::Naptr(domain.to_string());
+        if let Some(CacheEntry::Naptr(entries)) = self.get(&key) {
+            return Some(NaptrRecord { domain, entries });
+        }
+        let lookup = self
+            .resolver
+            .lookup(domain.to_string(), RecordType::NAPTR, Default::default())
+            .await
+            .ok()?;
+        let valid_until = lookup.valid_until();
+        let entries = lookup
+            .into_iter()
+            .filter_map(|rdata| rdata.try_into().ok())
+            .collect::<Vec<NaptrEntry>>();
+        self.put_lookup(key, valid_until, CacheEntry::Naptr(entries.clone()));
+        Some(NaptrRecord { domain, entries })
+    }
+
+    async fn srv_lookup(&self, domain: SrvDomain) -> Option<SrvRecord> {
+        let key = CacheKey::Srv(domain.to_string());
+        if let Some(CacheEntry::Srv(entries)) = self.get(&key) {
+            return Some(SrvRecord { domain, entries });
+        }
+        let lookup = self.resolver.srv_lookup(domain.to_string()).await.ok()?;
+        let valid_until = lookup.as_lookup().valid_until();
+        let entries = lookup
+            .into_iter()
+            .map(Into::into)
+            .collect::<Vec<SrvEntry>>();
+        self.put_lookup(key, valid_until, CacheEntry::Srv(entries.clone()));
+        Some(SrvRecord { domain, entries })
+    }
+
+    async fn ip_lookup(
+        &self,
+        domain: rsip::Domain,
+    ) -> std::result::Result<AddrRecord, rsip::Error> {
+        let key = CacheKey::Ip(domain.to_string());
+        if let Some(CacheEntry::Ip(ip_addrs)) = self.get(&key) {
+            return Ok(AddrRecord { domain, ip_addrs });
+        }
+        let lookup = self
+            .resolver
+            .lookup_ip(domain.to_string())
+            .await
+            .map_err(|e| rsip::Error::Unexpected(e.to_string()))?;
+        let valid_until = lookup.valid_until();
+        let ip_addrs = lookup.into_iter().collect::<Vec<IpAddr>>();
+        self.put_lookup(key, valid_until, CacheEntry::Ip(ip_addrs.clone()));
+        Ok(AddrRecord { domain, ip_addrs })
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_dns_cache_expiry() -> Result<()> {
+    use std::time::Duration;
+    let resolver = DnsResolver::new()?;
+    let client = &resolver.client;
+    let key = CacheKey::Ip("example.com".to_string());
+    client.put_lookup(
+        key.clone(),
+        std::time::Instant::now() + Duration::from_secs(60),
+        CacheEntry::Ip(vec![IpAddr::from([127, 0, 0, 1])]),
+    );
+    tokio::time::advance(Duration::from_secs(59)).await;
+    assert!(matches!(client.get(&key), Some(CacheEntry::Ip(ips)) if ips.len() == 1));
+
+    tokio::time::advance(Duration::from_secs(2)).await;
+    assert!(client.get(&key).is_none());
+    assert!(client.cache.lock().unwrap().is_empty());
+    Ok(())
+}
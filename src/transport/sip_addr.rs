@@ -1,6 +1,10 @@
 use crate::Result;
 use rsip::{host_with_port, HostWithPort};
-use std::{fmt, hash::Hash, net::SocketAddr};
+use std::{
+    fmt,
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+};
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct SipAddr {
@@ -40,18 +44,41 @@ impl SipAddr {
     }
 
     pub fn get_socketaddr(&self) -> Result<SocketAddr> {
-        match &self.addr.host {
-            host_with_port::Host::Domain(domain) => Err(crate::Error::Error(format!(
-                "Cannot convert domain {} to SocketAddr",
-                domain
-            ))),
-            host_with_port::Host::IpAddr(ip_addr) => {
+        match host_ip(&self.addr.host) {
+            Some(ip_addr) => {
                 let port = self.addr.port.map_or(5060, |p| p.value().to_owned());
-                Ok(SocketAddr::new(ip_addr.to_owned(), port))
+                Ok(SocketAddr::new(ip_addr, port))
             }
+            None => Err(crate::Error::Error(format!(
+                "Cannot convert domain {} to SocketAddr",
+                self.addr.host
+            ))),
         }
     }
 }
+
+/// The host as written in a SIP message, rsip prints an IPv6 address without
+/// the brackets of an IPv6 reference (RFC 3261 25.1)
+pub fn bracket_host(host: rsip::Host) -> rsip::Host {
+    match host {
+        rsip::Host::IpAddr(IpAddr::V6(ip)) => rsip::Host::Domain(format!("[{}]", ip).into()),
+        host => host,
+    }
+}
+
+/// The address of an IP host, an IPv6 reference included
+pub fn host_ip(host: &rsip::Host) -> Option<IpAddr> {
+    match host {
+        rsip::Host::IpAddr(ip_addr) => Some(*ip_addr),
+        rsip::Host::Domain(domain) => domain
+            .to_string()
+            .strip_prefix('[')?
+            .strip_suffix(']')?
+            .parse()
+            .ok(),
+    }
+}
+
 impl From<SocketAddr> for SipAddr {
     fn from(addr: SocketAddr) -> Self {
         let host_with_port = HostWithPort {
@@ -79,9 +106,13 @@ impl TryFrom<&rsip::Uri> for SipAddr {
 
     fn try_from(uri: &rsip::Uri) -> Result<Self> {
         let transport = uri.transport().cloned();
+        let mut addr = uri.host_with_port.clone();
+        if let Some(ip_addr) = host_ip(&addr.host) {
+            addr.host = ip_addr.into();
+        }
         Ok(SipAddr {
             r#type: transport,
-            addr: uri.host_with_port.clone(),
+            addr,
         })
     }
 }
@@ -1,13 +1,17 @@
 use crate::{
     transport::{
-        connection::{TransportSender, KEEPALIVE_REQUEST, KEEPALIVE_RESPONSE},
+        connection::{TransportSender, KEEPALIVE_REQUEST, KEEPALIVE_RESPONSE, MAX_MESSAGE_SIZE},
+        keepalive::KeepaliveState,
         SipAddr, SipConnection, TransportEvent,
     },
     Result,
 };
 use bytes::{Buf, BytesMut};
 use rsip::SipMessage;
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     sync::Mutex,
@@ -15,16 +19,22 @@ use tokio::{
 use tokio_util::codec::{Decoder, Encoder};
 use tracing::{debug, error, warn};
 
-const MAX_SIP_MESSAGE_SIZE: usize = 65535;
-
 pub struct SipCodec {
     max_size: usize,
+    /// a keepalive pong was skipped since the last check
+    pong: bool,
 }
 
 impl SipCodec {
     pub fn new() -> Self {
+        Self::with_max_size(MAX_MESSAGE_SIZE)
+    }
+
+    /// A codec refusing the messages larger than `max_size` bytes
+    pub fn with_max_size(max_size: usize) -> Self {
         Self {
-            max_size: MAX_SIP_MESSAGE_SIZE,
+            max_size,
+            pong: false,
         }
     }
 }
@@ -45,49 +55,60 @@ impl Decoder for SipCodec {
             return Err(crate::Error::Keepalive);
         }
 
+        // a pong must not be answered again
         if src.len() >= 2 && &src[0..2] == KEEPALIVE_RESPONSE {
             src.advance(2);
-            return Err(crate::Error::Keepalive);
+            self.pong = true;
+            return self.decode(src);
         }
 
-        let data = match std::str::from_utf8(&src[..]) {
-            Ok(s) => s,
-            Err(_) => {
+        let header_end = match src.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => pos + 4,
+            None => {
+                // dropped, or the read loops would decode the same bytes forever
                 if src.len() > self.max_size {
-                    return Err(crate::Error::Error("SIP message too large".to_string()));
+                    src.clear();
+                    return Err(crate::Error::MessageTooLarge);
                 }
                 return Ok(None);
             }
         };
 
-        if !data.contains("\r\n\r\n") {
-            if src.len() > self.max_size {
-                return Err(crate::Error::Error("SIP message too large".to_string()));
-            }
+        // the body may arrive in later reads, wait for Content-Length bytes
+        let content_length = content_length(&src[..header_end]);
+        let msg_len = header_end + content_length;
+        if msg_len > self.max_size {
+            src.clear();
+            return Err(crate::Error::MessageTooLarge);
+        }
+        if src.len() < msg_len {
             return Ok(None);
         }
 
-        match SipMessage::try_from(data) {
-            Ok(msg) => {
-                let msg_len = data.find("\r\n\r\n").unwrap() + 4;
-                src.advance(msg_len);
-                Ok(Some(msg))
-            }
-            Err(e) => {
-                if let Some(pos) = data[1..].find("\r\n\r\n") {
-                    src.advance(pos + 5);
-                } else {
-                    src.clear();
-                }
-                Err(crate::Error::Error(format!(
-                    "Failed to parse SIP message: {}",
-                    e
-                )))
-            }
-        }
+        let result = SipMessage::try_from(&src[..msg_len])
+            .map_err(|e| crate::Error::parse(e, &src[..msg_len]));
+        src.advance(msg_len);
+        result.map(Some)
     }
 }
 
+/// the Content-Length (or compact `l`) of a header block, 0 if missing
+fn content_length(headers: &[u8]) -> usize {
+    let headers = String::from_utf8_lossy(headers);
+    headers
+        .split("\r\n")
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("l") {
+                value.trim().parse::<usize>().ok()
+            } else {
+                None
+            }
+        })
+        .unwrap_or(0)
+}
+
 impl Encoder<SipMessage> for SipCodec {
     type Error = crate::Error;
 
@@ -102,6 +123,13 @@ impl Encoder<SipMessage> for SipCodec {
 pub trait StreamConnection: Send + Sync + 'static {
     fn get_addr(&self) -> &SipAddr;
 
+    /// the pings sent on the connection, see [`keepalive_loop`](super::keepalive::keepalive_loop)
+    fn keepalive(&self) -> &KeepaliveState;
+
+    /// the size of the largest message read, a larger one closes the
+    /// connection, see [`SipConnection::set_max_message_size`]
+    fn max_message_size(&self) -> &AtomicUsize;
+
     async fn send_message(&self, msg: SipMessage) -> Result<()>;
 
     async fn send_raw(&self, data: &[u8]) -> Result<()>;
@@ -131,7 +159,7 @@ where
 
     let mut read_buf = [0u8; 4096];
 
-    loop {
+    'read: loop {
         match read_half.read(&mut read_buf).await {
             Ok(0) => {
                 debug!("Connection closed: {}", local_addr);
@@ -159,6 +187,12 @@ where
                             lock.write_all(KEEPALIVE_RESPONSE).await?;
                             lock.flush().await?;
                         }
+                        // the rest of the message would be read as the next one
+                        Err(crate::Error::MessageTooLarge) => {
+                            warn!("message too large from {}, closing", remote_addr);
+                            write_half.lock().await.shutdown().await.ok();
+                            break 'read;
+                        }
                         Err(e) => {
                             warn!("Error decoding message from {}: {:?}", remote_addr, e);
                         }
@@ -177,23 +211,102 @@ where
     Ok(())
 }
 
-pub async fn send_to_stream<W>(write_half: &Arc<Mutex<W>>, msg: SipMessage) -> Result<()>
+/// Read the SIP messages of a stream framed by Content-Length until it is closed,
+/// keepalive pings are answered on `connection` and its pongs recorded. A
+/// message over the size limit closes `connection` with
+/// [`Error::MessageTooLarge`](crate::Error::MessageTooLarge)
+pub async fn serve_stream<C, R>(
+    connection: &C,
+    read_half: &mut R,
+    sip_connection: SipConnection,
+    remote_addr: SipAddr,
+    sender: TransportSender,
+) -> Result<()>
+where
+    C: StreamConnection,
+    R: AsyncRead + Unpin + Send,
+{
+    let received = remote_addr.get_socketaddr()?;
+    let mut codec = SipCodec::with_max_size(connection.max_message_size().load(Ordering::Relaxed));
+    let mut buffer = BytesMut::with_capacity(4096);
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let len = read_half.read(&mut buf).await?;
+        if len == 0 {
+            debug!("connection closed: {}", remote_addr);
+            break;
+        }
+        buffer.extend_from_slice(&buf[..len]);
+        // the limit may have been changed since the last read
+        codec.max_size = connection.max_message_size().load(Ordering::Relaxed);
+
+        loop {
+            match codec.decode(&mut buffer) {
+                Ok(Some(sip_msg)) => {
+                    connection.keepalive().on_message();
+                    let sip_msg = match SipConnection::update_msg_received(sip_msg, received) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            warn!("error parsing Via from {}: {}", remote_addr, e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = sender.send(TransportEvent::Incoming(
+                        sip_msg,
+                        sip_connection.clone(),
+                        remote_addr.clone(),
+                    )) {
+                        error!("Error sending incoming message: {:?}", e);
+                        return Ok(());
+                    }
+                }
+                Ok(None) => break,
+                Err(crate::Error::Keepalive) => {
+                    connection.send_raw(KEEPALIVE_RESPONSE).await?;
+                }
+                // the rest of the message would be read as the next one
+                Err(crate::Error::MessageTooLarge) => {
+                    warn!("message too large from {}, closing", remote_addr);
+                    connection.close().await.ok();
+                    return Err(crate::Error::MessageTooLarge);
+                }
+                Err(e) => {
+                    warn!("error decoding SIP message from {}: {}", remote_addr, e);
+                }
+            }
+        }
+        if std::mem::take(&mut codec.pong) {
+            connection.keepalive().on_pong();
+        }
+    }
+    Ok(())
+}
+
+pub async fn send_to_stream<W>(
+    write_half: &Arc<Mutex<W>>,
+    msg: SipMessage,
+    remote_addr: &SipAddr,
+) -> Result<()>
 where
     W: AsyncWrite + Unpin + Send,
 {
-    let data = msg.to_string();
-    let mut lock = write_half.lock().await;
-    lock.write_all(data.as_bytes()).await?;
-    lock.flush().await?;
-    Ok(())
+    send_raw_to_stream(write_half, msg.to_string().as_bytes(), remote_addr).await
 }
 
-pub async fn send_raw_to_stream<W>(write_half: &Arc<Mutex<W>>, data: &[u8]) -> Result<()>
+pub async fn send_raw_to_stream<W>(
+    write_half: &Arc<Mutex<W>>,
+    data: &[u8],
+    remote_addr: &SipAddr,
+) -> Result<()>
 where
     W: AsyncWrite + Unpin + Send,
 {
     let mut lock = write_half.lock().await;
-    lock.write_all(data).await?;
-    lock.flush().await?;
+    lock.write_all(data)
+        .await
+        .map_err(|e| crate::Error::transport(e, remote_addr))?;
+    lock.flush()
+        .await
+        .map_err(|e| crate::Error::transport(e, remote_addr))?;
     Ok(())
 }
@@ -1,16 +1,24 @@
 use crate::{
+    error::TransportErrorKind,
     transport::{
-        connection::{TransportSender, KEEPALIVE_REQUEST, KEEPALIVE_RESPONSE},
+        connection::{TransportSender, MAX_MESSAGE_SIZE},
+        keepalive::KeepaliveState,
         sip_addr::SipAddr,
-        stream::{handle_stream, send_raw_to_stream, send_to_stream, StreamConnection},
+        stream::{send_raw_to_stream, send_to_stream, serve_stream, StreamConnection},
         SipConnection, TransportEvent,
     },
     Result,
 };
 use rsip::SipMessage;
-use std::{fmt, net::SocketAddr, sync::Arc};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 use tokio::{
-    io::{AsyncWrite, AsyncWriteExt, AsyncReadExt},
+    io::AsyncWriteExt,
     net::{TcpListener, TcpStream},
     sync::Mutex,
 };
@@ -20,6 +28,8 @@ pub struct TcpInner {
     pub remote_addr: Option<SipAddr>,
     pub read_half: Arc<Mutex<tokio::io::ReadHalf<TcpStream>>>,
     pub write_half: Arc<Mutex<tokio::io::WriteHalf<TcpStream>>>,
+    pub keepalive: KeepaliveState,
+    pub max_message_size: AtomicUsize,
 }
 
 #[derive(Clone)]
@@ -30,11 +40,13 @@ pub struct TcpConnection {
 impl TcpConnection {
     pub async fn connect(remote: &SipAddr) -> Result<Self> {
         let socket_addr = remote.get_socketaddr()?;
-        let stream = TcpStream::connect(socket_addr).await?;
+        let stream = TcpStream::connect(socket_addr)
+            .await
+            .map_err(|e| crate::Error::transport(e, remote))?;
 
         let local_addr = SipAddr {
             r#type: Some(rsip::transport::Transport::Tcp),
-            addr: remote.addr.clone(),
+            addr: stream.local_addr()?.into(),
         };
 
         let (read_half, write_half) = tokio::io::split(stream);
@@ -45,6 +57,8 @@ impl TcpConnection {
                 remote_addr: Some(remote.clone()),
                 read_half: Arc::new(Mutex::new(read_half)),
                 write_half: Arc::new(Mutex::new(write_half)),
+                keepalive: KeepaliveState::default(),
+                max_message_size: AtomicUsize::new(MAX_MESSAGE_SIZE),
             }),
         };
 
@@ -72,6 +86,8 @@ impl TcpConnection {
                 remote_addr: Some(remote_sip_addr),
                 read_half: Arc::new(Mutex::new(read_half)),
                 write_half: Arc::new(Mutex::new(write_half)),
+                keepalive: KeepaliveState::default(),
+                max_message_size: AtomicUsize::new(MAX_MESSAGE_SIZE),
             }),
         };
 
@@ -98,10 +114,13 @@ impl TcpConnection {
         Ok((listener, sip_addr))
     }
 
+    /// Serve a TCP listener, the accepted connections read messages up to
+    /// `max_message_size` at the time they are accepted
     pub async fn serve_listener(
         listener: TcpListener,
         local_addr: SipAddr,
         sender: TransportSender,
+        max_message_size: Arc<AtomicUsize>,
     ) -> Result<()> {
         info!("Starting TCP listener on {}", local_addr);
 
@@ -112,6 +131,10 @@ impl TcpConnection {
 
                     let tcp_connection =
                         TcpConnection::from_stream(stream, local_addr.clone()).await?;
+                    tcp_connection
+                        .inner
+                        .max_message_size
+                        .store(max_message_size.load(Ordering::Relaxed), Ordering::Relaxed);
                     let sip_connection = SipConnection::Tcp(tcp_connection.clone());
 
                     let sender_clone = sender.clone();
@@ -137,79 +160,51 @@ impl TcpConnection {
 #[async_trait::async_trait]
 impl StreamConnection for TcpConnection {
     fn get_addr(&self) -> &SipAddr {
-
         &self.inner.local_addr
     }
 
+    fn keepalive(&self) -> &KeepaliveState {
+        &self.inner.keepalive
+    }
+
+    fn max_message_size(&self) -> &AtomicUsize {
+        &self.inner.max_message_size
+    }
+
     async fn send_message(&self, msg: SipMessage) -> Result<()> {
         info!("TcpConnection send:{}", msg);
-        send_to_stream(&self.inner.write_half, msg).await
+        let remote_addr = self.inner.remote_addr.as_ref();
+        send_to_stream(
+            &self.inner.write_half,
+            msg,
+            remote_addr.unwrap_or(&self.inner.local_addr),
+        )
+        .await
     }
 
     async fn send_raw(&self, data: &[u8]) -> Result<()> {
-        send_raw_to_stream(&self.inner.write_half, data).await
+        let remote_addr = self.inner.remote_addr.as_ref();
+        send_raw_to_stream(
+            &self.inner.write_half,
+            data,
+            remote_addr.unwrap_or(&self.inner.local_addr),
+        )
+        .await
     }
 
     async fn serve_loop(&self, sender: TransportSender) -> Result<()> {
-        let mut buf = vec![0u8; 2048];
         let sip_connection = SipConnection::Tcp(self.clone());
-        let remote_addr = self.inner.remote_addr.clone().unwrap().clone();
+        let remote_addr = self
+            .inner
+            .remote_addr
+            .clone()
+            .ok_or(crate::Error::Transport {
+                kind: TransportErrorKind::Other,
+                addr: self.inner.local_addr.clone(),
+                source: "tcp connection without remote address".to_string(),
+            })?;
         let mut read_half = self.inner.read_half.lock().await;
-        loop {
-
-            let len = read_half.read(&mut buf).await?;
-            if len <= 0 {
-                continue;
-            }
-
-            match &buf[..len] {
-                KEEPALIVE_REQUEST => {
-                    self.send_raw(KEEPALIVE_RESPONSE);
-                    continue;
-                }
-                KEEPALIVE_RESPONSE => continue,
-                _ => {
-                    if buf.iter().all(|&b| b.is_ascii_whitespace()) {
-                        continue;
-                    }
-                }
-            }
-
-            let undecoded = match std::str::from_utf8(&buf[..len]) {
-                Ok(s) => s,
-                Err(e) => {
-                    info!(
-                        "decoding text ferror: {} buf: {:?}",
-                        e,
-                        &buf[..len]
-                    );
-                    continue;
-                }
-            };
-
-            let sip_msg = match rsip::SipMessage::try_from(undecoded) {
-                Ok(msg) => msg,
-                Err(e) => {
-                    info!(
-                        "error parsing SIP message error: {} buf: {}",
-                        e, undecoded
-                    );
-                    continue;
-                }
-            };
-
-            if let Err(e) =
-                sender.send(TransportEvent::Incoming(
-                    sip_msg,
-                    sip_connection.clone(),
-                    remote_addr.clone(),
-                ))
-            {
-                error!("Error sending incoming message: {:?}", e);
-                break;
-            }
-        }
-        Ok(())
+        serve_stream(self, &mut *read_half, sip_connection, remote_addr, sender).await
     }
 
     async fn close(&self) -> Result<()> {
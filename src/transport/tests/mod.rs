@@ -1,3 +1,4 @@
 mod test_sipaddr;
+mod test_loopback;
 mod test_udp;
 mod transport_tests;
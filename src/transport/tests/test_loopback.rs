@@ -0,0 +1,70 @@
+use crate::{
+    transport::{loopback::LoopbackNetwork, TransportLayer},
+    EndpointBuilder, Result,
+};
+use rsip::prelude::{HeadersExt, UntypedHeader};
+use std::{sync::Arc, time::Duration};
+use tokio::{select, time::sleep};
+use tokio_util::sync::CancellationToken;
+
+#[tokio::test]
+async fn test_loopback_transaction() -> Result<()> {
+    let network = LoopbackNetwork::new();
+    let endpoint = |addr: &str| {
+        let token = CancellationToken::new();
+        EndpointBuilder::new()
+            .user_agent("rsipstack-test")
+            .transport_layer(TransportLayer::new(token.child_token()))
+            .cancel_token(token)
+            .transport(Arc::new(network.bind(addr.parse().unwrap())))
+            .build()
+    };
+    let alice = endpoint("192.0.2.1:5060");
+    let bob = endpoint("192.0.2.2:5060");
+
+    let bob_loop = async {
+        let mut incoming = bob.incoming_transactions();
+        let mut tx = incoming.recv().await.expect("incoming");
+        tx.reply(rsip::StatusCode::OK).await.expect("reply");
+        tx.original.clone()
+    };
+    let alice_loop = async {
+        let mut tx = alice
+            .request_builder(
+                rsip::Method::Options,
+                rsip::Uri::try_from("sip:bob@192.0.2.2:5060")?,
+            )
+            .send()
+            .await?;
+        while let Some(msg) = tx.receive().await {
+            if let rsip::SipMessage::Response(resp) = msg {
+                return Result::Ok(resp);
+            }
+        }
+        panic!("must not reach here");
+    };
+
+    let (req, resp) = select! {
+        r = async { tokio::join!(bob_loop, alice_loop) } => r,
+        _ = alice.serve() => panic!("must not reach here"),
+        _ = bob.serve() => panic!("must not reach here"),
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+    assert_eq!(resp?.status_code, rsip::StatusCode::OK);
+    assert_eq!(req.method, rsip::Method::Options);
+    assert!(req
+        .via_header()?
+        .value()
+        .starts_with("SIP/2.0/UDP 192.0.2.1:5060;"));
+
+    // nothing is bound at the target
+    let lost = alice
+        .request_builder(
+            rsip::Method::Options,
+            rsip::Uri::try_from("sip:carol@192.0.2.3:5060")?,
+        )
+        .send()
+        .await;
+    assert!(lost.is_err());
+    Ok(())
+}
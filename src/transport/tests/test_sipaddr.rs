@@ -1,3 +1,4 @@
+use crate::rsip_ext::parse_via;
 use crate::transport::{SipAddr, SipConnection};
 use rsip::{headers::*, prelude::HeadersExt, HostWithPort, SipMessage};
 
@@ -41,6 +42,16 @@ fn test_via_received() {
         }
         _ => {}
     }
+
+    // the Via address is right but rport was asked for, it must still be filled
+    let mut via: rsip::headers::Via =
+        Via::new("SIP/2.0/UDP 127.0.0.1:1234;rport;branch=z9hG4bKnashd92");
+    SipConnection::build_via_received(&mut via, "127.0.0.1:1234".parse().unwrap())
+        .expect("build_via_received");
+    assert_eq!(
+        via.value(),
+        "SIP/2.0/UDP 127.0.0.1:1234;branch=z9hG4bKnashd92;received=127.0.0.1;rport=1234"
+    );
 }
 
 #[test]
@@ -57,3 +68,36 @@ fn test_sipaddr() {
         }
     );
 }
+
+#[test]
+fn test_ipv6_via() {
+    // a local IPv6 address is written in brackets
+    let local = SipAddr::new(
+        rsip::transport::Transport::Udp,
+        "[2001:db8::1]:5060"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into(),
+    );
+    let sent_by: HostWithPort = local.clone().into();
+    assert_eq!(sent_by.to_string(), "[2001:db8::1]:5060");
+    let uri: rsip::Uri = local.into();
+    assert_eq!(uri.to_string(), "sip:[2001:db8::1]:5060");
+
+    let mut via = Via::new("SIP/2.0/UDP [2001:db8::1]:5060;rport;branch=z9hG4bKv6");
+    let typed = parse_via(&via).expect("parse_via");
+    assert_eq!(typed.uri.host_with_port, sent_by);
+    assert_eq!(typed.to_string(), via.value());
+
+    SipConnection::build_via_received(&mut via, "[2001:db8::2]:5062".parse().unwrap())
+        .expect("build_via_received");
+    assert_eq!(
+        via.value(),
+        "SIP/2.0/UDP [2001:db8::1]:5060;branch=z9hG4bKv6;received=2001:db8::2;rport=5062"
+    );
+    let target = SipConnection::parse_target_from_via(&via).expect("parse_target_from_via");
+    assert_eq!(
+        SipAddr::from(target).get_socketaddr().expect("socket addr"),
+        "[2001:db8::2]:5062".parse().unwrap()
+    );
+}
@@ -1,7 +1,16 @@
 use crate::{
+    error::{set_parse_error_raw_limit, TransportErrorKind, DEFAULT_PARSE_ERROR_RAW_LIMIT},
+    transaction::{
+        clock::MockClock,
+        key::{TransactionKey, TransactionRole},
+    },
     transport::{
-        connection::TransportEvent, stream::StreamConnection, tcp::TcpConnection,
-        transport_layer::TransportConfig, TransportLayer,
+        connection::{TransportEvent, KEEPALIVE_REQUEST, KEEPALIVE_RESPONSE},
+        keepalive::KeepaliveConfig,
+        stream::{SipCodec, StreamConnection},
+        tcp::TcpConnection,
+        transport_layer::TransportConfig,
+        SipConnection, TransportLayer,
     },
     Result,
 };
@@ -9,16 +18,20 @@ use crate::{
 #[cfg(feature = "rustls")]
 use crate::transport::tls::{TlsConfig, TlsConnection};
 
-use rsip::{SipMessage, Transport};
-use std::time::Duration;
+use bytes::BytesMut;
+use rsip::{
+    prelude::{HeadersExt, ToTypedHeader, UntypedHeader},
+    SipMessage, Transport,
+};
+use std::{sync::Arc, time::Duration};
 use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
     sync::mpsc::{self, UnboundedReceiver},
     time::timeout,
 };
-use tokio_util::sync::CancellationToken;
+use tokio_util::{codec::Decoder, sync::CancellationToken};
 use tracing::info;
 
-
 /// Test TCP client and server
 #[tokio::test]
 async fn test_tcp_client_server() -> Result<()> {
@@ -64,6 +77,7 @@ async fn test_tcp_client_server() -> Result<()> {
             assert_eq!(msg.to_string(), sip_message.to_string());
             assert_eq!(addr.r#type, Some(Transport::Tcp));
         }
+        TransportEvent::TooLarge(_, _, _) => panic!("message too large"),
         TransportEvent::Closed(_conn) => {
             info!("Connection closed by the server");
         }
@@ -79,6 +93,539 @@ async fn test_tcp_client_server() -> Result<()> {
     Ok(())
 }
 
+/// A body split over several reads is framed by Content-Length
+#[tokio::test]
+async fn test_tcp_content_length_framing() -> Result<()> {
+    let cancel_token = CancellationToken::new();
+    let transport_layer = TransportLayer::new(cancel_token.clone());
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let server_addr = transport_layer
+        .add_tcp_listener("127.0.0.1:0".parse()?, sender.clone())
+        .await?;
+    let client_connection = TcpConnection::connect(&server_addr).await?;
+
+    let body = "v=0\r\no=- 0 0 IN IP4 127.0.0.1\r\ns=-\r\n";
+    let head = format!(
+        "MESSAGE sip:bob@example.com SIP/2.0\r\n\
+         Via: SIP/2.0/TCP 127.0.0.1:5060;branch=z9hG4bK-framing\r\n\
+         From: <sip:alice@example.com>;tag=test\r\n\
+         To: <sip:bob@example.com>\r\n\
+         Call-ID: framing-call-id\r\n\
+         CSeq: 1 MESSAGE\r\n\
+         Max-Forwards: 70\r\n\
+         Content-Type: application/sdp\r\n\
+         Content-Length: {}\r\n\r\n",
+        body.len()
+    );
+    client_connection.send_raw(head.as_bytes()).await?;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    client_connection.send_raw(&body.as_bytes()[..10]).await?;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    // the rest of the body together with a keepalive
+    let mut rest = body.as_bytes()[10..].to_vec();
+    rest.extend_from_slice(b"\r\n\r\n");
+    client_connection.send_raw(&rest).await?;
+
+    loop {
+        match wait_for_event(&mut receiver).await? {
+            TransportEvent::Incoming(msg, _, _) => {
+                assert_eq!(msg.body(), body.as_bytes());
+                break;
+            }
+            _ => continue,
+        }
+    }
+    cancel_token.cancel();
+    Ok(())
+}
+
+/// A malformed message is returned with its bytes and skipped
+#[test]
+fn test_codec_parse_error() {
+    let garbage = "NOT A SIP MESSAGE\r\nFoo bar\r\n\r\n";
+    let valid = "OPTIONS sip:bob@example.com SIP/2.0\r\n\
+         Via: SIP/2.0/TCP 127.0.0.1:5060;branch=z9hG4bK-parse\r\n\
+         From: <sip:alice@example.com>;tag=test\r\n\
+         To: <sip:bob@example.com>\r\n\
+         Call-ID: parse-call-id\r\n\
+         CSeq: 1 OPTIONS\r\n\
+         Content-Length: 0\r\n\r\n";
+    let mut codec = SipCodec::new();
+    let mut buffer = BytesMut::from(format!("{}{}", garbage, valid).as_bytes());
+    match codec.decode(&mut buffer) {
+        Err(crate::Error::Parse { raw, .. }) => assert_eq!(raw, garbage.as_bytes()),
+        _ => panic!("expected a parse error"),
+    }
+    assert!(matches!(
+        codec.decode(&mut buffer),
+        Ok(Some(SipMessage::Request(_)))
+    ));
+
+    set_parse_error_raw_limit(8);
+    let mut buffer = BytesMut::from(garbage.as_bytes());
+    let e = codec.decode(&mut buffer);
+    set_parse_error_raw_limit(DEFAULT_PARSE_ERROR_RAW_LIMIT);
+    match e {
+        Err(e @ crate::Error::Parse { .. }) => {
+            assert!(e.to_string().ends_with(": \"NOT A SI\""), "{}", e);
+        }
+        _ => panic!("expected a parse error"),
+    }
+}
+
+/// A header block larger than the limit without its blank line is dropped,
+/// the next message is decoded
+#[test]
+fn test_codec_oversized_headers() {
+    let valid = "OPTIONS sip:bob@example.com SIP/2.0\r\n\
+         Via: SIP/2.0/TCP 127.0.0.1:5060;branch=z9hG4bK-oversized\r\n\
+         From: <sip:alice@example.com>;tag=test\r\n\
+         To: <sip:bob@example.com>\r\n\
+         Call-ID: oversized-call-id\r\n\
+         CSeq: 1 OPTIONS\r\n\
+         Content-Length: 0\r\n\r\n";
+    let mut codec = SipCodec::new();
+    let mut buffer = BytesMut::from(format!("X-Filler: {}\r\n", "a".repeat(64 * 1024)).as_bytes());
+    assert!(matches!(
+        codec.decode(&mut buffer),
+        Err(crate::Error::MessageTooLarge)
+    ));
+    assert!(buffer.is_empty());
+    assert!(matches!(codec.decode(&mut buffer), Ok(None)));
+
+    buffer.extend_from_slice(valid.as_bytes());
+    assert!(matches!(
+        codec.decode(&mut buffer),
+        Ok(Some(SipMessage::Request(_)))
+    ));
+}
+
+/// A message over the size limit closes the stream, the bytes after it are
+/// never read as a message
+#[tokio::test]
+async fn test_tcp_oversized_message_closes() -> Result<()> {
+    let cancel_token = CancellationToken::new();
+    let transport_layer = TransportLayer::new(cancel_token.clone());
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let server_addr = transport_layer
+        .add_tcp_listener("127.0.0.1:0".parse()?, sender.clone())
+        .await?;
+    let mut client = tokio::net::TcpStream::connect(server_addr.get_socketaddr()?).await?;
+
+    let message = |call_id: &str, body: &str| {
+        format!(
+            "MESSAGE sip:bob@example.com SIP/2.0\r\n\
+             Via: SIP/2.0/TCP 127.0.0.1:5060;branch=z9hG4bK-{call_id}\r\n\
+             From: <sip:alice@example.com>;tag=test\r\n\
+             To: <sip:bob@example.com>\r\n\
+             Call-ID: {call_id}\r\n\
+             CSeq: 1 MESSAGE\r\n\
+             Content-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    };
+    // the body of the oversized one is a message itself
+    let valid = message("valid", "");
+    let oversized = message("oversized", &format!("{}{}", valid, "a".repeat(64 * 1024)));
+    client.write_all(oversized.as_bytes()).await.ok();
+    client.write_all(valid.as_bytes()).await.ok();
+
+    let mut buf = [0u8; 64];
+    let closed = timeout(Duration::from_secs(1), async {
+        while client.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+    })
+    .await;
+    assert!(closed.is_ok(), "connection not closed");
+    while let Ok(event) = receiver.try_recv() {
+        assert!(!matches!(event, TransportEvent::Incoming(_, _, _)));
+    }
+    cancel_token.cancel();
+    Ok(())
+}
+
+/// A limit raised above the UDP datagram size lets larger messages through
+/// on TCP
+#[tokio::test]
+async fn test_tcp_max_message_size() -> Result<()> {
+    let cancel_token = CancellationToken::new();
+    let transport_layer = TransportLayer::new(cancel_token.clone());
+    transport_layer.set_max_message_size(128 * 1024);
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let server_addr = transport_layer
+        .add_tcp_listener("127.0.0.1:0".parse()?, sender.clone())
+        .await?;
+    let client = TcpConnection::connect(&server_addr).await?;
+
+    let body = "a".repeat(100 * 1024);
+    let message = format!(
+        "MESSAGE sip:bob@example.com SIP/2.0\r\n\
+         Via: SIP/2.0/TCP 127.0.0.1:5060;branch=z9hG4bK-large\r\n\
+         From: <sip:alice@example.com>;tag=test\r\n\
+         To: <sip:bob@example.com>\r\n\
+         Call-ID: large\r\n\
+         CSeq: 1 MESSAGE\r\n\
+         Content-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    client.send_raw(message.as_bytes()).await?;
+    loop {
+        if let TransportEvent::Incoming(SipMessage::Request(req), _, _) =
+            wait_for_event(&mut receiver).await?
+        {
+            assert_eq!(req.body.len(), body.len());
+            break;
+        }
+    }
+    cancel_token.cancel();
+    Ok(())
+}
+
+/// Compact names are expanded and a folded Via line split into its hops
+#[test]
+fn test_codec_compact_folded_vias() {
+    let folded = "INVITE sip:bob@example.com SIP/2.0\r\n\
+         v: SIP/2.0/UDP 192.0.2.10:5060;branch=z9hG4bK-proxy, SIP/2.0/UDP 192.0.2.1:5060;branch=z9hG4bK-alice\r\n\
+         Via: SIP/2.0/UDP 192.0.2.0:5060;branch=z9hG4bK-origin\r\n\
+         f: \"Alice, A.\" <sip:alice@example.com>;tag=a1\r\n\
+         t: <sip:bob@example.com>\r\n\
+         i: folded-call-id\r\n\
+         CSeq: 1 INVITE\r\n\
+         m: <sip:alice@192.0.2.1:5060>\r\n\
+         l: 0\r\n\r\n";
+    let mut codec = SipCodec::new();
+    let mut buffer = BytesMut::from(folded.as_bytes());
+    let msg = codec.decode(&mut buffer).unwrap().unwrap();
+    let msg = SipConnection::update_msg_received(msg, "192.0.2.10:5060".parse().unwrap()).unwrap();
+    let req = match msg {
+        SipMessage::Request(req) => req,
+        _ => panic!("expected a request"),
+    };
+    let branches = req
+        .headers
+        .iter()
+        .filter_map(|h| match h {
+            rsip::Header::Via(via) => via.typed().ok(),
+            _ => None,
+        })
+        .map(|via| via.branch().unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        branches,
+        vec!["z9hG4bK-proxy", "z9hG4bK-alice", "z9hG4bK-origin"]
+    );
+    assert_eq!(req.call_id_header().unwrap().value(), "folded-call-id");
+    assert_eq!(
+        req.from_header().unwrap().tag().unwrap().unwrap().value(),
+        "a1"
+    );
+    assert!(req.to_header().is_ok() && req.contact_header().is_ok());
+    let key = TransactionKey::from_request(&req, TransactionRole::Server).unwrap();
+    assert!(key.to_string().contains("z9hG4bK-proxy"), "{}", key);
+}
+
+#[tokio::test]
+async fn test_tcp_connect_error() -> Result<()> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    drop(listener);
+    let target = crate::transport::SipAddr {
+        r#type: Some(Transport::Tcp),
+        addr: addr.into(),
+    };
+    match TcpConnection::connect(&target).await {
+        Err(crate::Error::Transport { kind, addr, .. }) => {
+            assert_eq!(kind, TransportErrorKind::ConnectionRefused);
+            assert!(kind.is_retriable());
+            assert_eq!(addr, target);
+        }
+        r => panic!(
+            "expected transport error, got {:?}",
+            r.map(|c| c.to_string())
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "rustls")]
+fn tls_test_config() -> TlsConfig {
+    TlsConfig {
+        cert: Some(include_bytes!("certs/server.pem").to_vec()),
+        key: Some(include_bytes!("certs/server.key").to_vec()),
+        roots: Some(include_bytes!("certs/ca.pem").to_vec()),
+        ..Default::default()
+    }
+}
+
+/// Test TLS client and server with a private root store
+#[cfg(feature = "rustls")]
+#[tokio::test]
+async fn test_tls_client_server() -> Result<()> {
+    let cancel_token = CancellationToken::new();
+    let config = TransportConfig {
+        tls: Some(tls_test_config()),
+        ..Default::default()
+    };
+    let transport_layer = TransportLayer::with_config(cancel_token.clone(), config);
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+
+    let server_addr = transport_layer
+        .add_tls_listener("127.0.0.1:0".parse()?, sender.clone())
+        .await?;
+    assert_eq!(server_addr.r#type, Some(Transport::Tls));
+
+    let client_connection =
+        TlsConnection::connect(&server_addr, Some("localhost"), Some(&tls_test_config())).await?;
+    assert_eq!(client_connection.get_addr().r#type, Some(Transport::Tls));
+
+    let test_message = "OPTIONS sip:bob@localhost SIP/2.0\r\n\
+                        Via: SIP/2.0/TLS 127.0.0.1:5061;branch=z9hG4bK-tls\r\n\
+                        From: <sips:alice@localhost>;tag=test\r\n\
+                        To: <sips:bob@localhost>\r\n\
+                        Call-ID: tls-call-id\r\n\
+                        CSeq: 1 OPTIONS\r\n\
+                        Max-Forwards: 70\r\n\
+                        Content-Length: 0\r\n\r\n";
+    let sip_message = SipMessage::try_from(test_message)?;
+    client_connection.send_message(sip_message.clone()).await?;
+
+    loop {
+        match wait_for_event(&mut receiver).await? {
+            TransportEvent::Incoming(msg, _, addr) => {
+                // the Via is stamped with the source of the connection
+                let expected = SipConnection::update_msg_received(
+                    sip_message.clone(),
+                    client_connection.get_addr().get_socketaddr()?,
+                )?;
+                assert_eq!(msg.to_string(), expected.to_string());
+                assert_eq!(addr.r#type, Some(Transport::Tls));
+                break;
+            }
+            _ => continue,
+        }
+    }
+    client_connection.close().await?;
+    cancel_token.cancel();
+    Ok(())
+}
+
+/// A server certificate that is not trusted is a certificate error
+#[cfg(feature = "rustls")]
+#[tokio::test]
+async fn test_tls_untrusted_certificate() -> Result<()> {
+    let cancel_token = CancellationToken::new();
+    let config = TransportConfig {
+        tls: Some(tls_test_config()),
+        ..Default::default()
+    };
+    let transport_layer = TransportLayer::with_config(cancel_token.clone(), config);
+    let (sender, _receiver) = mpsc::unbounded_channel();
+    let server_addr = transport_layer
+        .add_tls_listener("127.0.0.1:0".parse()?, sender)
+        .await?;
+
+    // the webpki roots do not know the test CA
+    match TlsConnection::connect(&server_addr, Some("localhost"), None).await {
+        Err(crate::Error::Transport {
+            kind: TransportErrorKind::TlsCertificate,
+            addr,
+            ..
+        }) => assert_eq!(addr, server_addr),
+        r => panic!(
+            "expected certificate error, got {:?}",
+            r.map(|c| c.to_string())
+        ),
+    }
+
+    // the name does not match the certificate
+    let client_config = TlsConfig {
+        sni_override: Some("example.com".to_string()),
+        ..tls_test_config()
+    };
+    match TlsConnection::connect(&server_addr, Some("localhost"), Some(&client_config)).await {
+        Err(crate::Error::Transport {
+            kind: TransportErrorKind::TlsCertificate,
+            ..
+        }) => {}
+        r => panic!(
+            "expected certificate error, got {:?}",
+            r.map(|c| c.to_string())
+        ),
+    }
+    cancel_token.cancel();
+    Ok(())
+}
+
+/// Test CRLF keepalive of an outgoing TCP connection
+#[tokio::test]
+async fn test_tcp_keepalive() -> Result<()> {
+    let cancel_token = CancellationToken::new();
+    let config = TransportConfig {
+        keepalive: Some(KeepaliveConfig {
+            interval: Duration::from_millis(20),
+            max_misses: 2,
+        }),
+        ..Default::default()
+    };
+    let transport_layer = TransportLayer::with_config(cancel_token.clone(), config);
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    transport_layer.serve_listens(sender).await?;
+
+    // a server answering the first 3 pings only
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.expect("accept");
+        let mut pings = 0;
+        let mut buf = [0u8; 64];
+        loop {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    for ping in buf[..n].chunks(KEEPALIVE_REQUEST.len()) {
+                        assert_eq!(ping, KEEPALIVE_REQUEST);
+                        pings += 1;
+                        if pings <= 3 {
+                            stream.write_all(KEEPALIVE_RESPONSE).await.expect("pong");
+                        }
+                    }
+                }
+            }
+        }
+        pings
+    });
+
+    let uri: rsip::Uri = format!("sip:127.0.0.1:{};transport=tcp", port).try_into()?;
+    transport_layer.lookup(&uri).await?;
+    loop {
+        if let TransportEvent::Closed(connection) = wait_for_event(&mut receiver).await? {
+            assert_eq!(connection.get_addr().r#type, Some(Transport::Tcp));
+            break;
+        }
+    }
+
+    // closed after two pings without a pong
+    let pings = timeout(Duration::from_secs(1), server)
+        .await
+        .expect("server closed")
+        .expect("server task");
+    assert_eq!(pings, 5);
+    cancel_token.cancel();
+    Ok(())
+}
+
+/// Outgoing TCP connections are pooled per target until idle or evicted
+#[tokio::test]
+async fn test_tcp_connection_pool() -> Result<()> {
+    let cancel_token = CancellationToken::new();
+    let config = TransportConfig {
+        idle_timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let transport_layer = TransportLayer::with_config(cancel_token.clone(), config);
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    transport_layer.serve_listens(sender).await?;
+
+    // a server counting its connections, each read until closed
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let (accepted_sender, mut accepted) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            accepted_sender.send(()).ok();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 64];
+                while stream.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+            });
+        }
+    });
+
+    let uri: rsip::Uri = format!("sip:127.0.0.1:{};transport=tcp", port).try_into()?;
+    let (first, target) = transport_layer.lookup_destination(&uri).await?;
+    timeout(Duration::from_secs(1), accepted.recv())
+        .await
+        .expect("first connection");
+    let second = transport_layer.lookup(&uri).await?;
+    assert!(first.same_connection(&second));
+
+    // closed without a message for the idle timeout, the next lookup reconnects
+    loop {
+        if let TransportEvent::Closed(connection) = wait_for_event(&mut receiver).await? {
+            assert!(connection.same_connection(&first));
+            break;
+        }
+    }
+    let third = transport_layer.lookup(&uri).await?;
+    timeout(Duration::from_secs(1), accepted.recv())
+        .await
+        .expect("connection after idle");
+    assert!(!third.same_connection(&first));
+
+    // evicting a connection already replaced keeps the new one
+    transport_layer.evict_connection(&target, &first).await;
+    assert!(transport_layer.lookup(&uri).await?.same_connection(&third));
+
+    // an evicted connection is closed and not reused
+    transport_layer.evict_connection(&target, &third).await;
+    loop {
+        if let TransportEvent::Closed(connection) = wait_for_event(&mut receiver).await? {
+            assert!(connection.same_connection(&third));
+            break;
+        }
+    }
+    let fourth = transport_layer.lookup(&uri).await?;
+    timeout(Duration::from_secs(1), accepted.recv())
+        .await
+        .expect("connection after eviction");
+    assert!(!fourth.same_connection(&third));
+    cancel_token.cancel();
+    Ok(())
+}
+
+/// The idle timeout of the outgoing connections runs on the clock of the
+/// transport layer
+#[tokio::test]
+async fn test_tcp_idle_mock_clock() -> Result<()> {
+    let cancel_token = CancellationToken::new();
+    let config = TransportConfig {
+        idle_timeout: Some(Duration::from_secs(60)),
+        ..Default::default()
+    };
+    let transport_layer = TransportLayer::with_config(cancel_token.clone(), config);
+    let clock = Arc::new(MockClock::new());
+    transport_layer.set_clock(clock.clone());
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    transport_layer.serve_listens(sender).await?;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 64];
+                while stream.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+            });
+        }
+    });
+
+    let uri: rsip::Uri = format!("sip:127.0.0.1:{};transport=tcp", port).try_into()?;
+    let first = transport_layer.lookup(&uri).await?;
+    clock.advance(Duration::from_secs(59));
+    assert!(first.same_connection(&transport_layer.lookup(&uri).await?));
+    while let Ok(event) = receiver.try_recv() {
+        assert!(!matches!(event, TransportEvent::Closed(_)));
+    }
+    clock.advance(Duration::from_secs(1));
+    loop {
+        if let TransportEvent::Closed(connection) = wait_for_event(&mut receiver).await? {
+            assert!(connection.same_connection(&first));
+            break;
+        }
+    }
+    cancel_token.cancel();
+    Ok(())
+}
+
 /// Wait for event with timeout
 async fn wait_for_event(
     receiver: &mut UnboundedReceiver<TransportEvent>,
@@ -155,7 +702,6 @@ async fn test_tcp_udp_interop() -> Result<()> {
 /// Test WebSocket functionality
 #[cfg(feature = "websocket")]
 #[tokio::test]
-#[ignore] // Requires external WebSocket server to run
 async fn test_websocket() -> Result<()> {
     // Create transport layer
     let cancel_token = CancellationToken::new();
@@ -199,14 +745,32 @@ async fn test_websocket() -> Result<()> {
     connection.send(sip_message.clone(), None).await?;
 
     // Wait for message
-    let event = wait_for_event(&mut receiver).await?;
-    match event {
-        TransportEvent::Incoming(msg, _, _) => {
-            assert_eq!(msg.to_string(), sip_message.to_string());
+    loop {
+        match wait_for_event(&mut receiver).await? {
+            TransportEvent::Incoming(msg, _, addr) => {
+                assert_eq!(addr.r#type, Some(Transport::Ws));
+                // the ephemeral client port is recorded in the Via
+                let via = msg.via_header()?.typed()?;
+                assert_eq!(
+                    via.received()?.map(|r| r.to_string()),
+                    Some("127.0.0.1".to_string())
+                );
+                assert!(via.params.iter().any(|p| matches!(
+                    p,
+                    rsip::Param::Other(k, Some(v)) if k.value() == "rport"
+                        && v.value() == addr.addr.port.unwrap().to_string()
+                )));
+                assert_eq!(msg.body(), sip_message.body());
+                break;
+            }
+            _ => continue,
         }
-        _ => panic!("Expected Incoming event"),
     }
 
+    // upgrades without the sip subprotocol are refused
+    let url = format!("ws://127.0.0.1:{}/sip", ws_addr.addr.port.unwrap().value());
+    assert!(tokio_tungstenite::connect_async(url).await.is_err());
+
     cancel_token.cancel();
 
     Ok(())
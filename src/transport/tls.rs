@@ -1,14 +1,24 @@
 use super::{
-    connection::{TransportSender, KEEPALIVE_REQUEST, KEEPALIVE_RESPONSE},
+    connection::{TransportSender, MAX_MESSAGE_SIZE},
+    keepalive::KeepaliveState,
     sip_addr::SipAddr,
-    stream::{send_raw_to_stream, send_to_stream, StreamConnection}, SipConnection, TransportEvent
+    stream::{send_raw_to_stream, send_to_stream, serve_stream, StreamConnection},
+    SipConnection, TransportEvent,
+};
+use crate::{
+    error::{Error, TransportErrorKind},
+    Result,
 };
-use crate::{error::Error, Result};
 use rsip::SipMessage;
-use std::{fmt, net::SocketAddr, sync::Arc};
-use rustls::client::danger::ServerCertVerifier;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 use tokio::{
-    io::{AsyncWriteExt, AsyncReadExt},
+    io::AsyncWriteExt,
     net::{TcpListener, TcpStream},
     sync::Mutex,
 };
@@ -16,426 +26,307 @@ use tokio_rustls::{
     rustls::{pki_types, ClientConfig, RootCertStore, ServerConfig},
     TlsAcceptor, TlsConnector,
 };
-use tracing::{error, info};
+use tracing::{debug, error, info};
 
 // TLS configuration
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct TlsConfig {
     // Server certificate in PEM format
     pub cert: Option<Vec<u8>>,
     // Server private key in PEM format
     pub key: Option<Vec<u8>>,
-    // Client certificate in PEM format
+    // Root CA certificates in PEM format to validate servers with,
+    // the webpki roots are used when not set
+    pub roots: Option<Vec<u8>>,
+    // Client certificate in PEM format, for mutual TLS
     pub client_cert: Option<Vec<u8>>,
-    // Client private key in PEM format
+    // Client private key in PEM format, for mutual TLS
     pub client_key: Option<Vec<u8>>,
-    // Root CA certificates in PEM format
-    pub ca_certs: Option<Vec<u8>>,
+    // Server name to send and validate instead of the target host
+    pub sni_override: Option<String>,
 }
 
-impl Default for TlsConfig {
-    fn default() -> Self {
-        Self {
-            cert: None,
-            key: None,
-            client_cert: None,
-            client_key: None,
-            ca_certs: None,
-        }
-    }
-}
+type TlsStream = tokio_rustls::TlsStream<TcpStream>;
 
-// Define a type alias for the TLS stream to make the code more readable
-type TlsClientStream = tokio_rustls::client::TlsStream<TcpStream>;
+pub struct TlsInner {
+    pub local_addr: SipAddr,
+    pub remote_addr: SipAddr,
+    pub read_half: Arc<Mutex<tokio::io::ReadHalf<TlsStream>>>,
+    pub write_half: Arc<Mutex<tokio::io::WriteHalf<TlsStream>>>,
+    pub keepalive: KeepaliveState,
+    pub max_message_size: AtomicUsize,
+}
 
 // TLS connection
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct TlsConnection {
-    remote_addr: SipAddr,
-    read_half: Arc<Mutex<Option<tokio::io::ReadHalf<TlsClientStream>>>>,
-    write_half: Arc<Mutex<Option<tokio::io::WriteHalf<TlsClientStream>>>>,
+    pub inner: Arc<TlsInner>,
 }
 
-impl TlsConnection {
-    // Create a new TLS connection with an acceptor
-    pub fn new_with_acceptor(_acceptor: TlsAcceptor, addr: SipAddr) -> Self {
-        let read_half = Arc::new(Mutex::new(None));
-        let write_half = Arc::new(Mutex::new(None));
-
-        Self {
-            remote_addr: addr,
-            read_half,
-            write_half,
-        }
-    }
-
-    // Connect to a remote TLS server
-    pub async fn connect(remote_addr: &SipAddr, custom_verifier: Option<Arc<dyn ServerCertVerifier>>) -> Result<Self> {
-        // Create TLS configuration
-        let root_store = RootCertStore::empty();
+fn load_certs(pem: &[u8]) -> Result<Vec<pki_types::CertificateDer<'static>>> {
+    let mut reader = std::io::BufReader::new(pem);
+    rustls_pemfile::certs(&mut reader)
+        .collect::<std::result::Result<Vec<_>, std::io::Error>>()
+        .map_err(|e| Error::Error(format!("Failed to parse certificate: {}", e)))
+}
 
-        // Use webpki-roots to add trusted root certificates
-        // Create client configuration
-        let mut config = ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
+fn load_private_key(pem: &[u8]) -> Result<pki_types::PrivateKeyDer<'static>> {
+    let mut reader = std::io::BufReader::new(pem);
+    rustls_pemfile::private_key(&mut reader)
+        .map_err(|e| Error::Error(format!("Failed to parse private key: {}", e)))?
+        .ok_or(Error::Error("No valid private key found".to_string()))
+}
 
-        match custom_verifier {
-            Some(verifier) => {
-                config.dangerous().set_certificate_verifier(verifier);
+impl TlsConnection {
+    // Create the client configuration: the root store to validate the server
+    // and the optional client certificate
+    pub fn create_client_config(config: &TlsConfig) -> Result<ClientConfig> {
+        let mut root_store = RootCertStore::empty();
+        match &config.roots {
+            Some(roots) => {
+                for cert in load_certs(roots)? {
+                    root_store
+                        .add(cert)
+                        .map_err(|e| Error::Error(format!("Invalid root certificate: {}", e)))?;
+                }
             }
-            None => {}
+            None => root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
         }
-        // Create TLS connector
-        let connector = TlsConnector::from(Arc::new(config));
-
-        // Connect to remote server
-        let socket_addr = match &remote_addr.addr.host {
-            rsip::host_with_port::Host::Domain(domain) => {
-                let port = remote_addr.addr.port.as_ref().map_or(5061, |p| *p.value());
-                format!("{}:{}", domain.to_string(), port).parse()?
-            }
-            rsip::host_with_port::Host::IpAddr(ip) => {
-                let port = remote_addr.addr.port.as_ref().map_or(5061, |p| *p.value());
-                SocketAddr::new(*ip, port)
-            }
-        };
 
-        let domain_string = match &remote_addr.addr.host {
-            rsip::host_with_port::Host::Domain(domain) => domain.to_string(),
-            rsip::host_with_port::Host::IpAddr(ip) => ip.to_string(),
+        let builder = ClientConfig::builder().with_root_certificates(root_store);
+        let client_config = match (&config.client_cert, &config.client_key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(load_certs(cert)?, load_private_key(key)?)
+                .map_err(|e| Error::Error(format!("TLS client certificate error: {}", e)))?,
+            _ => builder.with_no_client_auth(),
         };
+        Ok(client_config)
+    }
 
-        let server_name = pki_types::ServerName::try_from(domain_string.as_str())
-            .map_err(|_| Error::Error(format!("Invalid DNS name: {}", domain_string)))?
-            .to_owned();
-
-        let stream = TcpStream::connect(socket_addr).await?;
-
-        // Perform TLS handshake
-        let tls_stream = connector.connect(server_name, stream).await?;
-
-        // Split stream into read and write halves
-        let (read_half, write_half) = tokio::io::split(tls_stream);
-
-        // Create TLS connection
-        let connection = Self {
-            remote_addr: remote_addr.clone(),
-            read_half: Arc::new(Mutex::new(Some(read_half))),
-            write_half: Arc::new(Mutex::new(Some(write_half))),
+    // Connect to a remote TLS server, `server_name` is the host the target was resolved from
+    pub async fn connect(
+        remote_addr: &SipAddr,
+        server_name: Option<&str>,
+        config: Option<&TlsConfig>,
+    ) -> Result<Self> {
+        let default_config = TlsConfig::default();
+        let config = config.unwrap_or(&default_config);
+        let connector = TlsConnector::from(Arc::new(Self::create_client_config(config)?));
+
+        let domain_string = match (&config.sni_override, server_name) {
+            (Some(sni), _) => sni.clone(),
+            (None, Some(name)) => name.to_string(),
+            (None, None) => remote_addr.addr.host.to_string(),
+        };
+        let server_name = pki_types::ServerName::try_from(domain_string.clone()).map_err(|_| {
+            Error::Transport {
+                kind: TransportErrorKind::Other,
+                addr: remote_addr.clone(),
+                source: format!("Invalid DNS name: {}", domain_string),
+            }
+        })?;
+
+        let socket_addr = remote_addr.get_socketaddr()?;
+        let stream = TcpStream::connect(socket_addr)
+            .await
+            .map_err(|e| Error::transport(e, remote_addr))?;
+        let local_addr = SipAddr {
+            r#type: Some(rsip::transport::Transport::Tls),
+            addr: stream.local_addr()?.into(),
         };
 
+        let tls_stream = connector
+            .connect(server_name, stream)
+            .await
+            .map_err(|e| handshake_error(e, remote_addr))?;
+
+        let connection = Self::from_stream(tls_stream.into(), local_addr, remote_addr.clone());
+        info!(
+            "Created TLS client connection: {} -> {}",
+            connection.get_addr(),
+            remote_addr
+        );
         Ok(connection)
     }
 
-    // Create TLS connection from existing TLS stream
-    pub async fn from_stream(stream: TlsClientStream, remote_addr: SipAddr) -> Result<Self> {
-        // Split stream into read and write halves
+    // Create TLS connection from an established TLS stream
+    pub fn from_stream(stream: TlsStream, local_addr: SipAddr, remote_addr: SipAddr) -> Self {
         let (read_half, write_half) = tokio::io::split(stream);
-
-        // Create TLS connection
-        let connection = Self {
-            remote_addr,
-            read_half: Arc::new(Mutex::new(Some(read_half))),
-            write_half: Arc::new(Mutex::new(Some(write_half))),
-        };
-
-        Ok(connection)
+        Self {
+            inner: Arc::new(TlsInner {
+                local_addr,
+                remote_addr,
+                read_half: Arc::new(Mutex::new(read_half)),
+                write_half: Arc::new(Mutex::new(write_half)),
+                keepalive: KeepaliveState::default(),
+                max_message_size: AtomicUsize::new(MAX_MESSAGE_SIZE),
+            }),
+        }
     }
 
     // Create TLS acceptor from configuration
-    pub async fn create_acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
-        // Load certificates
+    pub fn create_acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
         let certs = match &config.cert {
-            Some(cert_data) => {
-                let mut reader = std::io::BufReader::new(cert_data.as_slice());
-                // Use rustls-pemfile to parse certificates
-                let certs = rustls_pemfile::certs(&mut reader)
-                    .collect::<std::result::Result<Vec<_>, std::io::Error>>()
-                    .map_err(|e| Error::Error(format!("Failed to parse certificate: {}", e)))?;
-                certs
-                    .into_iter()
-                    .map(pki_types::CertificateDer::from)
-                    .collect()
-            }
+            Some(cert_data) => load_certs(cert_data)?,
             None => return Err(Error::Error("No certificate provided".to_string())),
         };
-
-        // Load private key
         let key = match &config.key {
-            Some(key_data) => {
-                let mut reader = std::io::BufReader::new(key_data.as_slice());
-                // Try PKCS8 format first
-                let keys = rustls_pemfile::pkcs8_private_keys(&mut reader)
-                    .collect::<std::result::Result<Vec<_>, std::io::Error>>()
-                    .map_err(|e| Error::Error(format!("Failed to parse PKCS8 key: {}", e)))?;
-
-                if !keys.is_empty() {
-                    let key_der = pki_types::PrivatePkcs8KeyDer::from(keys[0].clone_key());
-                    pki_types::PrivateKeyDer::Pkcs8(key_der)
-                } else {
-                    // Try PKCS1 format
-                    let mut reader = std::io::BufReader::new(key_data.as_slice());
-                    let keys = rustls_pemfile::rsa_private_keys(&mut reader)
-                        .collect::<std::result::Result<Vec<_>, std::io::Error>>()
-                        .map_err(|e| Error::Error(format!("Failed to parse RSA key: {}", e)))?;
-
-                    if !keys.is_empty() {
-                        let key_der = pki_types::PrivatePkcs1KeyDer::from(keys[0].clone_key());
-                        pki_types::PrivateKeyDer::Pkcs1(key_der)
-                    } else {
-                        return Err(Error::Error("No valid private key found".to_string()));
-                    }
-                }
-            }
+            Some(key_data) => load_private_key(key_data)?,
             None => return Err(Error::Error("No private key provided".to_string())),
         };
 
-        // Create server configuration
         let server_config = ServerConfig::builder()
             .with_no_client_auth()
             .with_single_cert(certs, key)
             .map_err(|e| Error::Error(format!("TLS configuration error: {}", e)))?;
 
-        // Create TLS acceptor
-        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        Ok(TlsAcceptor::from(Arc::new(server_config)))
+    }
+
+    pub async fn create_listener(local: std::net::SocketAddr) -> Result<(TcpListener, SipAddr)> {
+        let listener = TcpListener::bind(local).await?;
+        let local_addr = listener.local_addr()?;
+
+        let sip_addr = SipAddr {
+            r#type: Some(rsip::transport::Transport::Tls),
+            addr: local_addr.into(),
+        };
 
-        Ok(acceptor)
+        info!("Created TLS listener on {}", local_addr);
+
+        Ok((listener, sip_addr))
     }
 
-    // Serve TLS listener
+    // Serve TLS listener, the accepted connections read messages up to
+    // `max_message_size` at the time they are accepted
     pub async fn serve_listener(
         listener: TcpListener,
-        config: &TlsConfig,
+        local_addr: SipAddr,
+        acceptor: TlsAcceptor,
         sender: TransportSender,
+        max_message_size: Arc<AtomicUsize>,
     ) -> Result<()> {
-        // Create TLS acceptor
-        let acceptor = Self::create_acceptor(config).await?;
+        info!("Starting TLS listener on {}", local_addr);
 
-        // Accept connections
         loop {
-            // Accept TCP connection
             let (stream, peer_addr) = match listener.accept().await {
                 Ok(conn) => conn,
                 Err(e) => {
-                    error!("Failed to accept connection: {}", e);
+                    error!("Error accepting TLS connection: {}", e);
                     continue;
                 }
             };
+            debug!("New TLS connection from {}", peer_addr);
 
-            // Clone acceptor and sender for this connection
             let acceptor = acceptor.clone();
+            let local_addr = local_addr.clone();
             let sender = sender.clone();
+            let max_message_size = max_message_size.load(Ordering::Relaxed);
 
-            // Handle connection in a separate task
+            // the handshake must not block the accept loop
             tokio::spawn(async move {
-                // Perform TLS handshake
                 let tls_stream = match acceptor.accept(stream).await {
                     Ok(stream) => stream,
                     Err(e) => {
-                        error!("TLS handshake failed: {}", e);
+                        info!("TLS handshake failed: {}: {}", peer_addr, e);
                         return;
                     }
                 };
 
-                // Create remote SIP address
-                let remote_sip_addr = SipAddr {
-                    r#type: Some(rsip::Transport::Tls),
+                let remote_addr = SipAddr {
+                    r#type: Some(rsip::transport::Transport::Tls),
                     addr: peer_addr.into(),
                 };
-
-                let (server_io, _server_session) = tls_stream.into_inner();
-
-                let local_addr = match server_io.local_addr() {
-                    Ok(addr) => addr,
-                    Err(e) => {
-                        error!("Failed to get local address: {}", e);
-                        return;
-                    }
-                };
-
-                let mut root_store = RootCertStore::empty();
-
-                // Use webpki-roots to add trusted root certificates
-                //root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
-
-                let client_config = ClientConfig::builder()
-                    .with_root_certificates(root_store)
-                    .with_no_client_auth();
-
-                let domain_string = match &remote_sip_addr.addr.host {
-                    rsip::host_with_port::Host::Domain(domain) => domain.to_string(),
-                    rsip::host_with_port::Host::IpAddr(ip) => ip.to_string(),
-                };
-
-                let server_name = match pki_types::ServerName::try_from(domain_string.as_str()) {
-                    Ok(name) => name.to_owned(),
-                    Err(_) => {
-                        error!("Invalid DNS name: {}", domain_string);
-                        return;
-                    }
-                };
-
-                let client_stream = match TcpStream::connect(local_addr).await {
-                    Ok(stream) => stream,
-                    Err(e) => {
-                        error!("Failed to create TCP connection: {}", e);
-                        return;
-                    }
-                };
-
-                let connector = TlsConnector::from(Arc::new(client_config));
-                let client_tls_stream = match connector.connect(server_name, client_stream).await {
-                    Ok(stream) => stream,
-                    Err(e) => {
-                        error!("Failed to create client TLS stream: {}", e);
-                        return;
-                    }
-                };
-
-                // Create TLS connection
                 let connection =
-                    match TlsConnection::from_stream(client_tls_stream, remote_sip_addr.clone())
-                        .await
-                    {
-                        Ok(conn) => conn,
-                        Err(e) => {
-                            error!("Failed to create TLS connection: {}", e);
-                            return;
-                        }
-                    };
-
-                // Convert to SIP connection
-                let sip_connection = super::connection::SipConnection::from(connection);
-
-                // Notify about new connection
-                if let Err(e) = sender.send(super::connection::TransportEvent::New(
-                    sip_connection.clone(),
-                )) {
-                    error!("Failed to send new connection event: {}", e);
+                    TlsConnection::from_stream(tls_stream.into(), local_addr, remote_addr);
+                connection
+                    .inner
+                    .max_message_size
+                    .store(max_message_size, Ordering::Relaxed);
+                let sip_connection = SipConnection::Tls(connection.clone());
+
+                if let Err(e) = sender.send(TransportEvent::New(sip_connection)) {
+                    error!("Error sending new connection event: {:?}", e);
                     return;
                 }
 
-                // Serve connection
-                if let Err(e) = sip_connection.serve_loop(sender).await {
-                    error!("Error serving TLS connection: {}", e);
+                if let Err(e) = connection.serve_loop(sender).await {
+                    error!("Error handling TLS connection: {:?}", e);
                 }
             });
         }
     }
 }
 
+/// Certificate validation failures are reported apart from other connect errors
+pub(super) fn handshake_error(e: std::io::Error, remote_addr: &SipAddr) -> Error {
+    match e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<tokio_rustls::rustls::Error>())
+    {
+        Some(tokio_rustls::rustls::Error::InvalidCertificate(reason)) => Error::Transport {
+            kind: TransportErrorKind::TlsCertificate,
+            addr: remote_addr.clone(),
+            source: format!("{:?}", reason),
+        },
+        _ => Error::Transport {
+            kind: TransportErrorKind::Handshake,
+            addr: remote_addr.clone(),
+            source: format!("tls handshake failed: {}", e),
+        },
+    }
+}
+
 // Implement StreamConnection trait for TlsConnection
 #[async_trait::async_trait]
 impl StreamConnection for TlsConnection {
     fn get_addr(&self) -> &SipAddr {
-        &self.remote_addr
+        &self.inner.local_addr
+    }
+
+    fn keepalive(&self) -> &KeepaliveState {
+        &self.inner.keepalive
+    }
+
+    fn max_message_size(&self) -> &AtomicUsize {
+        &self.inner.max_message_size
     }
 
     async fn send_message(&self, msg: SipMessage) -> Result<()> {
         info!("TlsConnection send:{}", msg);
-        let mut write_half_guard = self.write_half.lock().await;
-        if let Some(write_half) = &mut *write_half_guard {
-            let mut buf = Vec::new();
-            buf.extend_from_slice(msg.to_string().as_bytes());
-            write_half.write_all(&buf).await?;
-
-            Ok(())
-        } else {
-            Err(Error::Error("TLS connection not established".to_string()))
-        }
+        send_to_stream(&self.inner.write_half, msg, &self.inner.remote_addr).await
     }
 
     async fn send_raw(&self, data: &[u8]) -> Result<()> {
-        let mut write_half_guard = self.write_half.lock().await;
-        if let Some(write_half) = &mut *write_half_guard {
-            write_half.write_all(data).await?;
-
-            Ok(())
-        } else {
-            Err(Error::Error("TLS connection not established".to_string()))
-        }
+        send_raw_to_stream(&self.inner.write_half, data, &self.inner.remote_addr).await
     }
 
     async fn close(&self) -> Result<()> {
-        let mut write_half_guard = self.write_half.lock().await;
-        if let Some(write_half) = &mut *write_half_guard {
-            write_half.shutdown().await?;
-            *write_half_guard = None;
-        }
+        let mut write_half = self.inner.write_half.lock().await;
+        write_half.shutdown().await?;
         Ok(())
     }
 
     async fn serve_loop(&self, sender: TransportSender) -> Result<()> {
-        let mut buf = vec![0u8; 2048];
         let sip_connection = SipConnection::Tls(self.clone());
-        let remote_addr = self.remote_addr.clone();
-        let mut read_half_guard = self.read_half.lock().await;
-        loop {
-            let mut len = 0;
-            if let Some(read_half) = &mut *read_half_guard {
-                len = read_half.read(&mut buf).await?;
-                if len <= 0 {
-                    continue;
-                }
-            } else {
-                continue;
-            }
-
-            match &buf[..len] {
-                KEEPALIVE_REQUEST => {
-                    self.send_raw(KEEPALIVE_RESPONSE);
-                    continue;
-                }
-                KEEPALIVE_RESPONSE => continue,
-                _ => {
-                    if buf.iter().all(|&b| b.is_ascii_whitespace()) {
-                        continue;
-                    }
-                }
-            }
-
-            let undecoded = match std::str::from_utf8(&buf[..len]) {
-                Ok(s) => s,
-                Err(e) => {
-                    info!(
-                        "decoding text ferror: {} buf: {:?}",
-                        e,
-                        &buf[..len]
-                    );
-                    continue;
-                }
-            };
-
-            let sip_msg = match rsip::SipMessage::try_from(undecoded) {
-                Ok(msg) => msg,
-                Err(e) => {
-                    info!(
-                        "error parsing SIP message error: {} buf: {}",
-                        e, undecoded
-                    );
-                    continue;
-                }
-            };
-
-            if let Err(e) =
-                sender.send(TransportEvent::Incoming(
-                    sip_msg,
-                    sip_connection.clone(),
-                    remote_addr.clone(),
-                ))
-            {
-                error!("Error sending incoming message: {:?}", e);
-                break;
-            }
-        }
-        info!("TLS connection closed");
-        Ok(())
+        let remote_addr = self.inner.remote_addr.clone();
+        let mut read_half = self.inner.read_half.lock().await;
+        serve_stream(self, &mut *read_half, sip_connection, remote_addr, sender).await
     }
 }
 
 impl fmt::Display for TlsConnection {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "TLS {}", self.remote_addr)
+        write!(
+            f,
+            "TLS {} -> {}",
+            self.inner.local_addr, self.inner.remote_addr
+        )
+    }
+}
+
+impl fmt::Debug for TlsConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
     }
 }
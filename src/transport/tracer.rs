@@ -0,0 +1,172 @@
+use super::SipAddr;
+use rsip::SipMessage;
+use std::fmt;
+use tracing::{enabled, trace, Level};
+
+const REDACTED: &str = "<redacted>";
+
+/// The way a traced message went
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Incoming => write!(f, "recv"),
+            Direction::Outgoing => write!(f, "send"),
+        }
+    }
+}
+
+/// Logs each message an endpoint sends or receives in full, at TRACE level
+/// under the `rsipstack::wire` target, see
+/// [`EndpointBuilder::message_tracer`](crate::EndpointBuilder::message_tracer).
+///
+/// The outgoing messages are logged as written on the wire, the incoming ones
+/// as received, before the incoming hook runs.
+#[derive(Clone, Debug)]
+pub struct MessageTracer {
+    /// log the body after the headers, off by default
+    pub body: bool,
+    /// hide the digest response of the Authorization and Proxy-Authorization
+    /// headers, on by default
+    pub redact_credentials: bool,
+    /// hide the keys of the SDP crypto attributes (RFC 4568 9.1), on by default
+    pub redact_crypto: bool,
+}
+
+impl Default for MessageTracer {
+    fn default() -> Self {
+        Self {
+            body: false,
+            redact_credentials: true,
+            redact_crypto: true,
+        }
+    }
+}
+
+impl MessageTracer {
+    pub fn trace(&self, direction: Direction, peer: Option<&SipAddr>, msg: &SipMessage) {
+        if !enabled!(target: "rsipstack::wire", Level::TRACE) {
+            return;
+        }
+        let transport = peer
+            .and_then(|p| p.r#type)
+            .map(|t| t.to_string())
+            .unwrap_or_default();
+        let peer = peer.map(|p| p.to_string()).unwrap_or_default();
+        trace!(
+            target: "rsipstack::wire",
+            %direction,
+            %peer,
+            %transport,
+            "\n{}",
+            self.format(msg)
+        );
+    }
+
+    /// The first line and headers of `msg`, then its body when `body` is set,
+    /// with the credentials and keys hidden as configured
+    pub fn format(&self, msg: &SipMessage) -> String {
+        let text = msg.to_string();
+        let (head, body) = text.split_once("\r\n\r\n").unwrap_or((&text, ""));
+        let mut lines: Vec<String> = head
+            .split("\r\n")
+            .map(|line| match self.redact_credentials {
+                true => redact_authorization(line),
+                false => line.to_string(),
+            })
+            .collect();
+        if self.body && !body.is_empty() {
+            lines.push(String::new());
+            lines.extend(body.split("\r\n").map(|line| match self.redact_crypto {
+                true => redact_crypto(line),
+                false => line.to_string(),
+            }));
+        }
+        lines.join("\r\n")
+    }
+}
+
+// an Authorization or Proxy-Authorization line with its `response` param hidden
+fn redact_authorization(line: &str) -> String {
+    let (name, value) = match line.split_once(':') {
+        Some((name, value))
+            if name.trim().eq_ignore_ascii_case("Authorization")
+                || name.trim().eq_ignore_ascii_case("Proxy-Authorization") =>
+        {
+            (name, value)
+        }
+        _ => return line.to_string(),
+    };
+    let params: Vec<String> = value
+        .split(',')
+        .map(|param| match param.split_once('=') {
+            Some((key, _)) if key.trim().to_ascii_lowercase().ends_with("response") => {
+                format!("{}=\"{}\"", key, REDACTED)
+            }
+            _ => param.to_string(),
+        })
+        .collect();
+    format!("{}:{}", name, params.join(","))
+}
+
+// `a=crypto:<tag> <suite> <key-params>` with its key params hidden
+fn redact_crypto(line: &str) -> String {
+    let attribute = match line.strip_prefix("a=crypto:") {
+        Some(attribute) => attribute,
+        None => return line.to_string(),
+    };
+    let mut parts = attribute.splitn(3, ' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(tag), Some(suite), Some(_)) => {
+            format!("a=crypto:{} {} inline:{}", tag, suite, REDACTED)
+        }
+        _ => line.to_string(),
+    }
+}
+
+#[test]
+fn test_format() {
+    let msg = SipMessage::try_from(
+        "INVITE sip:bob@example.com SIP/2.0\r\n\
+         Via: SIP/2.0/UDP 192.0.2.1:5060;branch=z9hG4bKtrace\r\n\
+         From: <sip:alice@example.com>;tag=1\r\n\
+         To: <sip:bob@example.com>\r\n\
+         Call-ID: trace-test\r\n\
+         CSeq: 2 INVITE\r\n\
+         Authorization: Digest username=\"alice\", realm=\"example.com\", nonce=\"abc\", uri=\"sip:bob@example.com\", response=\"6629fae49393a05397450978507c4ef1\"\r\n\
+         Content-Type: application/sdp\r\n\
+         Content-Length: 88\r\n\r\n\
+         v=0\r\n\
+         m=audio 49170 RTP/SAVP 0\r\n\
+         a=crypto:1 AES_CM_128_HMAC_SHA1_80 inline:PS1uQCVeeCFCanVmcjkpPywjNWhcYD0mXXtxaVBR|2^20|1:32\r\n",
+    )
+    .unwrap();
+    let tracer = MessageTracer {
+        body: true,
+        ..Default::default()
+    };
+    let text = tracer.format(&msg);
+    assert!(text.starts_with("INVITE sip:bob@example.com SIP/2.0\r\n"));
+    assert!(text.contains("username=\"alice\""));
+    assert!(text.contains("response=\"<redacted>\""));
+    assert!(!text.contains("6629fae4"));
+    assert!(text.contains("a=crypto:1 AES_CM_128_HMAC_SHA1_80 inline:<redacted>"));
+    assert!(!text.contains("PS1uQCVe"));
+    assert!(text.contains("m=audio 49170 RTP/SAVP 0"));
+
+    // the headers only, nothing hidden
+    let tracer = MessageTracer {
+        body: false,
+        redact_credentials: false,
+        redact_crypto: false,
+    };
+    let text = tracer.format(&msg);
+    assert!(text.contains("6629fae4"));
+    assert!(!text.contains("m=audio"));
+    assert!(text.ends_with("Content-Length: 88"));
+}
@@ -1,13 +1,22 @@
 use super::tls::{TlsConfig, TlsConnection};
 use super::websocket::WebSocketConnection;
-use super::{connection::TransportSender, sip_addr::SipAddr, tcp::TcpConnection, SipConnection};
-use crate::{transport::TransportEvent, Result};
-use rsip::HostWithPort;
-use rsip_dns::{trust_dns_resolver::TokioAsyncResolver, ResolvableExt};
+use super::{
+    connection::{TransportSender, MAX_MESSAGE_SIZE},
+    keepalive::{idle_loop, keepalive_loop, KeepaliveConfig},
+    resolver::{DnsResolver, ResolverRef},
+    sip_addr::{host_ip, SipAddr},
+    tcp::TcpConnection,
+    SipConnection,
+};
+use crate::{transaction::clock::Clock, transport::TransportEvent, Result};
 use std::net::SocketAddr;
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 use tokio::select;
 use tokio_util::sync::CancellationToken;
@@ -20,13 +29,43 @@ pub struct TransportConfig {
     pub tls: Option<TlsConfig>,
     pub enable_ws: bool,
     pub enable_wss: bool,
+    /// CRLF pings on the outgoing stream connections, none when not set
+    pub keepalive: Option<KeepaliveConfig>,
+    /// outgoing stream connections without a message for this long are
+    /// closed, they are kept until the peer closes them when not set
+    pub idle_timeout: Option<Duration>,
 }
 
-#[derive(Default)]
 pub struct TransportLayerInner {
     cancel_token: CancellationToken,
     listens: Arc<Mutex<HashMap<SipAddr, SipConnection>>>, // 监听的传输
+    /// outgoing stream connections keyed by the remote target, reused for later requests
+    connections: Arc<Mutex<HashMap<SipAddr, SipConnection>>>,
+    transport_sender: Mutex<Option<TransportSender>>,
     config: Arc<Mutex<TransportConfig>>,
+    /// resolves request targets, the DNS resolver is created on first use
+    resolver: Mutex<Option<ResolverRef>>,
+    /// the clock of the keepalive pings and the idle timeout, the tokio
+    /// clock when not set
+    clock: Mutex<Option<Arc<dyn Clock>>>,
+    /// the size of the largest message read by the connections, shared with
+    /// the listeners for the connections they accept
+    max_message_size: Arc<AtomicUsize>,
+}
+
+impl Default for TransportLayerInner {
+    fn default() -> Self {
+        Self {
+            cancel_token: CancellationToken::default(),
+            listens: Arc::default(),
+            connections: Arc::default(),
+            transport_sender: Mutex::default(),
+            config: Arc::default(),
+            resolver: Mutex::default(),
+            clock: Mutex::default(),
+            max_message_size: Arc::new(AtomicUsize::new(MAX_MESSAGE_SIZE)),
+        }
+    }
 }
 
 #[derive(Default)]
@@ -41,6 +80,7 @@ impl TransportLayer {
             cancel_token,
             listens: Arc::new(Mutex::new(HashMap::new())),
             config: Arc::new(Mutex::new(TransportConfig::default())),
+            ..Default::default()
         };
         Self {
             outbound: None,
@@ -53,6 +93,7 @@ impl TransportLayer {
             cancel_token,
             listens: Arc::new(Mutex::new(HashMap::new())),
             config: Arc::new(Mutex::new(config)),
+            ..Default::default()
         };
         Self {
             outbound: None,
@@ -72,7 +113,81 @@ impl TransportLayer {
         self.inner.lookup(uri, self.outbound.as_ref()).await
     }
 
+    /// Like `lookup`, also returning the target address the connection sends to
+    pub async fn lookup_destination(
+        &self,
+        uri: &rsip::uri::Uri,
+    ) -> Result<(SipConnection, SipAddr)> {
+        self.inner
+            .lookup_destination(uri, self.outbound.as_ref())
+            .await
+    }
+
+    /// Connect one resolved target of a uri
+    pub async fn connect_target(
+        &self,
+        uri: &rsip::uri::Uri,
+        target: &SipAddr,
+    ) -> Result<SipConnection> {
+        self.inner
+            .connect_target(uri, target, self.outbound.as_ref())
+            .await
+    }
+
+    /// Drop `connection` from the pool and close it, the next request to
+    /// `target` opens a new one. A connection already replaced by a new one
+    /// to the target is left alone
+    pub async fn evict_connection(&self, target: &SipAddr, connection: &SipConnection) {
+        let evicted = {
+            let mut connections = self.inner.connections.lock().unwrap();
+            match connections.get(target) {
+                Some(c) if c.same_connection(connection) => connections.remove(target),
+                _ => None,
+            }
+        };
+        if let Some(evicted) = evicted {
+            evicted.close().await.ok();
+        }
+    }
+
+    /// Resolve a uri into the ordered targets to try
+    pub async fn resolve(&self, uri: &rsip::uri::Uri) -> Result<Vec<SipAddr>> {
+        self.inner.resolve(uri, self.outbound.as_ref()).await
+    }
+
+    /// Replace the DNS resolver, e.g. with a static one in tests
+    pub fn set_resolver(&self, resolver: ResolverRef) {
+        self.inner.resolver.lock().unwrap().replace(resolver);
+    }
+
+    /// Run the keepalive pings and the idle timeout of the outgoing
+    /// connections on `clock`, the endpoint sets its own
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        self.inner.clock.lock().unwrap().replace(clock);
+    }
+
+    /// Replace the size of the largest message read, [`MAX_MESSAGE_SIZE`] by
+    /// default. A larger datagram is reported as [`TransportEvent::TooLarge`],
+    /// a stream carrying one is closed
+    pub fn set_max_message_size(&self, size: usize) {
+        self.inner.max_message_size.store(size, Ordering::Relaxed);
+        let listens = self.inner.listens.lock().unwrap();
+        let connections = self.inner.connections.lock().unwrap();
+        for connection in listens.values().chain(connections.values()) {
+            connection.set_max_message_size(size);
+        }
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.inner.max_message_size.load(Ordering::Relaxed)
+    }
+
     pub async fn serve_listens(&self, sender: TransportSender) -> Result<()> {
+        self.inner
+            .transport_sender
+            .lock()
+            .unwrap()
+            .replace(sender.clone());
         let listens = self.inner.listens.lock().unwrap().clone();
         for (_, transport) in listens {
             let sub_token = self.inner.cancel_token.child_token();
@@ -118,13 +233,14 @@ impl TransportLayer {
         let cancel_token = self.inner.cancel_token.child_token();
         let addr_clone = addr.clone();
         let sender_clone = sender.clone();
+        let max_message_size = self.inner.max_message_size.clone();
 
         tokio::spawn(async move {
             select! {
                 _ = cancel_token.cancelled() => {
                     info!("TCP listener cancelled: {}", addr_clone);
                 }
-                result = TcpConnection::serve_listener(listener, addr_clone.clone(), sender_clone) => {
+                result = TcpConnection::serve_listener(listener, addr_clone.clone(), sender_clone, max_message_size) => {
                     if let Err(e) = result {
                         warn!("TCP listener error: {}: {:?}", addr_clone, e);
                     }
@@ -135,42 +251,44 @@ impl TransportLayer {
         Ok(addr)
     }
 
+    pub fn set_tls_config(&self, tls: TlsConfig) {
+        self.inner.config.lock().unwrap().tls.replace(tls);
+    }
+
+    /// 创建并添加 TLS 监听器, 需要 TLS 配置中的证书和私钥
     pub async fn add_tls_listener(
         &self,
         local: SocketAddr,
         sender: TransportSender,
     ) -> Result<SipAddr> {
-        let config_guard = self.inner.config.lock().unwrap();
-        let config = match &config_guard.tls {
-            Some(cfg) => cfg,
+        let acceptor = match &self.inner.config.lock().unwrap().tls {
+            Some(config) => TlsConnection::create_acceptor(config)?,
             None => {
                 return Err(crate::Error::Error(
                     "TLS configuration not provided".to_string(),
                 ));
             }
         };
+        let (listener, addr) = TlsConnection::create_listener(local).await?;
 
-        let acceptor = TlsConnection::create_acceptor(config).await?;
-        drop(config_guard);
-
-        // Create TCP listener
-        let (listener, addr) = tokio::net::TcpListener::bind(local).await.map(|l| {
-            let local_addr = l.local_addr().unwrap();
-            let sip_addr = SipAddr {
-                r#type: Some(rsip::transport::Transport::Tls),
-                addr: local_addr.into(),
-            };
-            (l, sip_addr)
-        })?;
+        let cancel_token = self.inner.cancel_token.child_token();
+        let addr_clone = addr.clone();
+        let sender_clone = sender.clone();
+        let max_message_size = self.inner.max_message_size.clone();
 
-        // Add listener to the list
-        let mut listeners = self.inner.listens.lock().unwrap();
+        tokio::spawn(async move {
+            select! {
+                _ = cancel_token.cancelled() => {
+                    info!("TLS listener cancelled: {}", addr_clone);
+                }
+                result = TlsConnection::serve_listener(listener, addr_clone.clone(), acceptor, sender_clone, max_message_size) => {
+                    if let Err(e) = result {
+                        warn!("TLS listener error: {}: {:?}", addr_clone, e);
+                    }
+                }
+            }
+        });
 
-        listeners.insert(
-            addr.clone(),
-            SipConnection::Tls(TlsConnection::new_with_acceptor(acceptor, addr.clone())),
-        );
-        info!("Added TLS listener on {}", addr);
         Ok(addr)
     }
 
@@ -180,17 +298,27 @@ impl TransportLayer {
         sender: TransportSender,
         secure: bool,
     ) -> Result<SipAddr> {
-        let config = self.inner.config.lock().unwrap();
-        if secure && !config.enable_wss {
-            return Err(crate::Error::Error(
-                "WSS not enabled in configuration".to_string(),
-            ));
-        } else if !secure && !config.enable_ws {
-            return Err(crate::Error::Error(
-                "WS not enabled in configuration".to_string(),
-            ));
-        }
-        drop(config);
+        let acceptor = {
+            let config = self.inner.config.lock().unwrap();
+            if secure && !config.enable_wss {
+                return Err(crate::Error::Error(
+                    "WSS not enabled in configuration".to_string(),
+                ));
+            } else if !secure && !config.enable_ws {
+                return Err(crate::Error::Error(
+                    "WS not enabled in configuration".to_string(),
+                ));
+            }
+            match (&config.tls, secure) {
+                (Some(tls), true) => Some(TlsConnection::create_acceptor(tls)?),
+                (None, true) => {
+                    return Err(crate::Error::Error(
+                        "TLS configuration not provided".to_string(),
+                    ));
+                }
+                _ => None,
+            }
+        };
 
         let listener = tokio::net::TcpListener::bind(local).await?;
         let local_addr = listener.local_addr()?;
@@ -209,13 +337,14 @@ impl TransportLayer {
         let cancel_token = self.inner.cancel_token.child_token();
         let addr_clone = addr.clone();
         let sender_clone = sender.clone();
+        let max_message_size = self.inner.max_message_size.clone();
 
         tokio::spawn(async move {
             select! {
                 _ = cancel_token.cancelled() => {
                     info!("WebSocket listener cancelled: {}", addr_clone);
                 }
-                result = WebSocketConnection::serve_listener(listener, addr_clone.clone(), sender_clone, secure) => {
+                result = WebSocketConnection::serve_listener(listener, addr_clone.clone(), sender_clone, acceptor, max_message_size) => {
                     if let Err(e) = result {
                         warn!("WebSocket listener error: {}: {:?}", addr_clone, e);
                     }
@@ -229,6 +358,7 @@ impl TransportLayer {
 
 impl TransportLayerInner {
     pub fn add_connection(&self, connection: SipConnection) {
+        connection.set_max_message_size(self.max_message_size.load(Ordering::Relaxed));
         self.listens
             .lock()
             .unwrap()
@@ -239,57 +369,92 @@ impl TransportLayerInner {
         self.listens.lock().unwrap().remove(addr);
     }
 
-    async fn lookup<'a>(
-        &'a self,
-        uri: &rsip::uri::Uri,
-        outbound: Option<&'a SipAddr>,
-    ) -> Result<SipConnection> {
-        let target = if let Some(addr) = outbound {
-            addr
-        } else {
-            let context = rsip_dns::Context::initialize_from(
-                uri.clone(),
-                rsip_dns::AsyncTrustDnsClient::new(
-                    TokioAsyncResolver::tokio(Default::default(), Default::default()).unwrap(),
-                ),
-                rsip_dns::SupportedTransports::any(),
-            )?;
+    /// the targets to try for a uri in order, or just the outbound proxy
+    async fn resolve(&self, uri: &rsip::uri::Uri, outbound: Option<&SipAddr>) -> Result<Vec<SipAddr>> {
+        if let Some(addr) = outbound {
+            return Ok(vec![addr.clone()]);
+        }
+        let resolver = self.resolver.lock().unwrap().clone();
+        let resolver = match resolver {
+            Some(resolver) => resolver,
+            None => {
+                let resolver: ResolverRef = Arc::new(DnsResolver::new()?);
+                self.resolver.lock().unwrap().replace(resolver.clone());
+                resolver
+            }
+        };
+        resolver.resolve(uri).await
+    }
 
-            let mut lookup = rsip_dns::Lookup::from(context);
-            match lookup.resolve_next().await {
-                Some(mut target) => {
-                        match uri.host_with_port.host {
-                            rsip::Host::IpAddr(_) => {
-                                if let Some(port) = uri.host_with_port.port {
-                                    target.port = port;
-                                }
-                            }
-                            _ => {
-                            }
-                        }
-                        &SipAddr {
-                            r#type: Some(target.transport),
-                            addr: HostWithPort::from(SocketAddr::new(
-                                target.ip_addr,
-                                u16::from(target.port),
-                            )),
-                        }
-                },
-                None => {
-                    return Err(crate::Error::DnsResolutionError(format!(
-                        "DNS resolution error: {}",
-                        uri
-                    )))
+    async fn lookup(&self, uri: &rsip::uri::Uri, outbound: Option<&SipAddr>) -> Result<SipConnection> {
+        self.lookup_destination(uri, outbound)
+            .await
+            .map(|(connection, _)| connection)
+    }
+
+    /// the connection and address of the first reachable target of a uri
+    async fn lookup_destination(
+        &self,
+        uri: &rsip::uri::Uri,
+        outbound: Option<&SipAddr>,
+    ) -> Result<(SipConnection, SipAddr)> {
+        let mut targets = self.resolve(uri, outbound).await?;
+        // try the address families we listen on first, dual-stack peers resolve to both
+        let families = self
+            .listens
+            .lock()
+            .unwrap()
+            .keys()
+            .filter_map(|addr| addr.get_socketaddr().ok())
+            .map(|addr| addr.is_ipv6())
+            .collect::<Vec<_>>();
+        targets.sort_by_key(|target| {
+            !target
+                .get_socketaddr()
+                .is_ok_and(|addr| families.contains(&addr.is_ipv6()))
+        });
+        let mut last_error = None;
+        for target in targets {
+            match self.connect_target(uri, &target, outbound).await {
+                Ok(connection) => return Ok((connection, target)),
+                Err(e) => {
+                    info!("target unreachable: {} -> {} {:?}", uri, target, e);
+                    last_error = Some(e);
                 }
             }
-        };
+        }
+        Err(last_error.unwrap_or(crate::Error::Resolve(format!("no target: {}", uri))))
+    }
 
+    async fn connect_target(
+        &self,
+        uri: &rsip::uri::Uri,
+        target: &SipAddr,
+        outbound: Option<&SipAddr>,
+    ) -> Result<SipConnection> {
         info!("lookup target: {} -> {}", uri, target);
 
-        if let Some(transport) = self.listens.lock().unwrap().get(&target) {
+        if let Some(transport) = self.listens.lock().unwrap().get(target) {
+            return Ok(transport.clone());
+        }
+        if let Some(connection) = self.connections.lock().unwrap().get(target) {
+            return Ok(connection.clone());
+        }
+        // a custom transport sends to every target of its transport type
+        if let Some(transport) = self.listens.lock().unwrap().values().find(|transport| {
+            matches!(transport, SipConnection::Custom(_))
+                && transport.get_addr().r#type == target.r#type
+        }) {
             return Ok(transport.clone());
         }
 
+        // validate the certificate against the host, not the resolved address
+        let server_name = match &uri.host_with_port.host {
+            // an IPv6 reference is not a name either
+            rsip::Host::Domain(_) if host_ip(&uri.host_with_port.host).is_some() => None,
+            rsip::Host::Domain(domain) if outbound.is_none() => Some(domain.to_string()),
+            _ => None,
+        };
         match target.r#type {
             Some(rsip::transport::Transport::Udp) => {
                 let listens = self.listens.lock().unwrap();
@@ -302,28 +467,107 @@ impl TransportLayerInner {
             Some(rsip::transport::Transport::Tcp) => {
                 let connection = TcpConnection::connect(target).await?;
                 let sip_connection = SipConnection::Tcp(connection);
+                self.serve_connection(target, sip_connection.clone());
                 return Ok(sip_connection);
             }
             Some(rsip::transport::Transport::Tls) => {
-                let connection = TlsConnection::connect(target, None).await?;
+                let tls_config = self.config.lock().unwrap().tls.clone();
+                let connection =
+                    TlsConnection::connect(target, server_name.as_deref(), tls_config.as_ref())
+                        .await?;
                 let sip_connection = SipConnection::Tls(connection);
+                self.serve_connection(target, sip_connection.clone());
                 return Ok(sip_connection);
             }
             Some(rsip::transport::Transport::Ws) | Some(rsip::transport::Transport::Wss) => {
-                let connection = WebSocketConnection::connect(target).await?;
+                let tls_config = self.config.lock().unwrap().tls.clone();
+                let connection =
+                    WebSocketConnection::connect(target, server_name.as_deref(), tls_config.as_ref())
+                        .await?;
                 let sip_connection = SipConnection::WebSocket(connection);
+                self.serve_connection(target, sip_connection.clone());
                 return Ok(sip_connection);
             }
             _ => {}
         }
 
-        return Err(crate::Error::TransportLayerError(
-            format!("unsupported transport type: {:?}", target.r#type),
-            target.to_owned(),
-        ));
+        return Err(crate::Error::Transport {
+            kind: crate::error::TransportErrorKind::Unsupported,
+            addr: target.to_owned(),
+            source: format!("unsupported transport type: {:?}", target.r#type),
+        });
+    }
+
+    /// read the responses of an outgoing connection until it is closed
+    fn serve_connection(&self, target: &SipAddr, connection: SipConnection) {
+        let sender = match self.transport_sender.lock().unwrap().clone() {
+            Some(sender) => sender,
+            None => {
+                warn!("transport layer not serving, no reader for: {}", target);
+                return;
+            }
+        };
+        connection.set_max_message_size(self.max_message_size.load(Ordering::Relaxed));
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(target.clone(), connection.clone());
+        let sub_token = self.cancel_token.child_token();
+        let connections_ref = self.connections.clone();
+        let target = target.clone();
+        let (keepalive, idle_timeout) = {
+            let config = self.config.lock().unwrap();
+            (config.keepalive.clone(), config.idle_timeout)
+        };
+        let clock = self.clock.lock().unwrap().clone();
+        if let (Some(stream), Some(clock)) = (connection.stream(), clock) {
+            stream.keepalive().set_clock(clock);
+        }
+        tokio::spawn(async move {
+            sender.send(TransportEvent::New(connection.clone())).ok();
+            let pings = async {
+                match &keepalive {
+                    Some(config) => keepalive_loop(&connection, config, &target).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let idle = async {
+                match idle_timeout {
+                    Some(timeout) => idle_loop(&connection, timeout).await,
+                    None => std::future::pending().await,
+                }
+            };
+            select! {
+                _ = sub_token.cancelled() => { }
+                _ = idle => {
+                    info!("closing idle connection: {}", target);
+                    connection.close().await.ok();
+                }
+                r = connection.serve_loop(sender.clone()) => {
+                    if let Err(e) = r {
+                        info!("connection serve_loop error: {} {:?}", target, e);
+                    }
+                }
+                r = pings => {
+                    info!("connection keepalive failed: {} {:?}", target, r);
+                    connection.close().await.ok();
+                }
+            }
+            // an evicted connection may have been replaced by a new one already
+            let mut connections = connections_ref.lock().unwrap();
+            if connections
+                .get(&target)
+                .is_some_and(|c| c.same_connection(&connection))
+            {
+                connections.remove(&target);
+            }
+            drop(connections);
+            sender.send(TransportEvent::Closed(connection)).ok();
+        });
     }
 
     async fn serve_listens(&self, sender: TransportSender) -> Result<()> {
+        self.transport_sender.lock().unwrap().replace(sender.clone());
         let listens = self.listens.lock().unwrap().clone();
         for (_, transport) in listens {
             let sub_token = self.cancel_token.child_token();
@@ -377,6 +621,94 @@ mod tests {
         Ok(())
     }
 
+    struct StaticResolver(Vec<crate::transport::SipAddr>);
+
+    #[async_trait::async_trait]
+    impl crate::transport::resolver::Resolver for StaticResolver {
+        async fn resolve(&self, _uri: &rsip::Uri) -> Result<Vec<crate::transport::SipAddr>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lookup_failover() -> Result<()> {
+        let tl = super::TransportLayer::new(tokio_util::sync::CancellationToken::new());
+        let udp_peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+        let udp_addr = udp_peer.get_addr().to_owned();
+        tl.add_transport(udp_peer.into());
+
+        // nobody listens on the first candidate
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let closed = crate::transport::SipAddr {
+            r#type: Some(Transport::Tcp),
+            addr: listener.local_addr()?.into(),
+        };
+        drop(listener);
+        let fallback = crate::transport::SipAddr {
+            r#type: Some(Transport::Udp),
+            addr: "127.0.0.1:5099".parse::<std::net::SocketAddr>()?.into(),
+        };
+        tl.set_resolver(std::sync::Arc::new(StaticResolver(vec![
+            closed,
+            fallback.clone(),
+        ])));
+
+        let uri = "sip:bob@example.com".try_into().expect("parse uri");
+        let (connection, target) = tl.lookup_destination(&uri).await?;
+        assert_eq!(connection.get_addr(), &udp_addr);
+        assert_eq!(target, fallback);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lookup_local_family() -> Result<()> {
+        let tl = super::TransportLayer::new(tokio_util::sync::CancellationToken::new());
+        let udp_peer = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+        tl.add_transport(udp_peer.into());
+
+        // a dual-stack target, the IPv4 address matches the local socket
+        let ipv6 = crate::transport::SipAddr {
+            r#type: Some(Transport::Udp),
+            addr: "[2001:db8::1]:5060".parse::<std::net::SocketAddr>()?.into(),
+        };
+        let ipv4 = crate::transport::SipAddr {
+            r#type: Some(Transport::Udp),
+            addr: "192.0.2.1:5060".parse::<std::net::SocketAddr>()?.into(),
+        };
+        tl.set_resolver(std::sync::Arc::new(StaticResolver(vec![ipv6, ipv4.clone()])));
+
+        let uri = "sip:bob@example.com".try_into().expect("parse uri");
+        let (_, target) = tl.lookup_destination(&uri).await?;
+        assert_eq!(target, ipv4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ipv6_uri() -> Result<()> {
+        use crate::transport::resolver::{DnsResolver, Resolver};
+        let resolver = DnsResolver::new()?;
+        let uri = crate::rsip_ext::parse_uri("sip:bob@[2001:db8::1]:5080;transport=tcp")?;
+        let targets = resolver.resolve(&uri).await?;
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].r#type, Some(Transport::Tcp));
+        assert_eq!(targets[0].get_socketaddr()?, "[2001:db8::1]:5080".parse()?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ip_uri() -> Result<()> {
+        use crate::transport::resolver::{DnsResolver, Resolver};
+        let resolver = DnsResolver::new()?;
+        let uri = "sip:bob@127.0.0.1:5080;transport=tcp"
+            .try_into()
+            .expect("parse uri");
+        let targets = resolver.resolve(&uri).await?;
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].r#type, Some(Transport::Tcp));
+        assert_eq!(targets[0].addr.to_string(), "127.0.0.1:5080");
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_tcp_listener() -> Result<()> {
         let tl = super::TransportLayer::new(tokio_util::sync::CancellationToken::new());
@@ -1,12 +1,18 @@
-use super::{connection::TransportSender, SipAddr, SipConnection};
+use super::{connection::TransportSender, custom::Transport, SipAddr, SipConnection};
 use crate::{
     transport::{
-        connection::{KEEPALIVE_REQUEST, KEEPALIVE_RESPONSE},
+        connection::{KEEPALIVE_REQUEST, KEEPALIVE_RESPONSE, MAX_MESSAGE_SIZE},
         TransportEvent,
     },
     Result,
 };
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 use tokio::net::UdpSocket;
 use tracing::{debug, error, info, instrument};
 pub struct UdpInner {
@@ -18,6 +24,8 @@ pub struct UdpInner {
 pub struct UdpConnection {
     pub external: Option<SipAddr>,
     inner: Arc<UdpInner>,
+    /// the size of the largest datagram accepted, shared by the clones
+    max_message_size: Arc<AtomicUsize>,
 }
 
 impl UdpConnection {
@@ -28,6 +36,7 @@ impl UdpConnection {
                 addr: addr.into(),
             }),
             inner: Arc::new(inner),
+            max_message_size: Arc::new(AtomicUsize::new(MAX_MESSAGE_SIZE)),
         }
     }
 
@@ -48,15 +57,43 @@ impl UdpConnection {
                 addr: addr.into(),
             }),
             inner: Arc::new(UdpInner { addr, conn }),
+            max_message_size: Arc::new(AtomicUsize::new(MAX_MESSAGE_SIZE)),
         };
         info!("created UDP connection: {} external: {:?}", t, external);
         Ok(t)
     }
 
     pub async fn serve_loop(&self, sender: TransportSender) -> Result<()> {
-        let mut buf = vec![0u8; 2048];
+        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
         loop {
-            let (len, addr) = match self.inner.conn.recv_from(&mut buf).await {
+            let (msg, addr, len) = self.recv_message(&mut buf).await;
+            let msg = match SipConnection::update_msg_received(msg, addr) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    info!("error updating SIP via from: {} error: {:?}", addr, e);
+                    continue;
+                }
+            };
+
+            let connection = SipConnection::Udp(self.clone());
+            let from = SipAddr {
+                r#type: Some(rsip::transport::Transport::Udp),
+                addr: addr.into(),
+            };
+            if len > self.max_message_size() {
+                info!("message of {} bytes too large from: {}", len, addr);
+                sender.send(TransportEvent::TooLarge(msg, connection, from))?;
+                continue;
+            }
+            sender.send(TransportEvent::Incoming(msg, connection, from))?;
+        }
+    }
+
+    /// the next SIP message, its source and its size, keepalives are answered
+    /// and undecodable packets skipped
+    async fn recv_message(&self, buf: &mut [u8]) -> (rsip::SipMessage, SocketAddr, usize) {
+        loop {
+            let (len, addr) = match self.inner.conn.recv_from(buf).await {
                 Ok((len, addr)) => (len, addr),
                 Err(e) => {
                     error!("error receiving UDP packet: {}", e);
@@ -80,12 +117,8 @@ impl UdpConnection {
             let undecoded = match std::str::from_utf8(&buf[..len]) {
                 Ok(s) => s,
                 Err(e) => {
-                    info!(
-                        "decoding text from: {} error: {} buf: {:?}",
-                        addr,
-                        e,
-                        &buf[..len]
-                    );
+                    let e = crate::Error::parse(e, &buf[..len]);
+                    info!("decoding text from: {} {}", addr, e);
                     continue;
                 }
             };
@@ -93,21 +126,8 @@ impl UdpConnection {
             let msg = match rsip::SipMessage::try_from(undecoded) {
                 Ok(msg) => msg,
                 Err(e) => {
-                    info!(
-                        "error parsing SIP message from: {} error: {} buf: {}",
-                        addr, e, undecoded
-                    );
-                    continue;
-                }
-            };
-
-            let msg = match SipConnection::update_msg_received(msg, addr) {
-                Ok(msg) => msg,
-                Err(e) => {
-                    info!(
-                        "error updating SIP via from: {} error: {:?} buf: {}",
-                        addr, e, undecoded
-                    );
+                    let e = crate::Error::parse(e, &buf[..len]);
+                    info!("parsing SIP message from: {} {}", addr, e);
                     continue;
                 }
             };
@@ -119,15 +139,7 @@ impl UdpConnection {
                 self.get_addr(),
                 undecoded
             );
-
-            sender.send(TransportEvent::Incoming(
-                msg,
-                SipConnection::Udp(self.clone()),
-                SipAddr {
-                    r#type: Some(rsip::transport::Transport::Udp),
-                    addr: addr.into(),
-                },
-            ))?;
+            return (msg, addr, len);
         }
     }
 
@@ -148,9 +160,7 @@ impl UdpConnection {
             .conn
             .send_to(buf.as_bytes(), destination)
             .await
-            .map_err(|e| {
-                crate::Error::TransportLayerError(e.to_string(), self.get_addr().to_owned())
-            })
+            .map_err(|e| crate::Error::transport(e, self.get_addr()))
             .map(|_| ())
     }
 
@@ -161,9 +171,7 @@ impl UdpConnection {
             .conn
             .send_to(buf, destination.get_socketaddr()?)
             .await
-            .map_err(|e| {
-                crate::Error::TransportLayerError(e.to_string(), self.get_addr().to_owned())
-            })
+            .map_err(|e| crate::Error::transport(e, self.get_addr()))
             .map(|_| ())
     }
 
@@ -180,6 +188,16 @@ impl UdpConnection {
         ))
     }
 
+    /// Replace the size of the largest datagram accepted, a larger one is
+    /// reported as [`TransportEvent::TooLarge`]
+    pub fn set_max_message_size(&self, size: usize) {
+        self.max_message_size.store(size, Ordering::Relaxed);
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size.load(Ordering::Relaxed)
+    }
+
     pub fn get_addr(&self) -> &SipAddr {
         if let Some(external) = &self.external {
             external
@@ -189,6 +207,27 @@ impl UdpConnection {
     }
 }
 
+#[async_trait::async_trait]
+impl Transport for UdpConnection {
+    fn get_addr(&self) -> &SipAddr {
+        UdpConnection::get_addr(self)
+    }
+
+    async fn send(&self, msg: rsip::SipMessage, target: SocketAddr) -> Result<()> {
+        let target = SipAddr {
+            r#type: Some(rsip::transport::Transport::Udp),
+            addr: target.into(),
+        };
+        UdpConnection::send(self, msg, Some(&target)).await
+    }
+
+    async fn recv(&self) -> Option<(rsip::SipMessage, SocketAddr)> {
+        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+        let (msg, addr, _) = self.recv_message(&mut buf).await;
+        Some((msg, addr))
+    }
+}
+
 impl std::fmt::Display for UdpConnection {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.inner.conn.local_addr() {
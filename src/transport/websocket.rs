@@ -1,33 +1,60 @@
 use crate::{
+    error::TransportErrorKind,
     transport::{
-        connection::{TransportSender, KEEPALIVE_REQUEST, KEEPALIVE_RESPONSE},
+        connection::{TransportSender, KEEPALIVE_REQUEST, KEEPALIVE_RESPONSE, MAX_MESSAGE_SIZE},
+        keepalive::KeepaliveState,
         sip_addr::SipAddr,
         stream::StreamConnection,
+        tls::{handshake_error, TlsConfig, TlsConnection},
         SipConnection, TransportEvent,
     },
     Result,
 };
 use futures_util::{SinkExt, StreamExt};
 use rsip::SipMessage;
-use std::{fmt, sync::Arc};
-use tokio::{net::TcpListener, sync::Mutex};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+use tokio_rustls::{rustls::pki_types, TlsAcceptor, TlsConnector};
 use tokio_tungstenite::{
-    connect_async, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream,
+    tungstenite::{
+        client::IntoClientRequest,
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{HeaderValue, StatusCode},
+        protocol::Message,
+    },
+    WebSocketStream,
 };
 use tracing::{debug, error, info, warn};
 
+/// the WebSocket subprotocol of RFC 7118
+pub const SIP_SUBPROTOCOL: &str = "sip";
+
+// the plain TCP or TLS stream under the WebSocket, client or server side
+trait WsIo: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> WsIo for T {}
+
 // Define a type alias for the WebSocket sink to make the code more readable
-type WsSink = futures_util::stream::SplitSink<
-    WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
-    Message,
->;
-type WsRead = futures_util::stream::SplitStream<WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>>;
+type WsStream = WebSocketStream<Box<dyn WsIo>>;
+type WsSink = futures_util::stream::SplitSink<WsStream, Message>;
+type WsRead = futures_util::stream::SplitStream<WsStream>;
 
 pub struct WebSocketInner {
     pub local_addr: SipAddr,
     pub remote_addr: Option<SipAddr>,
-    pub ws_sink: Arc<Mutex<WsSink>>,
-    pub ws_read: Arc<Mutex<WsRead>>,
+    ws_sink: Arc<Mutex<WsSink>>,
+    ws_read: Arc<Mutex<WsRead>>,
+    keepalive: KeepaliveState,
+    max_message_size: AtomicUsize,
 }
 
 #[derive(Clone)]
@@ -36,38 +63,81 @@ pub struct WebSocketConnection {
 }
 
 impl WebSocketConnection {
-    pub async fn connect(remote: &SipAddr) -> Result<Self> {
-        let scheme = match remote.r#type {
-            Some(rsip::transport::Transport::Wss) => "wss",
-            _ => "ws",
+    /// Connect to a WS/WSS server, `server_name` is the host the target was
+    /// resolved from, used for the Host header and the TLS certificate
+    pub async fn connect(
+        remote: &SipAddr,
+        server_name: Option<&str>,
+        tls_config: Option<&TlsConfig>,
+    ) -> Result<Self> {
+        let transport = match remote.r#type {
+            Some(rsip::transport::Transport::Wss) => rsip::transport::Transport::Wss,
+            _ => rsip::transport::Transport::Ws,
         };
-
-        let host = match &remote.addr.host {
-            rsip::host_with_port::Host::Domain(domain) => domain.to_string(),
-            rsip::host_with_port::Host::IpAddr(ip) => ip.to_string(),
+        let scheme = match transport {
+            rsip::transport::Transport::Wss => "wss",
+            _ => "ws",
         };
 
+        let host = server_name
+            .map(|name| name.to_string())
+            .unwrap_or(remote.addr.host.to_string());
         let port = remote.addr.port.as_ref().map_or(5060, |p| *p.value());
         let url = format!("{}://{}:{}/sip", scheme, host, port);
+        let mut request = url.as_str().into_client_request()?;
+        request.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            HeaderValue::from_static(SIP_SUBPROTOCOL),
+        );
 
-        let (ws_stream, _) = connect_async(&url).await?;
-        let (ws_sink, _ws_stream) = ws_stream.split();
-
+        let stream = TcpStream::connect(remote.get_socketaddr()?)
+            .await
+            .map_err(|e| crate::Error::transport(e, remote))?;
         let local_addr = SipAddr {
-            r#type: Some(if scheme == "wss" {
-                rsip::transport::Transport::Wss
-            } else {
-                rsip::transport::Transport::Ws
-            }),
-            addr: remote.addr.clone(),
+            r#type: Some(transport),
+            addr: stream.local_addr()?.into(),
+        };
+
+        let stream: Box<dyn WsIo> = match transport {
+            rsip::transport::Transport::Wss => {
+                let default_config = TlsConfig::default();
+                let config = tls_config.unwrap_or(&default_config);
+                let connector =
+                    TlsConnector::from(Arc::new(TlsConnection::create_client_config(config)?));
+                let name = config.sni_override.clone().unwrap_or(host);
+                let server_name = pki_types::ServerName::try_from(name.clone()).map_err(|_| {
+                    crate::Error::Transport {
+                        kind: TransportErrorKind::Other,
+                        addr: remote.clone(),
+                        source: format!("Invalid DNS name: {}", name),
+                    }
+                })?;
+                let tls_stream = connector
+                    .connect(server_name, stream)
+                    .await
+                    .map_err(|e| handshake_error(e, remote))?;
+                Box::new(tls_stream)
+            }
+            _ => Box::new(stream),
         };
 
+        let (ws_stream, _) = tokio_tungstenite::client_async(request, stream)
+            .await
+            .map_err(|e| crate::Error::Transport {
+                kind: TransportErrorKind::Handshake,
+                addr: remote.clone(),
+                source: format!("websocket handshake failed: {}", e),
+            })?;
+        let (ws_sink, ws_read) = ws_stream.split();
+
         let connection = WebSocketConnection {
             inner: Arc::new(WebSocketInner {
                 local_addr,
                 remote_addr: Some(remote.clone()),
                 ws_sink: Arc::new(Mutex::new(ws_sink)),
-                ws_read: Arc::new(Mutex::new(_ws_stream)),
+                ws_read: Arc::new(Mutex::new(ws_read)),
+                keepalive: KeepaliveState::default(),
+                max_message_size: AtomicUsize::new(MAX_MESSAGE_SIZE),
             }),
         };
 
@@ -80,16 +150,19 @@ impl WebSocketConnection {
         Ok(connection)
     }
 
+    /// Serve a WS listener, or WSS when `acceptor` is set, the accepted
+    /// connections read messages up to `max_message_size` at the time they
+    /// are accepted
     pub async fn serve_listener(
         tcp_listener: TcpListener,
         local_addr: SipAddr,
         sender: TransportSender,
-        is_secure: bool,
+        acceptor: Option<TlsAcceptor>,
+        max_message_size: Arc<AtomicUsize>,
     ) -> Result<()> {
-        let transport_type = if is_secure {
-            rsip::transport::Transport::Wss
-        } else {
-            rsip::transport::Transport::Ws
+        let transport_type = match acceptor {
+            Some(_) => rsip::transport::Transport::Wss,
+            None => rsip::transport::Transport::Ws,
         };
 
         info!("Starting WebSocket listener on {}", local_addr);
@@ -100,20 +173,31 @@ impl WebSocketConnection {
                     debug!("New WebSocket connection from {}", remote_addr);
 
                     let remote_sip_addr = SipAddr {
-                        r#type: Some(transport_type.clone()),
+                        r#type: Some(transport_type),
                         addr: remote_addr.into(),
                     };
 
                     let local_addr_clone = local_addr.clone();
                     let sender_clone = sender.clone();
+                    let acceptor = acceptor.clone();
+                    let max_message_size = max_message_size.clone();
 
                     tokio::spawn(async move {
-                        // Wrap the TCP stream in MaybeTlsStream
-                        let maybe_tls_stream = MaybeTlsStream::Plain(stream);
+                        let stream: Box<dyn WsIo> = match acceptor {
+                            Some(acceptor) => match acceptor.accept(stream).await {
+                                Ok(stream) => Box::new(stream),
+                                Err(e) => {
+                                    info!("TLS handshake failed: {}: {}", remote_addr, e);
+                                    return;
+                                }
+                            },
+                            None => Box::new(stream),
+                        };
 
-                        // Accept the WebSocket connection
                         let ws_stream =
-                            match tokio_tungstenite::accept_async(maybe_tls_stream).await {
+                            match tokio_tungstenite::accept_hdr_async(stream, select_subprotocol)
+                                .await
+                            {
                                 Ok(ws) => ws,
                                 Err(e) => {
                                     error!("Error upgrading to WebSocket: {}", e);
@@ -121,19 +205,20 @@ impl WebSocketConnection {
                                 }
                             };
 
-                        // Split the WebSocket stream
-                        let (ws_sink, mut ws_stream) = ws_stream.split();
+                        let (ws_sink, ws_read) = ws_stream.split();
 
-                        // Create a new WebSocket connection
                         let connection = WebSocketConnection {
                             inner: Arc::new(WebSocketInner {
                                 local_addr: local_addr_clone.clone(),
                                 remote_addr: Some(remote_sip_addr.clone()),
                                 ws_sink: Arc::new(Mutex::new(ws_sink)),
-                                ws_read: Arc::new(Mutex::new(ws_stream)),
+                                ws_read: Arc::new(Mutex::new(ws_read)),
+                                keepalive: KeepaliveState::default(),
+                                max_message_size: AtomicUsize::new(
+                                    max_message_size.load(Ordering::Relaxed),
+                                ),
                             }),
                         };
-                        connection.serve_loop(sender_clone.clone());
                         let sip_connection = SipConnection::WebSocket(connection.clone());
 
                         if let Err(e) =
@@ -142,6 +227,13 @@ impl WebSocketConnection {
                             error!("Error sending new connection event: {:?}", e);
                             return;
                         }
+
+                        if let Err(e) = connection.serve_loop(sender_clone.clone()).await {
+                            error!("Error handling WebSocket connection: {:?}", e);
+                        }
+                        sender_clone
+                            .send(TransportEvent::Closed(sip_connection))
+                            .ok();
                     });
                 }
                 Err(e) => {
@@ -152,86 +244,104 @@ impl WebSocketConnection {
     }
 }
 
+impl WebSocketConnection {
+    fn send_error(&self, e: tokio_tungstenite::tungstenite::Error) -> crate::Error {
+        let addr = self.inner.remote_addr.as_ref();
+        crate::Error::Transport {
+            kind: match e {
+                tokio_tungstenite::tungstenite::Error::Io(ref e) => TransportErrorKind::from(e),
+                _ => TransportErrorKind::ConnectionClosed,
+            },
+            addr: addr.unwrap_or(&self.inner.local_addr).clone(),
+            source: e.to_string(),
+        }
+    }
+}
+
+/// Accept the upgrade only for clients offering the `sip` subprotocol
+#[allow(clippy::result_large_err)] // the handshake callback signature
+fn select_subprotocol(
+    request: &Request,
+    mut response: Response,
+) -> std::result::Result<Response, ErrorResponse> {
+    let offered = request
+        .headers()
+        .get_all("Sec-WebSocket-Protocol")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|p| p.trim().eq_ignore_ascii_case(SIP_SUBPROTOCOL));
+    if !offered {
+        let mut error = ErrorResponse::new(Some("sip subprotocol required".to_string()));
+        *error.status_mut() = StatusCode::BAD_REQUEST;
+        return Err(error);
+    }
+    response.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static(SIP_SUBPROTOCOL),
+    );
+    Ok(response)
+}
+
 #[async_trait::async_trait]
 impl StreamConnection for WebSocketConnection {
     fn get_addr(&self) -> &SipAddr {
         &self.inner.local_addr
     }
 
+    fn keepalive(&self) -> &KeepaliveState {
+        &self.inner.keepalive
+    }
+
+    fn max_message_size(&self) -> &AtomicUsize {
+        &self.inner.max_message_size
+    }
+
     async fn send_message(&self, msg: SipMessage) -> Result<()> {
         let data = msg.to_string();
         let mut sink = self.inner.ws_sink.lock().await;
-        info!("WebSocket send:{}",data);
-        sink.send(Message::Text(data.into())).await?;
-        Ok(())
+        info!("WebSocket send:{}", data);
+        sink.send(Message::Text(data.into()))
+            .await
+            .map_err(|e| self.send_error(e))
     }
 
     async fn send_raw(&self, data: &[u8]) -> Result<()> {
         let mut sink = self.inner.ws_sink.lock().await;
-        sink.send(Message::Binary(data.to_vec().into())).await?;
-        Ok(())
+        sink.send(Message::Binary(data.to_vec().into()))
+            .await
+            .map_err(|e| self.send_error(e))
     }
 
     async fn serve_loop(&self, sender: TransportSender) -> Result<()> {
         let sip_connection = SipConnection::WebSocket(self.clone());
-        let remote_addr = self.inner.remote_addr.clone().unwrap().clone();
+        let remote_addr = self
+            .inner
+            .remote_addr
+            .clone()
+            .ok_or(crate::Error::Transport {
+                kind: TransportErrorKind::Other,
+                addr: self.inner.local_addr.clone(),
+                source: "websocket connection without remote address".to_string(),
+            })?;
+        let received = remote_addr.get_socketaddr()?;
         let mut ws_read = self.inner.ws_read.lock().await;
         while let Some(msg) = ws_read.next().await {
-            match msg {
-                Ok(Message::Text(text)) => {
-                    match SipMessage::try_from(text.as_str()) {
-                        Ok(sip_msg) => {
-                            if let Err(e) =
-                                sender.send(TransportEvent::Incoming(
-                                    sip_msg,
-                                    sip_connection.clone(),
-                                    remote_addr.clone(),
-                                ))
-                            {
-                                error!("Error sending incoming message: {:?}", e);
-                                break;
-                            }
-                        }
-                        Err(e) => {
-                            warn!("Error parsing SIP message: {}", e);
-                        }
-                    }
-                }
+            // each frame carries exactly one SIP message (RFC 7118 5.3)
+            let data = match msg {
+                Ok(Message::Text(text)) => text.as_bytes().to_vec(),
                 Ok(Message::Binary(bin)) => {
                     if bin == KEEPALIVE_REQUEST {
-                        if let Err(e) =
-                            self.send_raw(KEEPALIVE_RESPONSE).await
-                        {
+                        if let Err(e) = self.send_raw(KEEPALIVE_RESPONSE).await {
                             error!("Error sending keepalive response: {:?}", e);
                         }
                         continue;
                     }
-
-                    match std::str::from_utf8(&bin) {
-                        Ok(text) => match SipMessage::try_from(text) {
-                            Ok(sip_msg) => {
-                                if let Err(e) =
-                                    sender.send(TransportEvent::Incoming(
-                                        sip_msg,
-                                        sip_connection.clone(),
-                                        remote_addr.clone(),
-                                    ))
-                                {
-                                    error!(
-                                        "Error sending incoming message: {:?}",
-                                        e
-                                    );
-                                    break;
-                                }
-                            }
-                            Err(e) => {
-                                warn!("Error parsing SIP message: {}", e);
-                            }
-                        },
-                        Err(e) => {
-                            warn!("Error decoding binary message: {}", e);
-                        }
+                    if bin == KEEPALIVE_RESPONSE {
+                        self.inner.keepalive.on_pong();
+                        continue;
                     }
+                    bin.to_vec()
                 }
                 Ok(Message::Ping(data)) => {
                     let mut sink = self.inner.ws_sink.lock().await;
@@ -239,21 +349,47 @@ impl StreamConnection for WebSocketConnection {
                         error!("Error sending pong: {}", e);
                         break;
                     }
+                    continue;
                 }
                 Ok(Message::Close(_)) => {
-                    debug!("WebSocket connection closed by peer");
+                    debug!("WebSocket connection closed by peer: {}", remote_addr);
                     break;
                 }
                 Err(e) => {
                     error!("WebSocket error: {}", e);
                     break;
                 }
-                _ => {}
+                _ => continue,
+            };
+            if data.iter().all(|b| b.is_ascii_whitespace()) {
+                continue;
             }
-        }
+            if data.len() > self.inner.max_message_size.load(Ordering::Relaxed) {
+                warn!("message too large from {}, closing", remote_addr);
+                self.close().await.ok();
+                return Err(crate::Error::MessageTooLarge);
+            }
+            self.inner.keepalive.on_message();
 
-        if let Err(e) = sender.send(TransportEvent::Closed(sip_connection.clone())) {
-            error!("Error sending connection closed event: {:?}", e);
+            // the client sits behind an ephemeral port, answer where it came from
+            let sip_msg = match SipMessage::try_from(data.as_slice())
+                .map_err(|e| crate::Error::parse(e, &data))
+                .and_then(|msg| SipConnection::update_msg_received(msg, received))
+            {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Error parsing SIP message: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = sender.send(TransportEvent::Incoming(
+                sip_msg,
+                sip_connection.clone(),
+                remote_addr.clone(),
+            )) {
+                error!("Error sending incoming message: {:?}", e);
+                break;
+            }
         }
         Ok(())
     }
@@ -271,7 +407,10 @@ impl fmt::Display for WebSocketConnection {
             Some(rsip::transport::Transport::Wss) => "WSS",
             _ => "WS",
         };
-        write!(f, "{} {}", transport, self.inner.local_addr)
+        match &self.inner.remote_addr {
+            Some(remote) => write!(f, "{} {} -> {}", transport, self.inner.local_addr, remote),
+            None => write!(f, "{} {}", transport, self.inner.local_addr),
+        }
     }
 }
 